@@ -0,0 +1,38 @@
+// Expose build information used by `GET /version`.
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+
+    // Prefer `GIT_SHA` from the environment so builds without `.git` can provide it.
+    let git_sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|out| out.status.success())
+            .and_then(|out| String::from_utf8(out.stdout).ok())
+            .map(|s| s.trim().to_owned())
+    });
+    if let Some(sha) = git_sha {
+        println!("cargo:rustc-env=GIT_SHA={}", sha);
+    }
+
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    if let Some(version) = locked_version(&lock, "lsp-types") {
+        println!("cargo:rustc-env=LSP_TYPES_VERSION={}", version);
+    }
+}
+
+// Find the version of package `name` in `Cargo.lock`.
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let mut lines = lock.lines();
+    let needle = format!("name = \"{}\"", name);
+    lines.find(|line| *line == needle)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(str::to_owned)
+}
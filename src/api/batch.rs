@@ -0,0 +1,144 @@
+//! JSON-RPC batches from the client. Messages in a batch are forwarded to the server one by
+//! one because servers rarely accept batches, and the responses are sent back to the client
+//! combined in an array.
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::lsp::types::Id;
+
+// Only the fields necessary to match responses to requests.
+#[derive(serde::Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+}
+
+/// Split `text` into the texts of the messages if it's a batch. Empty arrays are not batches.
+pub fn split(text: &str) -> Option<Vec<String>> {
+    if !text.trim_start().starts_with('[') {
+        return None;
+    }
+    match serde_json::from_str::<Vec<Value>>(text) {
+        Ok(values) if !values.is_empty() => Some(values.iter().map(Value::to_string).collect()),
+        _ => None,
+    }
+}
+
+/// Ids of the requests in the messages of a batch.
+pub fn request_ids(texts: &[String]) -> Vec<Id> {
+    texts
+        .iter()
+        .filter_map(|text| match serde_json::from_str(text) {
+            Ok(Head {
+                id: Some(id),
+                method: Some(_),
+            }) => Some(id),
+            _ => None,
+        })
+        .collect()
+}
+
+struct Batch {
+    /// Ids of the requests without responses yet.
+    waiting: HashSet<Id>,
+    responses: Vec<String>,
+}
+
+/// Batches waiting for the responses to the requests in them.
+#[derive(Default)]
+pub struct Batches {
+    pending: Vec<Batch>,
+}
+
+impl Batches {
+    /// Wait for responses to the requests with `ids`.
+    /// Batches of only notifications are ignored because they don't have any response.
+    pub fn start(&mut self, ids: Vec<Id>) {
+        if !ids.is_empty() {
+            self.pending.push(Batch {
+                waiting: ids.into_iter().collect(),
+                responses: Vec::new(),
+            });
+        }
+    }
+
+    /// Collect `text` to the client if it's a response to a request in a batch.
+    /// Returns the text to send: `text` as is if it's not collected, the combined responses
+    /// if it completes the batch, or `None` while waiting for the rest.
+    pub fn collect(&mut self, text: String) -> Option<String> {
+        if self.pending.is_empty() {
+            return Some(text);
+        }
+        let id = match serde_json::from_str(&text) {
+            Ok(Head {
+                id: Some(id),
+                method: None,
+            }) => id,
+            _ => return Some(text),
+        };
+        let index = match self.pending.iter().position(|b| b.waiting.contains(&id)) {
+            Some(index) => index,
+            None => return Some(text),
+        };
+        let batch = &mut self.pending[index];
+        batch.waiting.remove(&id);
+        batch.responses.push(text);
+        if batch.waiting.is_empty() {
+            let batch = self.pending.remove(index);
+            Some(format!("[{}]", batch.responses.join(",")))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let texts = split(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"shutdown"},{"jsonrpc":"2.0","method":"exit"}]"#,
+        )
+        .unwrap();
+        assert_eq!(texts.len(), 2);
+        assert_eq!(request_ids(&texts), vec![Id::Number(1)]);
+        assert!(split("[]").is_none());
+        assert!(split(r#"{"jsonrpc":"2.0","method":"exit"}"#).is_none());
+    }
+
+    #[test]
+    fn test_collect_two_element_batch() {
+        let mut batches = Batches::default();
+        batches.start(vec![Id::Number(1), Id::String("a".to_owned())]);
+        let notification = r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#;
+        assert_eq!(
+            batches.collect(notification.to_owned()).as_deref(),
+            Some(notification)
+        );
+        assert!(batches
+            .collect(r#"{"jsonrpc":"2.0","id":"a","result":2}"#.to_owned())
+            .is_none());
+        let combined = batches
+            .collect(r#"{"jsonrpc":"2.0","id":1,"result":1}"#.to_owned())
+            .unwrap();
+        let combined: Value = serde_json::from_str(&combined).unwrap();
+        assert_eq!(combined[0]["id"], "a");
+        assert_eq!(combined[1]["id"], 1);
+        // Not in a batch anymore.
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":1}"#;
+        assert_eq!(
+            batches.collect(response.to_owned()).as_deref(),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn test_notifications_only() {
+        let mut batches = Batches::default();
+        batches.start(Vec::new());
+        assert!(batches.pending.is_empty());
+    }
+}
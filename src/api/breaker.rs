@@ -0,0 +1,150 @@
+//! Stop starting servers that keep crashing.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Circuit breaker keyed by command name. After `crashes` within `window`, servers
+/// with the name are not started for `cooldown`.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    crashes: usize,
+    window: Duration,
+    cooldown: Duration,
+    states: Arc<Mutex<HashMap<String, State>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Times of the recent crashes, oldest first.
+    crashes: VecDeque<Instant>,
+    /// Rejecting until then.
+    open_until: Option<Instant>,
+}
+
+impl State {
+    fn forget_before(&mut self, now: Instant, window: Duration) {
+        while matches!(self.crashes.front(), Some(t) if now.duration_since(*t) > window) {
+            self.crashes.pop_front();
+        }
+        if matches!(self.open_until, Some(until) if until <= now) {
+            self.open_until = None;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreakerStatus {
+    /// Crashes within the window.
+    pub recent_crashes: usize,
+    /// Seconds until servers are started again, if rejecting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(crashes: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            crashes: crashes.max(1),
+            window,
+            cooldown,
+            states: Arc::default(),
+        }
+    }
+
+    /// Record a crash of the server `name`. Returns `true` if it opened the breaker.
+    pub fn record_crash(&self, name: &str, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(name.to_owned()).or_default();
+        state.forget_before(now, self.window);
+        state.crashes.push_back(now);
+        if state.open_until.is_none() && state.crashes.len() >= self.crashes {
+            state.crashes.clear();
+            state.open_until = Some(now + self.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time left until `name` can be started again. `None` if it can be started now.
+    pub fn open_for(&self, name: &str, now: Instant) -> Option<Duration> {
+        let mut states = self.states.lock().unwrap();
+        let state = states.get_mut(name)?;
+        state.forget_before(now, self.window);
+        state.open_until.map(|until| until - now)
+    }
+
+    pub fn status(&self, name: &str, now: Instant) -> BreakerStatus {
+        let mut states = self.states.lock().unwrap();
+        match states.get_mut(name) {
+            Some(state) => {
+                state.forget_before(now, self.window);
+                BreakerStatus {
+                    recent_crashes: state.crashes.len(),
+                    retry_after: state.open_until.map(|until| retry_after(until - now)),
+                }
+            }
+            None => BreakerStatus {
+                recent_crashes: 0,
+                retry_after: None,
+            },
+        }
+    }
+}
+
+/// Seconds to wait, rounded up so that retrying after it succeeds.
+pub fn retry_after(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_after_crashes_in_window() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_secs(30));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!breaker.record_crash("ra", at(0)));
+        assert!(!breaker.record_crash("ra", at(5)));
+        // The first crash is out of the window.
+        assert!(!breaker.record_crash("ra", at(11)));
+        assert_eq!(breaker.open_for("ra", at(11)), None);
+        assert!(breaker.record_crash("ra", at(12)));
+        assert_eq!(
+            breaker.open_for("ra", at(12)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(breaker.open_for("other", at(12)), None);
+        assert_eq!(
+            breaker.status("ra", at(20)),
+            BreakerStatus {
+                recent_crashes: 0,
+                retry_after: Some(22),
+            }
+        );
+
+        // Allowed again after the cooldown.
+        assert_eq!(breaker.open_for("ra", at(42)), None);
+        assert!(!breaker.record_crash("ra", at(43)));
+        assert_eq!(
+            breaker.status("ra", at(43)),
+            BreakerStatus {
+                recent_crashes: 1,
+                retry_after: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(retry_after(Duration::from_secs(3)), 3);
+        assert_eq!(retry_after(Duration::from_millis(2001)), 3);
+        assert_eq!(retry_after(Duration::from_millis(1)), 1);
+    }
+}
@@ -0,0 +1,105 @@
+//! Capture messages to a file for reproducing bugs.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::lsp;
+
+/// Direction of a captured message.
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Record<'a> {
+    /// Milliseconds since the Unix epoch.
+    time: u128,
+    connection: u64,
+    direction: Direction,
+    /// The message as JSON, or the text if it's not valid JSON.
+    message: &'a serde_json::Value,
+}
+
+/// Appends messages forwarded by every connection to a newline-delimited JSON file.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    file: Arc<Mutex<File>>,
+    /// Redact document contents.
+    redact: bool,
+}
+
+impl Capture {
+    pub fn open(path: &Path, redact: bool) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            redact,
+        })
+    }
+
+    /// Append message `text` forwarded in `direction`.
+    pub fn record(&self, connection: u64, direction: Direction, text: &str) {
+        let text = lsp::redact::Redacted::new(text, self.redact).to_string();
+        let message = serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text));
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let record = Record {
+            time,
+            connection,
+            direction,
+            message: &message,
+        };
+        let mut line = serde_json::to_string(&record).expect("serializable record");
+        line.push('\n');
+        // Write each line at once so records from connections don't interleave.
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!("failed to capture message: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let path =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-capture-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let capture = Capture::open(&path, true).unwrap();
+        capture.record(
+            1,
+            Direction::ToServer,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.rs","languageId":"rust","version":1,"text":"fn main() {}"}}}"#,
+        );
+        capture.record(1, Direction::ToClient, "not json");
+
+        let captured = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<serde_json::Value> = captured
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["connection"], 1);
+        assert_eq!(records[0]["direction"], "to_server");
+        assert_eq!(
+            records[0]["message"]["params"]["textDocument"]["text"],
+            "<redacted 12 bytes>"
+        );
+        assert!(records[0]["time"].as_u64().unwrap() > 0);
+        assert_eq!(records[1]["direction"], "to_client");
+        // Unparsable messages are redacted entirely.
+        assert_eq!(records[1]["message"], "<redacted 8 bytes>");
+    }
+}
@@ -0,0 +1,73 @@
+//! Address of the client for logs, optionally from the headers set by a reverse proxy.
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use warp::{http::HeaderMap, Filter};
+
+use crate::listen::RemoteAddr;
+
+/// Address of the client. With `trust_proxy_headers`, from `X-Forwarded-For` or `X-Real-IP`
+/// if set, falling back to the peer of the connection. `None` for Unix sockets.
+pub fn client_addr(
+    trust_proxy_headers: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .and(warp::header::headers_cloned())
+        .map(move |remote: Option<RemoteAddr>, headers: HeaderMap| {
+            let forwarded = if trust_proxy_headers {
+                forwarded_addr(&headers)
+            } else {
+                None
+            };
+            forwarded.or_else(|| remote.map(|r| r.0.ip()))
+        })
+}
+
+// The last entry of `X-Forwarded-For` is the one added by the proxy in front. Earlier ones
+// are from the request, and can be set by the client.
+fn forwarded_addr(headers: &HeaderMap) -> Option<IpAddr> {
+    let value = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .or_else(|| headers.get("x-real-ip").and_then(|v| v.to_str().ok()))?;
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn extract(trust: bool, headers: &[(&str, &str)]) -> Option<IpAddr> {
+        let mut req = warp::test::request().extension(RemoteAddr(([10, 0, 0, 1], 4000).into()));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.filter(&client_addr(trust)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_addr() {
+        let lb = Some(IpAddr::from([10, 0, 0, 1]));
+        let client = Some(IpAddr::from([203, 0, 113, 7]));
+        let forwarded = [("x-forwarded-for", "198.51.100.1, 203.0.113.7")];
+        assert_eq!(extract(false, &forwarded).await, lb);
+        assert_eq!(extract(true, &forwarded).await, client);
+        assert_eq!(extract(true, &[("x-real-ip", "203.0.113.7")]).await, client);
+        assert_eq!(
+            extract(true, &[("x-forwarded-for", "[2001:db8::1]:443")]).await,
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(extract(true, &[("x-real-ip", "unknown")]).await, lb);
+        assert_eq!(extract(true, &[]).await, lb);
+
+        let unix = warp::test::request().filter(&client_addr(true)).await;
+        assert_eq!(unix.unwrap(), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Registry of active connections for debugging.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use tokio::sync::{mpsc, Notify};
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+use super::{authorized, json_error_response, json_response, with_context};
+
+/// Number of messages forwarded by a connection.
+#[derive(Debug, Default)]
+pub struct Counts {
+    pub to_server: AtomicU64,
+    pub to_client: AtomicU64,
+    /// Messages from the client with methods not handled by the proxy.
+    pub unknown: AtomicU64,
+}
+
+impl Counts {
+    pub fn to_server(&self) -> u64 {
+        self.to_server.load(Ordering::Relaxed)
+    }
+
+    pub fn to_client(&self) -> u64 {
+        self.to_client.load(Ordering::Relaxed)
+    }
+
+    pub fn unknown(&self) -> u64 {
+        self.unknown.load(Ordering::Relaxed)
+    }
+}
+
+struct Entry {
+    /// The command name of the server. `None` until the server is started.
+    command: Option<String>,
+    pid: Option<u32>,
+    since: Instant,
+    counts: Arc<Counts>,
+    /// Notified to terminate the connection.
+    kill: Arc<Notify>,
+    /// Notifications to send to the server, from `POST /notify-changed`.
+    notifications: mpsc::UnboundedSender<String>,
+    /// Files created by the connection, recorded with `--ephemeral`.
+    created: Vec<PathBuf>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatus {
+    pub id: u64,
+    pub command: Option<String>,
+    pub pid: Option<u32>,
+    /// Seconds since the client connected.
+    pub uptime_secs: u64,
+    pub to_server: u64,
+    pub to_client: u64,
+    pub unknown: u64,
+}
+
+/// Handles of a registered connection.
+pub struct Registration {
+    /// Counts to update while forwarding.
+    pub counts: Arc<Counts>,
+    /// Notified to terminate the connection.
+    pub kill: Arc<Notify>,
+    /// Notifications to send to the server, from `POST /notify-changed`.
+    pub notifications: mpsc::UnboundedReceiver<String>,
+}
+
+/// Active connections keyed by the connection id.
+#[derive(Clone, Default)]
+pub struct Registry {
+    connections: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl Registry {
+    /// Add a connection.
+    pub fn register(&self, id: u64) -> Registration {
+        let counts = Arc::new(Counts::default());
+        let kill = Arc::new(Notify::new());
+        let (notifications, notifications_rx) = mpsc::unbounded_channel();
+        self.connections.lock().unwrap().insert(
+            id,
+            Entry {
+                command: None,
+                pid: None,
+                since: Instant::now(),
+                counts: counts.clone(),
+                kill: kill.clone(),
+                notifications,
+                created: Vec::new(),
+            },
+        );
+        Registration {
+            counts,
+            kill,
+            notifications: notifications_rx,
+        }
+    }
+
+    /// Record the server started for the connection.
+    pub fn set_server(&self, id: u64, command: &str, pid: Option<u32>) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.command = Some(command.to_owned());
+            entry.pid = pid;
+        }
+    }
+
+    /// Record the file at absolute `path` created by the connection.
+    pub fn record_created(&self, id: u64, path: PathBuf) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.created.push(path);
+        }
+    }
+
+    /// Remove the connection. Returns the files recorded with `record_created`.
+    pub fn remove(&self, id: u64) -> Vec<PathBuf> {
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|entry| entry.created)
+            .unwrap_or_default()
+    }
+
+    /// Request the connection to terminate. Returns `false` if not found.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(entry) => {
+                // Stores a permit if the connection isn't waiting yet.
+                entry.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send the notification `text` to the server of every connection.
+    /// Returns the number of connections it was sent to.
+    pub fn broadcast(&self, text: &str) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.notifications.send(text.to_owned()).is_ok())
+            .count()
+    }
+
+    pub fn list(&self) -> Vec<ConnectionStatus> {
+        let mut list: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| ConnectionStatus {
+                id: *id,
+                command: entry.command.clone(),
+                pid: entry.pid,
+                uptime_secs: entry.since.elapsed().as_secs(),
+                to_server: entry.counts.to_server(),
+                to_client: entry.counts.to_client(),
+                unknown: entry.counts.unknown(),
+            })
+            .collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    pub connections: Registry,
+    /// Required as `Authorization: Bearer <token>` if set, from `--api-token`.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Connections {
+    connections: Vec<ConnectionStatus>,
+}
+
+/// Handler for `GET /connections`
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("connections"))
+        .and(warp::path::end())
+        .and(authorized(ctx.token.clone()))
+        .and(with_context(ctx))
+        .and_then(handle_list)
+}
+
+/// Handler for `DELETE /connections/{id}`. Only mounted with a token.
+pub fn delete(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::delete()
+        .and(warp::path!("connections" / u64))
+        .and(authorized(ctx.token.clone()))
+        .and(with_context(ctx))
+        .and_then(handle_delete)
+}
+
+async fn handle_list(ctx: Context) -> Result<impl Reply, Infallible> {
+    Ok(json_response(
+        &Connections {
+            connections: ctx.connections.list(),
+        },
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_delete(id: u64, ctx: Context) -> Result<reply::Response, Infallible> {
+    if ctx.connections.kill(id) {
+        tracing::info!("terminating connection {} on request", id);
+        Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response())
+    } else {
+        Ok(json_error_response(
+            format!("connection {} not found", id),
+            StatusCode::NOT_FOUND,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::default();
+        let Registration {
+            counts,
+            mut notifications,
+            ..
+        } = registry.register(2);
+        let _registration = registry.register(1);
+        registry.set_server(2, "rust-analyzer", Some(42));
+        counts.to_server.fetch_add(3, Ordering::Relaxed);
+
+        let list = registry.list();
+        assert_eq!(list.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(list[1].command.as_deref(), Some("rust-analyzer"));
+        assert_eq!(list[1].pid, Some(42));
+        assert_eq!(list[1].to_server, 3);
+
+        assert_eq!(registry.broadcast("{}"), 2);
+        assert_eq!(notifications.try_recv().unwrap(), "{}");
+
+        registry.record_created(2, PathBuf::from("/tmp/a.txt"));
+        registry.record_created(3, PathBuf::from("/tmp/b.txt"));
+        assert_eq!(registry.remove(2), vec![PathBuf::from("/tmp/a.txt")]);
+        assert!(registry.remove(3).is_empty());
+        assert_eq!(registry.list().len(), 1);
+        assert!(!registry.kill(2));
+        assert!(registry.kill(1));
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let ctx = Context {
+            connections: Registry::default(),
+            token: Some("secret".to_owned()),
+        };
+        let _registration = ctx.connections.register(1);
+        let filter = handler(ctx.clone())
+            .or(delete(ctx.clone()))
+            .recover(super::super::recover);
+
+        let res = warp::test::request()
+            .path("/connections")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/connections/1")
+            .header("authorization", "Bearer wrong")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(ctx.connections.list().len(), 1);
+
+        let res = warp::test::request()
+            .path("/connections")
+            .header("authorization", "Bearer secret")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/connections/1")
+            .header("authorization", "Bearer secret")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // Without a token, the list is open.
+        let res = warp::test::request()
+            .path("/connections")
+            .reply(&handler(Context {
+                connections: ctx.connections.clone(),
+                token: None,
+            }))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,130 @@
+//! Built-in fake server for testing clients without a Language Server.
+use std::sync::{atomic::Ordering, Arc};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::Notify;
+
+use super::{
+    connections::Counts,
+    proxy::{CloseReason, ConnError},
+};
+
+/// Respond to requests until the client disconnects. `initialize` gets minimal
+/// capabilities and any other request gets an empty successful response.
+pub async fn serve<S, R>(
+    mut ws_send: S,
+    mut ws_recv: R,
+    counts: Arc<Counts>,
+    kill: Arc<Notify>,
+) -> Result<(), ConnError>
+where
+    S: Sink<warp::ws::Message> + Unpin,
+    S::Error: std::fmt::Display,
+    R: Stream<Item = Result<warp::ws::Message, warp::Error>> + Unpin,
+{
+    tracing::info!("echoing requests");
+    loop {
+        tokio::select! {
+            msg = ws_recv.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    let text = msg.to_str().expect("text");
+                    tracing::debug!("-> {}", text);
+                    counts.to_server.fetch_add(1, Ordering::Relaxed);
+                    if let Some(res) = respond(text) {
+                        tracing::debug!("<- {}", res);
+                        send(&mut ws_send, warp::ws::Message::text(res)).await?;
+                        counts.to_client.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Some(Ok(msg)) if msg.is_close() => {
+                    tracing::info!("received Close message");
+                }
+                // Ignore any other message types
+                Some(Ok(_)) => {}
+                Some(Err(err)) => {
+                    tracing::error!("websocket error: {}", err);
+                }
+                None => {
+                    tracing::info!("connection closed");
+                    break;
+                }
+            },
+
+            _ = kill.notified() => {
+                tracing::warn!("terminating connection on request");
+                send(&mut ws_send, CloseReason::Killed.message()).await?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send<S>(ws_send: &mut S, msg: warp::ws::Message) -> Result<(), ConnError>
+where
+    S: Sink<warp::ws::Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    ws_send
+        .send(msg)
+        .await
+        .map_err(|err| ConnError::Send(err.to_string()))
+}
+
+// The response for `text` if it's a request. Notifications, responses, and
+// invalid messages are ignored.
+fn respond(text: &str) -> Option<String> {
+    let msg = serde_json::from_str::<serde_json::Value>(text).ok()?;
+    let id = msg.get("id")?;
+    let result = match msg.get("method")?.as_str()? {
+        "initialize" => serde_json::json!({
+            "capabilities": {},
+            "serverInfo": {
+                "name": "echo",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        }),
+        _ => serde_json::Value::Null,
+    };
+    let res = serde_json::json!({"jsonrpc": "2.0", "id": id, "result": result});
+    Some(res.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond_value(text: &str) -> Option<serde_json::Value> {
+        respond(text).map(|res| serde_json::from_str(&res).unwrap())
+    }
+
+    #[test]
+    fn test_initialize() {
+        let res = respond_value(
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+        )
+        .unwrap();
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["result"]["capabilities"], serde_json::json!({}));
+        assert_eq!(res["result"]["serverInfo"]["name"], "echo");
+    }
+
+    #[test]
+    fn test_other_requests() {
+        let res = respond_value(r#"{"jsonrpc":"2.0","id":"a","method":"shutdown"}"#).unwrap();
+        assert_eq!(
+            res,
+            serde_json::json!({"jsonrpc": "2.0", "id": "a", "result": null})
+        );
+    }
+
+    #[test]
+    fn test_ignored() {
+        assert_eq!(
+            respond(r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#),
+            None
+        );
+        assert_eq!(respond(r#"{"jsonrpc":"2.0","id":1,"result":null}"#), None);
+        assert_eq!(respond("not json"), None);
+    }
+}
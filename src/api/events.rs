@@ -0,0 +1,133 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::broadcast;
+use warp::{Filter, Rejection, Reply};
+
+use super::{authorized, with_context};
+use crate::lsp::types::Id;
+
+/// Connection events for observability. Must never include document contents.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A client connected.
+    Connected { connection: u64 },
+    /// A client disconnected.
+    Disconnected { connection: u64 },
+    /// The Language Server for the connection was started.
+    ServerSpawned {
+        connection: u64,
+        command: String,
+        pid: Option<u32>,
+    },
+    /// The Language Server exited while the client was still connected.
+    ServerCrashed { connection: u64, command: String },
+    /// Number of messages forwarded so far. Sent periodically and on disconnect.
+    MessageForwarded {
+        connection: u64,
+        to_server: u64,
+        to_client: u64,
+    },
+    /// The client sent a method not handled by the proxy for the first time on the connection.
+    UnknownMethod { connection: u64, method: String },
+    /// The server reported `$/progress` for the `workDoneToken` of a request from the client.
+    Progress {
+        connection: u64,
+        token: lsp_types::ProgressToken,
+        method: String,
+        id: Id,
+        kind: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        percentage: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub events: broadcast::Sender<Event>,
+    /// Required as `Authorization: Bearer <token>` if set, from `--api-token`.
+    pub token: Option<String>,
+}
+
+/// Handler for `GET /events` WebSocket streaming `Event`s as JSON.
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path("events")
+        .and(warp::path::end())
+        .and(authorized(ctx.token.clone()))
+        .and(warp::ws())
+        .and(with_context(ctx))
+        .map(|ws: warp::ws::Ws, ctx: Context| {
+            let events = ctx.events.subscribe();
+            ws.on_upgrade(move |socket| stream_events(socket, events))
+        })
+}
+
+async fn stream_events(ws: warp::ws::WebSocket, mut events: broadcast::Receiver<Event>) {
+    tracing::debug!("events subscriber connected");
+    let (mut ws_send, mut ws_recv) = ws.split();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let text = serde_json::to_string(&event).expect("serializable event");
+                    if ws_send.send(warp::ws::Message::text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("events subscriber lagged, skipped {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+
+            // Incoming messages are ignored. Stop when the subscriber disconnects.
+            msg = ws_recv.next() => match msg {
+                Some(Ok(msg)) if !msg.is_close() => {}
+                _ => break,
+            },
+        }
+    }
+    tracing::debug!("events subscriber disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token() {
+        let (events, _) = broadcast::channel(1);
+        let filter = handler(Context {
+            events: events.clone(),
+            token: Some("secret".to_owned()),
+        });
+
+        assert!(warp::test::ws()
+            .path("/events")
+            .handshake(filter.clone())
+            .await
+            .is_err());
+        assert!(warp::test::ws()
+            .path("/events")
+            .header("authorization", "Bearer wrong")
+            .handshake(filter.clone())
+            .await
+            .is_err());
+
+        let mut client = warp::test::ws()
+            .path("/events")
+            .header("authorization", "Bearer secret")
+            .handshake(filter)
+            .await
+            .expect("handshake");
+        // Wait for the subscription before sending.
+        while events.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        events.send(Event::Connected { connection: 1 }).unwrap();
+        let msg = client.recv().await.unwrap();
+        assert_eq!(
+            msg.to_str().unwrap(),
+            r#"{"event":"connected","connection":1}"#
+        );
+    }
+}
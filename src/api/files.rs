@@ -1,21 +1,34 @@
 use std::{
     convert::Infallible,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 
+use bytes::Buf;
+use futures_util::{Stream, StreamExt};
+use ignore::WalkBuilder;
 use lsp_types::{FileChangeType, FileEvent};
 use thiserror::Error;
-use tokio::fs;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use tokio_util::io::StreamReader;
 use url::Url;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
-use super::{json_body, json_response, with_context};
+use crate::lsp::ext::resolves_under;
+
+use super::{
+    connections::Registry, json_body, json_error_response, json_response, sync::LineEnding,
+    with_compression, with_context,
+};
 
 #[derive(Debug, Error)]
 enum Error {
     #[error("{0} is not under the project root")]
     NotProjectPath(String),
 
+    #[error("parent directory of {0} does not exist")]
+    MissingParent(String),
+
     #[error("failed to create dirs {path}: {source}")]
     CreateDirs {
         path: String,
@@ -40,6 +53,9 @@ enum Error {
         to: String,
         source: std::io::Error,
     },
+
+    #[error("failed to read directory {path}: {source}")]
+    ReadDir { path: String, source: ignore::Error },
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -60,7 +76,7 @@ enum Operation {
     /// Write `contents` to a file at relative `path`.
     ///
     /// This will create a file if it does not exist, and will replace its contents if it does.
-    /// Any missing directories are also created.
+    /// Any missing directories are also created unless disabled.
     Write { path: String, contents: String },
 
     /// Remove a file at relative `path`.
@@ -71,14 +87,28 @@ enum Operation {
 
     /// Rename a file or directory at relative path `from` to `to`.
     ///
-    /// Any missing directories are created.
+    /// Any missing directories are created unless disabled.
     /// Any empty parent directories under `cwd` as a result of renaming are removed.
     Rename { from: String, to: String },
+
+    /// Write the body of `PUT /files/{path}` to relative `path`.
+    /// Only used to report errors because the contents are not in JSON.
+    #[serde(skip_deserializing)]
+    Upload { path: String },
 }
 
 impl Operation {
     /// Perform operation relative to `cwd`.
-    async fn perform<P>(&self, cwd: P, remap: bool) -> Result<Vec<FileEvent>, Error>
+    /// Fails if a parent directory is missing unless `create_dirs` is set.
+    /// Line endings of written `contents` are rewritten to `eol`.
+    async fn perform<P>(
+        &self,
+        cwd: P,
+        remap: bool,
+        prefix: Option<&str>,
+        create_dirs: bool,
+        eol: LineEnding,
+    ) -> Result<Vec<FileEvent>, Error>
     where
         P: AsRef<Path>,
     {
@@ -86,17 +116,19 @@ impl Operation {
             Operation::Write { path, contents } => {
                 let apath = get_path(&cwd, path)?;
                 tracing::debug!("writing file {:?}", path);
-                create_parent_dirs(&cwd, path).await?;
-                let create = !apath.exists();
-                fs::write(&apath, contents.as_bytes())
+                create_parent_dirs(&cwd, path, create_dirs).await?;
+                let write_error = |source| Error::WriteFile {
+                    path: path.to_owned(),
+                    source,
+                };
+                let (mut file, create) = open_for_write(&apath).await.map_err(write_error)?;
+                file.write_all(eol.normalize(contents).as_bytes())
                     .await
-                    .map_err(|source| Error::WriteFile {
-                        path: path.to_owned(),
-                        source,
-                    })?;
+                    .map_err(write_error)?;
+                file.flush().await.map_err(write_error)?;
 
                 Ok(vec![FileEvent::new(
-                    path_uri(&cwd, path, false, remap),
+                    path_uri(&cwd, path, false, remap, prefix),
                     if create {
                         FileChangeType::Created
                     } else {
@@ -117,7 +149,7 @@ impl Operation {
                 remove_empty_parents(&cwd, path).await;
 
                 Ok(vec![FileEvent::new(
-                    path_uri(&cwd, path, false, remap),
+                    path_uri(&cwd, path, false, remap, prefix),
                     FileChangeType::Deleted,
                 )])
             }
@@ -127,7 +159,7 @@ impl Operation {
                 let dst = get_path(&cwd, to)?;
 
                 tracing::debug!("renaming file {:?} to {:?}", from, to);
-                create_parent_dirs(&cwd, to).await?;
+                create_parent_dirs(&cwd, to, create_dirs).await?;
                 let create = !dst.exists();
                 fs::rename(&src, &dst)
                     .await
@@ -140,9 +172,12 @@ impl Operation {
 
                 let is_dir = dst.is_dir();
                 Ok(vec![
-                    FileEvent::new(path_uri(&cwd, from, is_dir, remap), FileChangeType::Deleted),
                     FileEvent::new(
-                        path_uri(&cwd, to, is_dir, remap),
+                        path_uri(&cwd, from, is_dir, remap, prefix),
+                        FileChangeType::Deleted,
+                    ),
+                    FileEvent::new(
+                        path_uri(&cwd, to, is_dir, remap, prefix),
                         if create {
                             FileChangeType::Created
                         } else {
@@ -151,27 +186,137 @@ impl Operation {
                     ),
                 ])
             }
+
+            Operation::Upload { path } => {
+                unreachable!("uploading {} requires the body", path)
+            }
         }
     }
 }
 
+/// Write chunks of `body` to a file at relative `path` without buffering all of them.
+/// Otherwise the same as `Operation::Write`.
+async fn write_stream<P, S, B>(
+    cwd: P,
+    path: &str,
+    body: S,
+    remap: bool,
+    prefix: Option<&str>,
+    create_dirs: bool,
+) -> Result<Vec<FileEvent>, Error>
+where
+    P: AsRef<Path>,
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    let apath = get_path(&cwd, path)?;
+    tracing::debug!("writing file {:?} from stream", path);
+    create_parent_dirs(&cwd, path, create_dirs).await?;
+    let write_error = |source| Error::WriteFile {
+        path: path.to_owned(),
+        source,
+    };
+    let (mut file, create) = open_for_write(&apath).await.map_err(write_error)?;
+    let mut reader = StreamReader::new(body.map(|chunk| {
+        chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))
+    }));
+    tokio::io::copy(&mut reader, &mut file)
+        .await
+        .map_err(write_error)?;
+    // Wait for the last write to complete.
+    file.flush().await.map_err(write_error)?;
+
+    Ok(vec![FileEvent::new(
+        path_uri(&cwd, path, false, remap, prefix),
+        if create {
+            FileChangeType::Created
+        } else {
+            FileChangeType::Changed
+        },
+    )])
+}
+
+/// Open `path` for writing, truncating any contents, and whether it was created.
+/// Checking if it exists first races with concurrent requests writing the same path,
+/// so creating is attempted atomically instead.
+pub(super) async fn open_for_write(path: &Path) -> Result<(fs::File, bool), std::io::Error> {
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+    {
+        Ok(file) => Ok((file, true)),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .await?;
+            Ok((file, false))
+        }
+        Err(err) => Err(err),
+    }
+}
+
 fn get_path<P>(cwd: P, path: &str) -> Result<PathBuf, Error>
 where
     P: AsRef<Path>,
 {
     let apath = cwd.as_ref().join(path);
-    if !apath.starts_with(&cwd) {
+    // `starts_with` compares components, so `..` must be rejected separately.
+    let escapes = Path::new(path)
+        .components()
+        .any(|c| matches!(c, Component::ParentDir));
+    if escapes || !apath.starts_with(&cwd) || !resolves_under(cwd.as_ref(), &apath) {
         return Err(Error::NotProjectPath(path.to_owned()));
     }
     Ok(apath)
 }
 
-async fn create_parent_dirs<P, Q>(cwd: P, path: Q) -> Result<(), Error>
+/// Check if absolute `path` is under `cwd`.
+pub(super) fn is_project_path(cwd: &Path, path: &Path) -> bool {
+    matches!(path.to_str(), Some(path) if get_path(cwd, path).is_ok())
+}
+
+#[test]
+fn test_get_path() {
+    assert!(get_path("/tmp", "foo/bar.js").is_ok());
+    assert!(get_path("/tmp", "").is_ok());
+    assert!(get_path("/tmp", "/etc/passwd").is_err());
+    assert!(get_path("/tmp", "..").is_err());
+    assert!(get_path("/tmp", "foo/../../etc").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_get_path_symlink() {
+    let dir = TempDir::new("symlink");
+    let project = dir.path().join("project");
+    std::fs::create_dir_all(&project).unwrap();
+    std::os::unix::fs::symlink(dir.path(), project.join("outside")).unwrap();
+    assert!(get_path(&project, "outside").is_err());
+    assert!(get_path(&project, "outside/foo.js").is_err());
+    assert!(get_path(&project, "foo.js").is_ok());
+}
+
+async fn create_parent_dirs<P, Q>(cwd: P, path: Q, create: bool) -> Result<(), Error>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
     if let Some(parent) = path.as_ref().parent() {
+        if !create {
+            return if cwd.as_ref().join(parent).is_dir() {
+                Ok(())
+            } else {
+                Err(Error::MissingParent(
+                    path.as_ref().to_str().expect("utf-8").to_owned(),
+                ))
+            };
+        }
+
         tracing::debug!("creating directories for {:?}", path.as_ref());
         fs::create_dir_all(cwd.as_ref().join(parent))
             .await
@@ -201,13 +346,39 @@ where
     }
 }
 
-fn path_uri<P>(cwd: P, path: &str, is_dir: bool, remap: bool) -> Url
+/// Remove files at absolute `paths` created by a connection with `--ephemeral`.
+/// Any empty parent directories under `cwd` are also removed.
+pub(super) async fn remove_created(cwd: &Path, paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path).await {
+            Ok(()) => tracing::debug!("removed created file {:?}", path),
+            // Already removed or renamed by the client.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                tracing::warn!("failed to remove created file {:?}: {}", path, err);
+                continue;
+            }
+        }
+        if let Ok(relative) = path.strip_prefix(cwd) {
+            remove_empty_parents(cwd, relative).await;
+        }
+    }
+}
+
+pub(super) fn path_uri<P>(
+    cwd: P,
+    path: &str,
+    is_dir: bool,
+    remap: bool,
+    prefix: Option<&str>,
+) -> Url
 where
     P: AsRef<Path>,
 {
     if remap {
         let uri = format!(
-            "source://{}{}",
+            "source://{}{}{}",
+            prefix.map(|p| format!("{}/", p)).unwrap_or_default(),
             path,
             if is_dir && !path.ends_with('/') {
                 "/"
@@ -233,21 +404,25 @@ fn test_path_uri() {
     let is_dir = true;
     let remap = true;
     assert_eq!(
-        path_uri(cwd, path, is_dir, remap).to_string(),
+        path_uri(cwd, path, is_dir, remap, None).to_string(),
         "source://foo/"
     );
     assert_eq!(
-        path_uri(cwd, path, !is_dir, remap).to_string(),
+        path_uri(cwd, path, !is_dir, remap, None).to_string(),
         "source://foo"
     );
     assert_eq!(
-        path_uri(cwd, path, is_dir, !remap).to_string(),
+        path_uri(cwd, path, is_dir, !remap, None).to_string(),
         "file:///tmp/foo/"
     );
     assert_eq!(
-        path_uri(cwd, path, !is_dir, !remap).to_string(),
+        path_uri(cwd, path, !is_dir, !remap, None).to_string(),
         "file:///tmp/foo"
     );
+    assert_eq!(
+        path_uri(cwd, path, !is_dir, remap, Some("project")).to_string(),
+        "source://project/foo"
+    );
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -265,30 +440,223 @@ struct OperationError {
     reason: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Context {
     pub cwd: PathBuf,
     pub remap: bool,
+    /// Name prepended to the paths of `source://` URIs.
+    pub remap_prefix: Option<String>,
+    /// Reject any operations modifying files.
+    pub readonly: bool,
+    /// Create missing parent directories when writing.
+    pub create_dirs: bool,
+    /// Line endings to rewrite `contents` to when writing.
+    pub normalize_eol: LineEnding,
+    /// Record files created for a connection to remove them on disconnect.
+    pub ephemeral: bool,
+    pub connections: Registry,
+    /// Held while modifying files to serialize requests with `--serialize-files`.
+    pub mutations: Option<Arc<Mutex<()>>>,
+    /// Limit of bodies of `POST /files`.
+    pub body_limit: u64,
 }
 
-/// Handler for `POST /files`
+impl Context {
+    // Record the file at relative `path` if it was created for `connection` with `--ephemeral`.
+    fn record_created(&self, connection: Option<u64>, path: &str, changes: &[FileEvent]) {
+        if let (true, Some(connection)) = (self.ephemeral, connection) {
+            if changes.iter().any(|c| c.typ == FileChangeType::Created) {
+                self.connections
+                    .record_created(connection, self.cwd.join(path));
+            }
+        }
+    }
+}
+
+/// Handler for `GET /files`, `POST /files`, and `PUT /files/{path}`
 pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::post()
+    // Listings can be large. Operations are not compressed because they read the body.
+    let list = with_compression(
+        warp::get()
+            .and(warp::path("files"))
+            .and(warp::path::end())
+            .and(with_context(ctx.clone()))
+            .and(warp::query::<ListQuery>())
+            .and_then(handle_list),
+    );
+    let operations = warp::post()
         .and(warp::path("files"))
         .and(warp::path::end())
+        .and(with_context(ctx.clone()))
+        .and(warp::query::<OwnerQuery>())
+        .and(json_body::<Payload>(ctx.body_limit))
+        .and_then(handle_operations);
+    // Not limited by size because the body is streamed to the file.
+    let upload = warp::put()
+        .and(warp::path("files"))
+        .and(warp::path::tail())
         .and(with_context(ctx))
-        .and(json_body::<Payload>())
-        .and_then(handle_operations)
+        .and(warp::query::<OwnerQuery>())
+        .and(warp::body::stream())
+        .and_then(handle_upload);
+    list.or(operations).or(upload)
+}
+
+/// Query of `POST /files` and `PUT /files/{path}`.
+#[derive(Debug, serde::Deserialize)]
+struct OwnerQuery {
+    /// Id of the connection the files are written for. Created files are removed when it
+    /// disconnects with `--ephemeral`.
+    connection: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ListQuery {
+    /// Directory to list, relative to `cwd`. Defaults to the project root.
+    #[serde(default)]
+    path: String,
+    /// List all entries under `path` instead of just its direct children.
+    #[serde(default)]
+    recursive: bool,
+    /// Include entries excluded by ignore files.
+    #[serde(default)]
+    all: bool,
+}
+
+/// An entry in the response of `GET /files`.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// File name.
+    name: String,
+    /// Path relative to `cwd`.
+    path: String,
+    /// `file://` URI, or `source://` URI if `remap` is enabled.
+    uri: Url,
+    is_dir: bool,
+    /// Size in bytes. Always 0 for directories.
+    size: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ListResponse {
+    entries: Vec<Entry>,
+}
+
+/// List entries under relative directory `path`.
+///
+/// Paths excluded by ignore files (`.gitignore`, `.ignore`, etc.) are skipped unless `all` is set.
+fn list_entries(
+    cwd: &Path,
+    path: &str,
+    recursive: bool,
+    all: bool,
+    remap: bool,
+    prefix: Option<&str>,
+) -> Result<Vec<Entry>, Error> {
+    let root = get_path(cwd, path)?;
+    let walker = WalkBuilder::new(&root)
+        .max_depth(if recursive { None } else { Some(1) })
+        .hidden(false)
+        .parents(!all)
+        .ignore(!all)
+        .git_ignore(!all)
+        .git_global(!all)
+        .git_exclude(!all)
+        // Synchronized projects are usually not Git repositories.
+        .require_git(false)
+        .build();
+
+    let mut entries = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|source| Error::ReadDir {
+            path: path.to_owned(),
+            source,
+        })?;
+        // Skip the directory being listed.
+        if entry.depth() == 0 {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // The entry might have been removed since reading the directory.
+            Err(_) => continue,
+        };
+        let rel = match entry.path().strip_prefix(cwd).ok().and_then(|p| p.to_str()) {
+            Some(rel) => rel.to_owned(),
+            None => continue,
+        };
+        let is_dir = metadata.is_dir();
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            uri: path_uri(cwd, &rel, is_dir, remap, prefix),
+            path: rel,
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+#[tracing::instrument(level = "debug", skip(ctx))]
+async fn handle_list(ctx: Context, query: ListQuery) -> Result<impl Reply, Infallible> {
+    let listed = tokio::task::spawn_blocking(move || {
+        list_entries(
+            &ctx.cwd,
+            &query.path,
+            query.recursive,
+            query.all,
+            ctx.remap,
+            ctx.remap_prefix.as_deref(),
+        )
+    })
+    .await
+    .expect("listing task panicked");
+    match listed {
+        Ok(entries) => Ok(json_response(&ListResponse { entries }, StatusCode::OK)),
+        Err(err @ Error::NotProjectPath(_)) => {
+            Ok(json_error_response(err.to_string(), StatusCode::FORBIDDEN))
+        }
+        Err(err) => Ok(json_error_response(err.to_string(), StatusCode::NOT_FOUND)),
+    }
 }
 
 #[tracing::instrument(level = "debug", skip(ctx, payload))]
-async fn handle_operations(ctx: Context, payload: Payload) -> Result<impl Reply, Infallible> {
+async fn handle_operations(
+    ctx: Context,
+    owner: OwnerQuery,
+    payload: Payload,
+) -> Result<impl Reply, Infallible> {
+    if ctx.readonly {
+        return Ok(json_error_response(
+            "file operations are disabled in read-only mode",
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
     let mut errors = Vec::new();
     let mut changes = Vec::new();
-    // Do them one by one in order
+    // Do them one by one in order, and without other requests in between if serialized.
+    let _guard = match &ctx.mutations {
+        Some(mutations) => Some(mutations.lock().await),
+        None => None,
+    };
     for op in payload.operations {
-        match op.perform(&ctx.cwd, ctx.remap).await {
+        match op
+            .perform(
+                &ctx.cwd,
+                ctx.remap,
+                ctx.remap_prefix.as_deref(),
+                ctx.create_dirs,
+                ctx.normalize_eol,
+            )
+            .await
+        {
             Ok(mut events) => {
+                if let Operation::Write { path, .. } = &op {
+                    ctx.record_created(owner.connection, path, &events);
+                }
                 changes.append(&mut events);
             }
             Err(err) => {
@@ -307,3 +675,355 @@ async fn handle_operations(ctx: Context, payload: Payload) -> Result<impl Reply,
     };
     Ok(json_response(&Response { changes, errors }, status))
 }
+
+#[tracing::instrument(level = "debug", skip(ctx, tail, body))]
+async fn handle_upload<S, B>(
+    tail: warp::path::Tail,
+    ctx: Context,
+    owner: OwnerQuery,
+    body: S,
+) -> Result<impl Reply, Infallible>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    if ctx.readonly {
+        return Ok(json_error_response(
+            "file operations are disabled in read-only mode",
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    let path = match percent_encoding::percent_decode_str(tail.as_str()).decode_utf8() {
+        Ok(path) => path.into_owned(),
+        Err(_) => {
+            return Ok(json_error_response(
+                "path must be UTF-8",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let _guard = match &ctx.mutations {
+        Some(mutations) => Some(mutations.lock().await),
+        None => None,
+    };
+    let (changes, errors, status) = match write_stream(
+        &ctx.cwd,
+        &path,
+        body,
+        ctx.remap,
+        ctx.remap_prefix.as_deref(),
+        ctx.create_dirs,
+    )
+    .await
+    {
+        Ok(changes) => {
+            ctx.record_created(owner.connection, &path, &changes);
+            (changes, None, StatusCode::OK)
+        }
+        Err(err) => {
+            let error = OperationError {
+                operation: Operation::Upload { path },
+                reason: err.to_string(),
+            };
+            (
+                Vec::new(),
+                Some(vec![error]),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            )
+        }
+    };
+    Ok(json_response(&Response { changes, errors }, status))
+}
+
+// Temporary directory for a test, removed on drop even if the test fails.
+#[cfg(test)]
+struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+fn test_context(cwd: &Path) -> Context {
+    Context {
+        cwd: cwd.to_owned(),
+        remap: false,
+        remap_prefix: None,
+        readonly: false,
+        create_dirs: true,
+        normalize_eol: LineEnding::None,
+        ephemeral: false,
+        connections: Registry::default(),
+        mutations: None,
+        body_limit: super::JSON_BODY_LIMIT,
+    }
+}
+
+#[tokio::test]
+async fn test_upload() {
+    let dir = TempDir::new("upload");
+    let cwd = dir.path();
+    let ctx = Context {
+        remap: true,
+        normalize_eol: LineEnding::Crlf,
+        ..test_context(cwd)
+    };
+    let upload = |path: &str| {
+        warp::test::request()
+            .method("PUT")
+            .path(path)
+            .body(b"x\n".repeat(1536 * 1024))
+    };
+
+    let res = upload("/files/dir/a%20b.txt")
+        .reply(&handler(ctx.clone()))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["changes"][0]["uri"], "source://dir/a%20b.txt");
+    assert_eq!(body["changes"][0]["type"], 1);
+    // Written as is without normalizing line endings.
+    let written = std::fs::read(cwd.join("dir/a b.txt")).unwrap();
+    assert_eq!(written.len(), 3 * 1024 * 1024);
+
+    let res = upload("/files/dir/a%20b.txt")
+        .reply(&handler(ctx.clone()))
+        .await;
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["changes"][0]["type"], 2);
+
+    let res = upload("/files/dir/%2E%2E/%2E%2E/escaped.txt")
+        .reply(&handler(ctx))
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["errors"][0]["operation"]["op"], "upload");
+}
+
+#[tokio::test]
+async fn test_write_normalized() {
+    let dir = TempDir::new("eol");
+    let cwd = dir.path();
+    let op = Operation::Write {
+        path: "a.txt".to_owned(),
+        contents: "a\r\nb\nc".to_owned(),
+    };
+    op.perform(cwd, false, None, false, LineEnding::Lf)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(cwd.join("a.txt")).unwrap(),
+        "a\nb\nc"
+    );
+    op.perform(cwd, false, None, false, LineEnding::Crlf)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(cwd.join("a.txt")).unwrap(),
+        "a\r\nb\r\nc"
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_writes_create_once() {
+    let dir = TempDir::new("race");
+    let cwd = dir.path();
+    let writes = (0..16).map(|i| {
+        let cwd = cwd.to_owned();
+        tokio::spawn(async move {
+            let op = Operation::Write {
+                path: "a.txt".to_owned(),
+                contents: i.to_string(),
+            };
+            op.perform(&cwd, false, None, false, LineEnding::None).await
+        })
+    });
+    let mut created = 0;
+    for events in futures_util::future::join_all(writes).await {
+        let events = events.unwrap().unwrap();
+        if events[0].typ == FileChangeType::Created {
+            created += 1;
+        } else {
+            assert_eq!(events[0].typ, FileChangeType::Changed);
+        }
+    }
+    assert_eq!(created, 1);
+}
+
+#[tokio::test]
+async fn test_ephemeral() {
+    let dir = TempDir::new("ephemeral");
+    let cwd = dir.path();
+    std::fs::write(cwd.join("existing.txt"), "a").unwrap();
+    let ctx = Context {
+        ephemeral: true,
+        ..test_context(cwd)
+    };
+    ctx.connections.register(7);
+    let write = |path: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/files?connection=7")
+            .json(&serde_json::json!({
+                "operations": [{"op": "write", "path": path, "contents": "b"}]
+            }))
+    };
+
+    let res = write("dir/new.txt").reply(&handler(ctx.clone())).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = write("existing.txt").reply(&handler(ctx.clone())).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/files/uploaded.txt?connection=7")
+        .body("c")
+        .reply(&handler(ctx.clone()))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let created = ctx.connections.remove(7);
+    assert_eq!(
+        created,
+        vec![cwd.join("dir/new.txt"), cwd.join("uploaded.txt")]
+    );
+    remove_created(cwd, &created).await;
+    assert!(!cwd.join("dir").exists());
+    assert!(!cwd.join("uploaded.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(cwd.join("existing.txt")).unwrap(),
+        "b"
+    );
+}
+
+#[tokio::test]
+async fn test_serialize_files() {
+    let dir = TempDir::new("serialize");
+    let cwd = dir.path();
+    let mutations = Arc::new(Mutex::new(()));
+    let ctx = Context {
+        mutations: Some(mutations.clone()),
+        ..test_context(cwd)
+    };
+    // Both write `a.txt` and rename it, so interleaving them moves the wrong contents.
+    let request = |contents: &str, to: &str| {
+        let ctx = ctx.clone();
+        let body = serde_json::json!({
+            "operations": [
+                {"op": "write", "path": "a.txt", "contents": contents},
+                {"op": "rename", "from": "a.txt", "to": to},
+            ]
+        });
+        tokio::spawn(async move {
+            warp::test::request()
+                .method("POST")
+                .path("/files")
+                .json(&body)
+                .reply(&handler(ctx))
+                .await
+                .status()
+        })
+    };
+
+    let held = mutations.lock().await;
+    let first = request("1", "b.txt");
+    let second = request("2", "c.txt");
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!cwd.join("a.txt").exists());
+    drop(held);
+    assert_eq!(first.await.unwrap(), StatusCode::OK);
+    assert_eq!(second.await.unwrap(), StatusCode::OK);
+    assert!(!cwd.join("a.txt").exists());
+    assert_eq!(std::fs::read_to_string(cwd.join("b.txt")).unwrap(), "1");
+    assert_eq!(std::fs::read_to_string(cwd.join("c.txt")).unwrap(), "2");
+}
+
+#[tokio::test]
+async fn test_gzip_body() {
+    use std::io::Write;
+
+    let dir = TempDir::new("gzip");
+    let cwd = dir.path();
+    let ctx = test_context(cwd);
+    let body = serde_json::json!({
+        "operations": [{"op": "write", "path": "a.txt", "contents": "compressed"}]
+    });
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body.to_string().as_bytes()).unwrap();
+    let gzipped = encoder.finish().unwrap();
+    let post = |encoding: &str, body: Vec<u8>| {
+        warp::test::request()
+            .method("POST")
+            .path("/files")
+            .header("content-type", "application/json")
+            .header("content-encoding", encoding)
+            .body(body)
+    };
+
+    let res = post("gzip", gzipped.clone())
+        .reply(&handler(ctx.clone()).recover(super::recover))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        std::fs::read_to_string(cwd.join("a.txt")).unwrap(),
+        "compressed"
+    );
+
+    let res = post("br", gzipped)
+        .reply(&handler(ctx.clone()).recover(super::recover))
+        .await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let res = post("gzip", body.to_string().into_bytes())
+        .reply(&handler(ctx).recover(super::recover))
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_body_limit() {
+    let dir = TempDir::new("body-limit");
+    let cwd = dir.path();
+    let ctx = test_context(cwd);
+    let post = |ctx: Context| async move {
+        let contents = "x".repeat(3 * 1024 * 1024);
+        warp::test::request()
+            .method("POST")
+            .path("/files")
+            .json(&serde_json::json!({
+                "operations": [{"op": "write", "path": "large.txt", "contents": contents}]
+            }))
+            .reply(&handler(ctx).recover(super::recover))
+            .await
+    };
+
+    let res = post(ctx.clone()).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(!cwd.join("large.txt").exists());
+    let res = post(Context {
+        body_limit: 4 * 1024 * 1024,
+        ..ctx
+    })
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        std::fs::metadata(cwd.join("large.txt")).unwrap().len(),
+        3 * 1024 * 1024
+    );
+}
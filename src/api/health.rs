@@ -0,0 +1,239 @@
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+};
+
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    connections::Registry,
+    json_response,
+    pool::{Pool, ServerCommand},
+    with_context,
+};
+
+#[derive(Clone)]
+pub struct Context {
+    /// Commands to start each Language Server.
+    pub commands: Vec<ServerCommand>,
+    pub connections: Registry,
+    pub pool: Pool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HealthQuery {
+    /// Respond with stats instead of plain `OK`.
+    #[serde(default)]
+    verbose: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Stats {
+    status: &'static str,
+    /// Resident set size of the proxy. Only available on Linux.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rss_bytes: Option<u64>,
+    /// Number of active connections.
+    connections: usize,
+    /// Number of running servers, including the ones started with `--warmup`.
+    servers: usize,
+}
+
+/// Handler for `GET /`.
+///
+/// Responds with `OK`, or stats as JSON with `?verbose=true`.
+pub fn healthz(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::end())
+        .and(with_context(ctx))
+        .and(warp::query::<HealthQuery>())
+        .and_then(handle_healthz)
+}
+
+async fn handle_healthz(ctx: Context, query: HealthQuery) -> Result<impl Reply, Infallible> {
+    if !query.verbose {
+        return Ok("OK".into_response());
+    }
+
+    let connections = ctx.connections.list();
+    let servers = connections.iter().filter(|c| c.pid.is_some()).count();
+    Ok(json_response(
+        &Stats {
+            status: "OK",
+            rss_bytes: read_rss(),
+            connections: connections.len(),
+            servers: servers + ctx.pool.parked_count(),
+        },
+        StatusCode::OK,
+    ))
+}
+
+// Read the resident set size from `/proc/self/status`.
+fn read_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ServerStatus {
+    /// The command name used to select the server.
+    name: String,
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Readiness {
+    servers: Vec<ServerStatus>,
+}
+
+/// Handler for `GET /readyz`.
+///
+/// Responds with `503` if any of the registered servers can't be started.
+pub fn readyz(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .and(with_context(ctx))
+        .and_then(handle_readyz)
+}
+
+async fn handle_readyz(ctx: Context) -> Result<impl Reply, Infallible> {
+    let servers: Vec<ServerStatus> = ctx
+        .commands
+        .iter()
+        .map(|command| {
+            let name = command.name.clone();
+            let program = command.program();
+            if find_executable(program).is_some() {
+                ServerStatus {
+                    name,
+                    ready: true,
+                    reason: None,
+                }
+            } else {
+                ServerStatus {
+                    reason: Some(format!("{} was not found or is not executable", program)),
+                    name,
+                    ready: false,
+                }
+            }
+        })
+        .collect();
+
+    let status = if servers.iter().all(|s| s.ready) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(json_response(&Readiness { servers }, status))
+}
+
+/// Resolve `command` like the shell does. Paths are checked as is, and names are searched in `PATH`.
+pub(super) fn find_executable(command: &str) -> Option<PathBuf> {
+    if command.contains(std::path::MAIN_SEPARATOR) {
+        let path = PathBuf::from(command);
+        return if is_executable(&path) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(command))
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_context(commands: &[&str]) -> Context {
+        Context {
+            commands: commands
+                .iter()
+                .map(|c| ServerCommand::new(vec![(*c).to_owned()]))
+                .collect(),
+            connections: Registry::default(),
+            pool: Pool::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_healthz_verbose() {
+        let ctx = test_context(&["sh"]);
+        let _without_server = ctx.connections.register(1);
+        let _with_server = ctx.connections.register(2);
+        ctx.connections.set_server(2, "sh", Some(42));
+        let filter = healthz(ctx);
+
+        let res = warp::test::request().path("/").reply(&filter).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "OK");
+
+        let res = warp::test::request()
+            .path("/?verbose=true")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        let stats: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(stats["status"], "OK");
+        assert_eq!(stats["connections"], 2);
+        assert_eq!(stats["servers"], 1);
+        #[cfg(target_os = "linux")]
+        assert!(stats["rssBytes"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let filter = readyz(test_context(&["sh"]));
+        let res = warp::test::request().path("/readyz").reply(&filter).await;
+        assert_eq!(res.status(), 200);
+
+        let filter = readyz(test_context(&[
+            "sh",
+            "/nonexistent/lsp-ws-proxy-test-server",
+        ]));
+        let res = warp::test::request().path("/readyz").reply(&filter).await;
+        assert_eq!(res.status(), 503);
+        let readiness: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            readiness,
+            serde_json::json!({
+                "servers": [
+                    {"name": "sh", "ready": true},
+                    {
+                        "name": "lsp-ws-proxy-test-server",
+                        "ready": false,
+                        "reason": "/nonexistent/lsp-ws-proxy-test-server was not found or is not executable",
+                    },
+                ],
+            })
+        );
+    }
+}
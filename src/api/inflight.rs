@@ -0,0 +1,220 @@
+//! Limit requests from the client outstanding at the server.
+use std::collections::{HashSet, VecDeque};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lsp::{error::ErrorCode, types::Id};
+
+// Only the fields necessary to match responses to requests.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+}
+
+/// Requests sent to the server and not responded yet, and the ones waiting to be sent.
+pub struct InflightLimit {
+    max: usize,
+    pending: HashSet<Id>,
+    queued: VecDeque<(Id, String, Value)>,
+    /// `exit` held until the queued requests are sent.
+    exit: Option<String>,
+}
+
+impl InflightLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            pending: HashSet::new(),
+            queued: VecDeque::new(),
+            exit: None,
+        }
+    }
+
+    /// Returns the message `text` parsed as `value` if it can be sent now. Requests over the
+    /// limit are queued, and `exit` is held until they're sent. Anything else is returned as is.
+    pub fn request(&mut self, text: String, value: Value) -> Option<(String, Value)> {
+        match Head::deserialize(&value) {
+            Ok(Head {
+                id: Some(id),
+                method: Some(_),
+            }) => {
+                if self.queued.is_empty() && self.pending.len() < self.max {
+                    self.pending.insert(id);
+                    Some((text, value))
+                } else {
+                    self.queued.push_back((id, text, value));
+                    None
+                }
+            }
+            Ok(Head {
+                id: None,
+                method: Some(method),
+            }) if method == "exit" && !self.queued.is_empty() => {
+                self.exit = Some(text);
+                None
+            }
+            _ => Some((text, value)),
+        }
+    }
+
+    /// Removes the queued request if `value` is `$/cancelRequest` for it, and returns the
+    /// response to the client. The server never sees either.
+    pub fn cancel(&mut self, value: &Value) -> Option<Value> {
+        if self.queued.is_empty() || value.get("method")? != "$/cancelRequest" {
+            return None;
+        }
+        let id = Id::deserialize(value.get("params")?.get("id")?).ok()?;
+        let index = self
+            .queued
+            .iter()
+            .position(|(queued, _, _)| *queued == id)?;
+        self.queued.remove(index);
+        Some(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": ErrorCode::RequestCancelled.code(),
+                "message": "cancelled before it was sent to the server",
+            },
+        }))
+    }
+
+    /// Returns the held `exit` once the queued requests are sent.
+    pub fn exit(&mut self) -> Option<String> {
+        if self.queued.is_empty() {
+            self.exit.take()
+        } else {
+            None
+        }
+    }
+
+    /// `exit` from the client is still held.
+    pub fn holds_exit(&self) -> bool {
+        self.exit.is_some()
+    }
+
+    /// Returns the next queued request to send if `value` is a response to a pending request.
+    pub fn response(&mut self, value: &Value) -> Option<(String, Value)> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        match Head::deserialize(value) {
+            Ok(Head {
+                id: Some(id),
+                method: None,
+            }) if self.pending.remove(&id) => {
+                let (id, text, value) = self.queued.pop_front()?;
+                self.pending.insert(id);
+                Some((text, value))
+            }
+            _ => None,
+        }
+    }
+
+    /// Number of requests waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(id: u32) -> (String, Value) {
+        let value =
+            json!({"jsonrpc": "2.0", "id": id, "method": "textDocument/hover", "params": {}});
+        (value.to_string(), value)
+    }
+
+    fn response(id: u32) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "result": null})
+    }
+
+    #[test]
+    fn test_release_in_order() {
+        let mut limit = InflightLimit::new(2);
+        let sent: Vec<_> = (1..=5)
+            .filter_map(|id| {
+                let (text, value) = request(id);
+                limit.request(text, value)
+            })
+            .collect();
+        assert_eq!(sent, vec![request(1), request(2)]);
+        assert_eq!(limit.queued(), 3);
+
+        // Responses in any order release the queued requests in order.
+        assert_eq!(limit.response(&response(2)), Some(request(3)));
+        assert_eq!(limit.response(&response(1)), Some(request(4)));
+        assert_eq!(limit.response(&response(4)), Some(request(5)));
+        assert_eq!(limit.queued(), 0);
+        assert_eq!(limit.response(&response(3)), None);
+        assert_eq!(limit.response(&response(5)), None);
+        let (text, value) = request(6);
+        assert_eq!(limit.request(text, value), Some(request(6)));
+    }
+
+    #[test]
+    fn test_bypass() {
+        let mut limit = InflightLimit::new(1);
+        let send =
+            |limit: &mut InflightLimit, value: Value| limit.request(value.to_string(), value);
+        assert!(send(&mut limit, request(1).1).is_some());
+        assert!(send(&mut limit, request(2).1).is_none());
+        // Notifications and responses to the server are not limited.
+        let notification = json!({"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}});
+        assert!(send(&mut limit, notification).is_some());
+        assert!(send(&mut limit, response(7)).is_some());
+        // Requests from the server and unknown responses don't release.
+        let from_server = json!({"jsonrpc":"2.0","id":1,"method":"workspace/configuration"});
+        assert_eq!(limit.response(&from_server), None);
+        assert_eq!(limit.response(&response(7)), None);
+        assert_eq!(limit.response(&response(1)), Some(request(2)));
+    }
+
+    #[test]
+    fn test_cancel_queued() {
+        let mut limit = InflightLimit::new(1);
+        for id in 1..=3 {
+            let (text, value) = request(id);
+            limit.request(text, value);
+        }
+        let cancel =
+            |id: u32| json!({"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":id}});
+        // Sent requests are cancelled by the server.
+        assert_eq!(limit.cancel(&cancel(1)), None);
+        assert_eq!(
+            limit.cancel(&cancel(2)),
+            Some(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "error": {"code": -32800, "message": "cancelled before it was sent to the server"},
+            }))
+        );
+        assert_eq!(limit.cancel(&cancel(2)), None);
+        assert_eq!(limit.queued(), 1);
+        assert_eq!(limit.response(&response(1)), Some(request(3)));
+    }
+
+    #[test]
+    fn test_hold_exit() {
+        let mut limit = InflightLimit::new(1);
+        let exit = json!({"jsonrpc":"2.0","method":"exit"});
+        for id in 1..=2 {
+            let (text, value) = request(id);
+            limit.request(text, value);
+        }
+        assert_eq!(limit.request(exit.to_string(), exit.clone()), None);
+        assert!(limit.holds_exit());
+        assert_eq!(limit.exit(), None);
+        assert_eq!(limit.response(&response(1)), Some(request(2)));
+        assert_eq!(limit.exit(), Some(exit.to_string()));
+        assert!(!limit.holds_exit());
+        // Sent as is without queued requests.
+        assert!(limit.request(exit.to_string(), exit).is_some());
+    }
+}
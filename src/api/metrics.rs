@@ -0,0 +1,201 @@
+//! Counts of requests from clients by method and outcome, exposed at `GET /metrics` in the
+//! Prometheus text format.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use warp::{http::header, Filter, Rejection, Reply};
+
+use super::with_context;
+use crate::lsp::types::Id;
+
+/// How a request from the client ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Success,
+    Error,
+    /// The connection ended before the server responded.
+    Unanswered,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Error => "error",
+            Self::Unanswered => "unanswered",
+        }
+    }
+}
+
+/// Counters shared by all connections.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    requests: Arc<Mutex<BTreeMap<(String, Outcome), u64>>>,
+}
+
+impl Metrics {
+    pub fn record(&self, method: &str, outcome: Outcome) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), outcome))
+            .or_default() += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::from(
+            "# HELP lsp_ws_proxy_requests_total Requests from clients by method and outcome.\n\
+             # TYPE lsp_ws_proxy_requests_total counter\n",
+        );
+        for ((method, outcome), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "lsp_ws_proxy_requests_total{{method=\"{}\",outcome=\"{}\"}} {}",
+                method.replace('\\', "\\\\").replace('"', "\\\""),
+                outcome.as_str(),
+                count
+            );
+        }
+        text
+    }
+}
+
+// Only the fields necessary to match responses to requests.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+    error: Option<serde::de::IgnoredAny>,
+}
+
+/// Methods of the outstanding requests of a connection keyed by id.
+pub struct MethodTracker {
+    metrics: Metrics,
+    pending: HashMap<Id, String>,
+}
+
+impl MethodTracker {
+    pub fn new(metrics: Metrics) -> Self {
+        Self {
+            metrics,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Track `value` if it's a request.
+    pub fn request(&mut self, value: &Value) {
+        if let Ok(Head {
+            id: Some(id),
+            method: Some(method),
+            ..
+        }) = Head::deserialize(value)
+        {
+            self.pending.insert(id, method);
+        }
+    }
+
+    /// Count the outcome if `value` is a response to a tracked request.
+    pub fn response(&mut self, value: &Value) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Ok(Head {
+            id: Some(id),
+            method: None,
+            error,
+        }) = Head::deserialize(value)
+        {
+            if let Some(method) = self.pending.remove(&id) {
+                let outcome = if error.is_some() {
+                    Outcome::Error
+                } else {
+                    Outcome::Success
+                };
+                self.metrics.record(&method, outcome);
+            }
+        }
+    }
+}
+
+impl Drop for MethodTracker {
+    fn drop(&mut self) {
+        for method in self.pending.values() {
+            self.metrics.record(method, Outcome::Unanswered);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    pub metrics: Metrics,
+}
+
+/// Handler for `GET /metrics`
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_context(ctx))
+        .map(|ctx: Context| {
+            warp::reply::with_header(
+                ctx.metrics.render(),
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn count(metrics: &Metrics, method: &str, outcome: Outcome) -> u64 {
+        let requests = metrics.requests.lock().unwrap();
+        requests
+            .get(&(method.to_owned(), outcome))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_outcomes() {
+        let metrics = Metrics::default();
+        let mut tracker = MethodTracker::new(metrics.clone());
+        for id in 1..=3 {
+            tracker.request(&json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "textDocument/completion",
+                "params": {},
+            }));
+        }
+        tracker.request(&json!({"jsonrpc":"2.0","method":"initialized","params":{}}));
+        tracker.response(&json!({"jsonrpc":"2.0","id":1,"result":null}));
+        tracker.response(&json!({"jsonrpc":"2.0","id":2,"error":{"code":-32603,"message":"x"}}));
+        // Not a response to a tracked request.
+        tracker.response(&json!({"jsonrpc":"2.0","id":9,"result":null}));
+        drop(tracker);
+
+        let method = "textDocument/completion";
+        assert_eq!(count(&metrics, method, Outcome::Success), 1);
+        assert_eq!(count(&metrics, method, Outcome::Error), 1);
+        assert_eq!(count(&metrics, method, Outcome::Unanswered), 1);
+
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&handler(Context { metrics }))
+            .await;
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body.contains(
+            r#"lsp_ws_proxy_requests_total{method="textDocument/completion",outcome="error"} 1"#
+        ));
+    }
+}
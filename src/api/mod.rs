@@ -1,9 +1,37 @@
-use std::{convert::Infallible, error::Error};
+use std::{
+    convert::Infallible,
+    error::Error,
+    io::{self, Read},
+};
 
 use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
 
+pub mod batch;
+pub mod breaker;
+pub mod capture;
+pub mod client_addr;
+pub mod connections;
+pub mod echo;
+pub mod events;
 pub mod files;
+pub mod health;
+pub mod inflight;
+pub mod metrics;
+pub mod notify;
+pub mod pool;
+pub mod progress;
 pub mod proxy;
+#[cfg(unix)]
+pub mod pty;
+pub mod rate_limit;
+pub mod replay;
+pub mod server_requests;
+pub mod servers;
+pub mod sse;
+pub mod sync;
+pub mod timing;
+pub mod version;
+pub mod workspace;
 
 fn with_context<T>(ctx: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone
 where
@@ -12,17 +40,170 @@ where
     warp::any().map(move || ctx.clone())
 }
 
-fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+/// Default limit of JSON bodies.
+pub const JSON_BODY_LIMIT: u64 = 2 * 1024 * 1024;
+
+/// Body, optionally compressed with `Content-Encoding: gzip`.
+/// `limit` is also applied to the decompressed size.
+fn body(limit: u64) -> impl Filter<Extract = (bytes::Bytes,), Error = Rejection> + Clone {
+    let identity = identity_encoding()
+        .and(warp::body::content_length_limit(limit))
+        .and(warp::body::bytes());
+    let gzip = warp::header::exact_ignore_case("content-encoding", "gzip")
+        .and(warp::body::content_length_limit(limit))
+        .and(warp::body::bytes())
+        .and_then(
+            move |body: bytes::Bytes| async move { gunzip(&body, limit).map(bytes::Bytes::from) },
+        );
+    identity.or(gzip).unify().or(unsupported_encoding()).unify()
+}
+
+/// JSON body, optionally compressed with `Content-Encoding: gzip`.
+/// `limit` is also applied to the decompressed size.
+fn json_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
 where
     T: serde::de::DeserializeOwned + Send,
 {
-    warp::body::content_length_limit(2 * 1024 * 1024).and(warp::body::json())
+    let identity = identity_encoding()
+        .and(warp::body::content_length_limit(limit))
+        .and(warp::body::json());
+    let gzip = warp::header::exact_ignore_case("content-encoding", "gzip")
+        .and(warp::body::content_length_limit(limit))
+        .and(warp::body::bytes())
+        .and_then(move |body: bytes::Bytes| async move {
+            let decoded = gunzip(&body, limit)?;
+            serde_json::from_slice(&decoded).map_err(|e| warp::reject::custom(BodyError::Json(e)))
+        });
+    identity.or(gzip).unify().or(unsupported_encoding()).unify()
+}
+
+fn identity_encoding() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-encoding")
+        .and_then(|encoding: Option<String>| async move {
+            match encoding {
+                Some(e) if !e.eq_ignore_ascii_case("identity") => Err(warp::reject()),
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+// Other encodings. Rejections of the supported ones are left to their filters.
+fn unsupported_encoding<T: Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::header::<String>("content-encoding").and_then(|encoding: String| async move {
+        if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("identity") {
+            Err::<T, _>(warp::reject())
+        } else {
+            Err(warp::reject::custom(BodyError::UnsupportedEncoding(
+                encoding,
+            )))
+        }
+    })
+}
+
+fn gunzip(body: &[u8], limit: u64) -> Result<Vec<u8>, Rejection> {
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(body)
+        .take(limit + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| warp::reject::custom(BodyError::Decompress(e)))?;
+    if decoded.len() as u64 > limit {
+        return Err(warp::reject::custom(BodyError::TooLarge));
+    }
+    Ok(decoded)
+}
+
+/// Rejections of bodies not covered by `warp::body`.
+#[derive(Debug)]
+enum BodyError {
+    UnsupportedEncoding(String),
+    Decompress(io::Error),
+    TooLarge,
+    Json(serde_json::Error),
+}
+
+impl warp::reject::Reject for BodyError {}
+
+/// Compress replies from `filter` with gzip or deflate if the client accepts it.
+///
+/// `warp::compression` doesn't check `Accept-Encoding`, so each is selected by the header.
+/// `filter` may run more than once when it rejects, so it must not read the request body.
+fn with_compression<F, T>(
+    filter: F,
+) -> impl Filter<Extract = (reply::Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    let gzip = accepts_encoding("gzip")
+        .and(filter.clone())
+        .with(warp::compression::gzip())
+        .map(Reply::into_response);
+    let deflate = accepts_encoding("deflate")
+        .and(filter.clone())
+        .with(warp::compression::deflate())
+        .map(Reply::into_response);
+    let identity = filter.map(Reply::into_response);
+    gzip.or(deflate).unify().or(identity).unify()
+}
+
+fn accepts_encoding(
+    encoding: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .and_then(move |accepted: Option<String>| async move {
+            let accepted = accepted.unwrap_or_default();
+            if accepted
+                .split(',')
+                .filter_map(|v| v.split(';').next())
+                .any(|v| v.trim().eq_ignore_ascii_case(encoding))
+            {
+                Ok(())
+            } else {
+                Err(warp::reject())
+            }
+        })
+        .untuple_one()
 }
 
 fn json_response<T: serde::Serialize>(res: &T, status: StatusCode) -> reply::Response {
     reply::with_status(reply::json(res), status).into_response()
 }
 
+/// The request is missing the token, or has a wrong one.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Require `Authorization: Bearer <token>` if `token` is set.
+fn authorized(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected = token.map(|token| format!("Bearer {}", token));
+    warp::header::optional::<String>("authorization")
+        .and_then(move |value: Option<String>| {
+            let ok = match (&expected, value) {
+                (None, _) => true,
+                (Some(expected), Some(value)) => {
+                    constant_time_eq(value.as_bytes(), expected.as_bytes())
+                }
+                (Some(_), None) => false,
+            };
+            async move {
+                if ok {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Compare without returning early, so the time taken doesn't tell how much of it matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Convert rejections into a JSON response.
 #[allow(clippy::unused_async)]
 pub async fn recover(err: Rejection) -> Result<impl Reply, Rejection> {
@@ -39,6 +220,24 @@ pub async fn recover(err: Rejection) -> Result<impl Reply, Rejection> {
             }
         }
         ("Bad Request", StatusCode::BAD_REQUEST)
+    } else if let Some(e) = err.find::<BodyError>() {
+        tracing::debug!("body error: {:?}", e);
+        return Ok(match e {
+            BodyError::UnsupportedEncoding(encoding) => json_error_response(
+                format!("unsupported content encoding {}", encoding),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            BodyError::Decompress(err) => json_error_response(
+                format!("failed to decompress body: {}", err),
+                StatusCode::BAD_REQUEST,
+            ),
+            BodyError::TooLarge => {
+                json_error_response("Payload Too Large", StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            BodyError::Json(err) => json_error_response(err.to_string(), StatusCode::BAD_REQUEST),
+        });
+    } else if err.find::<Unauthorized>().is_some() {
+        ("Unauthorized", StatusCode::UNAUTHORIZED)
     } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
         ("Unsupported Media Type", StatusCode::UNSUPPORTED_MEDIA_TYPE)
     } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
@@ -0,0 +1,179 @@
+//! `POST /notify-changed` to tell servers about files changed without `/files`.
+use std::{convert::Infallible, path::PathBuf};
+
+use lsp_types::{FileChangeType, FileEvent};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    authorized,
+    connections::Registry,
+    files::{is_project_path, path_uri},
+    json_body, json_error_response, json_response, with_context, JSON_BODY_LIMIT,
+};
+
+#[derive(Clone)]
+pub struct Context {
+    pub cwd: PathBuf,
+    pub remap: bool,
+    /// Name prepended to the paths of `source://` URIs.
+    pub remap_prefix: Option<String>,
+    /// Required as `Authorization: Bearer <token>`, from `--api-token`.
+    pub token: String,
+    pub connections: Registry,
+}
+
+/// Changed files.
+///
+/// ```json
+/// {"changes": [{"path": "foo.js", "type": "changed"}]}
+/// ```
+#[derive(Debug, serde::Deserialize)]
+struct Payload {
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Change {
+    /// Path relative to the project root.
+    path: String,
+    #[serde(rename = "type")]
+    typ: ChangeType,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeType {
+    Created,
+    Changed,
+    Deleted,
+}
+
+impl From<ChangeType> for FileChangeType {
+    fn from(typ: ChangeType) -> Self {
+        match typ {
+            ChangeType::Created => FileChangeType::Created,
+            ChangeType::Changed => FileChangeType::Changed,
+            ChangeType::Deleted => FileChangeType::Deleted,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Response {
+    /// `FileEvent`s of the `workspace/didChangeWatchedFiles` notification.
+    changes: Vec<FileEvent>,
+    /// Number of connections the notification was sent to.
+    connections: usize,
+}
+
+/// Handler for `POST /notify-changed`
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path("notify-changed"))
+        .and(warp::path::end())
+        // Checked before reading the body.
+        .and(authorized(Some(ctx.token.clone())))
+        .and(with_context(ctx))
+        .and(json_body::<Payload>(JSON_BODY_LIMIT))
+        .and_then(handle_notify)
+}
+
+#[tracing::instrument(level = "debug", skip(ctx, payload))]
+async fn handle_notify(ctx: Context, payload: Payload) -> Result<impl Reply, Infallible> {
+    let mut changes = Vec::with_capacity(payload.changes.len());
+    for change in &payload.changes {
+        if !is_project_path(&ctx.cwd, &ctx.cwd.join(&change.path)) {
+            return Ok(json_error_response(
+                format!("{} is not under the project root", change.path),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        changes.push(FileEvent::new(
+            path_uri(
+                &ctx.cwd,
+                &change.path,
+                false,
+                ctx.remap,
+                ctx.remap_prefix.as_deref(),
+            ),
+            change.typ.into(),
+        ));
+    }
+
+    let connections = if changes.is_empty() {
+        0
+    } else {
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "workspace/didChangeWatchedFiles",
+            "params": { "changes": &changes },
+        });
+        ctx.connections.broadcast(&notification.to_string())
+    };
+    tracing::debug!(
+        "notified {} connections of {} changes",
+        connections,
+        changes.len()
+    );
+    Ok(json_response(
+        &Response {
+            changes,
+            connections,
+        },
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn context() -> Context {
+        Context {
+            cwd: PathBuf::from("/tmp/project"),
+            remap: true,
+            remap_prefix: None,
+            token: "secret".to_owned(),
+            connections: Registry::default(),
+        }
+    }
+
+    async fn notify(ctx: &Context, token: &str, body: Value) -> (StatusCode, Value) {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/notify-changed")
+            .header("authorization", format!("Bearer {}", token))
+            .json(&body)
+            .reply(&handler(ctx.clone()).recover(super::super::recover))
+            .await;
+        (res.status(), serde_json::from_slice(res.body()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_notify_changed() {
+        let ctx = context();
+        let mut registration = ctx.connections.register(1);
+        let body = json!({"changes": [{"path": "src/a.js", "type": "changed"}]});
+
+        let (status, _) = notify(&ctx, "wrong", body.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(registration.notifications.try_recv().is_err());
+
+        let (status, res) = notify(&ctx, "secret", body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res["connections"], 1);
+        let sent: Value =
+            serde_json::from_str(&registration.notifications.try_recv().unwrap()).unwrap();
+        assert_eq!(sent["method"], "workspace/didChangeWatchedFiles");
+        assert_eq!(
+            sent["params"]["changes"],
+            json!([{"uri": "source://src/a.js", "type": 2}])
+        );
+
+        let body = json!({"changes": [{"path": "../a.js", "type": "deleted"}]});
+        let (status, _) = notify(&ctx, "secret", body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(registration.notifications.try_recv().is_err());
+    }
+}
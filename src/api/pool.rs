@@ -0,0 +1,945 @@
+//! Pool of Language Servers started ahead of connections with `--warmup`, or kept after
+//! disconnect with `--linger`.
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::{ExitStatus, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    process::{Child, Command},
+    sync::oneshot,
+    task::JoinHandle,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use url::Url;
+
+use crate::lsp::framed::{self, LspFrameCodec};
+
+// Request ID used for `initialize` sent by the proxy.
+const WARMUP_ID: &str = "lsp-ws-proxy/warmup";
+// Request ID used for `shutdown` sent by the proxy.
+const SHUTDOWN_ID: &str = "lsp-ws-proxy/shutdown";
+// Time for a parked server to respond to `shutdown`, and then to exit.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stdin of the server, or the pty with `--pty`.
+pub type ServerInput = Box<dyn AsyncWrite + Send + Unpin>;
+/// Stdout of the server, or the pty with `--pty`.
+pub type ServerOutput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A running Language Server.
+pub struct Server {
+    pub child: Child,
+    pub writer: FramedWrite<ServerInput, LspFrameCodec>,
+    pub reader: FramedRead<ServerOutput, LspFrameCodec>,
+}
+
+/// How to start servers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpawnOptions {
+    /// Buffer capacity for stdin and stdout. The default is used if not specified.
+    pub io_buffer_size: Option<usize>,
+    /// Connect stdin and stdout to a pseudo-terminal instead of pipes.
+    pub pty: bool,
+}
+
+/// Start the Language Server with `command` in `cwd`, or the current directory.
+pub fn spawn(
+    command: &[String],
+    cwd: Option<&Path>,
+    options: SpawnOptions,
+) -> Result<Server, std::io::Error> {
+    let mut cmd = Command::new(&command[0]);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd.args(&command[1..]).kill_on_drop(true);
+    let (child, stdin, stdout): (_, ServerInput, ServerOutput) = if options.pty {
+        spawn_pty(&mut cmd)?
+    } else {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        (child, Box::new(stdin), Box::new(stdout))
+    };
+    let (writer, reader) = match options.io_buffer_size {
+        Some(capacity) => (
+            framed::writer_with_capacity(stdin, capacity),
+            framed::reader_with_capacity(stdout, capacity),
+        ),
+        None => (framed::writer(stdin), framed::reader(stdout)),
+    };
+    Ok(Server {
+        child,
+        writer,
+        reader,
+    })
+}
+
+#[cfg(unix)]
+fn spawn_pty(cmd: &mut Command) -> Result<(Child, ServerInput, ServerOutput), std::io::Error> {
+    let pty = super::pty::open()?;
+    let child = cmd
+        .stdin(pty.slave.try_clone()?)
+        .stdout(pty.slave)
+        .spawn()?;
+    let output = super::pty::PtyMaster::new(pty.master.try_clone()?)?;
+    let input = super::pty::PtyMaster::new(pty.master)?;
+    Ok((child, Box::new(input), Box::new(output)))
+}
+
+#[cfg(not(unix))]
+fn spawn_pty(_: &mut Command) -> Result<(Child, ServerInput, ServerOutput), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "pty is not supported on this platform",
+    ))
+}
+
+// Delay before the first retry of a failed spawn. Doubled for each retry.
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Call `spawn` until it succeeds, retrying transient failures up to `retries` times
+/// with exponential backoff. Failures like a missing command are returned immediately.
+pub async fn spawn_with_retries<T, F>(retries: u32, mut spawn: F) -> Result<T, std::io::Error>
+where
+    F: FnMut() -> Result<T, std::io::Error>,
+{
+    let mut delay = SPAWN_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match spawn() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                tracing::warn!(
+                    "failed to start server ({}), retrying in {:?} ({}/{})",
+                    err,
+                    delay,
+                    attempt,
+                    retries
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Whether retrying the spawn failed with `err` can succeed, e.g., `EAGAIN` on busy hosts.
+fn is_transient(err: &std::io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        std::io::ErrorKind::NotFound
+            | std::io::ErrorKind::PermissionDenied
+            | std::io::ErrorKind::InvalidInput
+    )
+}
+
+/// Wait for `child` to exit, and kill it if it doesn't exit within `timeout`.
+pub async fn wait_or_kill(
+    child: &mut Child,
+    timeout: Duration,
+) -> Result<ExitStatus, std::io::Error> {
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            tracing::warn!("killing server not exiting within {:?}", timeout);
+            child.start_kill()?;
+            child.wait().await
+        }
+    }
+}
+
+/// Signal to stop servers with on disconnect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum KillSignal {
+    /// Ask the server to exit, e.g., after flushing its caches.
+    #[serde(rename = "SIGTERM")]
+    Term,
+    #[serde(rename = "SIGKILL")]
+    Kill,
+}
+
+impl FromStr for KillSignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "SIGTERM" => Ok(Self::Term),
+            "SIGKILL" => Ok(Self::Kill),
+            _ => Err(format!("expected SIGTERM or SIGKILL, got {}", s)),
+        }
+    }
+}
+
+/// Stop `child` with `signal`, and kill it if it doesn't exit within `timeout`.
+pub async fn terminate(
+    child: &mut Child,
+    signal: KillSignal,
+    timeout: Duration,
+) -> Result<ExitStatus, std::io::Error> {
+    if let Some(status) = child.try_wait()? {
+        return Ok(status);
+    }
+    match signal {
+        KillSignal::Term => {
+            send_sigterm(child)?;
+            wait_or_kill(child, timeout).await
+        }
+        KillSignal::Kill => {
+            child.start_kill()?;
+            child.wait().await
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send_sigterm(child: &mut Child) -> Result<(), std::io::Error> {
+    // `None` once it was waited for.
+    if let Some(pid) = child.id() {
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// There's no graceful signal, so it's killed right away.
+#[cfg(not(unix))]
+fn send_sigterm(child: &mut Child) -> Result<(), std::io::Error> {
+    child.start_kill()
+}
+
+/// A registered command to start a server with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerCommand {
+    /// Selects the server in the query parameter `name` and paths like `/servers/{name}`.
+    pub name: String,
+    /// The program and its arguments.
+    pub command: Vec<String>,
+}
+
+impl ServerCommand {
+    /// Named by the file name of the program, e.g., `rust-analyzer` for
+    /// `/usr/local/bin/rust-analyzer`.
+    pub fn new(command: Vec<String>) -> Self {
+        let name = command
+            .first()
+            .map(|program| {
+                Path::new(program)
+                    .file_name()
+                    .map_or_else(|| program.clone(), |n| n.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        Self { name, command }
+    }
+
+    /// The program to start.
+    pub fn program(&self) -> &str {
+        &self.command[0]
+    }
+}
+
+/// Substitute placeholders in the arguments of `command`:
+///
+/// - `{cwd}`: the path of the project root
+/// - `{name}`: the command name
+/// - `{port}`: the TCP port the proxy is listening on, or empty if none
+pub fn expand_command(command: &ServerCommand, cwd: &Url, port: Option<u16>) -> Vec<String> {
+    let cwd = cwd
+        .to_file_path()
+        .map(|p| p.to_string_lossy().trim_end_matches('/').to_owned())
+        .unwrap_or_default();
+    let port = port.map(|p| p.to_string()).unwrap_or_default();
+    let args = command.command[1..].iter().map(|arg| {
+        arg.replace("{cwd}", &cwd)
+            .replace("{name}", &command.name)
+            .replace("{port}", &port)
+    });
+    std::iter::once(command.program().to_owned())
+        .chain(args)
+        .collect()
+}
+
+/// Whether `program` is allowed to start with `--allow-binary`. Allowed if it's in
+/// `allowed` as is, or resolves to the same executable as an absolute path in `allowed`.
+pub fn is_allowed_binary(program: &str, allowed: &[String]) -> bool {
+    if allowed.iter().any(|a| a == program) {
+        return true;
+    }
+    // Compare canonical paths so that symlinks and `..` can't point elsewhere.
+    let resolved = match super::health::find_executable(program).and_then(|p| p.canonicalize().ok())
+    {
+        Some(resolved) => resolved,
+        None => return false,
+    };
+    allowed
+        .iter()
+        .map(Path::new)
+        .filter(|a| a.is_absolute())
+        .filter_map(|a| a.canonicalize().ok())
+        .any(|a| a == resolved)
+}
+
+/// Number of servers started for connections, limited with `--max-servers`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerCount(Arc<AtomicUsize>);
+
+impl ServerCount {
+    /// Reserve a server unless `max` are running. Released when the slot is dropped.
+    pub fn reserve(&self, max: Option<usize>) -> Option<ServerSlot> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| match max {
+                Some(max) if n >= max => None,
+                _ => Some(n + 1),
+            })
+            .ok()
+            .map(|_| ServerSlot(self.0.clone()))
+    }
+
+    pub fn running(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A server counted in [`ServerCount`] until dropped.
+#[derive(Debug)]
+pub struct ServerSlot(Arc<AtomicUsize>);
+
+impl Drop for ServerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An initialized Language Server waiting for a client.
+pub struct Parked {
+    pub server: Server,
+    /// The result of `initialize` sent on warmup, or by the previous client.
+    /// Used to respond to the client's `initialize` without forwarding it.
+    pub initialize_result: serde_json::Value,
+    /// Keeps the server counted for `--max-servers` while parked, and for the client adopting it.
+    pub slot: Option<ServerSlot>,
+    /// Kept with `--linger`, so `initialized` was already sent by the previous client.
+    pub lingered: bool,
+}
+
+// A parked server owned by the task draining its output until taken.
+struct Entry {
+    initialize_result: serde_json::Value,
+    slot: Option<ServerSlot>,
+    lingered: bool,
+    pid: Option<u32>,
+    since: Instant,
+    taken: oneshot::Sender<()>,
+    drain: JoinHandle<Option<Server>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParkedStatus {
+    pub pid: Option<u32>,
+    /// Seconds since the server was parked.
+    pub idle_secs: u64,
+}
+
+/// How to initialize a server started with `--warmup`.
+#[derive(Clone, Debug)]
+pub struct Warmup {
+    /// `rootUri` of `initialize`.
+    pub root: Url,
+    /// `capabilities` of `initialize`, since there's no client to take them from yet.
+    pub capabilities: serde_json::Value,
+    /// `initializationOptions` of `initialize`.
+    pub init_options: Option<serde_json::Value>,
+    /// Shut down the server if it isn't adopted in time.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Parked servers keyed by the command name.
+#[derive(Clone, Default)]
+pub struct Pool {
+    parked: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Pool {
+    /// Start a server with `command` and initialize it for the first connection to adopt.
+    /// `slot` counts it for `--max-servers` until it exits.
+    pub async fn warmup(
+        &self,
+        name: &str,
+        command: &[String],
+        warmup: Warmup,
+        options: SpawnOptions,
+        slot: Option<ServerSlot>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let name = name.to_owned();
+        tracing::info!("warming up {}", name);
+        let mut server = spawn(command, None, options)?;
+        let params = serde_json::json!({
+            "processId": std::process::id(),
+            "rootUri": warmup.root,
+            "capabilities": warmup.capabilities,
+            "initializationOptions": warmup.init_options,
+        });
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": WARMUP_ID,
+            "method": "initialize",
+            "params": params,
+        });
+        server.writer.send(request.to_string()).await?;
+
+        let initialize_result =
+            tokio::time::timeout(Duration::from_secs(60), wait_for_initialize(&mut server))
+                .await
+                .map_err(|_| format!("{} did not respond to initialize", name))??;
+        tracing::info!("{} is ready", name);
+        if !self.park(
+            name.clone(),
+            server,
+            initialize_result,
+            slot,
+            false,
+            warmup.idle_timeout,
+        ) {
+            return Err(format!("{} is already parked", name).into());
+        }
+        Ok(())
+    }
+
+    /// Keep `server` of the disconnected client for a client reconnecting within `timeout`.
+    /// Returns `false` and kills `server` if another server for the command `name` is parked.
+    pub fn linger(
+        &self,
+        name: &str,
+        server: Server,
+        initialize_result: serde_json::Value,
+        slot: Option<ServerSlot>,
+        timeout: Duration,
+    ) -> bool {
+        tracing::info!("keeping {} for {:?}", name, timeout);
+        self.park(
+            name.to_owned(),
+            server,
+            initialize_result,
+            slot,
+            true,
+            Some(timeout),
+        )
+    }
+
+    // Park `server` until taken, or shut it down after `timeout`.
+    // `server` is dropped if another one is parked for `name`.
+    fn park(
+        &self,
+        name: String,
+        server: Server,
+        initialize_result: serde_json::Value,
+        slot: Option<ServerSlot>,
+        lingered: bool,
+        timeout: Option<Duration>,
+    ) -> bool {
+        let since = Instant::now();
+        let mut parked = self.parked.lock().unwrap();
+        if parked.contains_key(&name) {
+            return false;
+        }
+        let pid = server.child.id();
+        let (taken, taken_rx) = oneshot::channel();
+        let drain = tokio::spawn(drain(
+            self.clone(),
+            name.clone(),
+            since,
+            server,
+            taken_rx,
+            timeout,
+        ));
+        parked.insert(
+            name,
+            Entry {
+                initialize_result,
+                slot,
+                lingered,
+                pid,
+                since,
+                taken,
+                drain,
+            },
+        );
+        true
+    }
+
+    /// Take the parked server for the command `name`.
+    pub async fn take(&self, name: &str) -> Option<Parked> {
+        let entry = self.parked.lock().unwrap().remove(name)?;
+        let _ = entry.taken.send(());
+        let server = entry.drain.await.ok()??;
+        Some(Parked {
+            server,
+            initialize_result: entry.initialize_result,
+            slot: entry.slot,
+            lingered: entry.lingered,
+        })
+    }
+
+    /// Number of servers waiting for a client.
+    pub fn parked_count(&self) -> usize {
+        self.parked.lock().unwrap().len()
+    }
+
+    /// Status of the parked server for the command `name`.
+    pub fn status(&self, name: &str) -> Option<ParkedStatus> {
+        self.parked.lock().unwrap().get(name).map(|p| ParkedStatus {
+            pid: p.pid,
+            idle_secs: p.since.elapsed().as_secs(),
+        })
+    }
+
+    // Remove the entry for `name` unless it's taken, or replaced by a server parked later.
+    fn remove(&self, name: &str, since: Instant) -> Option<Entry> {
+        let mut parked = self.parked.lock().unwrap();
+        match parked.get(name) {
+            Some(p) if p.since == since => parked.remove(name),
+            _ => None,
+        }
+    }
+}
+
+// Read the output of the parked `server` until it's taken, so it doesn't block writing to
+// a full pipe. Requests from the server are answered like `wait_for_initialize` does.
+// Shuts the server down after `timeout`, and returns it when taken.
+async fn drain(
+    pool: Pool,
+    name: String,
+    since: Instant,
+    mut server: Server,
+    mut taken: oneshot::Receiver<()>,
+    timeout: Option<Duration>,
+) -> Option<Server> {
+    let idle = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures_util::future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+    loop {
+        tokio::select! {
+            _ = &mut taken => return Some(server),
+
+            _ = &mut idle => {
+                // Dropping the entry releases the slot after the server exits.
+                if let Some(entry) = pool.remove(&name, since) {
+                    tracing::info!("shutting down idle {}", name);
+                    shutdown(server).await;
+                    drop(entry);
+                    return None;
+                }
+                break;
+            }
+
+            msg = server.reader.next() => match msg {
+                Some(Ok(text)) => {
+                    let msg = serde_json::from_str(&text).unwrap_or_default();
+                    if let Some(response) = respond_to_request(&msg) {
+                        if let Err(err) = server.writer.send(response).await {
+                            tracing::warn!("failed to respond to parked {}: {}", name, err);
+                        }
+                    }
+                }
+                Some(Err(err)) => tracing::warn!("invalid message from parked {}: {}", name, err),
+                None => {
+                    if let Some(entry) = pool.remove(&name, since) {
+                        tracing::warn!("parked {} exited", name);
+                        drop(server.writer);
+                        if let Err(err) = wait_or_kill(&mut server.child, SHUTDOWN_TIMEOUT).await {
+                            tracing::error!("failed to wait for the server to exit: {}", err);
+                        }
+                        drop(entry);
+                        return None;
+                    }
+                    break;
+                }
+            },
+        }
+    }
+    // Taken while stopping to read. The client finds out if it exited.
+    let _ = taken.await;
+    Some(server)
+}
+
+// Request `shutdown` and `exit`, and kill the server if it doesn't exit in time.
+async fn shutdown(mut server: Server) {
+    let request = serde_json::json!({"jsonrpc": "2.0", "id": SHUTDOWN_ID, "method": "shutdown"});
+    let exit = serde_json::json!({"jsonrpc": "2.0", "method": "exit"});
+    let requested = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        server.writer.send(request.to_string()).await?;
+        while let Some(text) = server.reader.next().await {
+            let msg: serde_json::Value = match serde_json::from_str(&text?) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            if msg["id"] == SHUTDOWN_ID && msg.get("method").is_none() {
+                break;
+            }
+        }
+        server.writer.send(exit.to_string()).await
+    })
+    .await;
+    match requested {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::warn!("failed to shut down the server: {}", err),
+        Err(_) => tracing::warn!("server did not respond to shutdown"),
+    }
+    // Close stdin too in case it's still reading.
+    drop(server.writer);
+    if let Err(err) = wait_or_kill(&mut server.child, SHUTDOWN_TIMEOUT).await {
+        tracing::error!("failed to wait for the server to exit: {}", err);
+    }
+}
+
+// Read messages until the response for warmup `initialize`.
+async fn wait_for_initialize(
+    server: &mut Server,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    while let Some(text) = server.reader.next().await {
+        let msg: serde_json::Value = match serde_json::from_str(&text?) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        if msg["id"] == WARMUP_ID && msg.get("method").is_none() {
+            if let Some(error) = msg.get("error") {
+                return Err(format!("initialize failed: {}", error).into());
+            }
+            return Ok(msg.get("result").cloned().unwrap_or_default());
+        }
+        if let Some(response) = respond_to_request(&msg) {
+            server.writer.send(response).await?;
+        }
+    }
+    Err("server exited before responding to initialize".into())
+}
+
+// Response to the request from the server in `msg`, since it can't be answered without
+// a client. `None` for notifications and responses.
+fn respond_to_request(msg: &serde_json::Value) -> Option<String> {
+    match (msg.get("id"), msg.get("method")) {
+        (Some(id), Some(_)) => {
+            Some(serde_json::json!({"jsonrpc": "2.0", "id": id, "result": null}).to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()]
+    }
+
+    // Fake spawn failing with `errors` in order before succeeding.
+    fn fake_spawn(
+        mut errors: Vec<std::io::ErrorKind>,
+    ) -> impl FnMut() -> Result<usize, std::io::Error> {
+        errors.reverse();
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            match errors.pop() {
+                Some(kind) => Err(std::io::Error::from(kind)),
+                None => Ok(attempts),
+            }
+        }
+    }
+
+    #[test]
+    fn test_server_count() {
+        let count = ServerCount::default();
+        let first = count.reserve(Some(2)).unwrap();
+        let _second = count.reserve(Some(2)).unwrap();
+        assert!(count.reserve(Some(2)).is_none());
+        drop(first);
+        let _third = count.reserve(Some(2)).unwrap();
+        assert_eq!(count.running(), 2);
+        assert!(count.reserve(None).is_some());
+    }
+
+    #[test]
+    fn test_is_allowed_binary() {
+        let sh_path = crate::api::health::find_executable("sh")
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let by_path = vec![sh_path.clone()];
+        assert!(is_allowed_binary("sh", &["sh".to_owned()]));
+        assert!(is_allowed_binary("sh", &by_path));
+        assert!(is_allowed_binary(&sh_path, &by_path));
+        // Names only match the same name, not paths to anything.
+        assert!(!is_allowed_binary(&sh_path, &["sh".to_owned()]));
+        assert!(!is_allowed_binary("cat", &["sh".to_owned(), sh_path]));
+        assert!(!is_allowed_binary("sh", &[]));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_retries_transient_failure() {
+        let spawn = fake_spawn(vec![std::io::ErrorKind::WouldBlock]);
+        assert_eq!(spawn_with_retries(2, spawn).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_gives_up_after_retries() {
+        let spawn = fake_spawn(vec![std::io::ErrorKind::WouldBlock; 2]);
+        let err = spawn_with_retries(1, spawn).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_does_not_retry_missing_command() {
+        let spawn = fake_spawn(vec![std::io::ErrorKind::NotFound]);
+        let err = spawn_with_retries(2, spawn).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_exits_after_stdin_closed() {
+        // Closes stdout early, but keeps reading stdin until it's closed.
+        let Server {
+            mut child,
+            writer,
+            mut reader,
+        } = spawn(
+            &sh("exec 1>&-; cat > /dev/null"),
+            None,
+            SpawnOptions::default(),
+        )
+        .unwrap();
+        assert!(reader.next().await.is_none());
+        drop(writer);
+        let status = wait_or_kill(&mut child, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn test_kills_hanging_server() {
+        let Server {
+            mut child,
+            writer,
+            mut reader,
+        } = spawn(&sh("exec 1>&-; sleep 30"), None, SpawnOptions::default()).unwrap();
+        assert!(reader.next().await.is_none());
+        drop(writer);
+        let status = wait_or_kill(&mut child, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(!status.success());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_spawn_pty() {
+        let frame = "Content-Length: 2\r\n\r\n{}";
+        let options = SpawnOptions {
+            pty: true,
+            ..SpawnOptions::default()
+        };
+        let script = format!("test -t 0 && test -t 1 && head -c {}", frame.len());
+        let Server {
+            mut child,
+            mut writer,
+            mut reader,
+        } = spawn(&sh(&script), None, options).unwrap();
+        writer.send("{}".to_owned()).await.unwrap();
+        // Passed through as is in raw mode, without echo or `\n` to `\r\n`.
+        assert_eq!(reader.next().await.unwrap().unwrap(), "{}");
+        assert!(reader.next().await.is_none());
+        let status = wait_or_kill(&mut child, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(status.success());
+    }
+
+    // Waiting for a pty doesn't hold a thread, so idle servers can't starve the others.
+    #[cfg(unix)]
+    #[test]
+    fn test_pty_without_blocking_threads() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let options = SpawnOptions {
+                pty: true,
+                ..SpawnOptions::default()
+            };
+            let mut idle = spawn(&sh("exec sleep 30"), None, options).unwrap();
+            let waiting = tokio::spawn(async move { idle.reader.next().await.is_some() });
+            tokio::task::yield_now().await;
+
+            let ready = "printf 'Content-Length: 2\\r\\n\\r\\n{}'; exec sleep 30";
+            let mut server = spawn(&sh(ready), None, options).unwrap();
+            let read = tokio::time::timeout(Duration::from_secs(5), server.reader.next()).await;
+            assert_eq!(read.unwrap().unwrap().unwrap(), "{}");
+            waiting.abort();
+        });
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_terminate() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Tells it's ready after setting the trap, since it's a race otherwise.
+        let ready = "printf 'Content-Length: 2\\r\\n\\r\\n{}'";
+        for (trap, signal) in &[("", libc::SIGTERM), ("trap '' TERM;", libc::SIGKILL)] {
+            let script = format!("{} {}; exec sleep 30", trap, ready);
+            let Server {
+                mut child,
+                writer: _writer,
+                mut reader,
+            } = spawn(&sh(&script), None, SpawnOptions::default()).unwrap();
+            assert_eq!(reader.next().await.unwrap().unwrap(), "{}");
+            let status = terminate(&mut child, KillSignal::Term, Duration::from_millis(200))
+                .await
+                .unwrap();
+            assert_eq!(status.signal(), Some(*signal));
+        }
+    }
+
+    #[test]
+    fn test_expand_command() {
+        let cwd = Url::parse("file:///home/user/project/").unwrap();
+        let command = ServerCommand::new(
+            vec![
+                "/opt/bin/server",
+                "--project",
+                "{cwd}",
+                "--log={name}-{port}.log",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        );
+        assert_eq!(command.name, "server");
+        assert_eq!(
+            expand_command(&command, &cwd, Some(9999)),
+            vec![
+                "/opt/bin/server",
+                "--project",
+                "/home/user/project",
+                "--log=server-9999.log"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_linger() {
+        let response = format!(
+            r#"{{"jsonrpc":"2.0","id":"{}","result":null}}"#,
+            SHUTDOWN_ID
+        );
+        // Respond to `shutdown`, and exit when stdin is closed.
+        let script = format!(
+            "read -r _; printf 'Content-Length: {}\\r\\n\\r\\n{}'; cat > /dev/null",
+            response.len(),
+            response
+        );
+        let pool = Pool::default();
+        let result = serde_json::json!({"capabilities": {}});
+        let timeout = Duration::from_millis(50);
+        let server = spawn(&sh(&script), None, SpawnOptions::default()).unwrap();
+        assert!(pool.linger("sh", server, result.clone(), None, timeout));
+        let server = spawn(&sh(&script), None, SpawnOptions::default()).unwrap();
+        assert!(!pool.linger("sh", server, result.clone(), None, timeout));
+
+        let parked = pool.take("sh").await.unwrap();
+        assert_eq!(parked.initialize_result, result);
+        // Counted until the idle server is shut down.
+        let count = ServerCount::default();
+        let slot = count.reserve(Some(1));
+        assert!(pool.linger("sh", parked.server, result.clone(), slot, timeout));
+        assert!(count.reserve(Some(1)).is_none());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(pool.parked_count(), 0);
+        assert_eq!(count.running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_capabilities() {
+        let response = format!(
+            r#"{{"jsonrpc":"2.0","id":"{}","result":{{"capabilities":{{}}}}}}"#,
+            WARMUP_ID
+        );
+        let received =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-warmup-{}", std::process::id()));
+        // Record `initialize`, and respond to it.
+        let script = format!(
+            "read -r h; read -r _; n=${{h#Content-Length: }}; head -c ${{n%?}} > {}; printf 'Content-Length: {}\\r\\n\\r\\n{}'; cat > /dev/null",
+            received.display(),
+            response.len(),
+            response
+        );
+        let pool = Pool::default();
+        let capabilities = serde_json::json!({"general": {"positionEncodings": ["utf-8"]}});
+        let warmup = Warmup {
+            root: Url::parse("file:///tmp/").unwrap(),
+            capabilities: capabilities.clone(),
+            init_options: None,
+            idle_timeout: None,
+        };
+        pool.warmup("sh", &sh(&script), warmup, SpawnOptions::default(), None)
+            .await
+            .unwrap();
+        let received_text = std::fs::read_to_string(&received).unwrap();
+        let request: serde_json::Value = serde_json::from_str(&received_text).unwrap();
+        assert_eq!(request["params"]["capabilities"], capabilities);
+
+        let parked = pool.take("sh").await.unwrap();
+        assert!(!parked.lingered);
+        std::fs::remove_file(&received).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_parked() {
+        let request =
+            r#"{"jsonrpc":"2.0","id":1,"method":"workspace/configuration","params":{"items":[]}}"#;
+        let response = r#"{"id":1,"jsonrpc":"2.0","result":null}"#;
+        let framed = format!("Content-Length: {}\r\n\r\n{}", response.len(), response);
+        let received =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-drain-{}", std::process::id()));
+        // Request while parked, and record the response.
+        let script = format!(
+            "printf 'Content-Length: {}\\r\\n\\r\\n{}'; head -c {} > {}; cat > /dev/null",
+            request.len(),
+            request,
+            framed.len(),
+            received.display()
+        );
+        let pool = Pool::default();
+        let result = serde_json::json!({"capabilities": {}});
+        let server = spawn(&sh(&script), None, SpawnOptions::default()).unwrap();
+        assert!(pool.linger("sh", server, result, None, Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&received).unwrap(), framed);
+
+        let parked = pool.take("sh").await.unwrap();
+        assert!(parked.lingered);
+        assert!(parked.server.child.id().is_some());
+        std::fs::remove_file(&received).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Correlate `$/progress` from the server with the requests that provided the `workDoneToken`.
+use std::collections::HashMap;
+
+use lsp_types::ProgressToken;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lsp::types::Id;
+
+// Only the fields necessary to track requests.
+#[derive(Deserialize)]
+struct RequestHead {
+    id: Option<Id>,
+    method: Option<String>,
+}
+
+// Only the fields necessary to match progress and responses to requests.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+    params: Option<ProgressHead>,
+}
+
+#[derive(Deserialize)]
+struct ProgressHead {
+    token: ProgressToken,
+    value: ValueHead,
+}
+
+#[derive(Deserialize)]
+struct ValueHead {
+    kind: Option<String>,
+    percentage: Option<u32>,
+}
+
+/// `$/progress` for a token from a request.
+#[derive(Debug, PartialEq)]
+pub struct Progress {
+    pub token: ProgressToken,
+    pub method: String,
+    pub id: Id,
+    /// `begin`, `report`, or `end`.
+    pub kind: String,
+    pub percentage: Option<u32>,
+}
+
+/// Tokens from requests still in progress.
+#[derive(Default)]
+pub struct ProgressTracker {
+    tokens: HashMap<ProgressToken, (String, Id)>,
+}
+
+impl ProgressTracker {
+    /// Track `token` provided by the request `value`.
+    pub fn request(&mut self, token: &ProgressToken, value: &Value) {
+        if let Ok(RequestHead {
+            id: Some(id),
+            method: Some(method),
+        }) = RequestHead::deserialize(value)
+        {
+            self.tokens.insert(token.clone(), (method, id));
+        }
+    }
+
+    /// Returns the progress if `value` is `$/progress` with a tracked token.
+    /// Tokens are forgotten on `end` or when the request is responded.
+    pub fn server(&mut self, value: &Value) -> Option<Progress> {
+        if self.tokens.is_empty() {
+            return None;
+        }
+        match Head::deserialize(value) {
+            Ok(Head {
+                id: None,
+                method: Some(method),
+                params: Some(ProgressHead { token, value }),
+            }) if method == "$/progress" => {
+                let kind = value.kind.unwrap_or_default();
+                let (method, id) = if kind == "end" {
+                    self.tokens.remove(&token)?
+                } else {
+                    self.tokens.get(&token).cloned()?
+                };
+                Some(Progress {
+                    token,
+                    method,
+                    id,
+                    kind,
+                    percentage: value.percentage,
+                })
+            }
+            Ok(Head {
+                id: Some(id),
+                method: None,
+                ..
+            }) => {
+                self.tokens.retain(|_, (_, pending)| *pending != id);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::NumberOrString;
+    use serde_json::json;
+
+    use super::*;
+
+    fn progress(token: &str, kind: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "$/progress",
+            "params": {"token": token, "value": {"kind": kind, "title": "Indexing"}},
+        })
+    }
+
+    #[test]
+    fn test_correlate() {
+        let mut tracker = ProgressTracker::default();
+        let token = NumberOrString::String("t1".to_owned());
+        tracker.request(
+            &token,
+            &json!({"jsonrpc":"2.0","id":3,"method":"workspace/symbol","params":{"query":"","workDoneToken":"t1"}}),
+        );
+        // Unknown tokens are ignored.
+        assert_eq!(tracker.server(&progress("t2", "begin")), None);
+        assert_eq!(
+            tracker.server(&progress("t1", "begin")),
+            Some(Progress {
+                token: token.clone(),
+                method: "workspace/symbol".to_owned(),
+                id: Id::Number(3),
+                kind: "begin".to_owned(),
+                percentage: None,
+            })
+        );
+        assert_eq!(
+            tracker.server(&progress("t1", "end")).map(|p| p.kind),
+            Some("end".to_owned())
+        );
+        assert_eq!(tracker.server(&progress("t1", "report")), None);
+    }
+
+    #[test]
+    fn test_forget_on_response() {
+        let mut tracker = ProgressTracker::default();
+        let token = NumberOrString::Number(9);
+        tracker.request(
+            &token,
+            &json!({"jsonrpc":"2.0","id":"a","method":"textDocument/references","params":{}}),
+        );
+        assert!(tracker
+            .server(&json!({"jsonrpc":"2.0","id":"b","result":null}))
+            .is_none());
+        let report = json!({"jsonrpc":"2.0","method":"$/progress","params":{"token":9,"value":{"kind":"report","percentage":50}}});
+        assert_eq!(tracker.server(&report).and_then(|p| p.percentage), Some(50));
+        tracker.server(&json!({"jsonrpc":"2.0","id":"a","result":[]}));
+        assert_eq!(tracker.server(&report), None);
+    }
+}
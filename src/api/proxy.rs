@@ -1,117 +1,618 @@
-use std::{convert::Infallible, process::Stdio, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{Infallible, TryFrom},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 
 use futures_util::{
     future::{select, Either},
-    stream, SinkExt, StreamExt,
+    stream, Sink, SinkExt, Stream, StreamExt,
+};
+use thiserror::Error;
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc, oneshot},
 };
-use tokio::{fs, process::Command};
 use url::Url;
-use warp::{Filter, Rejection, Reply};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
 
-use crate::lsp;
+use crate::lsp::{self, framed::CodecError};
 
-use super::with_context;
+use super::{
+    batch,
+    breaker::{self, CircuitBreaker},
+    capture::{Capture, Direction},
+    client_addr::client_addr,
+    connections::{Registration, Registry},
+    echo,
+    events::Event,
+    files,
+    inflight::InflightLimit,
+    json_error_response,
+    metrics::{MethodTracker, Metrics},
+    pool::{self, Pool},
+    progress::ProgressTracker,
+    rate_limit::{RateLimit, Throttled},
+    server_requests::ServerRequests,
+    sync::{LineEnding, SyncFilter},
+    timing::{RequestSpans, RequestTimer},
+    with_context,
+    workspace::Workspaces,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Context {
     /// One or more commands to start a Language Server.
-    pub commands: Vec<Vec<String>>,
+    pub commands: Vec<pool::ServerCommand>,
+    /// Index of the command to start when the client doesn't specify a name.
+    pub default_server: usize,
+    /// Origins allowed to connect from browsers. Any origin if empty.
+    pub allowed_origins: Vec<String>,
     /// Write file on save.
     pub sync: bool,
+    /// Never write files, even with `sync`.
+    pub readonly: bool,
+    /// Paths allowed to be written with `sync`.
+    pub sync_filter: SyncFilter,
+    /// Create missing parent directories when writing with `sync`.
+    pub sync_create_dirs: bool,
+    /// Line endings to rewrite documents to when writing with `sync`.
+    pub normalize_eol: LineEnding,
     /// Remap relative `source://` to absolute `file://`.
     pub remap: bool,
+    /// Name prepended to the paths of `source://` URIs when remapping.
+    pub remap_prefix: Option<String>,
+    /// Redact document contents when logging messages.
+    pub redact: bool,
+    /// Pretty-print messages when logging.
+    pub pretty_debug: bool,
+    /// Capture forwarded messages to a file.
+    pub capture: Option<Capture>,
+    /// Reject malformed messages from the client instead of forwarding them.
+    pub strict: bool,
+    /// Send malformed output of the server to the client as `window/logMessage`.
+    pub report_codec_errors: bool,
+    /// Delay writing notifications to the server to batch them.
+    pub write_delay: Option<std::time::Duration>,
+    /// Notifications from the server to drop.
+    pub filter: lsp::ext::MessageFilter,
+    /// Rewrite severities of diagnostics from the server.
+    pub diagnostic_severity: Vec<lsp::ext::SeverityRule>,
+    /// Respond to `workspace/configuration` from the server with these values by section.
+    pub config_response: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Methods from the client to answer with an error instead of forwarding.
+    pub blocked_methods: lsp::ext::BlockedMethods,
+    /// Log the client address from `X-Forwarded-For` or `X-Real-IP`.
+    pub trust_proxy_headers: bool,
+    /// Coerce ids of requests to the server to a single type.
+    pub coerce_ids: Option<lsp::ext::IdKind>,
+    /// Encoding of positions from the client. Translated to UTF-16 for the server.
+    pub position_encoding: lsp::ext::PositionEncoding,
+    /// Log requests taking longer than this to respond.
+    pub slow_request_threshold: Option<std::time::Duration>,
+    /// Open a span for each request from the client, e.g., to export with `--otlp-endpoint`.
+    pub trace_requests: bool,
+    /// Queue requests from the client while this many are waiting for responses.
+    pub max_inflight: Option<usize>,
+    /// Default `initializationOptions` for each command name.
+    pub init_options: HashMap<String, serde_json::Value>,
+    /// Prefer `init_options` over the client's.
+    pub force_init_options: bool,
+    /// Sender for connection events.
+    pub events: broadcast::Sender<Event>,
+    /// Limit the rate of messages from the client.
+    pub rate_limit: Option<RateLimit>,
+    /// Servers started ahead of connections, or kept after disconnect.
+    pub pool: Pool,
+    /// Keep the server after the client disconnects for a reconnecting client to adopt.
+    /// Not with `workspaces` because the directory is removed on disconnect.
+    pub linger: Option<std::time::Duration>,
+    /// Send `$/proxy/serverSelected` with the command of the server on connect.
+    pub announce_server: bool,
+    /// Signal to stop the server with on disconnect.
+    pub kill_signal: pool::KillSignal,
+    /// Time for the server to exit after `kill_signal` before it's killed.
+    pub kill_timeout: std::time::Duration,
+    /// Accept only one WebSocket connection, and signal when it closed.
+    pub single_shot: Option<SingleShot>,
+    /// Servers started for connections.
+    pub server_count: pool::ServerCount,
+    /// Reject connections starting a server when this many are running.
+    pub max_servers: Option<usize>,
+    /// Reject connections to servers crashing repeatedly.
+    pub breaker: Option<CircuitBreaker>,
+    /// Active connections.
+    pub connections: Registry,
+    /// Counts of requests by method and outcome.
+    pub metrics: Metrics,
     /// Project root.
     pub cwd: Url,
+    /// Create a directory for each connection to use as the project root instead of `cwd`.
+    pub workspaces: Option<Workspaces>,
+    /// Remove files created by each connection on disconnect.
+    pub ephemeral: bool,
+    /// Number of messages to buffer for the client.
+    pub buffer_size: usize,
+    /// How to start servers.
+    pub spawn_options: pool::SpawnOptions,
+    /// Number of times to retry starting the server on transient failures.
+    pub spawn_retries: u32,
+    /// Respond with the built-in fake server instead of starting one.
+    pub echo: bool,
+    /// The TCP port to substitute `{port}` in commands.
+    pub port: Option<u16>,
+    /// Root to use when the client's `initialize` doesn't specify one.
+    pub root: Option<Url>,
+}
+
+impl Context {
+    /// The context for a connection using `dir` as the project root.
+    fn in_workspace(&self, dir: &Path) -> Result<Self, ignore::Error> {
+        let mut ctx = self.clone();
+        ctx.cwd = Url::from_directory_path(dir).expect("workspace is absolute");
+        ctx.sync_filter = self.sync_filter.rebase(dir)?;
+        Ok(ctx)
+    }
+
+    /// Whether clients from `origin` are allowed to connect.
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|o| o == origin)
+    }
+
+    /// Whether there's a server to connect clients to.
+    pub(super) fn has_server(&self) -> bool {
+        self.echo || !self.commands.is_empty()
+    }
+
+    /// Reserve a server for a connection with `query` unless `max_servers` are running,
+    /// or the server is crashing repeatedly.
+    /// `Ok(None)` if the connection doesn't start one, e.g., adopting a parked server. The
+    /// parked server has its own slot, and one is reserved in `connected` if it's gone by then.
+    pub(super) fn reserve_server(
+        &self,
+        query: Option<&Query>,
+    ) -> Result<Option<pool::ServerSlot>, Unavailable> {
+        if self.echo {
+            return Ok(None);
+        }
+        let name = query.and_then(|q| q.name.as_deref());
+        if let Some(command) = select_command(&self.commands, self.default_server, name) {
+            if self.workspaces.is_none() && self.pool.status(&command.name).is_some() {
+                return Ok(None);
+            }
+            let open_for = self
+                .breaker
+                .as_ref()
+                .and_then(|b| b.open_for(&command.name, Instant::now()));
+            if let Some(open_for) = open_for {
+                return Err(Unavailable::Crashing(command.name.clone(), open_for));
+            }
+        }
+        self.server_count
+            .reserve(self.max_servers)
+            .map(Some)
+            .ok_or_else(|| Unavailable::TooManyServers(self.server_count.running()))
+    }
+
+    // Capture message `text` forwarded in `direction` if enabled.
+    fn capture(&self, connection: u64, direction: Direction, text: &str) {
+        if let Some(capture) = &self.capture {
+            capture.record(connection, direction, text);
+        }
+    }
+
+    // Format message `text` for logging.
+    fn logged<'a>(&self, text: &'a str) -> lsp::redact::Redacted<'a> {
+        lsp::redact::Redacted::new(text, self.redact).pretty(self.pretty_debug)
+    }
+}
+
+/// The only connection served with `--single-shot`.
+#[derive(Clone)]
+pub struct SingleShot {
+    accepted: Arc<AtomicBool>,
+    closed: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl SingleShot {
+    /// Returns the receiver completing when the connection closed.
+    pub fn new() -> (Self, oneshot::Receiver<()>) {
+        let (closed, closed_rx) = oneshot::channel();
+        let single_shot = Self {
+            accepted: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(Mutex::new(Some(closed))),
+        };
+        (single_shot, closed_rx)
+    }
+
+    /// Take the only connection. `false` if it was already taken.
+    fn accept(&self) -> bool {
+        !self.accepted.swap(true, Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        if let Some(closed) = self.closed.lock().unwrap().take() {
+            let _ = closed.send(());
+        }
+    }
+}
+
+/// Rejection of connections starting a server.
+#[derive(Debug)]
+pub(super) enum Unavailable {
+    /// `max_servers` are running.
+    TooManyServers(usize),
+    /// The server crashed repeatedly, and can be started again after the duration.
+    Crashing(String, std::time::Duration),
+}
+
+impl Reply for Unavailable {
+    fn into_response(self) -> warp::reply::Response {
+        match self {
+            Self::TooManyServers(running) => {
+                tracing::warn!("rejecting connection with {} servers running", running);
+                json_error_response(
+                    "too many Language Servers are running",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )
+            }
+            Self::Crashing(name, open_for) => {
+                tracing::warn!("rejecting connection to {} crashing repeatedly", name);
+                let res = json_error_response(
+                    format!(
+                        "{} is temporarily unavailable after crashing repeatedly",
+                        name
+                    ),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                );
+                warp::reply::with_header(
+                    res,
+                    warp::http::header::RETRY_AFTER,
+                    breaker::retry_after(open_for),
+                )
+                .into_response()
+            }
+        }
+    }
 }
 
+// Other parameters are ignored so they can be used for something else, e.g., auth.
 #[derive(Clone, Debug, serde::Deserialize)]
-struct Query {
+pub(super) struct Query {
     /// The command name of the Language Server to start.
-    /// If not specified, the first one is started.
-    name: String,
+    /// If not specified, the default one is started.
+    name: Option<String>,
 }
 
-fn with_optional_query() -> impl Filter<Extract = (Option<Query>,), Error = Infallible> + Clone {
+pub(super) fn with_optional_query(
+) -> impl Filter<Extract = (Option<Query>,), Error = Infallible> + Clone {
     warp::query::<Query>()
         .map(Some)
         .or_else(|_| async { Ok::<(Option<Query>,), Infallible>((None,)) })
 }
 
+// Select the server with `/?name={name}` or `/servers/{name}`.
+fn with_server_query() -> impl Filter<Extract = (Option<Query>,), Error = Rejection> + Clone {
+    let by_query = warp::path::end().and(with_optional_query());
+    let by_path = warp::path!("servers" / String).map(|name| Some(Query { name: Some(name) }));
+    by_query.or(by_path).unify()
+}
+
 /// Handler for WebSocket connection.
 pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path::end()
+    with_server_query()
         .and(warp::ws())
+        .and(client_addr(ctx.trust_proxy_headers))
         .and(with_context(ctx))
-        .and(with_optional_query())
-        .map(|ws: warp::ws::Ws, ctx, query| {
-            ws.with_compression()
-                .on_upgrade(move |socket| on_upgrade(socket, ctx, query))
-        })
+        .and(warp::header::optional::<String>("origin"))
+        .map(
+            |query: Option<Query>,
+             ws: warp::ws::Ws,
+             addr: Option<IpAddr>,
+             ctx: Context,
+             origin: Option<String>| {
+                // Browsers don't apply CORS to WebSocket, so check the origin before upgrading.
+                if let Some(origin) = origin.filter(|o| !ctx.allows_origin(o)) {
+                    tracing::warn!(
+                        addr = ?addr,
+                        "rejecting connection from disallowed origin {}",
+                        origin
+                    );
+                    return json_error_response(
+                        format!("origin {} is not allowed", origin),
+                        StatusCode::FORBIDDEN,
+                    );
+                }
+                if !ctx.has_server() {
+                    return no_server_response();
+                }
+                let slot = match ctx.reserve_server(query.as_ref()) {
+                    Ok(slot) => slot,
+                    Err(err) => return err.into_response(),
+                };
+                if matches!(&ctx.single_shot, Some(single_shot) if !single_shot.accept()) {
+                    tracing::warn!(addr = ?addr, "rejecting connection after the single shot");
+                    return json_error_response(
+                        "already served the only connection",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    );
+                }
+                ws.with_compression()
+                    .on_upgrade(move |socket| on_upgrade(socket, ctx, query, addr, slot))
+                    .into_response()
+            },
+        )
 }
 
-#[tracing::instrument(level = "debug", err, skip(msg))]
-async fn maybe_write_text_document(msg: &lsp::Message) -> Result<(), std::io::Error> {
+/// Reject clients when there's no server to connect to.
+pub(super) fn no_server_response() -> warp::reply::Response {
+    tracing::error!("rejecting connection without any Language Server configured");
+    json_error_response(
+        "no Language Server is configured",
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+}
+
+/// The command to start the server named `name`. Falls back to the one at `default`.
+/// `None` if there are no commands.
+fn select_command<'a>(
+    commands: &'a [pool::ServerCommand],
+    default: usize,
+    name: Option<&str>,
+) -> Option<&'a pool::ServerCommand> {
+    let default = || commands.get(default);
+    match name {
+        Some(name) => match commands.iter().find(|c| c.name == name) {
+            Some(command) => Some(command),
+            None => {
+                // TODO Validate this earlier and reject, or close immediately.
+                tracing::warn!(
+                    "Unknown Language Server '{}', falling back to the default",
+                    name
+                );
+                default()
+            }
+        },
+        None => default(),
+    }
+}
+
+/// Write the text of saved document to disk. Returns the path if the file was created.
+#[tracing::instrument(level = "debug", err, skip(msg, filter))]
+async fn maybe_write_text_document(
+    msg: &lsp::Message,
+    filter: &SyncFilter,
+    create_dirs: bool,
+    eol: LineEnding,
+) -> Result<Option<PathBuf>, std::io::Error> {
     if let lsp::Message::Notification(lsp::Notification::DidSave { params }) = msg {
         if let Some(text) = &params.text {
             let uri = &params.text_document.uri;
             if uri.scheme() == "file" {
                 if let Ok(path) = uri.to_file_path() {
-                    if let Some(parent) = path.parent() {
+                    if !filter.allows(&path) {
+                        tracing::warn!("skipped writing {:?} not allowed to sync", path);
+                    } else if let Some(parent) = path.parent() {
+                        if !create_dirs && !parent.is_dir() {
+                            tracing::warn!("skipped writing {:?} without parent directory", path);
+                            return Ok(None);
+                        }
                         tracing::debug!("writing to {:?}", path);
                         fs::create_dir_all(parent).await?;
-                        fs::write(&path, text.as_bytes()).await?;
+                        let (mut file, created) = files::open_for_write(&path).await?;
+                        file.write_all(eol.normalize(text).as_bytes()).await?;
+                        file.flush().await?;
+                        return Ok(if created { Some(path) } else { None });
                     }
                 }
             }
         }
     }
-    Ok(())
+    Ok(None)
+}
+
+// Used to identify connections in events.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+async fn on_upgrade(
+    socket: warp::ws::WebSocket,
+    ctx: Context,
+    query: Option<Query>,
+    addr: Option<IpAddr>,
+    slot: Option<pool::ServerSlot>,
+) {
+    let (ws_send, ws_recv) = socket.split();
+    let single_shot = ctx.single_shot.clone();
+    serve_client(ws_send, ws_recv, ctx, query, addr, slot).await;
+    if let Some(single_shot) = single_shot {
+        single_shot.close();
+    }
 }
 
-async fn on_upgrade(socket: warp::ws::WebSocket, ctx: Context, query: Option<Query>) {
-    tracing::info!("connected");
-    if let Err(err) = connected(socket, ctx, query).await {
-        tracing::error!("connection error: {}", err);
+/// Proxy messages between the client connected with `ws_send` and `ws_recv` and the server.
+/// Transports other than WebSocket adapt to these to share the same handling.
+/// `addr` is the address of the client, if known, for logs. `slot` is from `reserve_server`.
+pub(super) async fn serve_client<S, R>(
+    ws_send: S,
+    ws_recv: R,
+    ctx: Context,
+    query: Option<Query>,
+    addr: Option<IpAddr>,
+    slot: Option<pool::ServerSlot>,
+) where
+    S: Sink<warp::ws::Message> + Send + Unpin + 'static,
+    S::Error: std::fmt::Display,
+    R: Stream<Item = Result<warp::ws::Message, warp::Error>> + Send + Unpin + 'static,
+{
+    let connection = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    tracing::info!(addr = ?addr, "connected");
+    // Sending only fails when there are no subscribers.
+    let _ = ctx.events.send(Event::Connected { connection });
+    let events = ctx.events.clone();
+    let connections = ctx.connections.clone();
+    let cwd = ctx.cwd.to_file_path().expect("cwd is a file url");
+    let registration = connections.register(connection);
+    let connected = connected(ws_send, ws_recv, ctx, query, connection, registration, slot);
+    if let Err(err) = connected.await {
+        tracing::error!(kind = err.kind(), "connection error: {}", err);
     }
+    // Only has files with `--ephemeral`.
+    let created = connections.remove(connection);
+    files::remove_created(&cwd, &created).await;
+    let _ = events.send(Event::Disconnected { connection });
     tracing::info!("disconnected");
 }
 
-#[tracing::instrument(level = "debug", skip(ws, ctx), fields(remap = %ctx.remap, sync = %ctx.sync))]
-async fn connected(
-    ws: warp::ws::WebSocket,
+#[tracing::instrument(level = "debug", skip(ws_send, ws_recv, ctx, registration, slot), fields(remap = %ctx.remap, sync = %ctx.sync))]
+async fn connected<S, R>(
+    mut ws_send: S,
+    ws_recv: R,
     ctx: Context,
     query: Option<Query>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let command = if let Some(query) = query {
-        if let Some(command) = ctx.commands.iter().find(|v| v[0] == query.name) {
-            command
-        } else {
-            // TODO Validate this earlier and reject, or close immediately.
-            tracing::warn!(
-                "Unknown Language Server '{}', falling back to the default",
-                query.name
-            );
-            &ctx.commands[0]
+    connection: u64,
+    registration: Registration,
+    slot: Option<pool::ServerSlot>,
+) -> Result<(), ConnError>
+where
+    S: Sink<warp::ws::Message> + Send + Unpin + 'static,
+    S::Error: std::fmt::Display,
+    R: Stream<Item = Result<warp::ws::Message, warp::Error>> + Send + Unpin + 'static,
+{
+    let Registration {
+        counts,
+        kill,
+        notifications,
+    } = registration;
+    if ctx.echo {
+        return echo::serve(ws_send, ws_recv, counts, kill).await;
+    }
+
+    let name = query.and_then(|q| q.name);
+    let command = match select_command(&ctx.commands, ctx.default_server, name.as_deref()) {
+        Some(command) => command.clone(),
+        None => return Err(close_with(&mut ws_send, ConnError::NoServer).await),
+    };
+    // Give the connection its own directory with `--per-connection-workspace`.
+    let workspace = match &ctx.workspaces {
+        Some(workspaces) => match workspaces.create(connection).await {
+            Ok(workspace) => Some(workspace),
+            Err(err) => return Err(close_with(&mut ws_send, ConnError::Workspace(err)).await),
+        },
+        None => None,
+    };
+    let ctx = match &workspace {
+        Some(workspace) => ctx
+            .in_workspace(workspace.path())
+            .map_err(ConnError::SyncFilter)?,
+        None => ctx,
+    };
+    // Adopt the server initialized on warmup if available. Not in the workspace of the
+    // connection, so it's only adopted without one.
+    let parked = match workspace {
+        Some(_) => None,
+        None => ctx.pool.take(&command.name).await,
+    };
+    let expanded = pool::expand_command(&command, &ctx.cwd, ctx.port);
+    // The adopted server was kept with `linger`, and already got `initialized`.
+    let mut lingered = false;
+    // Counts the server for `max_servers` until it exits, or is handed over with `linger`.
+    let (server, mut warm_init, slot) = match parked {
+        Some(parked) => {
+            if parked.lingered {
+                tracing::info!("adopting lingering {}", command.name);
+            } else {
+                tracing::info!("adopting warmed up {}", command.name);
+            }
+            lingered = parked.lingered;
+            (parked.server, Some(parked.initialize_result), parked.slot)
+        }
+        None => {
+            // The parked server expected on reserve was taken by another connection.
+            let slot = match slot {
+                Some(slot) => slot,
+                None => match ctx.server_count.reserve(ctx.max_servers) {
+                    Some(slot) => slot,
+                    None => {
+                        let running = ctx.server_count.running();
+                        return Err(
+                            close_with(&mut ws_send, ConnError::TooManyServers(running)).await
+                        );
+                    }
+                },
+            };
+            tracing::info!("starting {} in {}", command.name, ctx.cwd);
+            let spawned = pool::spawn_with_retries(ctx.spawn_retries, || {
+                pool::spawn(
+                    &expanded,
+                    workspace.as_ref().map(|w| w.path()),
+                    ctx.spawn_options,
+                )
+            })
+            .await;
+            match spawned {
+                Ok(server) => (server, None, Some(slot)),
+                Err(err) => return Err(close_with(&mut ws_send, ConnError::Spawn(err)).await),
+            }
         }
+    };
+    tracing::debug!("running {}", command.name);
+    ctx.connections
+        .set_server(connection, &command.name, server.child.id());
+    let _ = ctx.events.send(Event::ServerSpawned {
+        connection,
+        command: command.name.clone(),
+        pid: server.child.id(),
+    });
+    let init_options = ctx.init_options.get(&command.name);
+    let source_root =
+        lsp::ext::SourceRoot::new(ctx.cwd.clone()).with_prefix(ctx.remap_prefix.clone());
+
+    // Keep `child` until the end of the connection because it's killed on drop.
+    let pool::Server {
+        mut child,
+        writer: mut server_send,
+        reader: mut server_recv,
+    } = server;
+    // Send to the client from a separate task through a bounded buffer, so a slow client
+    // slows down reading from the server instead of growing memory.
+    let (client_send, client_send_rx) = mpsc::channel(ctx.buffer_size);
+    let mut client_writer = tokio::spawn(write_to_client(ws_send, client_send_rx));
+    if ctx.announce_server {
+        let text = server_selected_message(&command.name, &expanded).to_string();
+        tracing::debug!("<- {}", ctx.logged(&text));
+        ctx.capture(connection, Direction::ToClient, &text);
+        send_to_client(&client_send, warp::ws::Message::text(text)).await?;
+    }
+    let strict = ctx.strict;
+    let client_recv = ws_recv
+        .filter_map(move |wsm| filter_map_warp_ws_message(wsm, strict))
+        .flat_map(flatten_batch)
+        .boxed();
+    let client_recv = if ctx.remap {
+        let source_root = source_root.clone();
+        client_recv
+            .map(move |item| remap_from_client(item, &source_root))
+            .boxed()
     } else {
-        &ctx.commands[0]
+        client_recv
     };
-    tracing::info!("starting {} in {}", command[0], ctx.cwd);
-    let mut server = Command::new(&command[0])
-        .args(&command[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()?;
-    tracing::debug!("running {}", command[0]);
-
-    let mut server_send = lsp::framed::writer(server.stdin.take().unwrap());
-    let mut server_recv = lsp::framed::reader(server.stdout.take().unwrap());
-    let (mut client_send, client_recv) = ws.split();
-    let client_recv = client_recv
-        .filter_map(filter_map_warp_ws_message)
-        // Chain this with `Done` so we know when the client disconnects
-        .chain(stream::once(async { Ok(Message::Done) }));
+    let client_recv = if let Some(limit) = ctx.rate_limit {
+        Throttled::new(client_recv, limit)
+            .map(|item| item.unwrap_or(Ok(Message::RateLimited)))
+            .boxed()
+    } else {
+        client_recv
+    };
+    // Chain this with `Done` so we know when the client disconnects
+    let client_recv = client_recv.chain(stream::once(async { Ok(Message::Done) }));
     // Tick every 30s so we can ping the client to keep the connection alive
     let ticks = stream::unfold(
         tokio::time::interval(std::time::Duration::from_secs(30)),
@@ -120,43 +621,321 @@ async fn connected(
             Some((Ok(Message::Tick), interval))
         },
     );
-    let mut client_recv = stream::select(client_recv, ticks).boxed();
+    // Terminate when requested with `DELETE /connections/{id}`.
+    let killed = stream::once(async move {
+        kill.notified().await;
+        Ok(Message::Killed)
+    });
+    // Flush notifications batched with `write_delay`.
+    let (flush_tx, flush_rx) = mpsc::unbounded_channel();
+    let flushes = stream::unfold(flush_rx, |mut rx| async move {
+        rx.recv().await.map(|()| (Ok(Message::Flush), rx))
+    });
+    // Notifications from `POST /notify-changed`.
+    let notifications = stream::unfold(notifications, |mut rx| async move {
+        rx.recv().await.map(|text| (Ok(Message::Notify(text)), rx))
+    });
+    let mut client_recv = stream::select(
+        stream::select(client_recv, ticks),
+        stream::select(stream::select(killed, flushes), notifications),
+    )
+    .boxed();
 
     let mut client_msg = client_recv.next();
     let mut server_msg = server_recv.next();
     // Keeps track if `pong` was received since sending the last `ping`.
     let mut is_alive = true;
+    // Tracks `shutdown` and `exit` to exit the server cleanly on disconnect.
+    let mut lifecycle = Lifecycle::default();
+    // The server closed stdout.
+    let mut server_exited = false;
+    let mut timer = ctx.slow_request_threshold.map(RequestTimer::new);
+    let mut spans = if ctx.trace_requests {
+        Some(RequestSpans::default())
+    } else {
+        None
+    };
+    let mut inflight = ctx.max_inflight.map(InflightLimit::new);
+    // Requests with `workDoneToken` to correlate `$/progress` from the server with.
+    let mut progress = ProgressTracker::default();
+    // Requests from the server waiting for the client, answered if the server is kept with `linger`.
+    let mut server_requests = ServerRequests::default();
+    // Requests still pending when the connection ends are counted as unanswered on drop.
+    let mut methods = MethodTracker::new(ctx.metrics.clone());
+    let mut ids = ctx.coerce_ids.map(lsp::ext::IdCoercion::new);
+    let mut positions = match ctx.position_encoding {
+        lsp::ext::PositionEncoding::Utf8 => Some(lsp::ext::PositionTranslator::new()),
+        lsp::ext::PositionEncoding::Utf16 => None,
+    };
+    // Whether messages written to the server are waiting to be flushed.
+    let mut unflushed = false;
+    let mut flush_scheduled = false;
+    // Methods from the client not handled by the proxy, to report each once.
+    let mut unknown_methods = HashSet::new();
+    // Responses to requests in batches are sent combined.
+    let mut batches = batch::Batches::default();
+    // Code and reason of the close frame from the client.
+    let mut client_close = None;
+    // Messages from the server until the client's `initialize` is forwarded, so that early
+    // logs like startup errors are sent in order once the client is ready for them.
+    let mut pre_init = Some(Vec::new());
+    // Id of the forwarded `initialize` to remove the capabilities of blocked methods from,
+    // and to keep the result of for `linger`.
+    let mut init_id = None;
+    let mut init_result = warm_init.clone();
+    // Documents opened by the client, closed before the server is kept with `linger`.
+    let mut open_documents = HashSet::new();
+    // The client disconnected leaving the server running for `linger`.
+    let mut lingering = false;
 
     loop {
         match select(client_msg, server_msg).await {
             // From Client
             Either::Left((from_client, p_server_msg)) => {
                 match from_client {
+                    // The server was initialized on warmup. Respond with the result from it.
+                    Some(Ok(Message::Message(
+                        lsp::Envelope {
+                            msg: lsp::Message::Request(lsp::Request::Initialize { id, params: _ }),
+                            extra: _,
+                        },
+                        _,
+                        _,
+                    ))) if warm_init.is_some() => {
+                        tracing::info!(
+                            "responding to initialize with the result of the adopted {}, ignoring the client's params",
+                            command.name
+                        );
+                        let mut result = warm_init.take().unwrap();
+                        ctx.blocked_methods.remove_capabilities(&mut result);
+                        let result = serde_json::from_value(result)?;
+                        let res = lsp::Message::from(lsp::Response::Success { id, result });
+                        let text = serde_json::to_string(&res)?;
+                        tracing::debug!("<- {}", ctx.logged(&text));
+                        ctx.capture(connection, Direction::ToClient, &text);
+                        if let Some(text) = batches.collect(text) {
+                            send_to_client(&client_send, warp::ws::Message::text(text)).await?;
+                        }
+                        flush_pre_init(&mut pre_init, &mut batches, &client_send).await?;
+                    }
+
+                    // The lingering server was initialized by the previous client.
+                    Some(Ok(Message::Message(
+                        lsp::Envelope {
+                            msg: lsp::Message::Notification(lsp::Notification::Initialized { .. }),
+                            extra: _,
+                        },
+                        text,
+                        _,
+                    ))) if lingered => {
+                        lingered = false;
+                        tracing::debug!("dropped already initialized -> {}", ctx.logged(&text));
+                    }
+
+                    // Blocked with `--block-method`. Answer requests without forwarding.
+                    Some(Ok(Message::Message(_, text, value)))
+                        if ctx.blocked_methods.blocks(&value) =>
+                    {
+                        match ctx.blocked_methods.respond(&value) {
+                            Some(res) => {
+                                let res = serde_json::to_string(&lsp::Message::from(res))?;
+                                tracing::debug!("blocked -> {}", ctx.logged(&text));
+                                tracing::debug!("<- {}", ctx.logged(&res));
+                                ctx.capture(connection, Direction::ToClient, &res);
+                                if let Some(res) = batches.collect(res) {
+                                    send_to_client(&client_send, warp::ws::Message::text(res))
+                                        .await?;
+                                }
+                            }
+                            None => tracing::debug!("dropped -> {}", ctx.logged(&text)),
+                        }
+                    }
+
                     // Valid LSP message
-                    Some(Ok(Message::Message(mut msg))) => {
-                        if ctx.remap {
-                            lsp::ext::remap_relative_uri(&mut msg, &ctx.cwd)?;
-                            tracing::debug!("remapped relative URI from client");
+                    Some(Ok(Message::Message(mut envelope, text, mut value))) => {
+                        let msg = &mut envelope.msg;
+                        let is_notification = matches!(msg, lsp::Message::Notification(_));
+                        let is_initialize =
+                            matches!(msg, lsp::Message::Request(lsp::Request::Initialize { .. }));
+                        if let lsp::Message::Request(lsp::Request::Initialize { id, .. }) = msg {
+                            if !ctx.blocked_methods.is_empty() || ctx.linger.is_some() {
+                                init_id = Some(id.clone());
+                            }
                         }
-                        if ctx.sync {
-                            maybe_write_text_document(&msg).await?;
+                        lifecycle.observe(msg);
+                        if let lsp::Message::Unknown(unknown) = msg {
+                            let method = unknown.method().unwrap_or_default();
+                            tracing::debug!("-> unknown method {:?}", method);
+                            counts.unknown.fetch_add(1, Ordering::Relaxed);
+                            if !method.is_empty() && unknown_methods.insert(method.to_owned()) {
+                                let _ = ctx.events.send(Event::UnknownMethod {
+                                    connection,
+                                    method: method.to_owned(),
+                                });
+                            }
+                        }
+                        // Remapped on receive with `remap`.
+                        let mut modified = ctx.remap;
+                        if let Some(positions) = &mut positions {
+                            positions.client_to_server(msg);
+                            modified = true;
+                        }
+                        if let Some(root) = &ctx.root {
+                            if lsp::ext::inject_root(msg, root) {
+                                tracing::debug!("injected root {}", root);
+                                modified = true;
+                            }
+                        }
+                        if ctx.linger.is_some() {
+                            track_open_documents(&mut open_documents, msg);
+                        }
+                        if ctx.sync && !ctx.readonly {
+                            let created = maybe_write_text_document(
+                                msg,
+                                &ctx.sync_filter,
+                                ctx.sync_create_dirs,
+                                ctx.normalize_eol,
+                            )
+                            .await?;
+                            if let (true, Some(path)) = (ctx.ephemeral, created) {
+                                ctx.connections.record_created(connection, path);
+                            }
+                        }
+                        if let Some(defaults) = init_options {
+                            lsp::ext::merge_initialization_options(
+                                msg,
+                                defaults,
+                                ctx.force_init_options,
+                            );
+                            modified = true;
+                        }
+                        // Forward the original text unless modified, so that messages are
+                        // passed through as is without the normalization from serializing.
+                        let mut text = if modified {
+                            to_string_or_original(&envelope, text)
+                        } else {
+                            text
+                        };
+                        // Modifications don't change the id and the method that `value` is used
+                        // for below, so it's only updated to coerce the id of the message sent.
+                        if let Some(ids) = &mut ids {
+                            if modified {
+                                if let Ok(modified) = serde_json::to_value(&envelope) {
+                                    value = modified;
+                                }
+                            }
+                            if ids.coerce(&mut value) {
+                                text = value.to_string();
+                            }
+                        }
+                        tracing::debug!("-> {}", ctx.logged(&text));
+                        ctx.capture(connection, Direction::ToServer, &text);
+                        if let Some(method) = server_requests.response(&value) {
+                            tracing::debug!("-> response to {}", method);
+                        }
+                        if let Some(token) = lsp::ext::work_done_token(&envelope.msg) {
+                            tracing::debug!("-> with workDoneToken {:?}", token);
+                            progress.request(token, &value);
+                        }
+                        // Answered without forwarding if the request is still queued.
+                        if let Some(mut res) = inflight.as_mut().and_then(|l| l.cancel(&value)) {
+                            if let Some(ids) = &mut ids {
+                                ids.restore(&mut res);
+                            }
+                            let res = res.to_string();
+                            tracing::debug!("<- {}", ctx.logged(&res));
+                            ctx.capture(connection, Direction::ToClient, &res);
+                            if let Some(res) = batches.collect(res) {
+                                send_to_client(&client_send, warp::ws::Message::text(res)).await?;
+                            }
+                            if let Some(exit) = inflight.as_mut().and_then(InflightLimit::exit) {
+                                tracing::debug!("released -> {}", ctx.logged(&exit));
+                                server_send.send(exit).await?;
+                                unflushed = false;
+                                counts.to_server.fetch_add(1, Ordering::Relaxed);
+                            }
+                        } else {
+                            let message = match &mut inflight {
+                                Some(limit) => limit.request(text, value),
+                                None => Some((text, value)),
+                            };
+                            if let Some((_, value)) = &message {
+                                record_request(value, &mut methods, &mut timer, &mut spans);
+                            }
+                            match (message, ctx.write_delay) {
+                                // Sent when responses to the outstanding requests come back.
+                                (None, _) => {
+                                    if let Some(limit) = &inflight {
+                                        tracing::debug!(
+                                            "queued request ({} waiting)",
+                                            limit.queued()
+                                        );
+                                    }
+                                }
+                                // Batch notifications. Anything else is written immediately along
+                                // with the pending notifications to avoid delaying responses.
+                                (Some((text, _)), Some(delay)) if is_notification => {
+                                    server_send.feed(text).await?;
+                                    unflushed = true;
+                                    if !flush_scheduled {
+                                        flush_scheduled = true;
+                                        let flush_tx = flush_tx.clone();
+                                        tokio::spawn(async move {
+                                            tokio::time::sleep(delay).await;
+                                            let _ = flush_tx.send(());
+                                        });
+                                    }
+                                    counts.to_server.fetch_add(1, Ordering::Relaxed);
+                                }
+                                (Some((text, _)), _) => {
+                                    server_send.send(text).await?;
+                                    unflushed = false;
+                                    counts.to_server.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                        if is_initialize {
+                            flush_pre_init(&mut pre_init, &mut batches, &client_send).await?;
                         }
-                        let text = serde_json::to_string(&msg)?;
-                        tracing::debug!("-> {}", text);
-                        server_send.send(text).await?;
+                    }
+
+                    // Malformed request. Respond with an error without forwarding.
+                    Some(Ok(Message::Rejected(res))) => {
+                        let text = serde_json::to_string(&lsp::Message::from(res))?;
+                        tracing::warn!("<- {}", ctx.logged(&text));
+                        ctx.capture(connection, Direction::ToClient, &text);
+                        if let Some(text) = batches.collect(text) {
+                            send_to_client(&client_send, warp::ws::Message::text(text)).await?;
+                        }
+                    }
+
+                    // URI in the message couldn't be remapped. Dropped without forwarding.
+                    Some(Ok(Message::Dropped(text, reason))) => {
+                        tracing::warn!("dropped -> {}: {}", ctx.logged(&text), reason);
+                    }
+
+                    // Start of a batch. The messages in it follow.
+                    Some(Ok(Message::Batch(ids, _))) => {
+                        tracing::debug!("-> batch with {} requests", ids.len());
+                        batches.start(ids);
                     }
 
                     // Invalid JSON body
                     Some(Ok(Message::Invalid(text))) => {
-                        tracing::warn!("-> {}", text);
+                        tracing::warn!("-> {}", ctx.logged(&text));
+                        ctx.capture(connection, Direction::ToServer, &text);
                         // Just forward it to the server as is.
                         server_send.send(text).await?;
+                        unflushed = false;
+                        counts.to_server.fetch_add(1, Ordering::Relaxed);
                     }
 
                     // Close message
-                    Some(Ok(Message::Close)) => {
+                    Some(Ok(Message::Close(frame))) => {
                         // The connection will terminate when None is received.
-                        tracing::info!("received Close message");
+                        tracing::info!("received Close message {:?}", frame);
+                        // Without a status code, the close is still intentional.
+                        client_close = Some(frame.unwrap_or((NO_STATUS_CODE, String::new())));
                     }
 
                     // Ping the client to keep the connection alive
@@ -164,12 +943,19 @@ async fn connected(
                         // Terminate if we haven't heard back from the previous ping.
                         if !is_alive {
                             tracing::warn!("terminating unhealthy connection");
+                            // Likely not delivered, but try in case only pongs are lost.
+                            send_to_client(&client_send, CloseReason::Unhealthy.message()).await?;
                             break;
                         }
 
                         is_alive = false;
                         tracing::debug!("pinging the client");
-                        client_send.send(warp::ws::Message::ping(vec![])).await?;
+                        send_to_client(&client_send, warp::ws::Message::ping(vec![])).await?;
+                        let _ = ctx.events.send(Event::MessageForwarded {
+                            connection,
+                            to_server: counts.to_server(),
+                            to_client: counts.to_client(),
+                        });
                     }
 
                     // Mark the connection as alive on any pong.
@@ -178,9 +964,98 @@ async fn connected(
                         is_alive = true;
                     }
 
+                    // Too many messages queued by the rate limit
+                    Some(Ok(Message::RateLimited)) => {
+                        tracing::warn!("terminating connection exceeding the rate limit");
+                        send_to_client(&client_send, CloseReason::RateLimited.message()).await?;
+                        break;
+                    }
+
+                    // Write notifications batched with `write_delay`
+                    Some(Ok(Message::Flush)) => {
+                        flush_scheduled = false;
+                        if unflushed {
+                            server_send.flush().await?;
+                            unflushed = false;
+                        }
+                    }
+
+                    // Notification from `POST /notify-changed`. Dropped until the server is
+                    // initialized by the client.
+                    Some(Ok(Message::Notify(text))) if pre_init.is_some() => {
+                        tracing::debug!("dropped before initialize -> {}", ctx.logged(&text));
+                    }
+
+                    Some(Ok(Message::Notify(text))) => {
+                        let text = if ctx.remap {
+                            let mut envelope = lsp::Envelope::from_str(&text)?;
+                            match lsp::ext::remap_relative_uri(&mut envelope.msg, &source_root) {
+                                Ok(()) => Some(to_string_or_original(&envelope, text)),
+                                Err(err) => {
+                                    tracing::warn!("dropped -> {}: {}", ctx.logged(&text), err);
+                                    None
+                                }
+                            }
+                        } else {
+                            Some(text)
+                        };
+                        if let Some(text) = text {
+                            tracing::debug!("-> {}", ctx.logged(&text));
+                            ctx.capture(connection, Direction::ToServer, &text);
+                            server_send.send(text).await?;
+                            unflushed = false;
+                            counts.to_server.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+
+                    // Termination requested
+                    Some(Ok(Message::Killed)) => {
+                        tracing::warn!("terminating connection on request");
+                        send_to_client(&client_send, CloseReason::Killed.message()).await?;
+                        child.kill().await?;
+                        break;
+                    }
+
                     // Connection closed
                     Some(Ok(Message::Done)) => {
-                        tracing::info!("connection closed");
+                        // Disconnected without a close frame if `None`.
+                        match &client_close {
+                            Some((code, _)) if is_normal_close(*code) => {
+                                tracing::info!(code, "connection closed");
+                            }
+                            Some((code, reason)) => {
+                                tracing::warn!(code, "connection closed abnormally: {}", reason);
+                            }
+                            None => {
+                                tracing::warn!(
+                                    code = ABNORMAL_CLOSE_CODE,
+                                    "connection closed without a close frame"
+                                );
+                            }
+                        }
+                        // Keep the server for a reconnecting client unless it's shutting down.
+                        if ctx.linger.is_some()
+                            && workspace.is_none()
+                            && init_result.is_some()
+                            && !lifecycle.is_shutting_down()
+                        {
+                            lingering = true;
+                        // Web clients often close without `exit` after `shutdown`. It may also
+                        // be held behind the requests queued with `max_inflight`.
+                        } else if lifecycle.needs_exit()
+                            || matches!(&inflight, Some(limit) if limit.holds_exit())
+                        {
+                            tracing::info!("sending exit to the server");
+                            let exit = serde_json::json!({"jsonrpc": "2.0", "method": "exit"});
+                            server_send.send(exit.to_string()).await?;
+                            unflushed = false;
+                            // Give the server a chance to exit before it's stopped.
+                            let _ = tokio::time::timeout(
+                                std::time::Duration::from_secs(1),
+                                child.wait(),
+                            )
+                            .await;
+                        }
                         break;
                     }
 
@@ -203,33 +1078,200 @@ async fn connected(
             Either::Right((from_server, p_client_msg)) => {
                 match from_server {
                     // Serialized LSP Message
-                    Some(Ok(text)) => {
-                        if ctx.remap {
-                            if let Ok(mut msg) = lsp::Message::from_str(&text) {
-                                lsp::ext::remap_relative_uri(&mut msg, &ctx.cwd)?;
-                                tracing::debug!("remapped relative URI from server");
-                                let text = serde_json::to_string(&msg)?;
-                                tracing::debug!("<- {}", text);
-                                client_send.send(warp::ws::Message::text(text)).await?;
-                            } else {
-                                tracing::warn!("<- {}", text);
-                                client_send.send(warp::ws::Message::text(text)).await?;
+                    Some(Ok(mut text)) => {
+                        // Parsed once for the features matching responses to requests.
+                        let mut value =
+                            serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
+                        methods.response(&value);
+                        if let Some(spans) = &mut spans {
+                            spans.response(&value);
+                        }
+                        if let Some(p) = progress.server(&value) {
+                            tracing::debug!(
+                                "<- progress {} {:?} for {} {}",
+                                p.kind,
+                                p.token,
+                                p.method,
+                                p.id
+                            );
+                            let _ = ctx.events.send(Event::Progress {
+                                connection,
+                                token: p.token,
+                                method: p.method,
+                                id: p.id,
+                                kind: p.kind,
+                                percentage: p.percentage,
+                            });
+                        }
+                        if let Some((next, next_value)) =
+                            inflight.as_mut().and_then(|l| l.response(&value))
+                        {
+                            tracing::debug!("released -> {}", ctx.logged(&next));
+                            record_request(&next_value, &mut methods, &mut timer, &mut spans);
+                            server_send.send(next).await?;
+                            unflushed = false;
+                            counts.to_server.fetch_add(1, Ordering::Relaxed);
+                            if let Some(exit) = inflight.as_mut().and_then(InflightLimit::exit) {
+                                tracing::debug!("released -> {}", ctx.logged(&exit));
+                                server_send.send(exit).await?;
+                                counts.to_server.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        if let Some(slow) = timer.as_mut().and_then(|t| t.response(&value)) {
+                            tracing::debug!(
+                                "slow request {} {} took {:?}",
+                                slow.method,
+                                slow.id,
+                                slow.elapsed
+                            );
+                        }
+                        if let Some(ids) = &mut ids {
+                            if ids.restore(&mut value) {
+                                text = value.to_string();
+                            }
+                        }
+                        if matches!(&init_id, Some(id) if is_response_to(&value, id)) {
+                            init_id = None;
+                            if let Some(result) = value.get_mut("result") {
+                                if ctx.linger.is_some() {
+                                    init_result = Some(result.clone());
+                                }
+                                if ctx.blocked_methods.remove_capabilities(result) {
+                                    text = value.to_string();
+                                }
+                            }
+                        }
+                        let text = if ctx.remap
+                            || positions.is_some()
+                            || ctx.filter.is_active()
+                            || !ctx.diagnostic_severity.is_empty()
+                            || ctx.config_response.is_some()
+                        {
+                            match lsp::Envelope::try_from(value.clone()) {
+                                Ok(envelope) if ctx.filter.drops(&envelope.msg) => {
+                                    tracing::debug!("dropped <- {}", ctx.logged(&text));
+                                    None
+                                }
+                                Ok(mut envelope) => match answer_configuration(&ctx, &envelope) {
+                                    // Answer the server instead of forwarding to the client.
+                                    Some(res) => {
+                                        let res = serde_json::to_string(&lsp::Message::from(res))?;
+                                        tracing::debug!("answered <- {}", ctx.logged(&text));
+                                        tracing::debug!("-> {}", ctx.logged(&res));
+                                        ctx.capture(connection, Direction::ToServer, &res);
+                                        server_send.send(res).await?;
+                                        unflushed = false;
+                                        None
+                                    }
+                                    None => {
+                                        if let Some(positions) = &mut positions {
+                                            positions.server_to_client(&mut envelope.msg);
+                                        }
+                                        let remapped = if ctx.remap {
+                                            lsp::ext::remap_relative_uri(
+                                                &mut envelope.msg,
+                                                &source_root,
+                                            )
+                                        } else {
+                                            Ok(())
+                                        };
+                                        match remapped {
+                                            // Dropped like the ones from the client, so that
+                                            // the connection is kept.
+                                            Err(err) => {
+                                                tracing::warn!(
+                                                    "dropped <- {}: {}",
+                                                    ctx.logged(&text),
+                                                    err
+                                                );
+                                                None
+                                            }
+                                            Ok(()) => {
+                                                if ctx.remap {
+                                                    tracing::debug!(
+                                                        "remapped relative URI from server"
+                                                    );
+                                                }
+                                                if lsp::ext::rewrite_diagnostic_severity(
+                                                    &mut envelope.msg,
+                                                    &ctx.diagnostic_severity,
+                                                ) {
+                                                    tracing::debug!(
+                                                        "rewrote severities of diagnostics"
+                                                    );
+                                                }
+                                                let text = to_string_or_original(&envelope, text);
+                                                tracing::debug!("<- {}", ctx.logged(&text));
+                                                Some(text)
+                                            }
+                                        }
+                                    }
+                                },
+                                Err(_) => {
+                                    tracing::warn!("<- {}", ctx.logged(&text));
+                                    Some(text)
+                                }
                             }
                         } else {
-                            tracing::debug!("<- {}", text);
-                            client_send.send(warp::ws::Message::text(text)).await?;
+                            tracing::debug!("<- {}", ctx.logged(&text));
+                            Some(text)
+                        };
+                        if let Some(text) = text {
+                            ctx.capture(connection, Direction::ToClient, &text);
+                            server_requests.request(&value);
+                            match &mut pre_init {
+                                Some(queue) => queue.push(text),
+                                None => {
+                                    if let Some(text) = batches.collect(text) {
+                                        let msg = warp::ws::Message::text(text);
+                                        send_to_client(&client_send, msg).await?;
+                                    }
+                                }
+                            }
+                            counts.to_client.fetch_add(1, Ordering::Relaxed);
                         }
                     }
 
                     // Codec Error
                     Some(Err(err)) => {
                         tracing::error!("{}", err);
+                        if ctx.report_codec_errors {
+                            let text = codec_error_message(&err).to_string();
+                            tracing::debug!("<- {}", ctx.logged(&text));
+                            ctx.capture(connection, Direction::ToClient, &text);
+                            match &mut pre_init {
+                                Some(queue) => queue.push(text),
+                                None => {
+                                    let msg = warp::ws::Message::text(text);
+                                    send_to_client(&client_send, msg).await?;
+                                }
+                            }
+                        }
                     }
 
                     // Server exited
                     None => {
                         tracing::error!("server process exited unexpectedly");
-                        client_send.send(warp::ws::Message::close()).await?;
+                        let tripped = match &ctx.breaker {
+                            Some(breaker) if !lifecycle.is_shutting_down() => {
+                                breaker.record_crash(&command.name, Instant::now())
+                            }
+                            _ => false,
+                        };
+                        if tripped {
+                            tracing::warn!(
+                                "{} crashed repeatedly, not starting it for a while",
+                                command.name
+                            );
+                        }
+                        let _ = ctx.events.send(Event::ServerCrashed {
+                            connection,
+                            command: command.name.clone(),
+                        });
+                        server_exited = true;
+                        // Likely the reason it exited.
+                        flush_pre_init(&mut pre_init, &mut batches, &client_send).await?;
+                        send_to_client(&client_send, CloseReason::ServerExited.message()).await?;
                         break;
                     }
                 }
@@ -240,41 +1282,394 @@ async fn connected(
         }
     }
 
+    if unflushed {
+        // Ignore the error because the server might have exited.
+        let _ = server_send.flush().await;
+    }
+    if let (true, Some(linger), Some(initialize_result)) = (lingering, ctx.linger, init_result) {
+        // Close the documents of the client, so the next one can open them again.
+        for uri in open_documents {
+            let close = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didClose",
+                "params": {"textDocument": {"uri": uri}},
+            });
+            server_send.feed(close.to_string()).await?;
+        }
+        // Answer the requests the client left, so the server isn't waiting for them.
+        for res in server_requests.cancel("lsp-ws-proxy: client disconnected") {
+            server_send.feed(res).await?;
+        }
+        server_send.flush().await?;
+        let server = pool::Server {
+            child,
+            writer: server_send,
+            reader: server_recv,
+        };
+        if !ctx
+            .pool
+            .linger(&command.name, server, initialize_result, slot, linger)
+        {
+            tracing::info!("stopped {} because another one is kept", command.name);
+        }
+    } else {
+        // Close stdin in case the server only closed stdout and is still reading.
+        drop(server_send);
+        let stopped = if server_exited {
+            pool::wait_or_kill(&mut child, SERVER_EXIT_TIMEOUT).await
+        } else {
+            pool::terminate(&mut child, ctx.kill_signal, ctx.kill_timeout).await
+        };
+        if let Err(err) = stopped {
+            tracing::error!("failed to wait for the server to exit: {}", err);
+        }
+    }
+    // Let the writer send the queued messages, e.g., close frame.
+    drop(client_send);
+    if tokio::time::timeout(CLIENT_SEND_TIMEOUT, &mut client_writer)
+        .await
+        .is_err()
+    {
+        client_writer.abort();
+    }
+    let _ = ctx.events.send(Event::MessageForwarded {
+        connection,
+        to_server: counts.to_server(),
+        to_client: counts.to_client(),
+    });
     Ok(())
 }
 
+// Notification telling the client which server it's connected to with `--announce-server`.
+fn server_selected_message(name: &str, command: &[String]) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "$/proxy/serverSelected",
+        "params": {"name": name, "command": command},
+    })
+}
+
+// `window/logMessage` telling the client that the server wrote a malformed message.
+fn codec_error_message(err: &CodecError) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "window/logMessage",
+        "params": {
+            "type": 1,
+            "message": format!("lsp-ws-proxy: malformed message from server: {}", err),
+        },
+    })
+}
+
+// Whether `value` is the response to the client's request with `id`, e.g., `initialize`.
+fn is_response_to(value: &serde_json::Value, id: &lsp::types::Id) -> bool {
+    let res_id = value
+        .get("id")
+        .and_then(|id| serde_json::from_value::<lsp::types::Id>(id.clone()).ok());
+    value.get("method").is_none() && res_id.as_ref() == Some(id)
+}
+
+// Track the documents opened by the client to close them before the server is kept.
+fn track_open_documents(open_documents: &mut HashSet<Url>, msg: &lsp::Message) {
+    match msg {
+        lsp::Message::Notification(lsp::Notification::DidOpen { params }) => {
+            open_documents.insert(params.text_document.uri.clone());
+        }
+        lsp::Message::Notification(lsp::Notification::DidClose { params }) => {
+            open_documents.remove(&params.text_document.uri);
+        }
+        _ => {}
+    }
+}
+
+// The response to `workspace/configuration` from the server with `--config-response`.
+fn answer_configuration(ctx: &Context, envelope: &lsp::Envelope) -> Option<lsp::Response> {
+    let presets = ctx.config_response.as_ref()?;
+    lsp::ext::respond_configuration(&envelope.msg, presets)
+}
+
+/// Why a connection ended with an error.
+#[derive(Debug, Error)]
+pub enum ConnError {
+    #[error("no Language Server is configured")]
+    NoServer,
+
+    #[error("failed to create workspace: {0}")]
+    Workspace(#[source] std::io::Error),
+
+    #[error("failed to filter synced files in workspace: {0}")]
+    SyncFilter(#[source] ignore::Error),
+
+    #[error("failed to start server: {0}")]
+    Spawn(#[source] std::io::Error),
+
+    #[error("too many Language Servers are running ({0})")]
+    TooManyServers(usize),
+
+    /// Failed to read from or write to the server.
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+
+    /// Failed to send to the client. Only the message is kept because the error depends on
+    /// the transport.
+    #[error("failed to send to client: {0}")]
+    Send(String),
+
+    #[error("client writer stopped")]
+    WriterStopped,
+
+    #[error("terminating unhealthy connection not accepting messages")]
+    Unhealthy,
+
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl ConnError {
+    /// Short name of the variant for logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NoServer => "no_server",
+            Self::Workspace(_) => "workspace",
+            Self::SyncFilter(_) => "sync_filter",
+            Self::Spawn(_) => "spawn",
+            Self::TooManyServers(_) => "too_many_servers",
+            Self::Codec(_) => "codec",
+            Self::Send(_) => "send",
+            Self::WriterStopped => "writer_stopped",
+            Self::Unhealthy => "unhealthy",
+            Self::Serde(_) => "serde",
+            Self::Io(_) => "io",
+        }
+    }
+
+    /// The reason to close the connection with if the client can still be told.
+    pub(super) fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            Self::NoServer
+            | Self::Workspace(_)
+            | Self::SyncFilter(_)
+            | Self::Spawn(_)
+            | Self::TooManyServers(_) => Some(CloseReason::SpawnFailed),
+            _ => None,
+        }
+    }
+}
+
+// Close the connection with the reason for `err` before the server started, and return it.
+async fn close_with<S>(ws_send: &mut S, err: ConnError) -> ConnError
+where
+    S: Sink<warp::ws::Message> + Unpin,
+{
+    if let Some(reason) = err.close_reason() {
+        // Ignore the error to return the more useful one.
+        let _ = ws_send.send(reason.message()).await;
+    }
+    err
+}
+
+// Serialize the modified `envelope`. Falls back to the `original` text on failure
+// so that a single unusual message doesn't terminate the connection.
+fn to_string_or_original(envelope: &lsp::Envelope, original: String) -> String {
+    match serde_json::to_string(envelope) {
+        Ok(text) => text,
+        Err(err) => {
+            tracing::warn!(
+                "forwarding the original message after failing to serialize: {}",
+                err
+            );
+            original
+        }
+    }
+}
+
+// How long to wait for the server to exit after closing stdout before killing it.
+const SERVER_EXIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How long to wait for the client to accept a message before giving up on it.
+// Same as the ping interval so a stuck client is handled like an unhealthy one.
+const CLIENT_SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Queue `msg` to be sent to the client. Fails if the buffer stays full for too long.
+async fn send_to_client(
+    client_send: &mpsc::Sender<warp::ws::Message>,
+    msg: warp::ws::Message,
+) -> Result<(), ConnError> {
+    match tokio::time::timeout(CLIENT_SEND_TIMEOUT, client_send.send(msg)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err(ConnError::WriterStopped),
+        Err(_) => Err(ConnError::Unhealthy),
+    }
+}
+
+// Send the messages from the server queued before `initialize`, and stop queueing.
+async fn flush_pre_init(
+    pre_init: &mut Option<Vec<String>>,
+    batches: &mut batch::Batches,
+    client_send: &mpsc::Sender<warp::ws::Message>,
+) -> Result<(), ConnError> {
+    for text in pre_init.take().into_iter().flatten() {
+        if let Some(text) = batches.collect(text) {
+            send_to_client(client_send, warp::ws::Message::text(text)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn write_to_client<S>(mut ws_send: S, mut messages: mpsc::Receiver<warp::ws::Message>)
+where
+    S: Sink<warp::ws::Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    while let Some(msg) = messages.recv().await {
+        if let Err(err) = ws_send.send(msg).await {
+            tracing::error!("failed to send to client: {}", err);
+            break;
+        }
+    }
+}
+
+/// Why the proxy closed the connection. Sent to the client as the close code and reason.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum CloseReason {
+    /// `1001`: terminated with `DELETE /connections/{id}`.
+    Killed,
+    /// `1008`: sent more messages than the rate limit can queue.
+    RateLimited,
+    /// `4000`: the server exited or closed stdout.
+    ServerExited,
+    /// `4001`: the server failed to start.
+    SpawnFailed,
+    /// `4002`: the client didn't respond to ping.
+    Unhealthy,
+}
+
+impl CloseReason {
+    pub(super) fn code(self) -> u16 {
+        match self {
+            Self::Killed => 1001,
+            Self::RateLimited => 1008,
+            Self::ServerExited => 4000,
+            Self::SpawnFailed => 4001,
+            Self::Unhealthy => 4002,
+        }
+    }
+
+    pub(super) fn reason(self) -> &'static str {
+        match self {
+            Self::Killed => "connection terminated",
+            Self::RateLimited => "rate limit exceeded",
+            Self::ServerExited => "server exited",
+            Self::SpawnFailed => "server failed to start",
+            Self::Unhealthy => "ping timeout",
+        }
+    }
+
+    pub(super) fn message(self) -> warp::ws::Message {
+        warp::ws::Message::close_with(self.code(), self.reason())
+    }
+}
+
+/// Tracks the lifecycle messages from the client.
+#[derive(Debug, Default)]
+struct Lifecycle {
+    shutdown: bool,
+    exit: bool,
+}
+
+impl Lifecycle {
+    fn observe(&mut self, msg: &lsp::Message) {
+        let method = match msg {
+            lsp::Message::Request(lsp::Request::Shutdown { .. }) => Some("shutdown"),
+            lsp::Message::Notification(lsp::Notification::Exit { .. }) => Some("exit"),
+            // Sent without `params`.
+            lsp::Message::Unknown(unknown) => unknown.method(),
+            _ => None,
+        };
+        match method {
+            Some("shutdown") => self.shutdown = true,
+            Some("exit") => self.exit = true,
+            _ => {}
+        }
+    }
+
+    /// `shutdown` was requested without a following `exit`.
+    fn needs_exit(&self) -> bool {
+        self.shutdown && !self.exit
+    }
+
+    /// `shutdown` or `exit` was sent.
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown || self.exit
+    }
+}
+
 // Type to describe a message from the client conveniently.
 #[allow(clippy::large_enum_variant)]
 #[allow(clippy::enum_variant_names)]
 enum Message {
-    // Valid LSP message
-    Message(lsp::Message),
+    // Valid LSP message with the original text, and the text parsed as JSON
+    Message(lsp::Envelope, String, serde_json::Value),
+    // Malformed request with the error response for it
+    Rejected(lsp::Response),
     // Invalid JSON
     Invalid(String),
-    // Close message
-    Close,
+    // Message that can't be forwarded, with the reason
+    Dropped(String, String),
+    // Batch with the ids of the requests in it, and the messages.
+    // Flattened to the start of the batch without any message followed by the messages.
+    Batch(Vec<lsp::types::Id>, Vec<Message>),
+    // Close message with the code and reason if any
+    Close(Option<(u16, String)>),
     // Ping the client to keep the connection alive.
     // Note that this is from the interval stream and not actually from client.
     Tick,
     // Client disconnected. Necessary because the combined stream is infinite.
     Done,
+    // Client sent more messages than the rate limit can queue.
+    RateLimited,
+    // Termination was requested. Not actually from client.
+    Killed,
+    // Flush notifications batched with `write_delay`. Not actually from client.
+    Flush,
+    // Notification for the server from `POST /notify-changed`. Not actually from client.
+    Notify(String),
     // A reply for ping or heartbeat from client.
     Pong,
 }
 
+// Close codes reserved for a close frame without a code, and for closing without a frame.
+const NO_STATUS_CODE: u16 = 1005;
+const ABNORMAL_CLOSE_CODE: u16 = 1006;
+
+/// Whether the client closed with a code for a normal closure, e.g., navigating away.
+fn is_normal_close(code: u16) -> bool {
+    matches!(code, 1000 | 1001 | NO_STATUS_CODE)
+}
+
 // Parse the message and ignore anything we don't care.
 async fn filter_map_warp_ws_message(
     wsm: Result<warp::ws::Message, warp::Error>,
+    strict: bool,
 ) -> Option<Result<Message, warp::Error>> {
     match wsm {
         Ok(msg) => {
             if msg.is_close() {
-                Some(Ok(Message::Close))
+                let frame = msg
+                    .close_frame()
+                    .map(|(code, reason)| (code, reason.to_owned()));
+                Some(Ok(Message::Close(frame)))
             } else if msg.is_text() {
                 let text = msg.to_str().expect("text");
-                match lsp::Message::from_str(text) {
-                    Ok(msg) => Some(Ok(Message::Message(msg))),
-                    Err(_) => Some(Ok(Message::Invalid(text.to_owned()))),
+                match batch::split(text) {
+                    Some(texts) => Some(Ok(Message::Batch(
+                        batch::request_ids(&texts),
+                        texts.iter().map(|text| parse_text(text, strict)).collect(),
+                    ))),
+                    None => Some(Ok(parse_text(text, strict))),
                 }
             } else if msg.is_pong() {
                 Some(Ok(Message::Pong))
@@ -287,3 +1682,955 @@ async fn filter_map_warp_ws_message(
         Err(err) => Some(Err(err)),
     }
 }
+
+fn parse_text(text: &str, strict: bool) -> Message {
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value,
+        Err(_) => return Message::Invalid(text.to_owned()),
+    };
+    if strict {
+        if let Some(res) = lsp::validate::check_strict(&value) {
+            return Message::Rejected(res);
+        }
+    }
+    match lsp::Envelope::try_from(value.clone()) {
+        Ok(lsp::Envelope {
+            msg: lsp::Message::Unknown(unknown),
+            extra: _,
+        }) => match lsp::validate::check_unknown(&unknown) {
+            Some(res) => Message::Rejected(res),
+            None => Message::Message(lsp::Message::from(unknown).into(), text.to_owned(), value),
+        },
+        Ok(envelope) => Message::Message(envelope, text.to_owned(), value),
+        Err(_) => Message::Invalid(text.to_owned()),
+    }
+}
+
+// Track the request forwarded to the server for metrics, `slow_request_threshold`, and spans.
+// Not when queued with `max_inflight`, so that the time waiting to be sent isn't included.
+fn record_request(
+    value: &serde_json::Value,
+    methods: &mut MethodTracker,
+    timer: &mut Option<RequestTimer>,
+    spans: &mut Option<RequestSpans>,
+) {
+    if let Some(timer) = timer {
+        timer.request(value);
+    }
+    if let Some(spans) = spans {
+        spans.request(value);
+    }
+    methods.request(value);
+}
+
+// Remap URI in the message from the client with `remap`. Requests with URI that can't be
+// remapped, e.g., outside of the project, are rejected, and anything else is dropped, so that
+// the connection is kept.
+fn remap_from_client(
+    item: Result<Message, warp::Error>,
+    root: &lsp::ext::SourceRoot,
+) -> Result<Message, warp::Error> {
+    match item {
+        Ok(Message::Message(mut envelope, text, value)) => {
+            match lsp::ext::remap_relative_uri(&mut envelope.msg, root) {
+                Ok(()) => {
+                    tracing::debug!("remapped relative URI from client");
+                    Ok(Message::Message(envelope, text, value))
+                }
+                Err(err) => match request_id(&envelope.msg, &value) {
+                    Some(id) => Ok(Message::Rejected(lsp::Response::Failure {
+                        id: Some(id),
+                        error: lsp::error::Error {
+                            code: lsp::error::ErrorCode::InvalidParams,
+                            message: err.to_string(),
+                            data: None,
+                        },
+                    })),
+                    None => Ok(Message::Dropped(text, err.to_string())),
+                },
+            }
+        }
+        item => item,
+    }
+}
+
+// The id of `msg` parsed as `value` if it's a request.
+fn request_id(msg: &lsp::Message, value: &serde_json::Value) -> Option<lsp::types::Id> {
+    if !matches!(msg, lsp::Message::Request(_)) {
+        return None;
+    }
+    serde_json::from_value(value.get("id")?.clone()).ok()
+}
+
+// Yield the messages in a batch one by one after the start of it without any message,
+// so that each is handled like any other message.
+fn flatten_batch(
+    item: Result<Message, warp::Error>,
+) -> stream::Iter<std::vec::IntoIter<Result<Message, warp::Error>>> {
+    match item {
+        Ok(Message::Batch(ids, messages)) => stream::iter(
+            std::iter::once(Message::Batch(ids, Vec::new()))
+                .chain(messages)
+                .map(Ok)
+                .collect::<Vec<_>>(),
+        ),
+        item => stream::iter(vec![item]),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn observe_all(messages: &[&str]) -> Lifecycle {
+        let mut lifecycle = Lifecycle::default();
+        for text in messages {
+            lifecycle.observe(&lsp::Message::from_str(text).unwrap());
+        }
+        lifecycle
+    }
+
+    // Request to upgrade to WebSocket for `handler`.
+    fn ws_upgrade_request() -> warp::test::RequestBuilder {
+        warp::test::request()
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    // Sink to pass to `serve_client`, and the receiver of the messages sent to the client.
+    fn client_sink() -> (
+        impl Sink<warp::ws::Message, Error = Infallible> + Send + Unpin + 'static,
+        mpsc::UnboundedReceiver<warp::ws::Message>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let sink = futures_util::sink::unfold(tx, |tx, msg: warp::ws::Message| async move {
+            let _ = tx.send(msg);
+            Ok::<_, Infallible>(tx)
+        });
+        (Box::pin(sink), rx)
+    }
+
+    pub(crate) fn test_context(command: &[&str]) -> Context {
+        let cwd = std::env::current_dir().unwrap();
+        Context {
+            commands: vec![pool::ServerCommand::new(
+                command.iter().map(|s| s.to_string()).collect(),
+            )],
+            default_server: 0,
+            allowed_origins: Vec::new(),
+            sync: false,
+            readonly: false,
+            sync_filter: SyncFilter::new(&cwd, &[], &[]).unwrap(),
+            sync_create_dirs: true,
+            normalize_eol: LineEnding::None,
+            remap: false,
+            remap_prefix: None,
+            redact: false,
+            pretty_debug: false,
+            capture: None,
+            strict: false,
+            report_codec_errors: false,
+            write_delay: None,
+            filter: lsp::ext::MessageFilter::default(),
+            diagnostic_severity: Vec::new(),
+            config_response: None,
+            blocked_methods: lsp::ext::BlockedMethods::default(),
+            trust_proxy_headers: false,
+            linger: None,
+            announce_server: false,
+            kill_signal: pool::KillSignal::Kill,
+            kill_timeout: std::time::Duration::from_secs(5),
+            single_shot: None,
+            coerce_ids: None,
+            position_encoding: lsp::ext::PositionEncoding::Utf16,
+            slow_request_threshold: None,
+            trace_requests: false,
+            max_inflight: None,
+            init_options: HashMap::new(),
+            force_init_options: false,
+            events: broadcast::channel(16).0,
+            rate_limit: None,
+            pool: Pool::default(),
+            server_count: pool::ServerCount::default(),
+            metrics: Metrics::default(),
+            max_servers: None,
+            breaker: None,
+            connections: Registry::default(),
+            cwd: Url::from_directory_path(&cwd).unwrap(),
+            workspaces: None,
+            ephemeral: false,
+            buffer_size: 32,
+            spawn_options: pool::SpawnOptions::default(),
+            spawn_retries: 0,
+            echo: false,
+            port: None,
+            root: None,
+        }
+    }
+
+    // Connect with a minimal client to see the close frame, which `warp::test::ws` hides.
+    async fn close_frame(command: &[&str]) -> Option<(u16, String)> {
+        close_frame_with(test_context(command)).await
+    }
+
+    async fn close_frame_with(ctx: Context) -> Option<(u16, String)> {
+        use std::io::{Read, Write};
+
+        let (addr, server) = warp::serve(handler(ctx)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let received = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\n\
+                    Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                )
+                .unwrap();
+            let mut received = Vec::new();
+            let _ = stream.read_to_end(&mut received);
+            received
+        })
+        .await
+        .unwrap();
+
+        // Skip the response headers and find the close frame in the unmasked frames.
+        let start = received.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let mut frames = &received[start..];
+        while frames.len() >= 2 {
+            let (opcode, len) = (frames[0] & 0x0f, (frames[1] & 0x7f) as usize);
+            let payload = frames.get(2..2 + len)?;
+            if opcode == 0x8 {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                return Some((code, String::from_utf8_lossy(&payload[2..]).into_owned()));
+            }
+            frames = &frames[2 + len..];
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_reject_without_server() {
+        let mut ctx = test_context(&["true"]);
+        ctx.commands.clear();
+        let res = ws_upgrade_request().reply(&handler(ctx)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_reject_over_max_servers() {
+        let mut ctx = test_context(&["true"]);
+        ctx.max_servers = Some(2);
+        // Held by connections until their servers exit.
+        let slots: Vec<_> = (0..2).map(|_| ctx.reserve_server(None).unwrap()).collect();
+        assert!(slots.iter().all(Option::is_some));
+        let res = ws_upgrade_request().reply(&handler(ctx.clone())).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(slots);
+        assert!(ctx.reserve_server(None).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reject_crashing_server() {
+        let mut ctx = test_context(&["true"]);
+        ctx.breaker = Some(CircuitBreaker::new(
+            2,
+            std::time::Duration::from_secs(60),
+            std::time::Duration::from_secs(60),
+        ));
+        let reason = CloseReason::ServerExited;
+        for _ in 0..2 {
+            assert_eq!(
+                close_frame_with(ctx.clone()).await,
+                Some((reason.code(), reason.reason().to_owned()))
+            );
+        }
+        let res = ws_upgrade_request().reply(&handler(ctx)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["retry-after"], "60");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            body["reason"],
+            "true is temporarily unavailable after crashing repeatedly"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_disallowed_origin() {
+        let mut ctx = test_context(&["true"]);
+        ctx.allowed_origins = vec!["https://example.com".to_owned()];
+        let upgrade = |origin: &str| ws_upgrade_request().header("origin", origin);
+        let res = upgrade("https://evil.example")
+            .reply(&handler(ctx.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = upgrade("https://example.com").reply(&handler(ctx)).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_single_shot() {
+        let mut ctx = test_context(&["true"]);
+        let (single_shot, closed) = SingleShot::new();
+        ctx.single_shot = Some(single_shot);
+        let reason = CloseReason::ServerExited;
+        assert_eq!(
+            close_frame_with(ctx.clone()).await,
+            Some((reason.code(), reason.reason().to_owned()))
+        );
+        tokio::time::timeout(std::time::Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+            .unwrap();
+        let res = ws_upgrade_request().reply(&handler(ctx)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_close_on_server_exit() {
+        let reason = CloseReason::ServerExited;
+        assert_eq!(
+            close_frame(&["true"]).await,
+            Some((reason.code(), reason.reason().to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_on_spawn_failure() {
+        let reason = CloseReason::SpawnFailed;
+        assert_eq!(
+            close_frame(&["lsp-ws-proxy-missing-server"]).await,
+            Some((reason.code(), reason.reason().to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_connection_workspace() {
+        let root = std::env::temp_dir().join(format!("lsp-ws-proxy-pcw-{}", std::process::id()));
+        let template = root.join("template");
+        std::fs::create_dir_all(&template).unwrap();
+        std::fs::write(template.join("a.txt"), "a").unwrap();
+        let seen = root.join("seen");
+        // Record the directory the server started in if it has the template file.
+        let script = format!("test -f a.txt && pwd > {}", seen.display());
+        let mut ctx = test_context(&["sh", "-c", &script]);
+        ctx.workspaces = Some(Workspaces {
+            base: root.join("base"),
+            template: Some(template),
+        });
+
+        let reason = CloseReason::ServerExited;
+        assert_eq!(
+            close_frame_with(ctx).await,
+            Some((reason.code(), reason.reason().to_owned()))
+        );
+        let dir = std::fs::read_to_string(&seen).unwrap();
+        assert!(Path::new(dir.trim()).starts_with(root.join("base")));
+        for _ in 0..50 {
+            if !Path::new(dir.trim()).exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!Path::new(dir.trim()).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_removes_created_on_disconnect() {
+        let cwd = std::env::temp_dir().join(format!("lsp-ws-proxy-eph-{}", std::process::id()));
+        std::fs::create_dir_all(&cwd).unwrap();
+        std::fs::write(cwd.join("existing.txt"), "a").unwrap();
+        let mut ctx = test_context(&["cat"]);
+        ctx.sync = true;
+        ctx.ephemeral = true;
+        ctx.sync_filter = SyncFilter::new(&cwd, &[], &[]).unwrap();
+        ctx.cwd = Url::from_directory_path(&cwd).unwrap();
+        let did_save = |path: &str| {
+            let uri = Url::from_file_path(cwd.join(path)).unwrap();
+            Ok(warp::ws::Message::text(format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didSave","params":{{"textDocument":{{"uri":"{}"}},"text":"b"}}}}"#,
+                uri
+            )))
+        };
+        // The connection closes after the messages.
+        let messages = stream::iter(vec![did_save("dir/new.txt"), did_save("existing.txt")]);
+
+        serve_client(futures_util::sink::drain(), messages, ctx, None, None, None).await;
+        assert!(!cwd.join("dir").exists());
+        assert_eq!(
+            std::fs::read_to_string(cwd.join("existing.txt")).unwrap(),
+            "b"
+        );
+        std::fs::remove_dir_all(&cwd).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remap_base() {
+        let base = std::env::temp_dir().join(format!("lsp-ws-proxy-base-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let did_save = || {
+            Ok(warp::ws::Message::text(
+                r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"source://src/main.rs"},"text":"b"}}"#,
+            ))
+        };
+        let mut ctx = test_context(&["cat"]);
+        ctx.sync = true;
+        ctx.remap = true;
+        ctx.sync_filter = SyncFilter::new(&base, &[], &[]).unwrap();
+        ctx.cwd = Url::from_directory_path(&base).unwrap();
+
+        ctx.readonly = true;
+        let messages = stream::iter(vec![did_save()]);
+        serve_client(
+            futures_util::sink::drain(),
+            messages,
+            ctx.clone(),
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(!base.join("src").exists());
+
+        ctx.readonly = false;
+        let messages = stream::iter(vec![did_save()]);
+        serve_client(futures_util::sink::drain(), messages, ctx, None, None, None).await;
+        assert_eq!(
+            std::fs::read_to_string(base.join("src/main.rs")).unwrap(),
+            "b"
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remap_traversal_rejected() {
+        let base =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-traversal-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let mut ctx = test_context(&["cat"]);
+        ctx.sync = true;
+        ctx.remap = true;
+        ctx.sync_filter = SyncFilter::new(&base, &[], &[]).unwrap();
+        ctx.cwd = Url::from_directory_path(&base).unwrap();
+        let (ws_send, mut rx) = client_sink();
+        // Outside of the project. The connection is kept for the messages after them.
+        let messages = stream::iter(vec![
+            Ok(warp::ws::Message::text(
+                r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"source://../escaped.rs"},"text":"a"}}"#,
+            )),
+            Ok(warp::ws::Message::text(
+                r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"source://../escaped.rs"},"position":{"line":0,"character":0}}}"#,
+            )),
+            Ok(warp::ws::Message::text(
+                r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"source://src/main.rs"},"text":"b"}}"#,
+            )),
+        ]);
+        serve_client(ws_send, messages, ctx, None, None, None).await;
+        assert!(!base.parent().unwrap().join("escaped.rs").exists());
+        assert_eq!(
+            std::fs::read_to_string(base.join("src/main.rs")).unwrap(),
+            "b"
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| msg.is_text())
+            .collect();
+        assert_eq!(sent.len(), 1);
+        let res: serde_json::Value = serde_json::from_str(sent[0].to_str().unwrap()).unwrap();
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["error"]["code"], -32602);
+    }
+
+    #[tokio::test]
+    async fn test_batch_responses_combined() {
+        let batch = r#"[{"jsonrpc":"2.0","id":1,"method":"shutdown"},{"jsonrpc":"2.0","id":2,"method":"shutdown"}]"#;
+        // Read both requests before responding in reverse order, then exit.
+        let read: usize = batch::split(batch)
+            .unwrap()
+            .iter()
+            .map(|text| format!("Content-Length: {}\r\n\r\n{}", text.len(), text).len())
+            .sum();
+        let responses: String = [2, 1]
+            .iter()
+            .map(|id| {
+                let body = format!(r#"{{"jsonrpc":"2.0","id":{},"result":null}}"#, id);
+                format!("Content-Length: {}\\r\\n\\r\\n{}", body.len(), body)
+            })
+            .collect();
+        let script = format!("head -c {} > /dev/null; printf '{}'", read, responses);
+        let ctx = test_context(&["sh", "-c", &script]);
+        let (ws_send, mut rx) = client_sink();
+        // Stay connected until the server exits.
+        let messages =
+            stream::iter(vec![Ok(warp::ws::Message::text(batch))]).chain(stream::pending());
+
+        serve_client(ws_send, messages, ctx, None, None, None).await;
+        // Skip the ping sent on connect.
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| !msg.is_ping())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        let combined: serde_json::Value = serde_json::from_str(sent[0].to_str().unwrap()).unwrap();
+        assert_eq!(combined[0]["id"], 2);
+        assert_eq!(combined[1]["id"], 1);
+        assert!(sent[1].is_close());
+    }
+
+    #[tokio::test]
+    async fn test_max_inflight() {
+        let request = |id: u32| format!(r#"{{"jsonrpc":"2.0","id":{},"method":"shutdown"}}"#, id);
+        let len = format!("Content-Length: {}\r\n\r\n{}", request(1).len(), request(1)).len();
+        // Respond to each request after reading it, so queued ones are answered once released.
+        let script: String = (1..=3)
+            .map(|id| {
+                let body = format!(r#"{{"jsonrpc":"2.0","id":{},"result":null}}"#, id);
+                format!(
+                    "head -c {} > /dev/null; printf 'Content-Length: {}\\r\\n\\r\\n{}'; ",
+                    len,
+                    body.len(),
+                    body
+                )
+            })
+            .collect();
+        let mut ctx = test_context(&["sh", "-c", &script]);
+        ctx.max_inflight = Some(1);
+        let (ws_send, mut rx) = client_sink();
+        // Flood the requests and stay connected until the server exits.
+        let messages =
+            stream::iter((1..=3).map(move |id| Ok(warp::ws::Message::text(request(id)))))
+                .chain(stream::pending());
+
+        serve_client(ws_send, messages, ctx, None, None, None).await;
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| !msg.is_ping())
+            .collect();
+        assert_eq!(sent.len(), 4);
+        for (i, msg) in sent[..3].iter().enumerate() {
+            let res: serde_json::Value = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+            assert_eq!(res["id"], i + 1);
+            assert_eq!(res["result"], serde_json::Value::Null);
+        }
+        assert!(sent[3].is_close());
+    }
+
+    #[tokio::test]
+    async fn test_max_inflight_records_forwarded() {
+        let mut ctx = test_context(&["sh", "-c", "cat > /dev/null"]);
+        ctx.max_inflight = Some(1);
+        let request = |id: u32| {
+            Ok(warp::ws::Message::text(format!(
+                r#"{{"jsonrpc":"2.0","id":{},"method":"shutdown"}}"#,
+                id
+            )))
+        };
+        // Disconnect while the second request is still queued.
+        let messages = stream::iter(vec![request(1), request(2)])
+            .chain(
+                stream::once(tokio::time::sleep(std::time::Duration::from_millis(200)))
+                    .filter_map(|_| async { None }),
+            )
+            .boxed();
+        serve_client(
+            futures_util::sink::drain(),
+            messages,
+            ctx.clone(),
+            None,
+            None,
+            None,
+        )
+        .await;
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&crate::api::metrics::handler(
+                crate::api::metrics::Context {
+                    metrics: ctx.metrics,
+                },
+            ))
+            .await;
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body
+            .contains(r#"lsp_ws_proxy_requests_total{method="shutdown",outcome="unanswered"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_max_inflight_cancel_queued() {
+        let mut ctx = test_context(&["sh", "-c", "cat > /dev/null"]);
+        ctx.max_inflight = Some(1);
+        let text = |value: serde_json::Value| Ok(warp::ws::Message::text(value.to_string()));
+        let messages = stream::iter(vec![
+            text(serde_json::json!({"jsonrpc":"2.0","id":1,"method":"workspace/symbol","params":{"query":""}})),
+            text(serde_json::json!({"jsonrpc":"2.0","id":2,"method":"workspace/symbol","params":{"query":""}})),
+            text(serde_json::json!({"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}})),
+        ])
+        .chain(
+            stream::once(tokio::time::sleep(std::time::Duration::from_millis(200)))
+                .filter_map(|_| async { None }),
+        )
+        .boxed();
+        let (ws_send, mut rx) = client_sink();
+        serve_client(ws_send, messages, ctx, None, None, None).await;
+        let res = std::iter::from_fn(|| rx.try_recv().ok())
+            .find(|msg| msg.is_text())
+            .expect("response to the cancelled request");
+        let res: serde_json::Value = serde_json::from_str(res.to_str().unwrap()).unwrap();
+        assert_eq!(res["id"], 2);
+        assert_eq!(res["error"]["code"], -32800);
+    }
+
+    #[tokio::test]
+    async fn test_pre_init_messages_sent_after_initialize() {
+        let log = r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":1,"message":"starting"}}"#;
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":null,"rootUri":null,"capabilities":{}}}"#;
+        let result = r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{}}}"#;
+        let frame = |body: &str| format!("Content-Length: {}\\r\\n\\r\\n{}", body.len(), body);
+        // Log on spawn, then respond to `initialize` and exit.
+        let script = format!(
+            "printf '{}'; head -c {} > /dev/null; printf '{}'",
+            frame(log),
+            format!("Content-Length: {}\r\n\r\n{}", initialize.len(), initialize).len(),
+            frame(result)
+        );
+        let ctx = test_context(&["sh", "-c", &script]);
+        let (ws_send, rx) = client_sink();
+        let rx = std::sync::Arc::new(std::sync::Mutex::new(rx));
+        // Send `initialize` well after the server logged.
+        let messages = stream::once({
+            let rx = rx.clone();
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                // Nothing is sent to the client before `initialize` is forwarded.
+                let mut rx = rx.lock().unwrap();
+                assert!(std::iter::from_fn(|| rx.try_recv().ok()).all(|msg| msg.is_ping()));
+                Ok(warp::ws::Message::text(initialize))
+            }
+        })
+        .chain(stream::pending())
+        .boxed();
+
+        serve_client(ws_send, messages, ctx, None, None, None).await;
+        let mut rx = rx.lock().unwrap();
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| !msg.is_ping())
+            .collect();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].to_str().unwrap(), log);
+        assert_eq!(sent[1].to_str().unwrap(), result);
+        assert!(sent[2].is_close());
+    }
+
+    #[tokio::test]
+    async fn test_linger() {
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":null,"rootUri":null,"capabilities":{}}}"#;
+        let did_open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///tmp/a.rs","languageId":"rust","version":1,"text":""}}}"#;
+        let result = r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{}}}"#;
+        let configuration = r#"{"jsonrpc":"2.0","id":"c1","method":"workspace/configuration","params":{"items":[]}}"#;
+        let received =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-linger-{}", std::process::id()));
+        // Respond to `initialize`, request the configuration the client won't answer,
+        // and record the rest.
+        let script = format!(
+            "head -c {} > /dev/null; printf 'Content-Length: {}\\r\\n\\r\\n{}Content-Length: {}\\r\\n\\r\\n{}'; cat > {}",
+            format!("Content-Length: {}\r\n\r\n{}", initialize.len(), initialize).len(),
+            result.len(),
+            result,
+            configuration.len(),
+            configuration,
+            received.display()
+        );
+        let mut ctx = test_context(&["sh", "-c", &script]);
+        ctx.linger = Some(std::time::Duration::from_secs(10));
+        // Disconnect after the response to `initialize`.
+        let messages = stream::iter(vec![
+            Ok(warp::ws::Message::text(initialize)),
+            Ok(warp::ws::Message::text(did_open)),
+        ])
+        .chain(
+            stream::once(tokio::time::sleep(std::time::Duration::from_millis(300)))
+                .filter_map(|_| async { None }),
+        )
+        .boxed();
+        serve_client(
+            futures_util::sink::drain(),
+            messages,
+            ctx.clone(),
+            None,
+            None,
+            None,
+        )
+        .await;
+
+        // The next client adopts it, and `initialized` isn't sent again.
+        let initialized = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+        let messages = stream::iter(vec![
+            Ok(warp::ws::Message::text(initialize)),
+            Ok(warp::ws::Message::text(initialized)),
+        ])
+        .chain(
+            stream::once(tokio::time::sleep(std::time::Duration::from_millis(300)))
+                .filter_map(|_| async { None }),
+        )
+        .boxed();
+        let (ws_send, mut rx) = client_sink();
+        serve_client(ws_send, messages, ctx.clone(), None, None, None).await;
+        let sent = std::iter::from_fn(|| rx.try_recv().ok()).find(|msg| !msg.is_ping());
+        assert_eq!(sent.unwrap().to_str().unwrap(), result);
+
+        let parked = ctx.pool.take("sh").await.unwrap();
+        assert!(parked.lingered);
+        assert_eq!(
+            parked.initialize_result,
+            serde_json::json!({"capabilities": {}})
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let received_text = std::fs::read_to_string(&received).unwrap();
+        assert!(received_text.contains("textDocument/didOpen"));
+        assert!(received_text.contains("textDocument/didClose"));
+        assert!(!received_text.contains(r#""method":"initialized""#));
+        // The pending request is answered before the server is kept.
+        assert!(received_text
+            .contains(r#"{"error":{"code":-32800,"message":"lsp-ws-proxy: client disconnected"},"id":"c1","jsonrpc":"2.0"}"#));
+        drop(parked);
+        std::fs::remove_file(&received).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exit_after_shutdown_then_close() {
+        let received = std::env::temp_dir().join(format!(
+            "lsp-ws-proxy-shutdown-close-{}",
+            std::process::id()
+        ));
+        let script = format!("cat > {}", received.display());
+        let ctx = test_context(&["sh", "-c", &script]);
+        // Close right after `shutdown` without `exit`.
+        let messages = stream::iter(vec![Ok(warp::ws::Message::text(
+            r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#,
+        ))])
+        .boxed();
+        serve_client(futures_util::sink::drain(), messages, ctx, None, None, None).await;
+
+        let received_text = std::fs::read_to_string(&received).unwrap();
+        std::fs::remove_file(&received).unwrap();
+        let shutdown = received_text.find(r#""method":"shutdown""#).unwrap();
+        let exit = received_text.find(r#""method":"exit""#).unwrap();
+        assert!(shutdown < exit);
+    }
+
+    #[tokio::test]
+    async fn test_report_codec_errors() {
+        // Misspelled header, then exit.
+        let mut ctx = test_context(&["sh", "-c", "printf 'Content-Lenght: 2\\r\\n\\r\\n{}'"]);
+        ctx.report_codec_errors = true;
+        let (ws_send, mut rx) = client_sink();
+        serve_client(ws_send, stream::pending().boxed(), ctx, None, None, None).await;
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| !msg.is_ping())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        let log: serde_json::Value = serde_json::from_str(sent[0].to_str().unwrap()).unwrap();
+        assert_eq!(log["method"], "window/logMessage");
+        assert_eq!(log["params"]["type"], 1);
+        assert!(log["params"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("malformed message from server"));
+        assert!(sent[1].is_close());
+    }
+
+    #[tokio::test]
+    async fn test_notify_changed() {
+        let input =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-notify-{}", std::process::id()));
+        let script = format!("cat > {}", input.display());
+        let mut ctx = test_context(&["sh", "-c", &script]);
+        ctx.remap = true;
+        let notification = r#"{"jsonrpc":"2.0","method":"workspace/didChangeWatchedFiles","params":{"changes":[{"uri":"source://src/a.js","type":2}]}}"#;
+        let initialize =
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#;
+        // Sent to the server after `initialize`, with the URI remapped.
+        let messages = stream::once(async move { Ok(warp::ws::Message::text(initialize)) })
+            .chain(stream::once({
+                let connections = ctx.connections.clone();
+                async move {
+                    assert_eq!(connections.broadcast(notification), 1);
+                    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                    Ok(warp::ws::Message::close())
+                }
+            }))
+            .boxed();
+        serve_client(futures_util::sink::drain(), messages, ctx, None, None, None).await;
+        let received = std::fs::read_to_string(&input).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        let cwd = Url::from_directory_path(std::env::current_dir().unwrap()).unwrap();
+        assert_eq!(received.matches("didChangeWatchedFiles").count(), 1);
+        assert!(received.contains(cwd.join("src/a.js").unwrap().as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_announce_server() {
+        // Logs on startup, then exits.
+        let log =
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#;
+        let script = format!(
+            "printf 'Content-Length: {}\\r\\n\\r\\n%s' '{}'",
+            log.len(),
+            log
+        );
+        let mut ctx = test_context(&["sh", "-c", &script]);
+        ctx.announce_server = true;
+        let (ws_send, mut rx) = client_sink();
+        serve_client(ws_send, stream::pending().boxed(), ctx, None, None, None).await;
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| !msg.is_ping())
+            .collect();
+        assert_eq!(sent.len(), 3);
+        let selected: serde_json::Value = serde_json::from_str(sent[0].to_str().unwrap()).unwrap();
+        assert_eq!(selected["method"], "$/proxy/serverSelected");
+        assert_eq!(selected["params"]["name"], "sh");
+        assert_eq!(
+            selected["params"]["command"],
+            serde_json::json!(["sh", "-c", script])
+        );
+        assert_eq!(sent[1].to_str().unwrap(), log);
+        assert!(sent[2].is_close());
+    }
+
+    #[tokio::test]
+    async fn test_close_frame() {
+        let close = warp::ws::Message::close_with(4000u16, "bye");
+        let msg = filter_map_warp_ws_message(Ok(close), false).await;
+        assert!(matches!(
+            msg,
+            Some(Ok(Message::Close(Some((4000, ref reason))))) if reason == "bye"
+        ));
+        let msg = filter_map_warp_ws_message(Ok(warp::ws::Message::close()), false).await;
+        assert!(matches!(msg, Some(Ok(Message::Close(None)))));
+        assert!(is_normal_close(1000));
+        assert!(is_normal_close(NO_STATUS_CODE));
+        assert!(!is_normal_close(4000));
+        assert!(!is_normal_close(ABNORMAL_CLOSE_CODE));
+    }
+
+    #[test]
+    fn test_conn_error_close_reason() {
+        let spawn = ConnError::Spawn(std::io::ErrorKind::NotFound.into());
+        assert_eq!(spawn.kind(), "spawn");
+        assert_eq!(spawn.close_reason(), Some(CloseReason::SpawnFailed));
+        // Too late to tell the client.
+        assert_eq!(ConnError::Unhealthy.close_reason(), None);
+    }
+
+    async fn query_name(path: &str) -> Option<String> {
+        warp::test::request()
+            .path(path)
+            .filter(&with_optional_query())
+            .await
+            .unwrap()
+            .and_then(|q| q.name)
+    }
+
+    #[tokio::test]
+    async fn test_query_with_other_params() {
+        assert_eq!(
+            query_name("/?token=abc&name=rust-analyzer")
+                .await
+                .as_deref(),
+            Some("rust-analyzer")
+        );
+        assert_eq!(
+            query_name("/?name=rust-analyzer&token=abc")
+                .await
+                .as_deref(),
+            Some("rust-analyzer")
+        );
+        assert_eq!(query_name("/?token=abc").await, None);
+    }
+
+    fn server_commands(programs: &[&str]) -> Vec<pool::ServerCommand> {
+        programs
+            .iter()
+            .map(|p| pool::ServerCommand::new(vec![p.to_string()]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_select_by_query_or_path() {
+        let commands = server_commands(&["rust-analyzer", "css"]);
+        for path in &["/?name=css", "/servers/css"] {
+            let query = warp::test::request()
+                .path(path)
+                .filter(&with_server_query())
+                .await
+                .unwrap();
+            let name = query.and_then(|q| q.name);
+            assert_eq!(
+                select_command(&commands, 0, name.as_deref()),
+                Some(&commands[1])
+            );
+        }
+        let query = warp::test::request()
+            .path("/")
+            .filter(&with_server_query())
+            .await
+            .unwrap();
+        let name = query.and_then(|q| q.name);
+        assert_eq!(
+            select_command(&commands, 0, name.as_deref()),
+            Some(&commands[0])
+        );
+        assert_eq!(select_command(&[], 0, Some("css")), None);
+        assert!(warp::test::request()
+            .path("/servers")
+            .filter(&with_server_query())
+            .await
+            .is_err());
+        assert_eq!(query_name("/").await, None);
+    }
+
+    #[test]
+    fn test_select_default() {
+        // Selected by the file name of the program.
+        let commands = server_commands(&["html", "/usr/local/bin/css"]);
+        assert_eq!(select_command(&commands, 1, None), Some(&commands[1]));
+        assert_eq!(
+            select_command(&commands, 1, Some("unknown")),
+            Some(&commands[1])
+        );
+        assert_eq!(
+            select_command(&commands, 1, Some("html")),
+            Some(&commands[0])
+        );
+        assert_eq!(
+            select_command(&commands, 0, Some("css")),
+            Some(&commands[1])
+        );
+    }
+
+    #[test]
+    fn test_shutdown_then_close() {
+        let lifecycle = observe_all(&[r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#]);
+        assert!(lifecycle.needs_exit());
+    }
+
+    #[test]
+    fn test_shutdown_then_exit() {
+        let lifecycle = observe_all(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"shutdown","params":null}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        assert!(!lifecycle.needs_exit());
+    }
+
+    #[test]
+    fn test_close_without_shutdown() {
+        let lifecycle = observe_all(&[r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#]);
+        assert!(!lifecycle.needs_exit());
+    }
+}
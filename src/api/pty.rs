@@ -0,0 +1,133 @@
+//! Pseudo-terminal for servers behaving differently without a TTY, with `--pty`.
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Both sides of a pseudo-terminal in raw mode.
+pub struct Pty {
+    /// Read and written by the proxy.
+    pub master: File,
+    /// Stdin and stdout of the server.
+    pub slave: File,
+}
+
+/// Open a pseudo-terminal in raw mode, so messages are passed through without echo or
+/// line ending conversion.
+pub fn open() -> io::Result<Pty> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned from here, so they're closed on drop.
+    let pty = unsafe {
+        Pty {
+            master: File::from_raw_fd(master),
+            slave: File::from_raw_fd(slave),
+        }
+    };
+    make_raw(&pty.slave)?;
+    Ok(pty)
+}
+
+fn make_raw(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut termios = unsafe { termios.assume_init() };
+    unsafe { libc::cfmakeraw(&mut termios) };
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The master side read and written by the proxy without blocking a thread.
+/// Reading fails with `EIO` once the server closed the slave side, so it's the end of output.
+pub struct PtyMaster(AsyncFd<File>);
+
+impl PtyMaster {
+    pub fn new(file: File) -> io::Result<Self> {
+        set_nonblocking(&file)?;
+        Ok(Self(AsyncFd::new(file)?))
+    }
+}
+
+fn set_nonblocking(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                // Not ready anymore. Wait again.
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            if let Ok(result) = guard.try_io(|fd| fd.get_ref().write(buf)) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    // Written directly to the fd without buffering.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Closed on drop.
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,181 @@
+//! Token bucket rate limiting for client messages.
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::stream::{Fuse, Stream, StreamExt};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Rate limit configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Messages allowed per second.
+    pub rate: f64,
+    /// Maximum number of messages allowed at once.
+    pub burst: u32,
+    /// Maximum number of messages to queue while limited.
+    pub queue: usize,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        let capacity = f64::from(burst.max(1));
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take a token if available.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The instant the next token becomes available.
+    pub fn next_available(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.last + Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+/// The queue of a `Throttled` stream exceeded its bound.
+#[derive(Debug, PartialEq)]
+pub struct Overflow;
+
+/// Stream adapter yielding items from `inner` at most at the rate of the token bucket.
+///
+/// Items are read ahead into a bounded queue while limited,
+/// and `Err(Overflow)` is yielded when the queue is full.
+pub struct Throttled<S: Stream> {
+    inner: Fuse<S>,
+    bucket: TokenBucket,
+    queue: VecDeque<S::Item>,
+    max_queue: usize,
+    delay: Pin<Box<Sleep>>,
+}
+
+impl<S: Stream> Throttled<S> {
+    pub fn new(inner: S, limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            inner: inner.fuse(),
+            bucket: TokenBucket::new(limit.rate, limit.burst, now),
+            queue: VecDeque::new(),
+            max_queue: limit.queue,
+            delay: Box::pin(sleep_until(now)),
+        }
+    }
+}
+
+impl<S> Stream for Throttled<S>
+where
+    S: Stream + Unpin,
+    S::Item: Unpin,
+{
+    type Item = Result<S::Item, Overflow>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        // Read ahead so that messages are not left waiting in the socket.
+        while this.queue.len() <= this.max_queue {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(item)) => this.queue.push_back(item),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        loop {
+            if this.queue.is_empty() {
+                return if this.inner.is_done() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+
+            if this.bucket.try_acquire(Instant::now()) {
+                return Poll::Ready(this.queue.pop_front().map(Ok));
+            }
+            if this.queue.len() > this.max_queue {
+                return Poll::Ready(Some(Err(Overflow)));
+            }
+
+            let next = this.bucket.next_available();
+            this.delay.as_mut().reset(next);
+            if this.delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 5, now);
+        for _ in 0..5 {
+            assert!(bucket.try_acquire(now));
+        }
+        assert!(!bucket.try_acquire(now));
+        assert_eq!(bucket.next_available(), now + Duration::from_millis(100));
+        assert!(bucket.try_acquire(now + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire(now + Duration::from_millis(100)));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_overflow() {
+        let limit = RateLimit {
+            rate: 1.0,
+            burst: 2,
+            queue: 3,
+        };
+        let mut throttled = Throttled::new(stream::iter(0..10), limit);
+        assert_eq!(throttled.next().await, Some(Ok(0)));
+        assert_eq!(throttled.next().await, Some(Ok(1)));
+        assert_eq!(throttled.next().await, Some(Err(Overflow)));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_within_limit() {
+        let limit = RateLimit {
+            rate: 1000.0,
+            burst: 2,
+            queue: 10,
+        };
+        let throttled = Throttled::new(stream::iter(0..5), limit);
+        let items: Vec<_> = throttled.collect().await;
+        assert_eq!(items, (0..5).map(Ok).collect::<Vec<_>>());
+    }
+}
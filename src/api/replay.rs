@@ -0,0 +1,196 @@
+//! Replay a session captured with `--capture` against a fresh server.
+//!
+//! Messages to the server are sent in order, and responses from the server are
+//! compared to the captured responses with the same id.
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+
+use super::{capture::Direction, pool::Server};
+use crate::lsp::{self, types::Id};
+
+// How long to wait for each response.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, serde::Deserialize)]
+struct Record {
+    connection: u64,
+    direction: Direction,
+    message: Value,
+}
+
+/// A captured session.
+#[derive(Debug)]
+pub struct Transcript {
+    /// Messages to the server in order.
+    to_server: Vec<Value>,
+    /// Responses to the client by the request id.
+    responses: HashMap<Id, Value>,
+}
+
+impl Transcript {
+    /// Parse the captured `text`. Only the first connection is used.
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        let mut to_server = Vec::new();
+        let mut responses = HashMap::new();
+        let mut connection = None;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let record: Record = serde_json::from_str(line)?;
+            if *connection.get_or_insert(record.connection) != record.connection {
+                continue;
+            }
+            match record.direction {
+                Direction::ToServer => to_server.push(record.message),
+                Direction::ToClient => {
+                    if let Some(id) = response_id(&record.message) {
+                        responses.insert(id, record.message);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            to_server,
+            responses,
+        })
+    }
+}
+
+/// A response different from the captured one.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// Index of the request in the messages to the server.
+    pub index: usize,
+    pub method: String,
+    pub expected: Value,
+    /// `None` if the server didn't respond in time.
+    pub actual: Option<Value>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of responses compared.
+    pub compared: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Send the messages in `transcript` to `server`, and compare the responses.
+pub async fn replay(
+    server: &mut Server,
+    transcript: &Transcript,
+) -> Result<Report, Box<dyn std::error::Error + Send + Sync>> {
+    let mut report = Report::default();
+    for (index, msg) in transcript.to_server.iter().enumerate() {
+        server.writer.send(msg.to_string()).await?;
+        // Only wait for requests with captured responses.
+        let expected = match request_id(msg).and_then(|id| transcript.responses.get(&id)) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        let method = msg["method"].as_str().unwrap_or_default().to_owned();
+        let actual =
+            match tokio::time::timeout(RESPONSE_TIMEOUT, next_response(server, expected)).await {
+                Ok(actual) => actual?,
+                Err(_) => None,
+            };
+        report.compared += 1;
+        let matched = matches!(&actual, Some(actual) if same_response(expected, actual));
+        if !matched {
+            report.mismatches.push(Mismatch {
+                index,
+                method,
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(report)
+}
+
+// Read from the server until the response with the same id as `expected`.
+async fn next_response(
+    server: &mut Server,
+    expected: &Value,
+) -> Result<Option<Value>, Box<dyn std::error::Error + Send + Sync>> {
+    let id = response_id(expected);
+    while let Some(text) = server.reader.next().await {
+        let value: Value = serde_json::from_str(&text?)?;
+        if response_id(&value).is_some() && response_id(&value) == id {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+fn same_response(expected: &Value, actual: &Value) -> bool {
+    expected.get("result") == actual.get("result") && expected.get("error") == actual.get("error")
+}
+
+fn request_id(msg: &Value) -> Option<Id> {
+    match lsp::Message::try_from(msg.clone()) {
+        Ok(lsp::Message::Response(_)) | Err(_) => None,
+        Ok(_) => msg
+            .get("id")
+            .and_then(|id| serde_json::from_value(id.clone()).ok()),
+    }
+}
+
+fn response_id(msg: &Value) -> Option<Id> {
+    match lsp::Message::try_from(msg.clone()) {
+        Ok(lsp::Message::Response(lsp::Response::Success { id, .. })) => Some(id),
+        Ok(lsp::Message::Response(lsp::Response::Failure { id, .. })) => id,
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::pool;
+
+    const TRANSCRIPT: &str = r#"
+{"time":1,"connection":1,"direction":"to_server","message":{"jsonrpc":"2.0","id":1,"method":"shutdown"}}
+{"time":2,"connection":2,"direction":"to_server","message":{"jsonrpc":"2.0","id":9,"method":"shutdown"}}
+{"time":3,"connection":1,"direction":"to_client","message":{"jsonrpc":"2.0","id":1,"result":null}}
+{"time":4,"connection":1,"direction":"to_server","message":{"jsonrpc":"2.0","method":"exit"}}
+"#;
+
+    // Fake server responding to a request with id 1 with `result`.
+    fn fake_server(result: &str) -> Server {
+        let body = format!(r#"{{"jsonrpc":"2.0","id":1,"result":{}}}"#, result);
+        let script = format!(
+            "printf 'Content-Length: {}\\r\\n\\r\\n%s' '{}'; cat > /dev/null",
+            body.len(),
+            body
+        );
+        let command = vec!["sh".to_owned(), "-c".to_owned(), script];
+        pool::spawn(&command, None, pool::SpawnOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_first_connection() {
+        let transcript = Transcript::parse(TRANSCRIPT).unwrap();
+        assert_eq!(transcript.to_server.len(), 2);
+        assert_eq!(transcript.responses.len(), 1);
+        assert!(transcript.responses.contains_key(&Id::Number(1)));
+    }
+
+    #[tokio::test]
+    async fn test_replay_matches() {
+        let transcript = Transcript::parse(TRANSCRIPT).unwrap();
+        let report = replay(&mut fake_server("null"), &transcript).await.unwrap();
+        assert_eq!(report.compared, 1);
+        assert!(report.mismatches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_mismatch() {
+        let transcript = Transcript::parse(TRANSCRIPT).unwrap();
+        let report = replay(&mut fake_server("42"), &transcript).await.unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!(mismatch.index, 0);
+        assert_eq!(mismatch.method, "shutdown");
+        assert_eq!(mismatch.actual.as_ref().unwrap()["result"], 42);
+    }
+}
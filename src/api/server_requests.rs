@@ -0,0 +1,123 @@
+//! Track requests from the server forwarded to the client until the client responds.
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lsp::{error::ErrorCode, types::Id};
+
+// Only the fields necessary to match responses to requests.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+}
+
+/// Requests like `workspace/configuration`, `workspace/applyEdit`, and
+/// `window/workDoneProgress/create` waiting for the response from the client.
+#[derive(Default)]
+pub struct ServerRequests {
+    pending: HashMap<Id, String>,
+}
+
+impl ServerRequests {
+    /// Track `value` forwarded to the client if it's a request.
+    pub fn request(&mut self, value: &Value) {
+        if let Ok(Head {
+            id: Some(id),
+            method: Some(method),
+        }) = Head::deserialize(value)
+        {
+            self.pending.insert(id, method);
+        }
+    }
+
+    /// Returns the method of the request if `value` from the client is a response to a pending one.
+    pub fn response(&mut self, value: &Value) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        match Head::deserialize(value) {
+            Ok(Head {
+                id: Some(id),
+                method: None,
+            }) => self.pending.remove(&id),
+            _ => None,
+        }
+    }
+
+    /// Error responses for the pending requests, to answer the server on behalf of the client
+    /// that won't respond anymore.
+    pub fn cancel(&mut self, message: &str) -> Vec<String> {
+        self.pending
+            .drain()
+            .map(|(id, method)| {
+                tracing::debug!("cancelling {} ({:?}) from the server", method, id);
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": ErrorCode::RequestCancelled.code(), "message": message},
+                })
+                .to_string()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_response() {
+        let mut requests = ServerRequests::default();
+        requests.request(&json!({"jsonrpc":"2.0","id":1,"method":"workspace/configuration","params":{"items":[]}}));
+        // Notifications and responses from the server are not tracked.
+        requests.request(&json!({"jsonrpc":"2.0","method":"window/logMessage","params":{}}));
+        requests.request(&json!({"jsonrpc":"2.0","id":2,"result":null}));
+        // Requests from the client are not responses.
+        assert_eq!(
+            requests.response(&json!({"jsonrpc":"2.0","id":1,"method":"shutdown"})),
+            None
+        );
+        assert_eq!(
+            requests.response(&json!({"jsonrpc":"2.0","id":2,"result":null})),
+            None
+        );
+        assert_eq!(
+            requests.response(&json!({"jsonrpc":"2.0","id":1,"result":[{}]})),
+            Some("workspace/configuration".to_owned())
+        );
+        assert_eq!(
+            requests.response(&json!({"jsonrpc":"2.0","id":1,"result":[{}]})),
+            None
+        );
+        assert!(requests.cancel("gone").is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut requests = ServerRequests::default();
+        requests.request(&json!({"jsonrpc":"2.0","id":"p1","method":"window/workDoneProgress/create","params":{"token":"t"}}));
+        requests.request(
+            &json!({"jsonrpc":"2.0","id":7,"method":"workspace/applyEdit","params":{"edit":{}}}),
+        );
+        requests.response(&json!({"jsonrpc":"2.0","id":7,"result":{"applied":true}}));
+        let cancelled = requests
+            .cancel("client disconnected")
+            .iter()
+            .map(|text| serde_json::from_str::<Value>(text).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cancelled,
+            vec![json!({
+                "jsonrpc": "2.0",
+                "id": "p1",
+                "error": {"code": -32800, "message": "client disconnected"},
+            })]
+        );
+        assert!(requests.cancel("client disconnected").is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{convert::Infallible, time::Instant};
+
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    breaker::{BreakerStatus, CircuitBreaker},
+    json_response,
+    pool::{ParkedStatus, Pool, ServerCommand},
+    with_context,
+};
+
+#[derive(Clone)]
+pub struct Context {
+    /// Commands to start each Language Server.
+    pub commands: Vec<ServerCommand>,
+    pub pool: Pool,
+    /// Rejects servers crashing repeatedly, if enabled.
+    pub breaker: Option<CircuitBreaker>,
+    /// Writing files is disabled.
+    pub readonly: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerInfo {
+    /// The command name used to select the server.
+    name: String,
+    command: Vec<String>,
+    /// The server started with `--warmup` waiting for a client, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parked: Option<ParkedStatus>,
+    /// Recent crashes, and whether connections are rejected, with `--crash-limit`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    breaker: Option<BreakerStatus>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Servers {
+    servers: Vec<ServerInfo>,
+    /// Files are never written when set.
+    readonly: bool,
+}
+
+/// Handler for `GET /servers`
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("servers"))
+        .and(warp::path::end())
+        .and(with_context(ctx))
+        .and_then(handle_servers)
+}
+
+async fn handle_servers(ctx: Context) -> Result<impl Reply, Infallible> {
+    let now = Instant::now();
+    let servers = ctx
+        .commands
+        .iter()
+        .map(|command| ServerInfo {
+            name: command.name.clone(),
+            command: command.command.clone(),
+            parked: ctx.pool.status(&command.name),
+            breaker: ctx.breaker.as_ref().map(|b| b.status(&command.name, now)),
+        })
+        .collect();
+    Ok(json_response(
+        &Servers {
+            servers,
+            readonly: ctx.readonly,
+        },
+        StatusCode::OK,
+    ))
+}
@@ -0,0 +1,293 @@
+//! Fallback transport for networks blocking WebSocket upgrades.
+//!
+//! `GET /sse` starts a session streaming messages from the server as Server-Sent Events,
+//! and `POST /send` sends a message to the server. Sessions are identified by a cookie,
+//! or the `session` query parameter for clients not sending cookies.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{future, stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use warp::{http::StatusCode, reply, sse, Filter, Rejection, Reply};
+
+use super::{body, client_addr::client_addr, json_error_response, proxy, with_context};
+
+const SESSION_COOKIE: &str = "lsp-ws-proxy-session";
+
+// Messages posted to a session, forwarded to the server.
+type Sessions = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<warp::ws::Message>>>>;
+
+#[derive(Clone)]
+pub struct Context {
+    pub proxy: proxy::Context,
+    pub sessions: Sessions,
+    /// Limit in bytes of the messages posted to `/send`.
+    pub body_limit: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SendQuery {
+    session: Option<String>,
+}
+
+/// Handler for `GET /sse` and `POST /send`
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let events = warp::get()
+        .and(warp::path("sse"))
+        .and(warp::path::end())
+        .and(with_context(ctx.clone()))
+        .and(proxy::with_optional_query())
+        .and(client_addr(ctx.proxy.trust_proxy_headers))
+        .map(start_session);
+    let send = warp::post()
+        .and(warp::path("send"))
+        .and(warp::path::end())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(warp::query::<SendQuery>())
+        .and(body(ctx.body_limit))
+        .and(with_context(ctx))
+        .and_then(handle_send);
+    events.or(send)
+}
+
+fn start_session(
+    ctx: Context,
+    query: Option<proxy::Query>,
+    addr: Option<IpAddr>,
+) -> reply::Response {
+    if !ctx.proxy.has_server() {
+        return proxy::no_server_response();
+    }
+    let session = match new_session_id() {
+        Ok(session) => session,
+        Err(err) => {
+            tracing::error!("failed to generate a session id: {}", err);
+            return json_error_response(
+                "failed to start a session",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
+    let slot = match ctx.proxy.reserve_server(query.as_ref()) {
+        Ok(slot) => slot,
+        Err(err) => return err.into_response(),
+    };
+    let (posted_tx, posted_rx) = mpsc::unbounded_channel();
+    ctx.sessions
+        .lock()
+        .unwrap()
+        .insert(session.clone(), posted_tx);
+    // Stop the connection when the event stream is dropped.
+    let (disconnected_tx, disconnected_rx) = oneshot::channel::<()>();
+    let guard = SessionGuard {
+        session: session.clone(),
+        sessions: ctx.sessions.clone(),
+        _disconnected: disconnected_tx,
+    };
+
+    // There's no pong with SSE, so reply to pings while the event stream is alive.
+    let (pong_tx, pong_rx) = mpsc::unbounded_channel();
+    let posted = stream::unfold(posted_rx, |mut rx| async move {
+        rx.recv().await.map(|msg| (msg, rx))
+    });
+    let pongs = stream::unfold(pong_rx, |mut rx| async move {
+        rx.recv().await.map(|msg| (msg, rx))
+    });
+    let ws_recv = stream::select(posted, pongs)
+        .map(Ok)
+        .take_until(disconnected_rx)
+        .boxed();
+
+    let (events_tx, events_rx) = mpsc::channel::<warp::ws::Message>(ctx.proxy.buffer_size);
+    let ws_send =
+        futures_util::sink::unfold(events_tx, move |events_tx, msg: warp::ws::Message| {
+            let pong_tx = pong_tx.clone();
+            async move {
+                if msg.is_ping() {
+                    let _ = pong_tx.send(warp::ws::Message::pong(vec![]));
+                    Ok(events_tx)
+                } else {
+                    events_tx
+                        .send(msg)
+                        .await
+                        .map(|()| events_tx)
+                        .map_err(|_| "event stream closed")
+                }
+            }
+        });
+    tokio::spawn(async move {
+        proxy::serve_client(Box::pin(ws_send), ws_recv, ctx.proxy, query, addr, slot).await;
+    });
+
+    let first = stream::once(future::ready(
+        sse::Event::default().event("session").data(session.clone()),
+    ));
+    let events = stream::unfold((events_rx, guard), |(mut rx, guard)| async move {
+        rx.recv().await.map(|msg| (msg, (rx, guard)))
+    })
+    // Close frames end the stream. Other non-text messages are irrelevant.
+    .take_while(|msg| future::ready(!msg.is_close()))
+    .filter_map(|msg| {
+        future::ready(
+            msg.to_str()
+                .ok()
+                .map(|text| sse::Event::default().data(text)),
+        )
+    });
+    let events = first.chain(events).map(Ok::<_, Infallible>);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict",
+        SESSION_COOKIE, session
+    );
+    reply::with_header(
+        sse::reply(sse::keep_alive().stream(events)),
+        "set-cookie",
+        cookie,
+    )
+    .into_response()
+}
+
+async fn handle_send(
+    cookie: Option<String>,
+    query: SendQuery,
+    body: bytes::Bytes,
+    ctx: Context,
+) -> Result<reply::Response, Infallible> {
+    let session = match query.session.or(cookie) {
+        Some(session) => session,
+        None => {
+            return Ok(json_error_response(
+                "missing session",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => {
+            return Ok(json_error_response(
+                "message must be UTF-8",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let sent = match ctx.sessions.lock().unwrap().get(&session) {
+        Some(posted_tx) => posted_tx.send(warp::ws::Message::text(text)).is_ok(),
+        None => false,
+    };
+    if sent {
+        Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response())
+    } else {
+        Ok(json_error_response(
+            format!("session {} not found", session),
+            StatusCode::NOT_FOUND,
+        ))
+    }
+}
+
+// Removes the session when the event stream is dropped.
+struct SessionGuard {
+    session: String,
+    sessions: Sessions,
+    _disconnected: oneshot::Sender<()>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        tracing::debug!("event stream for session {} closed", self.session);
+        self.sessions.lock().unwrap().remove(&self.session);
+    }
+}
+
+// Unguessable id from the operating system's random number generator.
+fn new_session_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_ids_differ() {
+        let a = new_session_id().unwrap();
+        let b = new_session_id().unwrap();
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+    }
+
+    // Read the next event from the stream of `/sse`.
+    async fn next_event(events: &mut hyper::Body, received: &mut String) -> String {
+        while !received.contains("\n\n") {
+            let chunk = events.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let end = received.find("\n\n").unwrap();
+        received.drain(..end + 2).collect()
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let ctx = Context {
+            proxy: proxy::tests::test_context(&["cat"]),
+            sessions: Sessions::default(),
+            body_limit: super::super::JSON_BODY_LIMIT,
+        };
+        let sessions = ctx.sessions.clone();
+        let (addr, server) = warp::serve(handler(ctx)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let client = hyper::Client::new();
+        let uri = |path: &str| {
+            format!("http://{}{}", addr, path)
+                .parse::<hyper::Uri>()
+                .unwrap()
+        };
+        let send = |session: &str, text: &'static str| {
+            let req = hyper::Request::post(uri(&format!("/send?session={}", session)))
+                .body(hyper::Body::from(text))
+                .unwrap();
+            client.request(req)
+        };
+
+        let res = client.get(uri("/sse")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut events = res.into_body();
+        let mut received = String::new();
+        let event = next_event(&mut events, &mut received).await;
+        let session = event
+            .strip_prefix("event:session\ndata:")
+            .unwrap()
+            .trim_end()
+            .to_owned();
+        assert_eq!(session.len(), 32);
+
+        // `cat` echoes the message back as if the server sent it.
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":null,"rootUri":null,"capabilities":{}}}"#;
+        let res = send(&session, initialize).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let event = next_event(&mut events, &mut received).await;
+        let data = event.strip_prefix("data:").unwrap().trim_end();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(data).unwrap(),
+            serde_json::from_str::<serde_json::Value>(initialize).unwrap()
+        );
+
+        // Dropping the event stream ends the session.
+        drop(events);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !sessions.lock().unwrap().is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session removed");
+        let res = send(&session, initialize).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,160 @@
+//! Restrict and normalize text documents written on save with `--sync`.
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ignore::overrides::{Override, OverrideBuilder};
+
+use super::files::is_project_path;
+
+/// Paths allowed to be written on save.
+#[derive(Clone, Debug)]
+pub struct SyncFilter {
+    cwd: PathBuf,
+    globs: Override,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SyncFilter {
+    /// Allow paths under `cwd` matching any of `include` (all if empty) and none of `exclude`.
+    /// Globs are matched relative to `cwd` like `.gitignore`.
+    pub fn new(cwd: &Path, include: &[String], exclude: &[String]) -> Result<Self, ignore::Error> {
+        let mut builder = OverrideBuilder::new(cwd);
+        for glob in include {
+            builder.add(glob)?;
+        }
+        // Later globs take precedence, so excludes win over includes.
+        for glob in exclude {
+            builder.add(&format!("!{}", glob))?;
+        }
+        Ok(Self {
+            cwd: cwd.to_owned(),
+            globs: builder.build()?,
+            include: include.to_owned(),
+            exclude: exclude.to_owned(),
+        })
+    }
+
+    /// The same filter for another `cwd`.
+    pub fn rebase(&self, cwd: &Path) -> Result<Self, ignore::Error> {
+        Self::new(cwd, &self.include, &self.exclude)
+    }
+
+    /// Check if absolute `path` can be written.
+    pub fn allows(&self, path: &Path) -> bool {
+        is_project_path(&self.cwd, path) && !self.globs.matched(path, false).is_ignore()
+    }
+}
+
+/// Line endings to rewrite text to before writing.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// Keep as is.
+    None,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::Crlf),
+            "none" => Ok(Self::None),
+            _ => Err(format!("{} is not one of lf, crlf, or none", s)),
+        }
+    }
+}
+
+impl LineEnding {
+    /// Rewrite `\r\n`, `\r`, and `\n` in `text` to this line ending.
+    pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let eol = match self {
+            Self::Lf => "\n",
+            Self::Crlf => "\r\n",
+            Self::None => return Cow::Borrowed(text),
+        };
+        // Nothing to rewrite without `\r` for `lf`, or without any line breaks.
+        if !text.contains('\r') && (*self == Self::Lf || !text.contains('\n')) {
+            return Cow::Borrowed(text);
+        }
+        let mut normalized = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => {
+                    chars.next_if_eq(&'\n');
+                    normalized.push_str(eol);
+                }
+                '\n' => normalized.push_str(eol),
+                c => normalized.push(c),
+            }
+        }
+        Cow::Owned(normalized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> SyncFilter {
+        let strings = |globs: &[&str]| globs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        SyncFilter::new(
+            Path::new("/tmp/project"),
+            &strings(include),
+            &strings(exclude),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let mixed = "a\r\nb\nc\rd\r\n";
+        assert_eq!(LineEnding::Lf.normalize(mixed), "a\nb\nc\nd\n");
+        assert_eq!(LineEnding::Crlf.normalize(mixed), "a\r\nb\r\nc\r\nd\r\n");
+        assert_eq!(LineEnding::None.normalize(mixed), mixed);
+        assert_eq!(LineEnding::Crlf.normalize("a\nb\r"), "a\r\nb\r\n");
+        assert!(matches!(LineEnding::Lf.normalize("a\nb"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_outside_cwd() {
+        let filter = filter(&[], &[]);
+        assert!(filter.allows(Path::new("/tmp/project/src/main.rs")));
+        assert!(!filter.allows(Path::new("/tmp/other/main.rs")));
+        assert!(!filter.allows(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_excluded_glob() {
+        let filter = filter(&[], &["*.env", "secrets/"]);
+        assert!(filter.allows(Path::new("/tmp/project/src/main.rs")));
+        assert!(!filter.allows(Path::new("/tmp/project/.env")));
+        assert!(!filter.allows(Path::new("/tmp/project/config/prod.env")));
+    }
+
+    #[test]
+    fn test_rebase() {
+        let filter = filter(&["src/**"], &[])
+            .rebase(Path::new("/tmp/other"))
+            .unwrap();
+        assert!(filter.allows(Path::new("/tmp/other/src/main.rs")));
+        assert!(!filter.allows(Path::new("/tmp/project/src/main.rs")));
+        assert!(!filter.allows(Path::new("/tmp/other/Cargo.toml")));
+    }
+
+    #[test]
+    fn test_included_glob() {
+        let filter = filter(&["src/**"], &["src/generated/**"]);
+        assert!(filter.allows(Path::new("/tmp/project/src/main.rs")));
+        assert!(!filter.allows(Path::new("/tmp/project/Cargo.toml")));
+        assert!(!filter.allows(Path::new("/tmp/project/src/generated/a.rs")));
+    }
+}
@@ -0,0 +1,173 @@
+//! Time requests from the client to find slow responses from the server, and trace them.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lsp::types::Id;
+
+// Only the fields necessary to match responses to requests.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+    error: Option<serde::de::IgnoredAny>,
+}
+
+/// A request that took longer than the threshold.
+#[derive(Debug)]
+pub struct SlowRequest {
+    pub id: Id,
+    pub method: String,
+    pub elapsed: Duration,
+}
+
+/// Outstanding requests from the client keyed by id.
+pub struct RequestTimer {
+    threshold: Duration,
+    pending: HashMap<Id, (String, Instant)>,
+}
+
+impl RequestTimer {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Start timing if `value` is a request.
+    pub fn request(&mut self, value: &Value) {
+        if let Ok(Head {
+            id: Some(id),
+            method: Some(method),
+            ..
+        }) = Head::deserialize(value)
+        {
+            self.pending.insert(id, (method, Instant::now()));
+        }
+    }
+
+    /// Stop timing if `value` is a response to a pending request.
+    /// Returns the request if it exceeded the threshold.
+    pub fn response(&mut self, value: &Value) -> Option<SlowRequest> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        match Head::deserialize(value) {
+            Ok(Head {
+                id: Some(id),
+                method: None,
+                ..
+            }) => {
+                let (method, since) = self.pending.remove(&id)?;
+                let elapsed = since.elapsed();
+                if elapsed >= self.threshold {
+                    Some(SlowRequest {
+                        id,
+                        method,
+                        elapsed,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Spans of outstanding requests from the client keyed by id, closed on response.
+#[derive(Default)]
+pub struct RequestSpans {
+    pending: HashMap<Id, tracing::Span>,
+}
+
+impl RequestSpans {
+    /// Open a span if `value` is a request.
+    pub fn request(&mut self, value: &Value) {
+        if let Ok(Head {
+            id: Some(id),
+            method: Some(method),
+            ..
+        }) = Head::deserialize(value)
+        {
+            let span = tracing::info_span!(
+                "request",
+                "otel.name" = %method,
+                "otel.kind" = "server",
+                method = %method,
+                id = %id,
+                error = tracing::field::Empty,
+            );
+            self.pending.insert(id, span);
+        }
+    }
+
+    /// Close the span if `value` is a response to a pending request.
+    pub fn response(&mut self, value: &Value) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Ok(Head {
+            id: Some(id),
+            method: None,
+            error,
+        }) = Head::deserialize(value)
+        {
+            if let (Some(span), Some(_)) = (self.pending.remove(&id), error) {
+                span.record("error", true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_slow_request() {
+        let mut timer = RequestTimer::new(Duration::from_millis(0));
+        timer.request(&json!({"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}));
+        // Notifications and requests from the server are ignored.
+        assert!(timer
+            .response(&json!({"jsonrpc":"2.0","id":1,"method":"workspace/configuration"}))
+            .is_none());
+        let slow = timer
+            .response(&json!({"jsonrpc":"2.0","id":1,"result":null}))
+            .unwrap();
+        assert_eq!(slow.id, Id::Number(1));
+        assert_eq!(slow.method, "textDocument/hover");
+        // Already responded.
+        assert!(timer
+            .response(&json!({"jsonrpc":"2.0","id":1,"result":null}))
+            .is_none());
+    }
+
+    #[test]
+    fn test_fast_request() {
+        let mut timer = RequestTimer::new(Duration::from_secs(60));
+        timer.request(&json!({"jsonrpc":"2.0","id":"a","method":"shutdown"}));
+        assert!(timer
+            .response(&json!({"jsonrpc":"2.0","id":"a","result":null}))
+            .is_none());
+    }
+
+    #[test]
+    fn test_request_spans() {
+        let mut spans = RequestSpans::default();
+        spans.request(&json!({"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}));
+        spans.request(&json!({"jsonrpc":"2.0","method":"initialized","params":{}}));
+        assert_eq!(spans.pending.len(), 1);
+        spans.response(&json!({"jsonrpc":"2.0","id":1,"method":"workspace/configuration"}));
+        assert_eq!(spans.pending.len(), 1);
+        spans.response(&json!({"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":""}}));
+        assert_eq!(spans.pending.len(), 0);
+    }
+}
@@ -0,0 +1,31 @@
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::json_response;
+
+#[derive(Debug, serde::Serialize)]
+struct Version {
+    name: &'static str,
+    version: &'static str,
+    /// Git commit the binary was built from, if known.
+    git_sha: Option<&'static str>,
+    /// Version of `lsp-types`, which determines the supported protocol features.
+    lsp_types_version: Option<&'static str>,
+}
+
+/// Handler for `GET /version`
+pub fn handler() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("version"))
+        .and(warp::path::end())
+        .map(|| {
+            json_response(
+                &Version {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                    git_sha: option_env!("GIT_SHA"),
+                    lsp_types_version: option_env!("LSP_TYPES_VERSION"),
+                },
+                StatusCode::OK,
+            )
+        })
+}
@@ -0,0 +1,110 @@
+//! Separate directories for each connection with `--per-connection-workspace`.
+use std::path::{Path, PathBuf};
+
+/// Creates a directory for each connection under `base`, copied from `template` if set.
+#[derive(Clone, Debug)]
+pub struct Workspaces {
+    pub base: PathBuf,
+    pub template: Option<PathBuf>,
+}
+
+impl Workspaces {
+    /// Create the directory for `connection`. Removed when the returned `Workspace` is dropped.
+    pub async fn create(&self, connection: u64) -> Result<Workspace, std::io::Error> {
+        // Include the process id so directories left by a crashed proxy don't collide.
+        let dir = self.base.join(format!(
+            "lsp-ws-proxy-{}-{}",
+            std::process::id(),
+            connection
+        ));
+        let template = self.template.clone();
+        let created = dir.clone();
+        tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&created)?;
+            match template {
+                Some(template) => copy_dir(&template, &created),
+                None => Ok(()),
+            }
+        })
+        .await
+        .expect("creating workspace panicked")?;
+        tracing::debug!("created workspace {:?}", dir);
+        Ok(Workspace { dir })
+    }
+}
+
+/// Directory of a connection.
+#[derive(Debug)]
+pub struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let dir = std::mem::take(&mut self.dir);
+        tokio::spawn(async move {
+            match tokio::fs::remove_dir_all(&dir).await {
+                Ok(()) => tracing::debug!("removed workspace {:?}", dir),
+                Err(err) => tracing::warn!("failed to remove workspace {:?}: {}", dir, err),
+            }
+        });
+    }
+}
+
+// Copy the contents of `src` to `dst` recursively. Symlinks are followed.
+fn copy_dir(src: &Path, dst: &Path) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.path().is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_create_from_template() {
+        let root = std::env::temp_dir().join(format!("lsp-ws-proxy-ws-{}", std::process::id()));
+        let template = root.join("template");
+        std::fs::create_dir_all(template.join("src")).unwrap();
+        std::fs::write(template.join("src/main.rs"), "fn main() {}").unwrap();
+        let workspaces = Workspaces {
+            base: root.join("base"),
+            template: Some(template),
+        };
+
+        let a = workspaces.create(1).await.unwrap();
+        let b = workspaces.create(2).await.unwrap();
+        assert_ne!(a.path(), b.path());
+        assert_eq!(
+            std::fs::read_to_string(a.path().join("src/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        let (a_dir, b_dir) = (a.path().to_owned(), b.path().to_owned());
+        drop(a);
+        for _ in 0..50 {
+            if !a_dir.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!a_dir.exists());
+        assert!(b_dir.exists());
+        drop(b);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+//! Listening on TCP addresses and Unix sockets.
+//!
+//! [`start`] serves the routes in the background, and returns a [`ProxyHandle`] to stop
+//! them from code, e.g., when the proxy is mounted in a host application:
+//!
+//! ```ignore
+//! let listens = vec![Listen::Tcp(([127, 0, 0, 1], 9999).into())];
+//! let handle = listen::start(routes, listens, ServeOptions::default());
+//! // ... until the host application is done with the proxy.
+//! handle.shutdown().await?;
+//! ```
+use std::{
+    convert::Infallible, fmt, future::Future, net::SocketAddr, path::PathBuf, time::Duration,
+};
+
+use futures_util::{future, stream, FutureExt};
+use hyper::{
+    server::{
+        accept::Accept,
+        conn::{AddrIncoming, AddrStream},
+        Builder,
+    },
+    service::Service,
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+use warp::{Filter, Reply};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Address to listen on.
+#[derive(Debug, Clone)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    /// Unix domain socket path, specified with `unix:` prefix.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub fn parse_listen(value: &str) -> Result<Listen, String> {
+    if let Some(path) = value.strip_prefix("unix:") {
+        return if cfg!(unix) {
+            Ok(Listen::Unix(PathBuf::from(path)))
+        } else {
+            Err("unix sockets are not supported on this platform".to_owned())
+        };
+    }
+
+    // Allow specifying only a port number.
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        return parse_listen(&format!("0.0.0.0:{}", value));
+    }
+
+    match value.parse::<SocketAddr>() {
+        Ok(addr) => Ok(Listen::Tcp(addr)),
+        Err(_) => Err(format!("{} cannot be parsed as SocketAddr", value)),
+    }
+}
+
+/// Address of the peer of the connection, in the extensions of requests over TCP.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+// Connections with the address of the peer.
+trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for tokio::net::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// HTTP options of the connections, mostly for clients of the REST endpoints like `/files`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServeOptions {
+    /// Also accept HTTP/2 with prior knowledge (h2c). WebSockets still use HTTP/1.1.
+    pub http2: bool,
+    /// Interval of keep-alive probes on idle connections. TCP keepalive, and pings with HTTP/2.
+    pub keep_alive: Option<Duration>,
+}
+
+impl ServeOptions {
+    fn configure<I>(&self, builder: Builder<I>) -> Builder<I> {
+        builder
+            .http1_only(!self.http2)
+            .http2_keep_alive_interval(self.keep_alive)
+    }
+}
+
+/// Serve `filter` on `listen` until `shutdown` completes.
+pub async fn serve<F, S>(
+    filter: F,
+    listen: Listen,
+    options: ServeOptions,
+    shutdown: S,
+) -> Result<(), Error>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+    S: Future<Output = ()> + Send + 'static,
+{
+    match listen {
+        Listen::Tcp(addr) => {
+            let mut incoming = AddrIncoming::bind(&addr)?;
+            incoming.set_keepalive(options.keep_alive);
+            tracing::info!("listening on {}", incoming.local_addr());
+            serve_incoming(filter, incoming, options, shutdown).await?;
+        }
+
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            tracing::info!("listening on unix:{}", path.display());
+            let incoming = stream::unfold(listener, |listener| async move {
+                let conn = listener.accept().await.map(|(stream, _)| stream);
+                Some((conn, listener))
+            });
+            let result = serve_incoming(
+                filter,
+                hyper::server::accept::from_stream(incoming),
+                options,
+                shutdown,
+            )
+            .await;
+            let _ = std::fs::remove_file(&path);
+            result?;
+        }
+
+        #[cfg(not(unix))]
+        Listen::Unix(_) => unreachable!("rejected when parsing"),
+    }
+    Ok(())
+}
+
+async fn serve_incoming<F, I, S>(
+    filter: F,
+    incoming: I,
+    options: ServeOptions,
+    shutdown: S,
+) -> Result<(), hyper::Error>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+    I: Accept,
+    I::Conn: PeerAddr + tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    I::Error: Into<Error>,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let service = warp::service(filter);
+    let make_service = hyper::service::make_service_fn(move |conn: &I::Conn| {
+        let remote_addr = conn.peer_addr().map(RemoteAddr);
+        let service = service.clone();
+        let service = hyper::service::service_fn(move |mut req| {
+            if let Some(remote_addr) = remote_addr {
+                req.extensions_mut().insert(remote_addr);
+            }
+            service.clone().call(req)
+        });
+        async move { Ok::<_, Infallible>(service) }
+    });
+    options
+        .configure(hyper::Server::builder(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Handle to stop the servers started with [`start`].
+/// Dropping the handle also shuts them down.
+pub struct ProxyHandle {
+    shutdown: oneshot::Sender<()>,
+    servers: JoinHandle<Result<Vec<()>, Error>>,
+}
+
+impl ProxyHandle {
+    /// Stop accepting connections, and wait for the servers to finish.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let Self {
+            shutdown,
+            mut servers,
+        } = self;
+        let _ = shutdown.send(());
+        wait_servers(&mut servers).await
+    }
+
+    /// Completes when all servers stopped on their own, or any of them failed, e.g., to bind.
+    pub async fn stopped(&mut self) -> Result<(), Error> {
+        wait_servers(&mut self.servers).await
+    }
+}
+
+async fn wait_servers(servers: &mut JoinHandle<Result<Vec<()>, Error>>) -> Result<(), Error> {
+    servers.await??;
+    Ok(())
+}
+
+/// Serve `filter` on all of `listens` in the background until shut down with the handle.
+pub fn start<F>(filter: F, listens: Vec<Listen>, options: ServeOptions) -> ProxyHandle
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    // Shut down all listeners together.
+    let shutdown_rx = shutdown_rx.map(|_| ()).shared();
+    let servers = listens
+        .into_iter()
+        .map(|l| serve(filter.clone(), l, options, shutdown_rx.clone()));
+    // The other listeners are dropped on the first error, so a bad address fails the startup.
+    let servers = tokio::spawn(future::try_join_all(servers));
+    ProxyHandle { shutdown, servers }
+}
+
+// Remove the socket left by the previous run. Anything else is left for `bind` to fail.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Completes on Ctrl-C, or `SIGTERM` on Unix.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    tracing::info!("shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_handle() {
+        let handle = start(
+            warp::any().map(warp::reply),
+            vec![Listen::Tcp(([127, 0, 0, 1], 0).into())],
+            ServeOptions::default(),
+        );
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("shut down in time")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stopped_on_bind_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut handle = start(
+            warp::any().map(warp::reply),
+            vec![Listen::Tcp(addr)],
+            ServeOptions::default(),
+        );
+        assert!(handle.stopped().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stopped_on_bind_failure_of_any() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut handle = start(
+            warp::any().map(warp::reply),
+            vec![Listen::Tcp(([127, 0, 0, 1], 0).into()), Listen::Tcp(addr)],
+            ServeOptions::default(),
+        );
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle.stopped())
+            .await
+            .expect("stopped without shutdown")
+            .unwrap_err();
+    }
+
+    // Send a request with HTTP/2 prior knowledge to a server with `options`.
+    async fn get_http2(options: ServeOptions) -> Result<String, hyper::Error> {
+        let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = incoming.local_addr();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_incoming(
+            warp::path("files").map(|| "ok"),
+            incoming,
+            options,
+            shutdown_rx.map(|_| ()),
+        ));
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<hyper::Body>();
+        let uri = format!("http://{}/files", addr).parse().unwrap();
+        let result = match client.get(uri).await {
+            Ok(res) => hyper::body::to_bytes(res.into_body())
+                .await
+                .map(|body| String::from_utf8_lossy(&body).into_owned()),
+            Err(err) => Err(err),
+        };
+        let _ = shutdown.send(());
+        server.await.unwrap().unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn test_http2() {
+        let options = ServeOptions {
+            http2: true,
+            keep_alive: Some(Duration::from_secs(30)),
+        };
+        assert_eq!(get_http2(options).await.unwrap(), "ok");
+        assert!(get_http2(ServeOptions::default()).await.is_err());
+    }
+}
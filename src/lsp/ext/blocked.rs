@@ -0,0 +1,200 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lsp::{
+    error::{Error, ErrorCode},
+    types::Id,
+    Response,
+};
+
+// Capabilities in `InitializeResult.capabilities` advertising each method, as paths of keys.
+const CAPABILITIES: &[(&str, &[&str])] = &[
+    ("textDocument/hover", &["hoverProvider"]),
+    ("textDocument/completion", &["completionProvider"]),
+    ("textDocument/signatureHelp", &["signatureHelpProvider"]),
+    ("textDocument/declaration", &["declarationProvider"]),
+    ("textDocument/definition", &["definitionProvider"]),
+    ("textDocument/typeDefinition", &["typeDefinitionProvider"]),
+    ("textDocument/implementation", &["implementationProvider"]),
+    ("textDocument/references", &["referencesProvider"]),
+    (
+        "textDocument/documentHighlight",
+        &["documentHighlightProvider"],
+    ),
+    ("textDocument/documentSymbol", &["documentSymbolProvider"]),
+    ("textDocument/codeAction", &["codeActionProvider"]),
+    ("textDocument/codeLens", &["codeLensProvider"]),
+    ("textDocument/documentLink", &["documentLinkProvider"]),
+    ("textDocument/documentColor", &["colorProvider"]),
+    ("textDocument/formatting", &["documentFormattingProvider"]),
+    (
+        "textDocument/rangeFormatting",
+        &["documentRangeFormattingProvider"],
+    ),
+    (
+        "textDocument/onTypeFormatting",
+        &["documentOnTypeFormattingProvider"],
+    ),
+    ("textDocument/rename", &["renameProvider"]),
+    ("textDocument/foldingRange", &["foldingRangeProvider"]),
+    ("textDocument/selectionRange", &["selectionRangeProvider"]),
+    (
+        "textDocument/prepareCallHierarchy",
+        &["callHierarchyProvider"],
+    ),
+    (
+        "textDocument/linkedEditingRange",
+        &["linkedEditingRangeProvider"],
+    ),
+    ("textDocument/moniker", &["monikerProvider"]),
+    ("textDocument/inlayHint", &["inlayHintProvider"]),
+    ("workspace/symbol", &["workspaceSymbolProvider"]),
+    ("workspace/executeCommand", &["executeCommandProvider"]),
+    (
+        "workspace/willCreateFiles",
+        &["workspace", "fileOperations", "willCreate"],
+    ),
+    (
+        "workspace/didCreateFiles",
+        &["workspace", "fileOperations", "didCreate"],
+    ),
+    (
+        "workspace/willRenameFiles",
+        &["workspace", "fileOperations", "willRename"],
+    ),
+    (
+        "workspace/didRenameFiles",
+        &["workspace", "fileOperations", "didRename"],
+    ),
+    (
+        "workspace/willDeleteFiles",
+        &["workspace", "fileOperations", "willDelete"],
+    ),
+    (
+        "workspace/didDeleteFiles",
+        &["workspace", "fileOperations", "didDelete"],
+    ),
+];
+
+// Only the fields necessary to block messages.
+#[derive(Deserialize)]
+struct Head {
+    id: Option<Id>,
+    method: Option<String>,
+}
+
+/// Methods from the client that are not forwarded to the server.
+#[derive(Clone, Debug, Default)]
+pub struct BlockedMethods {
+    methods: Vec<String>,
+}
+
+impl BlockedMethods {
+    pub fn new(methods: Vec<String>) -> Self {
+        Self { methods }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+
+    /// Whether `value` from the client is a request or a notification of a blocked method.
+    pub fn blocks(&self, value: &Value) -> bool {
+        if self.methods.is_empty() {
+            return false;
+        }
+        match Head::deserialize(value) {
+            Ok(Head {
+                method: Some(method),
+                ..
+            }) => self.methods.contains(&method),
+            _ => false,
+        }
+    }
+
+    /// The error response to a blocked request. `None` for notifications.
+    pub fn respond(&self, value: &Value) -> Option<Response> {
+        let Head { id, method } = Head::deserialize(value).ok()?;
+        Some(Response::Failure {
+            id: Some(id?),
+            error: Error {
+                code: ErrorCode::MethodNotFound,
+                message: format!("{} is disabled", method.unwrap_or_default()),
+                data: None,
+            },
+        })
+    }
+
+    /// Remove the capabilities of the blocked methods from `InitializeResult`.
+    /// Returns `true` if any was removed.
+    pub fn remove_capabilities(&self, result: &mut Value) -> bool {
+        let mut removed = false;
+        for (_, path) in CAPABILITIES
+            .iter()
+            .filter(|(method, _)| self.methods.iter().any(|m| m == method))
+        {
+            let (key, parents) = path.split_last().unwrap();
+            let mut caps = result.get_mut("capabilities");
+            for parent in parents {
+                caps = caps.and_then(|c| c.get_mut(*parent));
+            }
+            if let Some(caps) = caps.and_then(Value::as_object_mut) {
+                removed |= caps.remove(*key).is_some();
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_blocks() {
+        let blocked = BlockedMethods::new(vec![
+            "workspace/executeCommand".to_owned(),
+            "workspace/didRenameFiles".to_owned(),
+        ]);
+        let request = json!({"jsonrpc":"2.0","id":1,"method":"workspace/executeCommand","params":{"command":"x"}});
+        assert!(blocked.blocks(&request));
+        let res = serde_json::to_value(blocked.respond(&request).unwrap()).unwrap();
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["error"]["code"], -32601);
+
+        let notification =
+            json!({"jsonrpc":"2.0","method":"workspace/didRenameFiles","params":{"files":[]}});
+        assert!(blocked.blocks(&notification));
+        assert!(blocked.respond(&notification).is_none());
+
+        assert!(!blocked.blocks(&json!({"jsonrpc":"2.0","id":2,"method":"shutdown"})));
+        assert!(!BlockedMethods::default().blocks(&request));
+    }
+
+    #[test]
+    fn test_remove_capabilities() {
+        let blocked = BlockedMethods::new(vec![
+            "workspace/executeCommand".to_owned(),
+            "workspace/didRenameFiles".to_owned(),
+            "textDocument/inlayHint".to_owned(),
+        ]);
+        let mut result = json!({
+            "capabilities": {
+                "hoverProvider": true,
+                "executeCommandProvider": {"commands": ["x"]},
+                "workspace": {"fileOperations": {"didRename": {"filters": []}, "didCreate": {"filters": []}}},
+            },
+        });
+        assert!(blocked.remove_capabilities(&mut result));
+        assert_eq!(
+            result["capabilities"],
+            json!({
+                "hoverProvider": true,
+                "workspace": {"fileOperations": {"didCreate": {"filters": []}}},
+            })
+        );
+        // Nothing left to remove.
+        assert!(!blocked.remove_capabilities(&mut result));
+    }
+}
@@ -0,0 +1,63 @@
+use serde_json::{Map, Value};
+
+use crate::lsp::{Message, Request, Response, ResponseResult};
+
+/// Respond to `workspace/configuration` from the server with `presets` keyed by section.
+/// Returns `None` unless every requested item has a preset, so that the client is asked instead.
+pub fn respond_configuration(msg: &Message, presets: &Map<String, Value>) -> Option<Response> {
+    let (id, params) = match msg {
+        Message::Request(Request::Configuration { id, params }) => (id, params),
+        _ => return None,
+    };
+    let values = params
+        .items
+        .iter()
+        .map(|item| item.section.as_ref().and_then(|s| presets.get(s)).cloned())
+        .collect::<Option<Vec<_>>>()?;
+    Some(Response::Success {
+        id: id.clone(),
+        result: ResponseResult::Any(Value::Array(values)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn configuration(items: Value) -> Message {
+        serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "workspace/configuration",
+            "params": {"items": items},
+        }))
+        .unwrap()
+    }
+
+    fn presets() -> Map<String, Value> {
+        match json!({"rust-analyzer": {"checkOnSave": false}, "files": {}}) {
+            Value::Object(presets) => presets,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_respond_with_presets() {
+        let msg = configuration(json!([{"section": "rust-analyzer"}, {"section": "files"}]));
+        let res = respond_configuration(&msg, &presets()).unwrap();
+        assert_eq!(
+            serde_json::to_value(Message::from(res)).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "result": [{"checkOnSave": false}, {}]})
+        );
+    }
+
+    #[test]
+    fn test_forward_without_preset() {
+        let msg = configuration(json!([{"section": "rust-analyzer"}, {"section": "editor"}]));
+        assert!(respond_configuration(&msg, &presets()).is_none());
+        let msg = configuration(json!([{"scopeUri": "file:///a.rs"}]));
+        assert!(respond_configuration(&msg, &presets()).is_none());
+    }
+}
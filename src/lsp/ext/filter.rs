@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+use crate::lsp::{Message, Notification};
+
+/// Severity of `window/showMessage`.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageSeverity {
+    Error,
+    Warning,
+    Info,
+    Log,
+}
+
+impl FromStr for MessageSeverity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warning" => Ok(Self::Warning),
+            "info" => Ok(Self::Info),
+            "log" => Ok(Self::Log),
+            _ => Err(format!("{} is not one of error, warning, info, or log", s)),
+        }
+    }
+}
+
+impl From<lsp_types::MessageType> for MessageSeverity {
+    fn from(typ: lsp_types::MessageType) -> Self {
+        match typ {
+            lsp_types::MessageType::Error => Self::Error,
+            lsp_types::MessageType::Warning => Self::Warning,
+            lsp_types::MessageType::Info => Self::Info,
+            lsp_types::MessageType::Log => Self::Log,
+        }
+    }
+}
+
+/// Drops notifications from the server that shouldn't reach the client.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    /// Drop `telemetry/event`.
+    pub drop_telemetry: bool,
+    /// Drop `window/showMessage` with these severities.
+    pub drop_show_message: Vec<MessageSeverity>,
+}
+
+impl MessageFilter {
+    /// Whether any message can be dropped. Messages don't need to be parsed otherwise.
+    pub fn is_active(&self) -> bool {
+        self.drop_telemetry || !self.drop_show_message.is_empty()
+    }
+
+    /// Whether `msg` from the server should be dropped.
+    pub fn drops(&self, msg: &Message) -> bool {
+        match msg {
+            Message::Notification(Notification::TelemetryEvent { .. }) => self.drop_telemetry,
+            Message::Notification(Notification::ShowMessage { params }) => self
+                .drop_show_message
+                .contains(&MessageSeverity::from(params.typ)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Message {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_drops() {
+        let telemetry = parse(r#"{"jsonrpc":"2.0","method":"telemetry/event","params":{"a":1}}"#);
+        let info = parse(
+            r#"{"jsonrpc":"2.0","method":"window/showMessage","params":{"type":3,"message":"hi"}}"#,
+        );
+        let error = parse(
+            r#"{"jsonrpc":"2.0","method":"window/showMessage","params":{"type":1,"message":"oops"}}"#,
+        );
+        let log = parse(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#,
+        );
+
+        let filter = MessageFilter::default();
+        assert!(!filter.is_active());
+        assert!(!filter.drops(&telemetry));
+
+        let filter = MessageFilter {
+            drop_telemetry: true,
+            drop_show_message: vec!["info".parse().unwrap()],
+        };
+        assert!(filter.is_active());
+        assert!(filter.drops(&telemetry));
+        assert!(filter.drops(&info));
+        assert!(!filter.drops(&error));
+        assert!(!filter.drops(&log));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde_json::Value;
+
+use crate::lsp::types::Id;
+
+/// The type to coerce request ids to.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdKind {
+    Number,
+    String,
+}
+
+impl FromStr for IdKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(Self::Number),
+            "string" => Ok(Self::String),
+            _ => Err(format!("{} is not one of number or string", s)),
+        }
+    }
+}
+
+/// Replaces ids of requests from the client with ids of a single type for servers that
+/// don't accept the other. The original ids are restored in the responses.
+#[derive(Debug)]
+pub struct IdCoercion {
+    kind: IdKind,
+    next: u64,
+    /// Original ids of the pending requests keyed by the replaced ids.
+    pending: HashMap<Id, Id>,
+}
+
+impl IdCoercion {
+    pub fn new(kind: IdKind) -> Self {
+        Self {
+            kind,
+            next: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Replace the id of a request from the client, or the id to cancel with `$/cancelRequest`.
+    /// Returns whether `value` was changed.
+    pub fn coerce(&mut self, value: &mut Value) -> bool {
+        self.try_coerce(value).is_some()
+    }
+
+    /// Restore the original id of a response from the server.
+    /// Returns whether `value` was changed.
+    pub fn restore(&mut self, value: &mut Value) -> bool {
+        !self.pending.is_empty() && self.try_restore(value).is_some()
+    }
+
+    fn try_coerce(&mut self, value: &mut Value) -> Option<()> {
+        let obj = value.as_object_mut()?;
+        let method = obj.get("method")?.as_str()?.to_owned();
+        if let Some(id) = obj.get("id") {
+            let original = serde_json::from_value::<Id>(id.clone()).ok()?;
+            let id = self.next_id();
+            obj.insert("id".to_owned(), serde_json::to_value(&id).ok()?);
+            self.pending.insert(id, original);
+        } else if method == "$/cancelRequest" {
+            let params = obj.get_mut("params")?.as_object_mut()?;
+            let original = serde_json::from_value::<Id>(params.get("id")?.clone()).ok()?;
+            let (id, _) = self.pending.iter().find(|(_, o)| **o == original)?;
+            params.insert("id".to_owned(), serde_json::to_value(id).ok()?);
+        } else {
+            return None;
+        }
+        Some(())
+    }
+
+    fn try_restore(&mut self, value: &mut Value) -> Option<()> {
+        let obj = value.as_object_mut()?;
+        if obj.contains_key("method") {
+            return None;
+        }
+        let id = serde_json::from_value::<Id>(obj.get("id")?.clone()).ok()?;
+        let original = self.pending.remove(&id)?;
+        obj.insert("id".to_owned(), serde_json::to_value(&original).ok()?);
+        Some(())
+    }
+
+    fn next_id(&mut self) -> Id {
+        let n = self.next;
+        self.next += 1;
+        match self.kind {
+            IdKind::Number => Id::Number(n),
+            IdKind::String => Id::String(n.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_string_to_number() {
+        let mut ids = IdCoercion::new(IdKind::Number);
+        let mut req = json!({"jsonrpc":"2.0","id":"a","method":"shutdown"});
+        assert!(ids.coerce(&mut req));
+        assert_eq!(req["id"], json!(1));
+        let mut res = json!({"jsonrpc":"2.0","id":1,"result":null});
+        assert!(ids.restore(&mut res));
+        assert_eq!(res["id"], json!("a"));
+        // Already responded.
+        let mut res = json!({"jsonrpc":"2.0","id":1,"result":null});
+        assert!(!ids.restore(&mut res));
+        assert_eq!(res["id"], json!(1));
+    }
+
+    #[test]
+    fn test_number_to_string() {
+        let mut ids = IdCoercion::new(IdKind::String);
+        let mut req = json!({"jsonrpc":"2.0","id":7,"method":"shutdown"});
+        assert!(ids.coerce(&mut req));
+        assert_eq!(req["id"], json!("1"));
+        let mut res = json!({"jsonrpc":"2.0","id":"1","result":null});
+        assert!(ids.restore(&mut res));
+        assert_eq!(res["id"], json!(7));
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let mut ids = IdCoercion::new(IdKind::Number);
+        ids.coerce(&mut json!({"jsonrpc":"2.0","id":"a","method":"shutdown"}));
+        let mut cancel = json!({"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":"a"}});
+        assert!(ids.coerce(&mut cancel));
+        assert_eq!(cancel["params"]["id"], json!(1));
+    }
+
+    #[test]
+    fn test_ignores_others() {
+        let mut ids = IdCoercion::new(IdKind::Number);
+        assert!(!ids.coerce(&mut json!({"jsonrpc":"2.0","method":"initialized","params":{}})));
+        // Responses from the client to requests from the server keep the server's ids.
+        assert!(!ids.coerce(&mut json!({"jsonrpc":"2.0","id":"x","result":null})));
+        ids.coerce(&mut json!({"jsonrpc":"2.0","id":"a","method":"shutdown"}));
+        // Requests from the server are not changed.
+        assert!(
+            !ids.restore(&mut json!({"jsonrpc":"2.0","id":1,"method":"workspace/configuration"}))
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use serde_json::Value;
+
+use crate::lsp::{Message, Request};
+
+/// Merge `defaults` into `initializationOptions` of `initialize` request.
+///
+/// Values from the client take precedence unless `force` is set.
+pub fn merge_initialization_options(msg: &mut Message, defaults: &Value, force: bool) {
+    if let Message::Request(Request::Initialize { id: _, params: p }) = msg {
+        let merged = match p.initialization_options.take() {
+            Some(mut client) if force => {
+                merge_json(&mut client, defaults);
+                client
+            }
+            Some(client) => {
+                let mut merged = defaults.clone();
+                merge_json(&mut merged, &client);
+                merged
+            }
+            None => defaults.clone(),
+        };
+        p.initialization_options = Some(merged);
+    }
+}
+
+/// Deep merge `other` into `base`. Objects are merged recursively, and anything else is replaced.
+pub fn merge_json(base: &mut Value, other: &Value) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn initialize(options: Option<Value>) -> Message {
+        let mut v =
+            json!({"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{}},"id":1});
+        if let Some(options) = options {
+            v["params"]["initializationOptions"] = options;
+        }
+        serde_json::from_value(v).unwrap()
+    }
+
+    fn options(msg: &Message) -> Value {
+        match msg {
+            Message::Request(Request::Initialize { id: _, params: p }) => {
+                p.initialization_options.clone().unwrap()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_defaults_without_client_options() {
+        let mut msg = initialize(None);
+        merge_initialization_options(&mut msg, &json!({"a": 1}), false);
+        assert_eq!(options(&msg), json!({"a": 1}));
+    }
+
+    #[test]
+    fn test_client_options_win() {
+        let mut msg = initialize(Some(json!({"a": {"b": 1}, "c": 3})));
+        merge_initialization_options(&mut msg, &json!({"a": {"b": 2, "d": 4}}), false);
+        assert_eq!(options(&msg), json!({"a": {"b": 1, "d": 4}, "c": 3}));
+    }
+
+    #[test]
+    fn test_forced_options_win() {
+        let mut msg = initialize(Some(json!({"a": {"b": 1}, "c": 3})));
+        merge_initialization_options(&mut msg, &json!({"a": {"b": 2, "d": 4}}), true);
+        assert_eq!(options(&msg), json!({"a": {"b": 2, "d": 4}, "c": 3}));
+    }
+}
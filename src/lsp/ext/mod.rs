@@ -1,4 +1,22 @@
 //! Nonstandard LSP features.
+mod blocked;
+mod configuration;
+mod filter;
+mod id;
+mod init_options;
+mod position;
+mod progress;
 mod relative_uri;
+mod root;
+mod severity;
 
-pub use relative_uri::remap_relative_uri;
+pub use blocked::BlockedMethods;
+pub use configuration::respond_configuration;
+pub use filter::{MessageFilter, MessageSeverity};
+pub use id::{IdCoercion, IdKind};
+pub use init_options::{merge_initialization_options, merge_json};
+pub use position::{PositionEncoding, PositionTranslator};
+pub use progress::work_done_token;
+pub use relative_uri::{remap_relative_uri, resolves_under, SourceRoot};
+pub use root::inject_root;
+pub use severity::{rewrite_diagnostic_severity, SeverityRule};
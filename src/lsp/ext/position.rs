@@ -0,0 +1,570 @@
+use std::{collections::HashMap, str::FromStr};
+
+use lsp_types::{Position, Range, TextDocumentPositionParams};
+use serde_json::Value;
+use url::Url;
+
+use crate::lsp::{types::Id, Message, Notification, Request, Response};
+
+/// Encoding of `Position.character`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum PositionEncoding {
+    /// UTF-8 code units, i.e., bytes.
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// UTF-16 code units. Required by the LSP.
+    #[serde(rename = "utf-16")]
+    Utf16,
+}
+
+impl FromStr for PositionEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf-8" => Ok(Self::Utf8),
+            "utf-16" => Ok(Self::Utf16),
+            _ => Err(format!("expected utf-8 or utf-16, got {}", s)),
+        }
+    }
+}
+
+/// Translates `Position.character` between a client using UTF-8 and a server using UTF-16.
+///
+/// Documents opened by the client are tracked to find the text of each line.
+/// Positions in documents not tracked are passed through. Ranges in results are translated
+/// in the document of the request, or the document of the nearest `uri` like in `Location`,
+/// `LocationLink`, and `WorkspaceEdit`.
+#[derive(Debug, Default)]
+pub struct PositionTranslator {
+    documents: HashMap<Url, String>,
+    /// Requests with results to translate, and the document in the request.
+    pending: HashMap<Id, Url>,
+}
+
+impl PositionTranslator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate positions in `msg` from the client to UTF-16, and track the documents.
+    pub fn client_to_server(&mut self, msg: &mut Message) {
+        match msg {
+            Message::Notification(Notification::DidOpen { params: p }) => {
+                self.documents
+                    .insert(p.text_document.uri.clone(), p.text_document.text.clone());
+            }
+
+            Message::Notification(Notification::DidChange { params: p }) => {
+                let text = match self.documents.get_mut(&p.text_document.uri) {
+                    Some(text) => text,
+                    None => return,
+                };
+                // Each change is relative to the document after the previous change.
+                for change in &mut p.content_changes {
+                    match &mut change.range {
+                        Some(range) => {
+                            let start = byte_offset(text, range.start);
+                            let end = byte_offset(text, range.end);
+                            translate_range(text, range, utf8_to_utf16);
+                            // The length is in UTF-16 and deprecated in favor of `range`.
+                            change.range_length = None;
+                            if let (Some(start), Some(end)) = (start, end) {
+                                if start <= end {
+                                    text.replace_range(start..end, &change.text);
+                                }
+                            }
+                        }
+                        None => *text = change.text.clone(),
+                    }
+                }
+            }
+
+            Message::Notification(Notification::DidClose { params: p }) => {
+                self.documents.remove(&p.text_document.uri);
+            }
+
+            Message::Request(request) => self.translate_client_request(request),
+
+            _ => {}
+        }
+    }
+
+    fn translate_client_request(&mut self, request: &mut Request) {
+        let (id, uri) = match request {
+            Request::Completion { id, params: p } => {
+                self.translate_params(&mut p.text_document_position);
+                (id, &p.text_document_position.text_document.uri)
+            }
+
+            Request::Hover { id, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                (id, &p.text_document_position_params.text_document.uri)
+            }
+
+            Request::SignatureHelp { id, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                (id, &p.text_document_position_params.text_document.uri)
+            }
+
+            Request::GotoDeclaration { id, params: p }
+            | Request::GotoDefinition { id, params: p }
+            | Request::GotoTypeDefinition { id, params: p }
+            | Request::GotoImplementation { id, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                (id, &p.text_document_position_params.text_document.uri)
+            }
+
+            Request::References { id, params: p } => {
+                self.translate_params(&mut p.text_document_position);
+                (id, &p.text_document_position.text_document.uri)
+            }
+
+            Request::DocumentHighlight { id, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                (id, &p.text_document_position_params.text_document.uri)
+            }
+
+            Request::LinkedEditingRange { id, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                (id, &p.text_document_position_params.text_document.uri)
+            }
+
+            Request::Moniker { id: _, params: p } => {
+                self.translate_params(&mut p.text_document_position_params);
+                return;
+            }
+
+            Request::PrepareCallHierarchy { id: _, params: p } => {
+                // Results have their own URIs.
+                self.translate_params(&mut p.text_document_position_params);
+                return;
+            }
+
+            Request::Rename { id, params: p } => {
+                self.translate_params(&mut p.text_document_position);
+                (id, &p.text_document_position.text_document.uri)
+            }
+
+            Request::PrepareRename { id, params: p } => {
+                self.translate_params(p);
+                (id, &p.text_document.uri)
+            }
+
+            Request::OnTypeFormatting { id, params: p } => {
+                self.translate_params(&mut p.text_document_position);
+                (id, &p.text_document_position.text_document.uri)
+            }
+
+            Request::Formatting { id, params: p } => (id, &p.text_document.uri),
+
+            Request::RangeFormatting { id, params: p } => {
+                if let Some(text) = self.documents.get(&p.text_document.uri) {
+                    translate_range(text, &mut p.range, utf8_to_utf16);
+                }
+                (id, &p.text_document.uri)
+            }
+
+            Request::CodeAction { id, params: p } => {
+                if let Some(text) = self.documents.get(&p.text_document.uri) {
+                    translate_range(text, &mut p.range, utf8_to_utf16);
+                    for diagnostic in &mut p.context.diagnostics {
+                        translate_range(text, &mut diagnostic.range, utf8_to_utf16);
+                    }
+                }
+                (id, &p.text_document.uri)
+            }
+
+            _ => return,
+        };
+        // Results can refer to other tracked documents.
+        if !self.documents.is_empty() {
+            self.pending.insert(id.clone(), uri.clone());
+        }
+    }
+
+    /// Translate positions in `msg` from the server to UTF-8.
+    pub fn server_to_client(&mut self, msg: &mut Message) {
+        match msg {
+            Message::Notification(Notification::PublishDiagnostics { params: p }) => {
+                if let Some(text) = self.documents.get(&p.uri) {
+                    for diagnostic in &mut p.diagnostics {
+                        translate_range(text, &mut diagnostic.range, utf16_to_utf8);
+                    }
+                }
+            }
+
+            Message::Response(Response::Success { id, result }) => {
+                let uri = match self.pending.remove(id) {
+                    Some(uri) => uri,
+                    None => return,
+                };
+                // Results of these requests are mostly untyped, so translate any range.
+                if let Ok(mut value) = serde_json::to_value(&*result) {
+                    self.translate_ranges_in_value(Some(&uri), &mut value);
+                    if let Ok(translated) = serde_json::from_value(value) {
+                        *result = translated;
+                    }
+                }
+            }
+
+            Message::Response(Response::Failure { id: Some(id), .. }) => {
+                self.pending.remove(id);
+            }
+
+            _ => {}
+        }
+    }
+
+    // Translate anything shaped like `Range` in `value` from UTF-16 to UTF-8, in the
+    // document `uri` unless a nearer one is specified.
+    fn translate_ranges_in_value(&self, uri: Option<&Url>, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                if map.contains_key("start") && map.contains_key("end") {
+                    if let Some(text) = uri.and_then(|uri| self.documents.get(uri)) {
+                        let range = serde_json::from_value::<Range>(Value::Object(map.clone()));
+                        if let Ok(mut range) = range {
+                            translate_range(text, &mut range, utf16_to_utf8);
+                            map.insert("start".to_owned(), serde_json::json!(range.start));
+                            map.insert("end".to_owned(), serde_json::json!(range.end));
+                        }
+                    }
+                    return;
+                }
+                // `Location` has `uri`, and `TextDocumentEdit` has `textDocument`.
+                let nearest = map
+                    .get("uri")
+                    .or_else(|| map.get("textDocument").and_then(|d| d.get("uri")))
+                    .and_then(parse_uri);
+                let uri = nearest.as_ref().or(uri);
+                // `LocationLink` has `originSelectionRange` in the document of the request.
+                let target = map.get("targetUri").and_then(parse_uri);
+                for (key, value) in map.iter_mut() {
+                    match (key.as_str(), &target, value) {
+                        ("targetRange", Some(target), value)
+                        | ("targetSelectionRange", Some(target), value) => {
+                            self.translate_ranges_in_value(Some(target), value)
+                        }
+                        // `changes` of `WorkspaceEdit` are keyed by URI.
+                        ("changes", _, Value::Object(changes)) => {
+                            for (uri, edits) in changes.iter_mut() {
+                                let uri = Url::parse(uri).ok();
+                                self.translate_ranges_in_value(uri.as_ref(), edits);
+                            }
+                        }
+                        (_, _, value) => self.translate_ranges_in_value(uri, value),
+                    }
+                }
+            }
+
+            Value::Array(values) => {
+                for value in values {
+                    self.translate_ranges_in_value(uri, value);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    fn translate_params(&self, params: &mut TextDocumentPositionParams) {
+        if let Some(text) = self.documents.get(&params.text_document.uri) {
+            translate_position(text, &mut params.position, utf8_to_utf16);
+        }
+    }
+}
+
+fn parse_uri(value: &Value) -> Option<Url> {
+    value.as_str().and_then(|uri| Url::parse(uri).ok())
+}
+
+fn translate_position(text: &str, position: &mut Position, convert: fn(&str, u32) -> u32) {
+    if let Some(line) = text.split('\n').nth(position.line as usize) {
+        position.character = convert(line, position.character);
+    }
+}
+
+fn translate_range(text: &str, range: &mut Range, convert: fn(&str, u32) -> u32) {
+    translate_position(text, &mut range.start, convert);
+    translate_position(text, &mut range.end, convert);
+}
+
+// Byte offset of UTF-8 `position` in `text`.
+fn byte_offset(text: &str, position: Position) -> Option<usize> {
+    let mut start = 0;
+    for _ in 0..position.line {
+        start += text[start..].find('\n')? + 1;
+    }
+    let line_len = text[start..].find('\n').unwrap_or(text.len() - start);
+    let offset = start + (position.character as usize).min(line_len);
+    // Round down to a character boundary in case the client sent an invalid offset.
+    (0..=offset).rev().find(|&i| text.is_char_boundary(i))
+}
+
+/// Convert UTF-8 `offset` in `line` to UTF-16.
+fn utf8_to_utf16(line: &str, offset: u32) -> u32 {
+    let mut utf16 = 0;
+    for (i, c) in line.char_indices() {
+        if i >= offset as usize {
+            break;
+        }
+        utf16 += c.len_utf16() as u32;
+    }
+    utf16
+}
+
+/// Convert UTF-16 `offset` in `line` to UTF-8.
+fn utf16_to_utf8(line: &str, offset: u32) -> u32 {
+    let mut utf16 = 0;
+    for (i, c) in line.char_indices() {
+        if utf16 >= offset {
+            return i as u32;
+        }
+        utf16 += c.len_utf16() as u32;
+    }
+    line.len() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(value: Value) -> Message {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn opened(text: &str) -> PositionTranslator {
+        let mut translator = PositionTranslator::new();
+        open(&mut translator, "file:///a.txt", text);
+        translator
+    }
+
+    fn open(translator: &mut PositionTranslator, uri: &str, text: &str) {
+        translator.client_to_server(&mut message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": {"uri": uri, "languageId": "plaintext", "version": 1, "text": text},
+            },
+        })));
+    }
+
+    // Characters of the start and end of `range`.
+    fn characters(range: &Value) -> (u64, u64) {
+        (
+            range["start"]["character"].as_u64().unwrap(),
+            range["end"]["character"].as_u64().unwrap(),
+        )
+    }
+
+    fn hover(translator: &mut PositionTranslator, line: u32, character: u32) -> u32 {
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/hover",
+            "params": {
+                "textDocument": {"uri": "file:///a.txt"},
+                "position": {"line": line, "character": character},
+            },
+        }));
+        translator.client_to_server(&mut msg);
+        serde_json::to_value(&msg).unwrap()["params"]["position"]["character"]
+            .as_u64()
+            .unwrap() as u32
+    }
+
+    #[test]
+    fn test_conversions() {
+        // `é` is 2 bytes and 1 unit, `😀` is 4 bytes and 2 units.
+        let line = "café 😀!";
+        assert_eq!(utf8_to_utf16(line, 3), 3);
+        assert_eq!(utf8_to_utf16(line, 5), 4);
+        assert_eq!(utf8_to_utf16(line, 6), 5);
+        assert_eq!(utf8_to_utf16(line, 10), 7);
+        assert_eq!(utf8_to_utf16(line, 11), 8);
+        assert_eq!(utf16_to_utf8(line, 4), 5);
+        assert_eq!(utf16_to_utf8(line, 7), 10);
+        assert_eq!(utf16_to_utf8(line, 8), 11);
+        assert_eq!(utf16_to_utf8(line, 100), 11);
+    }
+
+    #[test]
+    fn test_request_position() {
+        let mut translator = opened("let x = 1;\nlet é = \"😀\";\n");
+        // After `"😀"` on the second line.
+        assert_eq!(hover(&mut translator, 1, 15), 12);
+        assert_eq!(hover(&mut translator, 0, 4), 4);
+    }
+
+    #[test]
+    fn test_untracked_document() {
+        let mut translator = PositionTranslator::new();
+        assert_eq!(hover(&mut translator, 1, 15), 15);
+    }
+
+    #[test]
+    fn test_did_change_updates_document() {
+        let mut translator = opened("😀\n");
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": "file:///a.txt", "version": 2},
+                "contentChanges": [
+                    {"range": {"start": {"line": 0, "character": 4}, "end": {"line": 0, "character": 4}}, "text": "é"},
+                    {"range": {"start": {"line": 0, "character": 6}, "end": {"line": 0, "character": 6}}, "text": "!"},
+                ],
+            },
+        }));
+        translator.client_to_server(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        let changes = &value["params"]["contentChanges"];
+        assert_eq!(changes[0]["range"]["start"]["character"], 2);
+        assert_eq!(changes[1]["range"]["start"]["character"], 3);
+        assert_eq!(
+            translator.documents[&Url::parse("file:///a.txt").unwrap()],
+            "😀é!\n"
+        );
+    }
+
+    #[test]
+    fn test_response_and_diagnostics() {
+        let mut translator = opened("é😀x\n");
+        hover(&mut translator, 0, 7);
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "contents": "x",
+                "range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 4}},
+            },
+        }));
+        translator.server_to_client(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(value["result"]["range"]["start"]["character"], 6);
+        assert_eq!(value["result"]["range"]["end"]["character"], 7);
+        assert!(translator.pending.is_empty());
+
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///a.txt",
+                "diagnostics": [{
+                    "range": {"start": {"line": 0, "character": 1}, "end": {"line": 0, "character": 3}},
+                    "message": "emoji",
+                }],
+            },
+        }));
+        translator.server_to_client(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        let range = &value["params"]["diagnostics"][0]["range"];
+        assert_eq!(range["start"]["character"], 2);
+        assert_eq!(range["end"]["character"], 6);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut translator = opened("😀 = x;\nlet y = 😀;\n");
+        open(&mut translator, "file:///b.txt", "é😀\n");
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/rename",
+            "params": {
+                "textDocument": {"uri": "file:///a.txt"},
+                "position": {"line": 1, "character": 8},
+                "newName": "z",
+            },
+        }));
+        translator.client_to_server(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(value["params"]["position"]["character"], 8);
+
+        let range = |line, start, end| {
+            serde_json::json!({
+                "start": {"line": line, "character": start},
+                "end": {"line": line, "character": end},
+            })
+        };
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "changes": {
+                    "file:///a.txt": [
+                        {"range": range(0, 0, 2), "newText": "z"},
+                        {"range": range(1, 8, 10), "newText": "z"},
+                    ],
+                },
+                "documentChanges": [{
+                    "textDocument": {"uri": "file:///b.txt", "version": 1},
+                    "edits": [{"range": range(0, 1, 3), "newText": "z"}],
+                }],
+            },
+        }));
+        translator.server_to_client(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        let edits = &value["result"]["changes"]["file:///a.txt"];
+        assert_eq!(characters(&edits[0]["range"]), (0, 4));
+        assert_eq!(characters(&edits[1]["range"]), (8, 12));
+        let edits = &value["result"]["documentChanges"][0]["edits"];
+        assert_eq!(characters(&edits[0]["range"]), (2, 6));
+        assert!(translator.pending.is_empty());
+    }
+
+    #[test]
+    fn test_definition_in_other_document() {
+        let mut translator = opened("x\n");
+        open(&mut translator, "file:///b.txt", "é😀x\n");
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/definition",
+            "params": {
+                "textDocument": {"uri": "file:///a.txt"},
+                "position": {"line": 0, "character": 0},
+            },
+        }));
+        translator.client_to_server(&mut msg);
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [{
+                "uri": "file:///b.txt",
+                "range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 4}},
+            }],
+        }));
+        translator.server_to_client(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(characters(&value["result"][0]["range"]), (6, 7));
+    }
+
+    #[test]
+    fn test_formatting() {
+        let mut translator = opened("😀  =  1\n");
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/formatting",
+            "params": {
+                "textDocument": {"uri": "file:///a.txt"},
+                "options": {"tabSize": 4, "insertSpaces": true},
+            },
+        }));
+        translator.client_to_server(&mut msg);
+        let mut msg = message(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [
+                {"range": {"start": {"line": 0, "character": 2}, "end": {"line": 0, "character": 4}}, "newText": " "},
+                {"range": {"start": {"line": 0, "character": 5}, "end": {"line": 0, "character": 7}}, "newText": " "},
+            ],
+        }));
+        translator.server_to_client(&mut msg);
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(characters(&value["result"][0]["range"]), (4, 6));
+        assert_eq!(characters(&value["result"][1]["range"]), (7, 9));
+    }
+}
@@ -0,0 +1,83 @@
+use lsp_types::ProgressToken;
+
+use crate::lsp::{Message, Request};
+
+/// The `workDoneToken` provided by the client in the params of a request.
+pub fn work_done_token(msg: &Message) -> Option<&ProgressToken> {
+    let params = match msg {
+        Message::Request(req) => match req {
+            Request::Symbol { params, .. } => &params.work_done_progress_params,
+            Request::ExecuteCommand { params, .. } => &params.work_done_progress_params,
+            Request::Completion { params, .. } => &params.work_done_progress_params,
+            Request::Hover { params, .. } => &params.work_done_progress_params,
+            Request::SignatureHelp { params, .. } => &params.work_done_progress_params,
+            Request::GotoDeclaration { params, .. } => &params.work_done_progress_params,
+            Request::GotoDefinition { params, .. } => &params.work_done_progress_params,
+            Request::GotoTypeDefinition { params, .. } => &params.work_done_progress_params,
+            Request::GotoImplementation { params, .. } => &params.work_done_progress_params,
+            Request::References { params, .. } => &params.work_done_progress_params,
+            Request::DocumentHighlight { params, .. } => &params.work_done_progress_params,
+            Request::DocumentSymbol { params, .. } => &params.work_done_progress_params,
+            Request::CodeAction { params, .. } => &params.work_done_progress_params,
+            Request::CodeLens { params, .. } => &params.work_done_progress_params,
+            Request::DocumentLink { params, .. } => &params.work_done_progress_params,
+            Request::DocumentColor { params, .. } => &params.work_done_progress_params,
+            Request::ColorPresentation { params, .. } => &params.work_done_progress_params,
+            Request::Formatting { params, .. } => &params.work_done_progress_params,
+            Request::RangeFormatting { params, .. } => &params.work_done_progress_params,
+            Request::Rename { params, .. } => &params.work_done_progress_params,
+            Request::FoldingRange { params, .. } => &params.work_done_progress_params,
+            Request::SelectionRange { params, .. } => &params.work_done_progress_params,
+            Request::LinkedEditingRange { params, .. } => &params.work_done_progress_params,
+            Request::Moniker { params, .. } => &params.work_done_progress_params,
+            Request::PrepareCallHierarchy { params, .. } => &params.work_done_progress_params,
+            Request::CallHierarchyIncomingCalls { params, .. } => &params.work_done_progress_params,
+            Request::CallHierarchyOutgoingCalls { params, .. } => &params.work_done_progress_params,
+            Request::InlayHint { params, .. } => &params.work_done_progress_params,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    params.work_done_token.as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::NumberOrString;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn request(method: &str, params: Value) -> Message {
+        serde_json::from_value(json!({"jsonrpc":"2.0","id":1,"method":method,"params":params}))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_work_done_token() {
+        let doc = json!({"uri": "file:///a.rs"});
+        let msg = request(
+            "textDocument/completion",
+            json!({
+                "textDocument": doc,
+                "position": {"line": 0, "character": 0},
+                "workDoneToken": "c1",
+            }),
+        );
+        assert_eq!(
+            work_done_token(&msg),
+            Some(&NumberOrString::String("c1".to_owned()))
+        );
+
+        let msg = request(
+            "textDocument/documentSymbol",
+            json!({"textDocument": doc, "workDoneToken": 7}),
+        );
+        assert_eq!(work_done_token(&msg), Some(&NumberOrString::Number(7)));
+
+        let msg = request("textDocument/documentSymbol", json!({"textDocument": doc}));
+        assert_eq!(work_done_token(&msg), None);
+        let msg = request("shutdown", Value::Null);
+        assert_eq!(work_done_token(&msg), None);
+    }
+}
@@ -1,69 +1,107 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
 
 use url::Url;
 
-use crate::lsp::{Message, Notification, Request, Response, ResponseResult};
+use crate::lsp::{
+    types::{InlayHint, InlayHintLabel},
+    Message, Notification, Request, Response, ResponseResult,
+};
+
+/// Maps `source://` URIs to `file://` URIs under `cwd`.
+#[derive(Clone, Debug)]
+pub struct SourceRoot {
+    pub cwd: Url,
+    /// Name prepended to the paths of `source://` URIs, e.g., `source://name/src/main.rs`.
+    pub prefix: Option<String>,
+    /// `cwd` with symlinks resolved, to check remapped paths against without the file system.
+    real: Option<PathBuf>,
+}
+
+impl SourceRoot {
+    pub fn new(cwd: Url) -> Self {
+        let real = cwd
+            .to_file_path()
+            .ok()
+            .map(|path| path.canonicalize().unwrap_or(path));
+        Self {
+            cwd,
+            prefix: None,
+            real,
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+}
 
 /// Remap URI relative to current directory (`source://`) to absolute URI (`file://`).  
 /// `source://` was chosen because it's used by [Metals Remote Language Server].
 ///
 /// [Metals Remote Language Server]: https://scalameta.org/metals/docs/contributors/remote-language-server.html
-pub fn remap_relative_uri(msg: &mut Message, cwd: &Url) -> Result<(), std::io::Error> {
+pub fn remap_relative_uri(msg: &mut Message, root: &SourceRoot) -> Result<(), std::io::Error> {
     match msg {
-        Message::Notification(notification) => remap_notification(notification, cwd)?,
-        Message::Request(request) => remap_request(request, cwd)?,
-        Message::Response(response) => remap_response(response, cwd)?,
+        Message::Notification(notification) => remap_notification(notification, root)?,
+        Message::Request(request) => remap_request(request, root)?,
+        Message::Response(response) => remap_response(response, root)?,
         Message::Unknown(_) => {}
     }
     Ok(())
 }
 
-fn remap_notification(notification: &mut Notification, cwd: &Url) -> Result<(), std::io::Error> {
+fn remap_notification(
+    notification: &mut Notification,
+    root: &SourceRoot,
+) -> Result<(), std::io::Error> {
     match notification {
         Notification::DidSave { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Notification::DidChangeWorkspaceFolders { params: p } => {
             for folder in &mut p.event.added {
-                remap_workspace_folder(folder, cwd)?;
+                remap_workspace_folder(folder, root)?;
             }
             for folder in &mut p.event.removed {
-                remap_workspace_folder(folder, cwd)?;
+                remap_workspace_folder(folder, root)?;
             }
         }
 
         Notification::DidChangeWatchedFiles { params: p } => {
             for event in &mut p.changes {
-                if let Some(uri) = to_file(&event.uri, cwd)? {
+                if let Some(uri) = to_file(&event.uri, root)? {
                     event.uri = uri;
                 }
             }
         }
 
         Notification::DidOpen { params: p } => {
-            if let Some(uri) = to_file(&p.text_document.uri, cwd)? {
+            if let Some(uri) = to_file(&p.text_document.uri, root)? {
                 p.text_document.uri = uri;
             }
         }
 
         Notification::DidChange { params: p } => {
-            if let Some(uri) = to_file(&p.text_document.uri, cwd)? {
+            if let Some(uri) = to_file(&p.text_document.uri, root)? {
                 p.text_document.uri = uri;
             }
         }
 
         Notification::WillSave { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Notification::DidClose { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Notification::PublishDiagnostics { params: p } => {
             // `to_source` because this goes to client
-            if let Some(uri) = to_source(&p.uri, cwd)? {
+            if let Some(uri) = to_source(&p.uri, root)? {
                 p.uri = uri;
             }
         }
@@ -81,44 +119,44 @@ fn remap_notification(notification: &mut Notification, cwd: &Url) -> Result<(),
     Ok(())
 }
 
-fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error> {
+fn remap_request(request: &mut Request, root: &SourceRoot) -> Result<(), std::io::Error> {
     match request {
         Request::Initialize { id: _, params: p } => {
             if let Some(root_uri) = &p.root_uri {
-                if let Some(root_uri) = to_file(root_uri, cwd)? {
+                if let Some(root_uri) = to_file(root_uri, root)? {
                     p.root_uri = Some(root_uri);
                 }
             }
             if let Some(folders) = &mut p.workspace_folders {
                 for folder in folders {
-                    remap_workspace_folder(folder, cwd)?;
+                    remap_workspace_folder(folder, root)?;
                 }
             }
         }
 
         Request::DocumentSymbol { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::WillSaveWaitUntil { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::Completion { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, root)?;
         }
 
         Request::Hover { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                root,
             )?;
         }
 
         Request::SignatureHelp { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                root,
             )?;
         }
 
@@ -128,98 +166,152 @@ fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error>
         | Request::GotoImplementation { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                root,
             )?;
         }
 
         Request::References { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, root)?;
         }
 
         Request::DocumentHighlight { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                root,
             )?;
         }
 
         Request::CodeAction { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::CodeLens { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::DocumentLink { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::DocumentLinkResolve { id: _, params: p } => {
             if let Some(target) = &p.target {
-                if let Some(target) = to_file(target, cwd)? {
+                if let Some(target) = to_file(target, root)? {
                     p.target = Some(target);
                 }
             }
         }
 
         Request::DocumentColor { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::ColorPresentation { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::Formatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::RangeFormatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::OnTypeFormatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, root)?;
         }
 
         Request::Rename { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, root)?;
         }
 
         Request::PrepareRename { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::FoldingRange { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
         }
 
         Request::SelectionRange { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, root)?;
+        }
+
+        // Results have no URIs.
+        Request::LinkedEditingRange { id: _, params: p } => {
+            remap_text_document_identifier(
+                &mut p.text_document_position_params.text_document,
+                root,
+            )?;
+        }
+
+        Request::Moniker { id: _, params: p } => {
+            remap_text_document_identifier(
+                &mut p.text_document_position_params.text_document,
+                root,
+            )?;
+        }
+
+        Request::InlayHint { id: _, params: p } => {
+            remap_text_document_identifier(&mut p.text_document, root)?;
+        }
+
+        Request::PrepareCallHierarchy { id: _, params: p } => {
+            remap_text_document_identifier(
+                &mut p.text_document_position_params.text_document,
+                root,
+            )?;
+        }
+
+        // The item from the result of `prepareCallHierarchy`.
+        Request::CallHierarchyIncomingCalls { id: _, params: p } => {
+            if let Some(uri) = to_file(&p.item.uri, root)? {
+                p.item.uri = uri;
+            }
+        }
+
+        Request::CallHierarchyOutgoingCalls { id: _, params: p } => {
+            if let Some(uri) = to_file(&p.item.uri, root)? {
+                p.item.uri = uri;
+            }
+        }
+
+        Request::InlayHintResolve { id: _, params: p } => {
+            remap_inlay_hint(p, root)?;
         }
 
         // To Client
         Request::ApplyEdit { id: _, params: p } => {
-            remap_workspace_edit(&mut p.edit, cwd)?;
+            remap_workspace_edit(&mut p.edit, root)?;
         }
 
         // To Client
         Request::Configuration { id: _, params: p } => {
             for item in &mut p.items {
                 if let Some(scope_uri) = &item.scope_uri {
-                    if let Some(scope_uri) = to_source(scope_uri, cwd)? {
+                    if let Some(scope_uri) = to_source(scope_uri, root)? {
                         item.scope_uri = Some(scope_uri);
                     }
                 }
             }
         }
 
+        // To Client
+        Request::RegisterCapability { id: _, params: p } => {
+            for registration in &mut p.registrations {
+                if registration.method == "workspace/didChangeWatchedFiles" {
+                    if let Some(options) = &mut registration.register_options {
+                        remap_watchers(options, root);
+                    }
+                }
+            }
+        }
+
+        // Registration ids are kept as is, so unregistering needs no remapping.
         Request::WorkspaceFolders { id: _, params: _ }
         | Request::ShowMessage { id: _, params: _ }
         | Request::CompletionResolve { id: _, params: _ }
         | Request::CodeLensResolve { id: _, params: _ }
-        | Request::RegisterCapability { id: _, params: _ }
         | Request::UnregisterCapability { id: _, params: _ }
         | Request::CreateWorkDoneProgress { id: _, params: _ }
         | Request::CancelWorkDoneProgress { id: _, params: _ }
@@ -231,20 +323,20 @@ fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error>
     Ok(())
 }
 
-fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Error> {
+fn remap_response(response: &mut Response, root: &SourceRoot) -> Result<(), std::io::Error> {
     match response {
         Response::Success { id: _, result } => {
             match result {
                 ResponseResult::DocumentLinkWithTarget(links) => {
                     for link in links {
-                        if let Some(target) = to_source(&link.target, cwd)? {
+                        if let Some(target) = to_source(&link.target, root)? {
                             link.target = target;
                         }
                     }
                 }
 
                 ResponseResult::DocumentLinkWithTargetResolve(link) => {
-                    if let Some(target) = to_source(&link.target, cwd)? {
+                    if let Some(target) = to_source(&link.target, root)? {
                         link.target = target;
                     }
                 }
@@ -255,7 +347,7 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
                             lsp_types::CodeActionOrCommand::Command(_) => {}
                             lsp_types::CodeActionOrCommand::CodeAction(action) => {
                                 if let Some(workspace_edit) = &mut action.edit {
-                                    remap_workspace_edit(workspace_edit, cwd)?;
+                                    remap_workspace_edit(workspace_edit, root)?;
                                 }
                             }
                         }
@@ -263,99 +355,299 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
                 }
 
                 ResponseResult::Location(location) => {
-                    remap_location(location, cwd)?;
+                    remap_location(location, root)?;
                 }
 
                 ResponseResult::Locations(locations) => {
                     for location in locations {
-                        remap_location(location, cwd)?;
+                        remap_location(location, root)?;
                     }
                 }
 
                 ResponseResult::LocationLinks(links) => {
                     for link in links {
-                        if let Some(target_uri) = to_source(&link.target_uri, cwd)? {
+                        if let Some(target_uri) = to_source(&link.target_uri, root)? {
                             link.target_uri = target_uri;
                         }
                     }
                 }
 
+                ResponseResult::CallHierarchyItems(items) => {
+                    for item in items {
+                        remap_call_hierarchy_item(item, root)?;
+                    }
+                }
+
+                ResponseResult::CallHierarchyIncomingCalls(calls) => {
+                    for call in calls {
+                        remap_call_hierarchy_item(&mut call.from, root)?;
+                    }
+                }
+
+                ResponseResult::CallHierarchyOutgoingCalls(calls) => {
+                    for call in calls {
+                        remap_call_hierarchy_item(&mut call.to, root)?;
+                    }
+                }
+
                 ResponseResult::SymbolInfos(syms) => {
                     for sym in syms {
-                        remap_location(&mut sym.location, cwd)?;
+                        remap_location(&mut sym.location, root)?;
                     }
                 }
 
                 ResponseResult::WorkspaceFolders(folders) => {
                     for folder in folders {
                         // `to_file` because this is a response from Client.
-                        if let Some(uri) = to_file(&folder.uri, cwd)? {
+                        if let Some(uri) = to_file(&folder.uri, root)? {
                             folder.uri = uri;
                         }
                     }
                 }
 
                 ResponseResult::WorkspaceEditWithBoth(edit) => {
-                    remap_workspace_edit_changes(&mut edit.changes, cwd)?;
-                    remap_document_changes(&mut edit.document_changes, cwd)?;
+                    remap_workspace_edit_changes(&mut edit.changes, root)?;
+                    remap_document_changes(&mut edit.document_changes, root)?;
                 }
 
                 ResponseResult::WorkspaceEditWithChanges(edit) => {
-                    remap_workspace_edit_changes(&mut edit.changes, cwd)?;
+                    remap_workspace_edit_changes(&mut edit.changes, root)?;
                 }
 
                 ResponseResult::WorkspaceEditWithDocumentChanges(edit) => {
-                    remap_document_changes(&mut edit.document_changes, cwd)?;
+                    remap_document_changes(&mut edit.document_changes, root)?;
+                }
+
+                ResponseResult::InlayHints(hints) => {
+                    for hint in hints {
+                        remap_inlay_hint(hint, root)?;
+                    }
+                }
+
+                ResponseResult::InlayHintResolve(hint) => {
+                    remap_inlay_hint(hint, root)?;
                 }
 
                 ResponseResult::Any(_) => {}
             }
         }
 
-        Response::Failure { id: _, error: _ } => {}
+        Response::Failure { id: _, error } => {
+            if let Some(data) = &mut error.data {
+                remap_uris_in_value(data, root)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn to_file(uri: &Url, cwd: &Url) -> Result<Option<Url>, std::io::Error> {
-    if uri.scheme() == "source" {
-        cwd.join(uri.as_str().strip_prefix("source://").unwrap())
-            .map_err(map_parse_error)
-            .map(Some)
-    } else {
-        Ok(None)
+// Keys of URIs in arbitrary values, e.g., `Location` and `LocationLink`.
+const URI_KEYS: &[&str] = &["uri", "targetUri"];
+
+/// Remap URIs in arbitrary values like `Error.data` embedded by some servers, e.g.,
+/// conflicting edit locations. Only the string values of `URI_KEYS` are changed.
+/// Values can be from either side, but only the client uses `source://`, so `file://` is
+/// remapped to `source://` and vice versa.
+fn remap_uris_in_value(
+    data: &mut serde_json::Value,
+    root: &SourceRoot,
+) -> Result<(), std::io::Error> {
+    match data {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    serde_json::Value::String(s) if URI_KEYS.contains(&key.as_str()) => {
+                        if let Ok(uri) = Url::parse(s) {
+                            let remapped = match uri.scheme() {
+                                "source" => to_file(&uri, root)?,
+                                _ => to_source(&uri, root)?,
+                            };
+                            if let Some(remapped) = remapped {
+                                *s = remapped.into();
+                            }
+                        }
+                    }
+                    _ => remap_uris_in_value(value, root)?,
+                }
+            }
+        }
+
+        serde_json::Value::Array(values) => {
+            for value in values {
+                remap_uris_in_value(value, root)?;
+            }
+        }
+
+        _ => {}
     }
+    Ok(())
 }
 
-fn to_source(uri: &Url, cwd: &Url) -> Result<Option<Url>, std::io::Error> {
-    if uri.scheme() == "file" {
-        if let Some(rel) = uri.as_str().strip_prefix(cwd.as_str()) {
-            let source_uri = format!("source://{}", rel);
-            Url::parse(&source_uri).map_err(map_parse_error).map(Some)
-        } else {
+fn to_file(uri: &Url, root: &SourceRoot) -> Result<Option<Url>, std::io::Error> {
+    match uri.scheme() {
+        "source" => {
+            let rest = uri.as_str().strip_prefix("source://").unwrap();
+            let rest = match &root.prefix {
+                Some(prefix) => match rest.strip_prefix(prefix.as_str()) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                        rest.trim_start_matches('/')
+                    }
+                    _ => {
+                        tracing::debug!("not remapping {} outside of {}", uri, prefix);
+                        return Ok(None);
+                    }
+                },
+                None => rest,
+            };
+            let uri = root.cwd.join(rest).map_err(map_parse_error)?;
+            if let (Ok(path), Ok(cwd), Some(real)) =
+                (uri.to_file_path(), root.cwd.to_file_path(), &root.real)
+            {
+                // Lexically, because this is done for every message. Symlinks are checked when
+                // writing files.
+                let under = match path.strip_prefix(&cwd) {
+                    Ok(rel) => normalize(&real.join(rel)).starts_with(real),
+                    Err(_) => false,
+                };
+                if !under {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!("{} resolves outside of the project root", uri),
+                    ));
+                }
+            }
+            Ok(Some(uri))
+        }
+        "file" => Ok(None),
+        scheme => {
+            tracing::debug!("not remapping {} URI {}", scheme, uri);
             Ok(None)
         }
-    } else {
-        Ok(None)
     }
 }
 
+fn to_source(uri: &Url, root: &SourceRoot) -> Result<Option<Url>, std::io::Error> {
+    match uri.scheme() {
+        "file" => {}
+        "source" => return Ok(None),
+        scheme => {
+            tracing::debug!("not remapping {} URI {}", scheme, uri);
+            return Ok(None);
+        }
+    }
+
+    // Compare decoded paths so that URIs percent-encoded differently from `cwd` still match.
+    match (uri.to_file_path(), root.cwd.to_file_path()) {
+        (Ok(path), Ok(cwd_path)) if path.starts_with(&cwd_path) => {}
+        _ => return Ok(None),
+    }
+    let depth = root
+        .cwd
+        .path_segments()
+        .map_or(0, |s| s.filter(|s| !s.is_empty()).count());
+    let rel = match uri.path_segments() {
+        Some(segments) => segments.skip(depth).collect::<Vec<_>>().join("/"),
+        None => return Ok(None),
+    };
+    let source_uri = match &root.prefix {
+        Some(prefix) => format!("source://{}/{}", prefix, rel),
+        None => format!("source://{}", rel),
+    };
+    Url::parse(&source_uri).map_err(map_parse_error).map(Some)
+}
+
+/// Check if `path` stays under `root` after resolving `..` and any symlinks in it.
+/// Parts of `path` that don't exist yet are only checked lexically.
+pub fn resolves_under(root: &Path, path: &Path) -> bool {
+    let normalized = normalize(path);
+    if !normalized.starts_with(root) {
+        return false;
+    }
+
+    // Nothing to follow if the root doesn't exist.
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return true,
+    };
+    let mut existing = normalized.as_path();
+    loop {
+        if existing.symlink_metadata().is_ok() {
+            // Fails for dangling symlinks, which could be written through.
+            return match existing.canonicalize() {
+                Ok(real) => real.starts_with(&root),
+                Err(_) => false,
+            };
+        }
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return true,
+        }
+    }
+}
+
+// Resolve `.` and `..` in `path` without the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
 fn map_parse_error(err: url::ParseError) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::InvalidData, err)
 }
 
+/// Make absolute `globPattern`s of `DidChangeWatchedFilesRegistrationOptions` relative
+/// to `cwd` so they match the client's `source://` view, including the prefix if set.
+/// Other patterns are kept as is.
+fn remap_watchers(options: &mut serde_json::Value, root: &SourceRoot) {
+    let watchers = match options.get_mut("watchers").and_then(|w| w.as_array_mut()) {
+        Some(watchers) => watchers,
+        None => return,
+    };
+    for watcher in watchers {
+        if let Some(pattern) = watcher.get_mut("globPattern") {
+            if let Some(relative) = pattern.as_str().and_then(|p| to_relative_glob(p, root)) {
+                *pattern = relative.into();
+            }
+        }
+    }
+}
+
+fn to_relative_glob(pattern: &str, root: &SourceRoot) -> Option<String> {
+    let relative = match pattern.strip_prefix(root.cwd.as_str()) {
+        Some(relative) => relative,
+        None => {
+            let cwd_path = root.cwd.to_file_path().ok()?;
+            let cwd_path = format!("{}/", cwd_path.to_str()?.trim_end_matches('/'));
+            pattern.strip_prefix(&cwd_path)?
+        }
+    };
+    match &root.prefix {
+        Some(prefix) => Some(format!("{}/{}", prefix, relative)),
+        None => Some(relative.to_owned()),
+    }
+}
+
 /// Remap `DocumentUri` in `WorkspaceEdit` to use `source://`
 fn remap_workspace_edit(
     workspace_edit: &mut lsp_types::WorkspaceEdit,
-    cwd: &Url,
+    root: &SourceRoot,
 ) -> Result<(), std::io::Error> {
     if let Some(changes) = &mut workspace_edit.changes {
-        remap_workspace_edit_changes(changes, cwd)?;
+        remap_workspace_edit_changes(changes, root)?;
     }
 
     if let Some(doc_changes) = &mut workspace_edit.document_changes {
-        remap_document_changes(doc_changes, cwd)?;
+        remap_document_changes(doc_changes, root)?;
     }
     Ok(())
 }
@@ -363,11 +655,11 @@ fn remap_workspace_edit(
 /// Remap keys of `WorkspaceEdit.changes`
 fn remap_workspace_edit_changes(
     changes: &mut HashMap<Url, Vec<lsp_types::TextEdit>>,
-    cwd: &Url,
+    root: &SourceRoot,
 ) -> Result<(), std::io::Error> {
     let mut tmp = Vec::with_capacity(changes.len());
     for (key, val) in changes.drain() {
-        if let Some(rel) = to_source(&key, cwd)? {
+        if let Some(rel) = to_source(&key, root)? {
             tmp.push((rel, val));
         } else {
             tmp.push((key, val));
@@ -381,12 +673,12 @@ fn remap_workspace_edit_changes(
 
 fn remap_document_changes(
     document_changes: &mut lsp_types::DocumentChanges,
-    cwd: &Url,
+    root: &SourceRoot,
 ) -> Result<(), std::io::Error> {
     match document_changes {
         lsp_types::DocumentChanges::Edits(edits) => {
             for edit in edits {
-                if let Some(uri) = to_source(&edit.text_document.uri, cwd)? {
+                if let Some(uri) = to_source(&edit.text_document.uri, root)? {
                     edit.text_document.uri = uri;
                 }
             }
@@ -397,27 +689,27 @@ fn remap_document_changes(
                 match op {
                     lsp_types::DocumentChangeOperation::Op(op) => match op {
                         lsp_types::ResourceOp::Create(c) => {
-                            if let Some(uri) = to_source(&c.uri, cwd)? {
+                            if let Some(uri) = to_source(&c.uri, root)? {
                                 c.uri = uri;
                             }
                         }
                         lsp_types::ResourceOp::Rename(r) => {
-                            if let Some(uri) = to_source(&r.old_uri, cwd)? {
+                            if let Some(uri) = to_source(&r.old_uri, root)? {
                                 r.old_uri = uri;
                             }
-                            if let Some(uri) = to_source(&r.new_uri, cwd)? {
+                            if let Some(uri) = to_source(&r.new_uri, root)? {
                                 r.new_uri = uri;
                             }
                         }
                         lsp_types::ResourceOp::Delete(d) => {
-                            if let Some(uri) = to_source(&d.uri, cwd)? {
+                            if let Some(uri) = to_source(&d.uri, root)? {
                                 d.uri = uri;
                             }
                         }
                     },
 
                     lsp_types::DocumentChangeOperation::Edit(e) => {
-                        if let Some(uri) = to_source(&e.text_document.uri, cwd)? {
+                        if let Some(uri) = to_source(&e.text_document.uri, root)? {
                             e.text_document.uri = uri;
                         }
                     }
@@ -429,20 +721,57 @@ fn remap_document_changes(
     Ok(())
 }
 
+/// Remap locations and command arguments in the label parts of `InlayHint`.
+/// Hints are resolved by the client, so these are remapped in both directions.
+fn remap_inlay_hint(hint: &mut InlayHint, root: &SourceRoot) -> Result<(), std::io::Error> {
+    if let InlayHintLabel::Parts(parts) = &mut hint.label {
+        for part in parts {
+            if let Some(location) = &mut part.location {
+                let remapped = match location.uri.scheme() {
+                    "source" => to_file(&location.uri, root)?,
+                    _ => to_source(&location.uri, root)?,
+                };
+                if let Some(uri) = remapped {
+                    location.uri = uri;
+                }
+            }
+            if let Some(arguments) = part.command.as_mut().and_then(|c| c.arguments.as_mut()) {
+                for argument in arguments {
+                    remap_uris_in_value(argument, root)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Remap `Location.uri` to use `source://`
-fn remap_location(location: &mut lsp_types::Location, cwd: &Url) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_source(&location.uri, cwd)? {
+fn remap_location(
+    location: &mut lsp_types::Location,
+    root: &SourceRoot,
+) -> Result<(), std::io::Error> {
+    if let Some(uri) = to_source(&location.uri, root)? {
         location.uri = uri;
     }
     Ok(())
 }
 
+fn remap_call_hierarchy_item(
+    item: &mut lsp_types::CallHierarchyItem,
+    root: &SourceRoot,
+) -> Result<(), std::io::Error> {
+    if let Some(uri) = to_source(&item.uri, root)? {
+        item.uri = uri;
+    }
+    Ok(())
+}
+
 /// Remap `TextDocumentIdentifier.uri` to use `file://`
 fn remap_text_document_identifier(
     text_document: &mut lsp_types::TextDocumentIdentifier,
-    cwd: &Url,
+    root: &SourceRoot,
 ) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_file(&text_document.uri, cwd)? {
+    if let Some(uri) = to_file(&text_document.uri, root)? {
         text_document.uri = uri;
     }
     Ok(())
@@ -450,9 +779,9 @@ fn remap_text_document_identifier(
 
 fn remap_workspace_folder(
     folder: &mut lsp_types::WorkspaceFolder,
-    cwd: &Url,
+    root: &SourceRoot,
 ) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_file(&folder.uri, cwd)? {
+    if let Some(uri) = to_file(&folder.uri, root)? {
         folder.uri = uri;
     }
     Ok(())
@@ -466,17 +795,401 @@ mod tests {
 
     #[test]
     fn test_to_file() {
-        let cwd = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
         let uri = Url::parse("source://src/main.rs").unwrap();
         let remapped = to_file(&uri, &cwd).unwrap().unwrap();
         assert_eq!(remapped.as_str(), "file:///workspace/src/main.rs");
     }
 
+    #[test]
+    fn test_to_file_traversal() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        for uri in &[
+            "source://../../etc/passwd",
+            "source://%2e%2e/etc/passwd",
+            "source://src/..%2F..%2Fetc/passwd",
+        ] {
+            let uri = Url::parse(uri).unwrap();
+            let err = to_file(&uri, &cwd).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{}", uri);
+        }
+        let uri = Url::parse("source://src/..%2Flib.rs").unwrap();
+        assert!(to_file(&uri, &cwd).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_file_symlinked_root() {
+        let dir =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-symlink-uri-{}", std::process::id()));
+        let project = dir.join("project");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::os::unix::fs::symlink(&project, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(&dir, project.join("outside")).unwrap();
+        let cwd = SourceRoot::new(Url::from_directory_path(dir.join("link")).unwrap());
+
+        let uri = Url::parse("source://src/..%2F..%2Fsecret").unwrap();
+        assert!(to_file(&uri, &cwd).is_err());
+        // Symlinks in the project are not followed for each message.
+        for uri in &["source://src/main.rs", "source://outside/secret"] {
+            let uri = Url::parse(uri).unwrap();
+            assert!(to_file(&uri, &cwd).unwrap().is_some(), "{}", uri);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_to_source() {
-        let cwd = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
         let uri = Url::from_file_path(Path::new("/workspace/src/main.rs")).unwrap();
         let remapped = to_source(&uri, &cwd).unwrap().unwrap();
         assert_eq!(remapped.as_str(), "source://src/main.rs");
     }
+
+    #[test]
+    fn test_remap_error_data() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        let mut msg: Message = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {
+                "code": -32803,
+                "message": "conflicting edits",
+                "data": {
+                    "conflicts": [{
+                        "uri": "file:///workspace/src/main.rs",
+                        "range": {
+                            "start": {"line": 0, "character": 0},
+                            "end": {"line": 0, "character": 1},
+                        },
+                    }],
+                    "targetUri": "source://src/lib.rs",
+                    "outside": {"uri": "file:///other/main.rs"},
+                    "message": "file:///workspace/src/main.rs",
+                    "uri": 1,
+                },
+            },
+        }))
+        .unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        let data = match msg {
+            Message::Response(Response::Failure { error, .. }) => error.data.unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(data["conflicts"][0]["uri"], "source://src/main.rs");
+        assert_eq!(data["targetUri"], "file:///workspace/src/lib.rs");
+        assert_eq!(data["outside"]["uri"], "file:///other/main.rs");
+        assert_eq!(data["message"], "file:///workspace/src/main.rs");
+        assert_eq!(data["uri"], 1);
+    }
+
+    #[test]
+    fn test_remap_inlay_hints() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        let mut msg: Message = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/inlayHint",
+            "params": {
+                "textDocument": {"uri": "source://src/main.rs"},
+                "range": {
+                    "start": {"line": 0, "character": 0},
+                    "end": {"line": 10, "character": 0},
+                },
+            },
+        }))
+        .unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        let value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            value["params"]["textDocument"]["uri"],
+            "file:///workspace/src/main.rs"
+        );
+
+        let location = serde_json::json!({
+            "uri": "file:///workspace/src/lib.rs",
+            "range": {
+                "start": {"line": 1, "character": 4},
+                "end": {"line": 1, "character": 7},
+            },
+        });
+        let mut msg: Message = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [
+                {
+                    "position": {"line": 2, "character": 9},
+                    "label": [
+                        {"value": ": "},
+                        {
+                            "value": "Foo",
+                            "location": location,
+                            "command": {
+                                "title": "Go to Foo",
+                                "command": "editor.action.goToLocations",
+                                "arguments": [location],
+                            },
+                        },
+                    ],
+                    "kind": 1,
+                },
+                {"position": {"line": 3, "character": 0}, "label": "i32"},
+            ],
+        }))
+        .unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        let value = serde_json::to_value(&msg).unwrap();
+        let part = &value["result"][0]["label"][1];
+        assert_eq!(part["location"]["uri"], "source://src/lib.rs");
+        assert_eq!(
+            part["command"]["arguments"][0]["uri"],
+            "source://src/lib.rs"
+        );
+        assert_eq!(value["result"][0]["kind"], 1);
+        assert_eq!(value["result"][1]["label"], "i32");
+
+        // Resolving sends the hint back to the server.
+        let mut msg: Message = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "inlayHint/resolve",
+            "params": value["result"][0],
+        }))
+        .unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        let value = serde_json::to_value(&msg).unwrap();
+        let part = &value["params"]["label"][1];
+        assert_eq!(part["location"]["uri"], "file:///workspace/src/lib.rs");
+        assert_eq!(
+            part["command"]["arguments"][0]["uri"],
+            "file:///workspace/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn test_remap_linked_editing_range_and_moniker() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        for method in &["textDocument/linkedEditingRange", "textDocument/moniker"] {
+            let request = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": {
+                    "textDocument": {"uri": "source://src/index.html"},
+                    "position": {"line": 1, "character": 2},
+                },
+            });
+            let mut msg: Message = serde_json::from_value(request.clone()).unwrap();
+            assert!(matches!(msg, Message::Request(_)));
+            remap_relative_uri(&mut msg, &cwd).unwrap();
+            let mut expected = request;
+            expected["params"]["textDocument"]["uri"] = "file:///workspace/src/index.html".into();
+            assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+        }
+
+        // Responses only have ranges and monikers, and are kept as is.
+        for result in &[
+            serde_json::json!({
+                "ranges": [{
+                    "start": {"line": 1, "character": 1},
+                    "end": {"line": 1, "character": 4},
+                }],
+                "wordPattern": "[a-z]+",
+            }),
+            serde_json::json!([{
+                "scheme": "tsc",
+                "identifier": "src/index:foo",
+                "unique": "project",
+                "kind": "export",
+            }]),
+        ] {
+            let response = serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": result});
+            let mut msg: Message = serde_json::from_value(response.clone()).unwrap();
+            remap_relative_uri(&mut msg, &cwd).unwrap();
+            assert_eq!(serde_json::to_value(&msg).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_remap_call_hierarchy() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        let remapped = |value: serde_json::Value| {
+            let mut msg: Message = serde_json::from_value(value).unwrap();
+            remap_relative_uri(&mut msg, &cwd).unwrap();
+            serde_json::to_value(&msg).unwrap()
+        };
+        let range = serde_json::json!({
+            "start": {"line": 1, "character": 3},
+            "end": {"line": 1, "character": 6},
+        });
+        let item = |uri: &str| {
+            serde_json::json!({
+                "name": "foo",
+                "kind": 12,
+                "detail": "fn foo()",
+                "uri": uri,
+                "range": range,
+                "selectionRange": range,
+                "data": {"id": 42},
+            })
+        };
+
+        let prepare = remapped(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/prepareCallHierarchy",
+            "params": {
+                "textDocument": {"uri": "source://src/lib.rs"},
+                "position": {"line": 1, "character": 4},
+            },
+        }));
+        assert_eq!(
+            prepare["params"]["textDocument"]["uri"],
+            "file:///workspace/src/lib.rs"
+        );
+        // The whole item is kept, not only the `uri` and `range` of `Location`.
+        let prepared = remapped(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [item("file:///workspace/src/lib.rs")],
+        }));
+        assert_eq!(
+            prepared["result"],
+            serde_json::json!([item("source://src/lib.rs")])
+        );
+
+        for (method, key) in &[
+            ("callHierarchy/incomingCalls", "from"),
+            ("callHierarchy/outgoingCalls", "to"),
+        ] {
+            let calls = remapped(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": method,
+                "params": {"item": prepared["result"][0]},
+            }));
+            assert_eq!(
+                calls["params"]["item"],
+                item("file:///workspace/src/lib.rs")
+            );
+            let result = remapped(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "result": [{*key: item("file:///workspace/src/main.rs"), "fromRanges": [range]}],
+            }));
+            assert_eq!(
+                result["result"],
+                serde_json::json!([{*key: item("source://src/main.rs"), "fromRanges": [range]}])
+            );
+        }
+    }
+
+    #[test]
+    fn test_watcher_registration_round_trip() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        let mut msg: Message = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "client/registerCapability",
+            "params": {
+                "registrations": [{
+                    "id": "watch-1",
+                    "method": "workspace/didChangeWatchedFiles",
+                    "registerOptions": {
+                        "watchers": [
+                            {"globPattern": "/workspace/src/**/*.rs"},
+                            {"globPattern": "file:///workspace/**/Cargo.toml", "kind": 7},
+                            {"globPattern": "**/*.js"},
+                            {"globPattern": "/other/**"},
+                        ],
+                    },
+                }],
+            },
+        }))
+        .unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        let value = serde_json::to_value(&msg).unwrap();
+        let registration = &value["params"]["registrations"][0];
+        assert_eq!(registration["id"], "watch-1");
+        let watchers = &registration["registerOptions"]["watchers"];
+        assert_eq!(watchers[0]["globPattern"], "src/**/*.rs");
+        assert_eq!(watchers[1]["globPattern"], "**/Cargo.toml");
+        assert_eq!(watchers[1]["kind"], 7);
+        assert_eq!(watchers[2]["globPattern"], "**/*.js");
+        assert_eq!(watchers[3]["globPattern"], "/other/**");
+
+        let unregister = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "client/unregisterCapability",
+            "params": {
+                "unregisterations": [{"id": "watch-1", "method": "workspace/didChangeWatchedFiles"}],
+            },
+        });
+        let mut msg: Message = serde_json::from_value(unregister.clone()).unwrap();
+        remap_relative_uri(&mut msg, &cwd).unwrap();
+        assert_eq!(serde_json::to_value(&msg).unwrap(), unregister);
+    }
+
+    #[test]
+    fn test_watcher_registration_with_prefix() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap())
+            .with_prefix(Some("project".to_owned()));
+        let mut options = serde_json::json!({
+            "watchers": [
+                {"globPattern": "/workspace/src/**/*.rs"},
+                {"globPattern": "file:///workspace/**/Cargo.toml"},
+                {"globPattern": "**/*.js"},
+            ],
+        });
+        remap_watchers(&mut options, &cwd);
+        let watchers = &options["watchers"];
+        assert_eq!(watchers[0]["globPattern"], "project/src/**/*.rs");
+        assert_eq!(watchers[1]["globPattern"], "project/**/Cargo.toml");
+        assert_eq!(watchers[2]["globPattern"], "**/*.js");
+    }
+
+    #[test]
+    fn test_unhandled_scheme() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap());
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        assert_eq!(to_file(&uri, &cwd).unwrap(), None);
+        assert_eq!(to_source(&uri, &cwd).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cwd_with_space() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/my workspace")).unwrap());
+        let uri = Url::parse("source://src/main.rs").unwrap();
+        let remapped = to_file(&uri, &cwd).unwrap().unwrap();
+        assert_eq!(remapped.as_str(), "file:///my%20workspace/src/main.rs");
+        let remapped = to_source(&remapped, &cwd).unwrap().unwrap();
+        assert_eq!(remapped.as_str(), "source://src/main.rs");
+    }
+
+    #[test]
+    fn test_prefix_round_trip() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/workspace")).unwrap())
+            .with_prefix(Some("project".to_owned()));
+        let uri = Url::parse("source://project/src/main.rs").unwrap();
+        let remapped = to_file(&uri, &cwd).unwrap().unwrap();
+        assert_eq!(remapped.as_str(), "file:///workspace/src/main.rs");
+        let remapped = to_source(&remapped, &cwd).unwrap().unwrap();
+        assert_eq!(remapped, uri);
+        // Not under the prefix.
+        let uri = Url::parse("source://other/src/main.rs").unwrap();
+        assert_eq!(to_file(&uri, &cwd).unwrap(), None);
+        let uri = Url::parse("source://projects/src/main.rs").unwrap();
+        assert_eq!(to_file(&uri, &cwd).unwrap(), None);
+    }
+
+    #[test]
+    fn test_to_source_encoded_differently() {
+        let cwd = SourceRoot::new(Url::from_directory_path(Path::new("/c++")).unwrap());
+        let uri = Url::parse("file:///c%2B%2B/src/main.rs").unwrap();
+        let remapped = to_source(&uri, &cwd).unwrap().unwrap();
+        assert_eq!(remapped.as_str(), "source://src/main.rs");
+        let uri = Url::parse("file:///other/main.rs").unwrap();
+        assert_eq!(to_source(&uri, &cwd).unwrap(), None);
+    }
 }
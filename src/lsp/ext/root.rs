@@ -0,0 +1,81 @@
+use lsp_types::WorkspaceFolder;
+use url::Url;
+
+use crate::lsp::{Message, Request};
+
+/// Set `rootUri` and `workspaceFolders` of `initialize` request to `root`
+/// if the client didn't specify either.
+///
+/// Returns `true` if `msg` was modified.
+pub fn inject_root(msg: &mut Message, root: &Url) -> bool {
+    if let Message::Request(Request::Initialize { id: _, params: p }) = msg {
+        let has_folders = matches!(&p.workspace_folders, Some(folders) if !folders.is_empty());
+        if p.root_uri.is_none() && !has_folders {
+            let name = root
+                .path_segments()
+                .and_then(|segments| segments.rev().find(|s| !s.is_empty()))
+                .unwrap_or_default()
+                .to_owned();
+            p.root_uri = Some(root.clone());
+            p.workspace_folders = Some(vec![WorkspaceFolder {
+                uri: root.clone(),
+                name,
+            }]);
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn initialize(params: Value) -> Message {
+        serde_json::from_value(
+            json!({"jsonrpc":"2.0","method":"initialize","params":params,"id":1}),
+        )
+        .unwrap()
+    }
+
+    fn root_and_folders(msg: &Message) -> (Option<Url>, Option<Vec<WorkspaceFolder>>) {
+        match msg {
+            Message::Request(Request::Initialize { id: _, params: p }) => {
+                (p.root_uri.clone(), p.workspace_folders.clone())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_inject_without_root() {
+        let root = Url::parse("file:///home/user/project/").unwrap();
+        let mut msg = initialize(json!({"capabilities":{},"rootUri":null}));
+        assert!(inject_root(&mut msg, &root));
+        let (root_uri, folders) = root_and_folders(&msg);
+        assert_eq!(root_uri, Some(root.clone()));
+        assert_eq!(
+            folders,
+            Some(vec![WorkspaceFolder {
+                uri: root,
+                name: "project".to_owned(),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_keep_client_root() {
+        let root = Url::parse("file:///home/user/project/").unwrap();
+        let mut msg = initialize(json!({"capabilities":{},"rootUri":"file:///tmp/a/"}));
+        assert!(!inject_root(&mut msg, &root));
+        let mut msg = initialize(json!({
+            "capabilities":{},
+            "workspaceFolders":[{"uri":"file:///tmp/a/","name":"a"}],
+        }));
+        assert!(!inject_root(&mut msg, &root));
+        let (root_uri, _) = root_and_folders(&msg);
+        assert_eq!(root_uri, None);
+    }
+}
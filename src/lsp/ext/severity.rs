@@ -0,0 +1,111 @@
+use std::{fmt, str::FromStr};
+
+use lsp_types::DiagnosticSeverity;
+
+use crate::lsp::{Message, Notification};
+
+/// Rewrite diagnostics with severity `from` to `to`, written as `from=to`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SeverityRule {
+    pub from: DiagnosticSeverity,
+    pub to: DiagnosticSeverity,
+}
+
+impl FromStr for SeverityRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not in the form of `from=to`", s))?;
+        Ok(Self {
+            from: parse_severity(from)?,
+            to: parse_severity(to)?,
+        })
+    }
+}
+
+impl fmt::Display for SeverityRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", severity_name(self.from), severity_name(self.to))
+    }
+}
+
+impl serde::Serialize for SeverityRule {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn parse_severity(s: &str) -> Result<DiagnosticSeverity, String> {
+    match s {
+        "error" => Ok(DiagnosticSeverity::Error),
+        "warning" => Ok(DiagnosticSeverity::Warning),
+        "info" => Ok(DiagnosticSeverity::Information),
+        "hint" => Ok(DiagnosticSeverity::Hint),
+        _ => Err(format!("{} is not one of error, warning, info, or hint", s)),
+    }
+}
+
+fn severity_name(severity: DiagnosticSeverity) -> &'static str {
+    match severity {
+        DiagnosticSeverity::Error => "error",
+        DiagnosticSeverity::Warning => "warning",
+        DiagnosticSeverity::Information => "info",
+        DiagnosticSeverity::Hint => "hint",
+    }
+}
+
+/// Rewrite severities of diagnostics in `textDocument/publishDiagnostics` with the first
+/// matching rule. Returns `true` if any was rewritten.
+pub fn rewrite_diagnostic_severity(msg: &mut Message, rules: &[SeverityRule]) -> bool {
+    let params = match msg {
+        Message::Notification(Notification::PublishDiagnostics { params }) => params,
+        _ => return false,
+    };
+    let mut rewritten = false;
+    for diagnostic in &mut params.diagnostics {
+        let severity = match diagnostic.severity {
+            Some(severity) => severity,
+            None => continue,
+        };
+        if let Some(rule) = rules.iter().find(|r| r.from == severity) {
+            diagnostic.severity = Some(rule.to);
+            rewritten = true;
+        }
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_to_warning() {
+        let rules = vec!["error=warning".parse().unwrap()];
+        let mut msg: Message = r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.rs","diagnostics":[
+            {"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":1}},"severity":1,"message":"a"},
+            {"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":1}},"severity":3,"message":"b"},
+            {"range":{"start":{"line":2,"character":0},"end":{"line":2,"character":1}},"message":"c"}
+        ]}}"#
+            .parse()
+            .unwrap();
+        assert!(rewrite_diagnostic_severity(&mut msg, &rules));
+        let value = serde_json::to_value(&msg).unwrap();
+        let diagnostics = &value["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["severity"], 2);
+        assert_eq!(diagnostics[1]["severity"], 3);
+        assert!(diagnostics[2].get("severity").is_none());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: SeverityRule = "info=hint".parse().unwrap();
+        assert_eq!(rule.from, DiagnosticSeverity::Information);
+        assert_eq!(rule.to, DiagnosticSeverity::Hint);
+        assert_eq!(rule.to_string(), "info=hint");
+        assert!("error".parse::<SeverityRule>().is_err());
+        assert!("error=fatal".parse::<SeverityRule>().is_err());
+    }
+}
@@ -13,7 +13,7 @@ use bytes::{Buf, BufMut, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
-use super::parser;
+use super::parser::{self, HeaderError};
 
 pub fn reader<R: AsyncRead>(inner: R) -> FramedRead<R, LspFrameCodec> {
     FramedRead::new(inner, LspFrameCodec::default())
@@ -23,15 +23,29 @@ pub fn writer<W: AsyncWrite>(inner: W) -> FramedWrite<W, LspFrameCodec> {
     FramedWrite::new(inner, LspFrameCodec::default())
 }
 
+/// `reader` with the initial read buffer of `capacity` bytes.
+pub fn reader_with_capacity<R: AsyncRead>(
+    inner: R,
+    capacity: usize,
+) -> FramedRead<R, LspFrameCodec> {
+    FramedRead::with_capacity(inner, LspFrameCodec::default(), capacity)
+}
+
+/// `writer` with the initial write buffer of `capacity` bytes.
+pub fn writer_with_capacity<W: AsyncWrite>(
+    inner: W,
+    capacity: usize,
+) -> FramedWrite<W, LspFrameCodec> {
+    let mut writer = writer(inner);
+    writer.write_buffer_mut().reserve(capacity);
+    writer
+}
+
 /// Errors from LspFrameCodec.
 #[derive(Debug)]
 pub enum CodecError {
-    /// The frame lacks the required `Content-Length` header.
-    MissingHeader,
-    /// The length value in the `Content-Length` header is invalid.
-    InvalidLength,
-    /// The media type in the `Content-Type` header is invalid.
-    InvalidType,
+    /// The header of the frame at `offset` bytes from the start of the stream is invalid.
+    Header { error: HeaderError, offset: usize },
     /// Failed to encode the frame.
     Encode(IoError),
     /// The frame contains invalid UTF8.
@@ -41,9 +55,7 @@ pub enum CodecError {
 impl Display for CodecError {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         match *self {
-            Self::MissingHeader => write!(fmt, "missing required `Content-Length` header"),
-            Self::InvalidLength => write!(fmt, "unable to parse content length"),
-            Self::InvalidType => write!(fmt, "unable to parse content type"),
+            Self::Header { error, offset } => write!(fmt, "{} at byte {}", error, offset),
             Self::Encode(ref e) => write!(fmt, "failed to encode frame: {}", e),
             Self::Utf8(ref e) => write!(fmt, "frame contains invalid UTF8: {}", e),
         }
@@ -72,9 +84,29 @@ impl From<Utf8Error> for CodecError {
     }
 }
 
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Where the decoder is in the current frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DecodeState {
+    /// Parsing is skipped until the buffer reaches `required_len`, the minimum length
+    /// required to make progress.
+    AwaitingHeader { required_len: usize },
+    /// The header of `len` bytes of body was consumed.
+    AwaitingBody { len: usize },
+}
+
+impl Default for DecodeState {
+    fn default() -> Self {
+        Self::AwaitingHeader { required_len: 0 }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct LspFrameCodec {
-    remaining_bytes: usize,
+    state: DecodeState,
+    /// Number of bytes decoded from the stream, to report where errors are.
+    position: usize,
 }
 
 impl Encoder<String> for LspFrameCodec {
@@ -96,48 +128,87 @@ impl Decoder for LspFrameCodec {
     type Error = CodecError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if self.remaining_bytes > src.len() {
-            return Ok(None);
+        loop {
+            let len = match self.state {
+                DecodeState::AwaitingHeader { required_len } if required_len > src.len() => {
+                    return Ok(None);
+                }
+                DecodeState::AwaitingHeader { .. } => match self.decode_header(src)? {
+                    Some(len) => len,
+                    None => return Ok(None),
+                },
+                // The header is not parsed again while the body arrives.
+                DecodeState::AwaitingBody { len } => len,
+            };
+            if src.len() < len {
+                return Ok(None);
+            }
+
+            let body = src.split_to(len);
+            self.position += len;
+            self.state = DecodeState::default();
+            // `Content-Length` is in bytes, so the body can end in the middle of a
+            // character if it was counted in characters. Skipped either way.
+            let message = str::from_utf8(&body)?;
+            // Ignore empty frame
+            if !message.is_empty() {
+                return Ok(Some(message.to_owned()));
+            }
         }
+    }
+}
 
-        match parser::parse_message(src) {
-            Ok((remaining, message)) => {
-                let message = str::from_utf8(message)?.to_string();
-                let len = src.len() - remaining.len();
-                src.advance(len);
-                self.remaining_bytes = 0;
-                // Ignore empty frame
-                if message.is_empty() {
-                    Ok(None)
-                } else {
-                    Ok(Some(message))
-                }
+impl LspFrameCodec {
+    // Consume the header and return the length of the body, or `None` to wait for more.
+    fn decode_header(&mut self, src: &mut BytesMut) -> Result<Option<usize>, CodecError> {
+        // Some servers built on Windows write a UTF-8 BOM before the first frame.
+        if self.position == 0 && !src.is_empty() {
+            if src.starts_with(BOM) {
+                src.advance(BOM.len());
+                self.position += BOM.len();
+            } else if BOM.starts_with(src) {
+                return Ok(None);
             }
+        }
 
+        match parser::parse_header(src) {
+            Ok((remaining, len)) => {
+                let header_len = src.len() - remaining.len();
+                src.advance(header_len);
+                self.position += header_len;
+                self.state = DecodeState::AwaitingBody { len };
+                Ok(Some(len))
+            }
+
+            // `needed` is relative to the input, which is a partial header.
             Err(nom::Err::Incomplete(nom::Needed::Size(needed))) => {
-                self.remaining_bytes = needed.get();
+                self.state = DecodeState::AwaitingHeader {
+                    required_len: src.len() + needed.get(),
+                };
                 Ok(None)
             }
 
-            Err(nom::Err::Incomplete(nom::Needed::Unknown)) => Ok(None),
+            Err(nom::Err::Incomplete(nom::Needed::Unknown)) => {
+                self.state = DecodeState::AwaitingHeader {
+                    required_len: src.len() + 1,
+                };
+                Ok(None)
+            }
 
             Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => {
-                let code = err.code;
-                let parsed_bytes = src.len() - err.input.len();
-                src.advance(parsed_bytes);
-                match parser::find_next_message(src) {
-                    Ok((_, position)) => src.advance(position),
-                    Err(_) => src.advance(src.len()),
-                }
-                match code {
-                    nom::error::ErrorKind::Digit | nom::error::ErrorKind::MapRes => {
-                        Err(CodecError::InvalidLength)
-                    }
-                    nom::error::ErrorKind::Char | nom::error::ErrorKind::IsNot => {
-                        Err(CodecError::InvalidType)
-                    }
-                    _ => Err(CodecError::MissingHeader),
-                }
+                self.state = DecodeState::default();
+                let error = CodecError::Header {
+                    error: err.kind,
+                    offset: self.position + err.offset,
+                };
+                src.advance(err.offset);
+                let skipped = match parser::find_next_message(src) {
+                    Ok((_, position)) => position,
+                    Err(_) => src.len(),
+                };
+                src.advance(skipped);
+                self.position += err.offset + skipped;
+                Err(error)
             }
         }
     }
@@ -174,6 +245,120 @@ mod tests {
         assert_eq!(message, Some(decoded));
     }
 
+    #[test]
+    fn skips_bom_before_first_frame() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let frame = format!("Content-Length: {}\r\n\r\n{}", decoded.len(), decoded);
+        let mut codec = LspFrameCodec::default();
+        // Split in the middle of the BOM.
+        let mut buffer = BytesMut::from(&b"\xEF\xBB"[..]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(b"\xBF");
+        buffer.extend_from_slice(frame.as_bytes());
+        buffer.extend_from_slice(frame.as_bytes());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(decoded.clone()));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(decoded));
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decodes_large_frame_with_small_capacity() {
+        use futures_util::{SinkExt, StreamExt};
+
+        let decoded = format!(r#"{{"jsonrpc":"2.0","method":"{}"}}"#, "x".repeat(1 << 20));
+        let mut encoded = Vec::new();
+        let mut writer = writer_with_capacity(&mut encoded, 16);
+        writer.send(decoded.clone()).await.unwrap();
+        drop(writer);
+
+        let mut reader = reader_with_capacity(encoded.as_slice(), 16);
+        assert_eq!(reader.next().await.unwrap().unwrap(), decoded);
+        assert!(reader.next().await.is_none());
+    }
+
+    #[test]
+    fn consumes_header_once_while_streaming_body() {
+        let decoded = format!(r#"{{"jsonrpc":"2.0","method":"{}"}}"#, "x".repeat(1 << 20));
+        let header = format!("Content-Length: {}\r\n\r\n", decoded.len());
+        let next = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let encoded = format!(
+            "{}{}Content-Length: {}\r\n\r\n{}",
+            header,
+            decoded,
+            next.len(),
+            next
+        );
+
+        let mut codec = LspFrameCodec::default();
+        let mut buffer = BytesMut::new();
+        let mut chunks = encoded.as_bytes().chunks(64 * 1024);
+        buffer.extend_from_slice(chunks.next().unwrap());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        // Only the body is left in the buffer.
+        assert_eq!(
+            codec.state,
+            DecodeState::AwaitingBody { len: decoded.len() }
+        );
+        assert_eq!(buffer.len(), 64 * 1024 - header.len());
+
+        let mut messages = Vec::new();
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages, vec![decoded, next.to_owned()]);
+        assert!(buffer.is_empty());
+        assert_eq!(codec.position, encoded.len());
+    }
+
+    #[tokio::test]
+    async fn streams_large_frames_through_pipe() {
+        use futures_util::{SinkExt, StreamExt};
+
+        let messages: Vec<_> = (0..3)
+            .map(|i| {
+                format!(
+                    r#"{{"jsonrpc":"2.0","id":{},"result":"{}"}}"#,
+                    i,
+                    "y".repeat(300_000)
+                )
+            })
+            .collect();
+        // The pipe passes the frames in pieces smaller than the buffers.
+        let (client, server) = tokio::io::duplex(1000);
+        let sent = messages.clone();
+        let sending = tokio::spawn(async move {
+            let mut writer = writer(server);
+            for message in sent {
+                writer.send(message).await.unwrap();
+            }
+        });
+
+        let mut reader = reader_with_capacity(client, 4096);
+        for message in &messages {
+            assert_eq!(&reader.next().await.unwrap().unwrap(), message);
+        }
+        sending.await.unwrap();
+        assert!(reader.next().await.is_none());
+    }
+
+    #[test]
+    fn skips_empty_frames() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let encoded = format!(
+            "{}Content-Length: {}\r\n\r\n{}",
+            "Content-Length: 0\r\n\r\n".repeat(3),
+            decoded.len(),
+            decoded
+        );
+        let mut codec = LspFrameCodec::default();
+        let mut buffer = BytesMut::from(encoded.as_str());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(decoded));
+        assert!(buffer.is_empty());
+    }
+
     #[test]
     fn skips_encoding_empty_message() {
         let mut codec = LspFrameCodec::default();
@@ -195,6 +380,128 @@ mod tests {
         assert_eq!(message, Some(decoded));
     }
 
+    // Feed `input` to the decoder one byte at a time, collecting all decoded messages.
+    fn decode_bytewise(codec: &mut LspFrameCodec, input: &str) -> Vec<String> {
+        let mut buffer = BytesMut::new();
+        let mut messages = Vec::new();
+        for b in input.as_bytes() {
+            buffer.extend_from_slice(&[*b]);
+            while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert!(buffer.is_empty());
+        messages
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let encoded = format!("Content-Length: {}\r\n\r\n{}", decoded.len(), decoded);
+
+        let mut codec = LspFrameCodec::default();
+        assert_eq!(decode_bytewise(&mut codec, &encoded), vec![decoded]);
+    }
+
+    #[test]
+    fn decodes_byte_by_byte_with_content_type() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let content_len = format!("Content-Length: {}", decoded.len());
+        let content_type = "Content-Type: application/vscode-jsonrpc; charset=utf-8".to_string();
+        let encoded = format!("{}\r\n{}\r\n\r\n{}", content_len, content_type, decoded);
+
+        let mut codec = LspFrameCodec::default();
+        assert_eq!(decode_bytewise(&mut codec, &encoded), vec![decoded]);
+    }
+
+    #[test]
+    fn decodes_consecutive_messages_byte_by_byte() {
+        let first = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#.to_string();
+        let second = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let encoded = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{}",
+            first.len(),
+            first,
+            second.len(),
+            second
+        );
+
+        let mut codec = LspFrameCodec::default();
+        assert_eq!(decode_bytewise(&mut codec, &encoded), vec![first, second]);
+    }
+
+    // Reads from a pty end at lines, so the empty line can arrive alone.
+    #[test]
+    fn decodes_header_split_across_reads() {
+        let frames: &[&[&str]] = &[
+            &["Content-Length: 2\r\n", "\r\n", "{}"],
+            &["Content-Length: 2\n", "\n{}"],
+            &[
+                "Content-Length: 2\r\n",
+                "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n",
+                "\r\n",
+                "{}",
+            ],
+            &["Content-Length: 2\r\nContent-", "Type: a\r\n\r", "\n{}"],
+        ];
+        for reads in frames {
+            let mut codec = LspFrameCodec::default();
+            let mut buffer = BytesMut::new();
+            let (last, init) = reads.split_last().unwrap();
+            for read in init {
+                buffer.extend_from_slice(read.as_bytes());
+                assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+            }
+            // Decoded as soon as the frame is complete without waiting for more input.
+            buffer.extend_from_slice(last.as_bytes());
+            assert_eq!(codec.decode(&mut buffer).unwrap(), Some("{}".to_owned()));
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn decodes_multibyte_body() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"🦀 ready"}}"#.to_string();
+        assert_ne!(decoded.len(), decoded.chars().count());
+
+        let mut codec = LspFrameCodec::default();
+        let mut buffer = BytesMut::new();
+        codec.encode(decoded.clone(), &mut buffer).unwrap();
+        let encoded = String::from_utf8(buffer.to_vec()).unwrap();
+        assert!(encoded.starts_with(&format!("Content-Length: {}\r\n", decoded.len())));
+        // Split in the middle of the emoji too.
+        assert_eq!(decode_bytewise(&mut codec, &encoded), vec![decoded.clone()]);
+
+        // A length that ends the body in the middle of the emoji.
+        let miscounted = format!(
+            "Content-Length: {}\r\n\r\n{}",
+            decoded.find('🦀').unwrap() + 2,
+            decoded
+        );
+        let encoded = format!("{}Content-Length: 2\r\n\r\n{{}}", miscounted);
+        let mut buffer = BytesMut::from(encoded.as_str());
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(CodecError::Utf8(_))
+        ));
+        // The rest of the body is skipped to the next frame.
+        assert!(codec.decode(&mut buffer).is_err());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some("{}".to_owned()));
+    }
+
+    #[test]
+    fn decodes_after_partial_header() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let encoded = format!("Content-Length: {}\r\n\r\n{}", decoded.len(), decoded);
+        let (head, tail) = encoded.split_at(17);
+
+        let mut codec = LspFrameCodec::default();
+        let mut buffer = BytesMut::from(head);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(tail.as_bytes());
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(decoded));
+    }
+
     #[test]
     fn recovers_from_parse_error() {
         let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
@@ -205,10 +512,28 @@ mod tests {
         let mut buffer = BytesMut::from(mixed.as_str());
 
         match codec.decode(&mut buffer) {
-            Err(CodecError::MissingHeader) => {}
-            other => panic!("expected `Err(ParseError::MissingHeader)`, got {:?}", other),
+            Err(CodecError::Header {
+                error: HeaderError::MissingLength,
+                offset: 0,
+            }) => {}
+            other => panic!("expected `Err(CodecError::Header)`, got {:?}", other),
         }
 
+        let message = codec.decode(&mut buffer).unwrap();
+        assert_eq!(message, Some(decoded.clone()));
+
+        // Offsets are from the start of the stream.
+        let mut buffer = BytesMut::from(format!("Content-Length: x\r\n\r\n{}", encoded).as_str());
+        match codec.decode(&mut buffer) {
+            Err(err @ CodecError::Header { .. }) => assert_eq!(
+                err.to_string(),
+                format!(
+                    "content length is not a number at byte {}",
+                    mixed.len() + "Content-Length: ".len()
+                )
+            ),
+            other => panic!("expected `Err(CodecError::Header)`, got {:?}", other),
+        }
         let message = codec.decode(&mut buffer).unwrap();
         assert_eq!(message, Some(decoded));
     }
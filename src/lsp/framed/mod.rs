@@ -2,4 +2,6 @@
 mod codec;
 mod parser;
 
-pub use codec::{reader, writer};
+pub use codec::{
+    reader, reader_with_capacity, writer, writer_with_capacity, CodecError, LspFrameCodec,
+};
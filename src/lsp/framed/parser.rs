@@ -2,33 +2,101 @@
 // Copyright (c) 2020 Eyal Kalderon. MIT License.
 // See codec.rs.
 
-use std::str;
+use std::{
+    fmt::{self, Display, Formatter},
+    str,
+};
 
 use nom::{
     branch::alt,
     bytes::streaming::{is_not, tag, take_until},
-    character::streaming::{char, crlf, digit1, space0},
-    combinator::{map, map_res, opt},
-    multi::length_data,
-    sequence::{delimited, terminated, tuple},
+    character::streaming::{char, digit1, line_ending, space0},
+    combinator::{map, opt},
+    sequence::tuple,
     IResult,
 };
 
-// Get JSON message from input using the Content-Length header.
-pub fn parse_message(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    let content_len = delimited(tag("Content-Length: "), digit1, crlf);
+/// What is wrong with the header of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderError {
+    /// The frame doesn't start with `Content-Length`.
+    MissingLength,
+    /// The value of `Content-Length` is not a number.
+    LengthNotNumber,
+    /// The value of `Content-Length` doesn't fit in `usize`.
+    LengthTooLarge,
+    /// `Content-Type` is malformed or has a charset other than UTF-8.
+    InvalidType,
+    /// The header is not followed by an empty line, e.g., has an unknown field.
+    Unterminated,
+}
 
-    let utf8 = alt((tag("utf-8"), tag("utf8")));
-    let charset = tuple((char(';'), space0, tag("charset="), utf8));
-    let content_type = tuple((tag("Content-Type: "), is_not(";\r"), opt(charset), crlf));
+impl Display for HeaderError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::MissingLength => write!(fmt, "missing required `Content-Length` header"),
+            Self::LengthNotNumber => write!(fmt, "content length is not a number"),
+            Self::LengthTooLarge => write!(fmt, "content length is too large"),
+            Self::InvalidType => write!(fmt, "unable to parse content type"),
+            Self::Unterminated => write!(fmt, "header is not terminated by an empty line"),
+        }
+    }
+}
+
+/// Error with the offset in bytes from the start of the frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: HeaderError,
+    pub offset: usize,
+}
 
-    let header = terminated(terminated(content_len, opt(content_type)), crlf);
+// Get the Content-Length of the message from the header, and the input after it.
+// Headers are terminated by `\r\n`, but bare `\n` is tolerated.
+// Parsed in steps to report exactly where it fails.
+pub fn parse_header(input: &[u8]) -> IResult<&[u8], usize, ParseError> {
+    // Keeps `Incomplete` to wait for more input.
+    let fail = |kind: HeaderError| {
+        move |err: nom::Err<nom::error::Error<&[u8]>>| {
+            err.map(|e| ParseError {
+                kind,
+                offset: input.len() - e.input.len(),
+            })
+        }
+    };
 
-    let header = map_res(header, str::from_utf8);
-    let length = map_res(header, |s: &str| s.parse::<usize>());
-    let mut message = length_data(length);
+    let (rest, _) = tag("Content-Length: ")(input).map_err(fail(HeaderError::MissingLength))?;
+    let (rest, digits) = digit1(rest).map_err(fail(HeaderError::LengthNotNumber))?;
+    let length = str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .ok_or_else(|| {
+            nom::Err::Error(ParseError {
+                kind: HeaderError::LengthTooLarge,
+                offset: input.len() - digits.len() - rest.len(),
+            })
+        })?;
+    let (rest, _) = line_ending(rest).map_err(fail(HeaderError::LengthNotNumber))?;
 
-    message(input)
+    let utf8 = alt((tag("utf-8"), tag("utf8")));
+    let charset = tuple((char(';'), space0, tag("charset="), utf8));
+    let mut content_type = tuple((
+        tag("Content-Type: "),
+        is_not(";\r\n"),
+        opt(charset),
+        line_ending,
+    ));
+    let parsed: IResult<&[u8], _> = content_type(rest);
+    let rest = match parsed {
+        Ok((rest, _)) => rest,
+        // Optional, so only an error if it failed after the name.
+        Err(nom::Err::Error(e)) if e.input.len() == rest.len() => rest,
+        // The empty line can be shorter than what `Content-Type` needs.
+        Err(nom::Err::Incomplete(_)) => return Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+        Err(err) => return Err(fail(HeaderError::InvalidType)(err)),
+    };
+
+    let (rest, _) = line_ending(rest).map_err(fail(HeaderError::Unterminated))?;
+    Ok((rest, length))
 }
 
 pub fn find_next_message(input: &[u8]) -> IResult<&[u8], usize> {
@@ -38,6 +106,18 @@ pub fn find_next_message(input: &[u8]) -> IResult<&[u8], usize> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nom::bytes::streaming::take;
+
+    // Get JSON message from input using the Content-Length header.
+    fn parse_message(input: &[u8]) -> IResult<&[u8], &[u8], ParseError> {
+        let (rest, length) = parse_header(input)?;
+        take(length)(rest).map_err(|err: nom::Err<nom::error::Error<&[u8]>>| {
+            err.map(|e| ParseError {
+                kind: HeaderError::Unterminated,
+                offset: input.len() - e.input.len(),
+            })
+        })
+    }
 
     #[test]
     fn test_parse_exact() {
@@ -68,6 +148,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_lf_line_endings() {
+        let decoded =
+            r#"{"jsonrpc":"2.0","method":"initialize","id":1,"params":{"capabilities":{}}}"#;
+        let sample = format!("Content-Length: {}\n\n{}", decoded.len(), decoded);
+        assert_eq!(
+            parse_message(sample.as_bytes()),
+            Ok(("".as_bytes(), decoded.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn test_mixed_line_endings() {
+        let decoded =
+            r#"{"jsonrpc":"2.0","method":"initialize","id":1,"params":{"capabilities":{}}}"#;
+        let content_type = "Content-Type: application/vscode-jsonrpc; charset=utf-8".to_string();
+
+        let sample = format!(
+            "Content-Length: {}\n{}\r\n\n{}",
+            decoded.len(),
+            content_type,
+            decoded
+        );
+        assert_eq!(
+            parse_message(sample.as_bytes()),
+            Ok(("".as_bytes(), decoded.as_bytes()))
+        );
+
+        let sample = format!("Content-Length: {}\r\n\n{}", decoded.len(), decoded);
+        assert_eq!(
+            parse_message(sample.as_bytes()),
+            Ok(("".as_bytes(), decoded.as_bytes()))
+        );
+    }
+
+    fn error_at(input: &str) -> (HeaderError, usize) {
+        match parse_message(input.as_bytes()) {
+            Err(nom::Err::Error(ParseError { kind, offset })) => (kind, offset),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_header_errors() {
+        assert_eq!(
+            error_at("Content-Type: x\r\n\r\n{}"),
+            (HeaderError::MissingLength, 0)
+        );
+        assert_eq!(
+            error_at("Content-Length: abc\r\n\r\n{}"),
+            (HeaderError::LengthNotNumber, 16)
+        );
+        assert_eq!(
+            error_at("Content-Length: 12x\r\n\r\n{}"),
+            (HeaderError::LengthNotNumber, 18)
+        );
+        assert_eq!(
+            error_at("Content-Length: 99999999999999999999999\r\n\r\n{}"),
+            (HeaderError::LengthTooLarge, 16)
+        );
+        assert_eq!(
+            error_at("Content-Length: 2\r\nContent-Type: a; charset=latin1\r\n\r\n{}"),
+            (HeaderError::InvalidType, 34)
+        );
+        assert_eq!(
+            error_at("Content-Length: 2\r\nContent-Foo: x\r\n\r\n{}"),
+            (HeaderError::Unterminated, 19)
+        );
+    }
+
+    #[test]
+    fn test_incomplete_after_length_line() {
+        // The empty line may follow, so don't wait for a whole `Content-Type` line.
+        for input in &["Content-Length: 2\r\n", "Content-Length: 2\n"] {
+            assert_eq!(
+                parse_header(input.as_bytes()),
+                Err(nom::Err::Incomplete(nom::Needed::Unknown))
+            );
+        }
+        assert_eq!(
+            parse_header(b"Content-Length: 2\r\nContent-Type: a"),
+            Err(nom::Err::Incomplete(nom::Needed::Unknown))
+        );
+    }
+
     #[test]
     fn test_incomplete_error_with_size() {
         let decoded =
@@ -2,9 +2,11 @@ pub mod error;
 pub mod ext;
 pub mod framed;
 mod notification;
+pub mod redact;
 mod request;
 mod response;
 pub mod types;
+pub mod validate;
 
 use std::{convert::TryFrom, str::FromStr};
 
@@ -112,6 +114,87 @@ impl Serialize for Message {
     }
 }
 
+// Top-level fields of JSON-RPC messages.
+const MESSAGE_FIELDS: [&str; 6] = ["jsonrpc", "id", "method", "params", "result", "error"];
+
+/// `Message` with any additional top-level fields, e.g., proprietary extensions.
+/// The additional fields are preserved when serialized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub msg: Message,
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl From<Message> for Envelope {
+    fn from(msg: Message) -> Self {
+        Self {
+            msg,
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+impl FromStr for Envelope {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(serde_json::from_str::<serde_json::Value>(s)?)
+    }
+}
+
+impl TryFrom<serde_json::Value> for Envelope {
+    type Error = serde_json::Error;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        let mut obj = match value {
+            serde_json::Value::Object(obj) => obj,
+            value => return Message::try_from(value).map(Self::from),
+        };
+
+        let mut extra = serde_json::Map::new();
+        let keys: Vec<String> = obj
+            .keys()
+            .filter(|k| !MESSAGE_FIELDS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some(v) = obj.remove(&key) {
+                extra.insert(key, v);
+            }
+        }
+
+        match Message::try_from(serde_json::Value::Object(obj))? {
+            // `Unknown` keeps everything as is.
+            Message::Unknown(Unknown(serde_json::Value::Object(mut obj))) => {
+                obj.append(&mut extra);
+                Ok(Self::from(Message::Unknown(Unknown(obj.into()))))
+            }
+            msg => Ok(Self { msg, extra }),
+        }
+    }
+}
+
+impl Serialize for Envelope {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct WithExtra<'a> {
+            #[serde(flatten)]
+            msg: &'a Message,
+            #[serde(flatten)]
+            extra: &'a serde_json::Map<String, serde_json::Value>,
+        }
+
+        WithExtra {
+            msg: &self.msg,
+            extra: &self.extra,
+        }
+        .serialize(serializer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -150,6 +233,31 @@ mod tests {
         assert_eq!(from_str, from_value);
     }
 
+    #[test]
+    fn test_envelope_preserves_extra_fields() {
+        let v = json!({"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{}},"id":1,"x-trace":"abc"});
+        let envelope = Envelope::from_str(&v.to_string()).unwrap();
+        assert!(matches!(
+            envelope.msg,
+            Message::Request(Request::Initialize { .. })
+        ));
+        assert_eq!(envelope.extra.get("x-trace"), Some(&json!("abc")));
+
+        let serialized: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&envelope).unwrap()).unwrap();
+        assert_eq!(serialized["x-trace"], json!("abc"));
+        assert_eq!(serialized["id"], json!(1));
+        assert_eq!(serialized["jsonrpc"], json!("2.0"));
+    }
+
+    #[test]
+    fn test_envelope_unknown_keeps_everything() {
+        let v = json!({"jsonrpc":"2.0","method":"custom/foo","params":{},"x-trace":"abc"});
+        let envelope = Envelope::from_str(&v.to_string()).unwrap();
+        assert!(envelope.extra.is_empty());
+        assert_eq!(serde_json::to_value(&envelope).unwrap(), v);
+    }
+
     #[test]
     fn test_serialize_unknown_notification() {
         let v = json!({"jsonrpc":"2.0","method":"language/status","params":{"message":""}});
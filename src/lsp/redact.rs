@@ -0,0 +1,222 @@
+//! Redact document contents from messages for logging.
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use lsp_types::Documentation;
+use serde_json::Value;
+
+use super::{Message, Notification, Request, Response, ResponseResult};
+
+/// Displays serialized message `text`, with document contents redacted if `redact` is set.
+///
+/// Redaction is deferred until formatted, so nothing is done unless it's actually logged.
+pub struct Redacted<'a> {
+    text: &'a str,
+    redact: bool,
+    pretty: bool,
+}
+
+impl<'a> Redacted<'a> {
+    pub fn new(text: &'a str, redact: bool) -> Self {
+        Self {
+            text,
+            redact,
+            pretty: false,
+        }
+    }
+
+    /// Pretty-print the message if `pretty` is set.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    fn to_json<T: serde::Serialize>(&self, value: &T) -> serde_json::Result<String> {
+        if self.pretty {
+            serde_json::to_string_pretty(value)
+        } else {
+            serde_json::to_string(value)
+        }
+    }
+}
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if !self.redact {
+            if self.pretty {
+                if let Ok(value) = serde_json::from_str::<Value>(self.text) {
+                    if let Ok(text) = self.to_json(&value) {
+                        return f.write_str(&text);
+                    }
+                }
+            }
+            return f.write_str(self.text);
+        }
+
+        match Message::from_str(self.text) {
+            Ok(msg) => match self.to_json(&redact(&msg)) {
+                Ok(text) => f.write_str(&text),
+                Err(_) => f.write_str(&placeholder(self.text)),
+            },
+            Err(_) => f.write_str(&placeholder(self.text)),
+        }
+    }
+}
+
+/// Returns a clone of `msg` with document contents replaced by a placeholder.
+pub fn redact(msg: &Message) -> Message {
+    let mut msg = msg.clone();
+    match &mut msg {
+        Message::Notification(Notification::DidOpen { params: p }) => {
+            p.text_document.text = placeholder(&p.text_document.text);
+        }
+
+        Message::Notification(Notification::DidChange { params: p }) => {
+            for change in &mut p.content_changes {
+                change.text = placeholder(&change.text);
+            }
+        }
+
+        Message::Notification(Notification::DidSave { params: p }) => {
+            if let Some(text) = &p.text {
+                p.text = Some(placeholder(text));
+            }
+        }
+
+        Message::Request(Request::CompletionResolve {
+            id: _,
+            params: item,
+        }) => {
+            redact_documentation(&mut item.documentation);
+        }
+
+        // Completion results are untyped.
+        Message::Response(Response::Success {
+            id: _,
+            result: ResponseResult::Any(result),
+        }) => {
+            let items = match result {
+                Value::Array(items) => Some(items),
+                Value::Object(list) => match list.get_mut("items") {
+                    Some(Value::Array(items)) => Some(items),
+                    _ => None,
+                },
+                _ => None,
+            };
+            for item in items.into_iter().flatten() {
+                redact_item_documentation(item);
+            }
+            // Resolved completion item.
+            redact_item_documentation(result);
+        }
+
+        _ => {}
+    }
+    msg
+}
+
+fn redact_documentation(doc: &mut Option<Documentation>) {
+    let len = match doc {
+        Some(Documentation::String(s)) => s.len(),
+        Some(Documentation::MarkupContent(m)) => m.value.len(),
+        None => return,
+    };
+    *doc = Some(Documentation::String(format!("<redacted {} bytes>", len)));
+}
+
+fn redact_item_documentation(item: &mut Value) {
+    if let Some(item) = item.as_object_mut() {
+        if !item.contains_key("label") {
+            return;
+        }
+        if let Some(doc) = item.get_mut("documentation") {
+            let len = match doc {
+                Value::String(s) => s.len(),
+                Value::Object(m) => m.get("value").and_then(Value::as_str).map_or(0, str::len),
+                _ => 0,
+            };
+            *doc = Value::String(format!("<redacted {} bytes>", len));
+        }
+    }
+}
+
+fn placeholder(text: &str) -> String {
+    format!("<redacted {} bytes>", text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn redacted(v: Value) -> Value {
+        let text = v.to_string();
+        let redacted = Redacted::new(&text, true).to_string();
+        serde_json::from_str(&redacted).unwrap()
+    }
+
+    #[test]
+    fn test_pretty() {
+        let text = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.rs","languageId":"rust","version":1,"text":"fn main() {}"}}}"#;
+        let pretty = Redacted::new(text, false).pretty(true).to_string();
+        assert!(pretty.contains("\n  \"params\": {"));
+        assert!(pretty.contains("fn main() {}"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&pretty).unwrap(),
+            serde_json::from_str::<Value>(text).unwrap()
+        );
+
+        let pretty = Redacted::new(text, true).pretty(true).to_string();
+        assert!(pretty.contains("\n  \"params\": {"));
+        assert!(pretty.contains("<redacted 12 bytes>"));
+
+        assert_eq!(
+            Redacted::new("not json", false).pretty(true).to_string(),
+            "not json"
+        );
+    }
+
+    #[test]
+    fn test_redact_did_open() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/didOpen","params":{
+            "textDocument":{"uri":"file:///a.rs","languageId":"rust","version":1,"text":"fn main() {}"}
+        }});
+        assert_eq!(
+            redacted(v)["params"]["textDocument"]["text"],
+            "<redacted 12 bytes>"
+        );
+    }
+
+    #[test]
+    fn test_redact_did_change() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/didChange","params":{
+            "textDocument":{"uri":"file:///a.rs","version":2},
+            "contentChanges":[{"text":"fn"}]
+        }});
+        assert_eq!(
+            redacted(v)["params"]["contentChanges"][0]["text"],
+            "<redacted 2 bytes>"
+        );
+    }
+
+    #[test]
+    fn test_redact_completion_list() {
+        let v = json!({"jsonrpc":"2.0","id":1,"result":{
+            "isIncomplete":false,
+            "items":[{"label":"main","documentation":"Entry point"}]
+        }});
+        assert_eq!(
+            redacted(v)["result"]["items"][0]["documentation"],
+            "<redacted 11 bytes>"
+        );
+    }
+
+    #[test]
+    fn test_keeps_text_without_redact() {
+        let text = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        assert_eq!(Redacted::new(text, false).to_string(), text);
+    }
+}
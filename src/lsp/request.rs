@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use super::types::Id;
+use super::types::{Id, InlayHint, InlayHintParams};
 
 // NOTE Not using `lsp_types::lsp_request!` because rust-analyzer
 // doesn't seem to understand it well at the moment and shows `{unknown}`.
@@ -252,6 +252,56 @@ pub enum Request {
         params: lsp_types::SelectionRangeParams,
     },
 
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_linkedEditingRange
+    #[serde(rename = "textDocument/linkedEditingRange")]
+    LinkedEditingRange {
+        id: Id,
+        params: lsp_types::LinkedEditingRangeParams,
+    },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_moniker
+    #[serde(rename = "textDocument/moniker")]
+    Moniker {
+        id: Id,
+        params: lsp_types::MonikerParams,
+    },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_prepareCallHierarchy
+    #[serde(rename = "textDocument/prepareCallHierarchy")]
+    PrepareCallHierarchy {
+        id: Id,
+        params: lsp_types::CallHierarchyPrepareParams,
+    },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#callHierarchy_incomingCalls
+    #[serde(rename = "callHierarchy/incomingCalls")]
+    CallHierarchyIncomingCalls {
+        id: Id,
+        params: lsp_types::CallHierarchyIncomingCallsParams,
+    },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#callHierarchy_outgoingCalls
+    #[serde(rename = "callHierarchy/outgoingCalls")]
+    CallHierarchyOutgoingCalls {
+        id: Id,
+        params: lsp_types::CallHierarchyOutgoingCallsParams,
+    },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_inlayHint
+    #[serde(rename = "textDocument/inlayHint")]
+    InlayHint { id: Id, params: InlayHintParams },
+
+    // To Server
+    // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#inlayHint_resolve
+    #[serde(rename = "inlayHint/resolve")]
+    InlayHintResolve { id: Id, params: InlayHint },
+
     // To Server
     // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#window_workDoneProgress_cancel
     #[serde(rename = "window/workDoneProgress/cancel")]
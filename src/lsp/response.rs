@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 use super::error::Error;
-use super::types::Id;
+use super::types::{Id, InlayHint};
 
 /// [Response message]. Either Success or Failure response.
 ///
@@ -29,6 +29,16 @@ pub enum ResponseResult {
     // {targetUri,targetRange,targetSelectionRange,originSelectionRange?}[]
     LocationLinks(Vec<lsp_types::LocationLink>),
     // remap uri
+    // {name,kind,uri,range,selectionRange, tags?,detail?,data?}[]
+    // Must be before `Locations`
+    CallHierarchyItems(Vec<lsp_types::CallHierarchyItem>),
+    // remap from.uri
+    // {from,fromRanges}[]
+    CallHierarchyIncomingCalls(Vec<lsp_types::CallHierarchyIncomingCall>),
+    // remap to.uri
+    // {to,fromRanges}[]
+    CallHierarchyOutgoingCalls(Vec<lsp_types::CallHierarchyOutgoingCall>),
+    // remap uri
     // {uri,range}[]
     Locations(Vec<lsp_types::Location>),
     // remap uri
@@ -55,6 +65,12 @@ pub enum ResponseResult {
     // remap documentChanges
     // {documentChanges}
     WorkspaceEditWithDocumentChanges(WorkspaceEditWithDocumentChanges),
+    // remap label parts
+    // {position,label, kind?,textEdits?,tooltip?,paddingLeft?,paddingRight?,data?}[]
+    InlayHints(Vec<InlayHint>),
+    // remap label parts
+    // {position,label, kind?,textEdits?,tooltip?,paddingLeft?,paddingRight?,data?}
+    InlayHintResolve(InlayHint),
 
     // noremap
     // {name,kind,range,selectionRange, detail?,tags?,deprecated?,children?}[]
@@ -136,10 +152,6 @@ pub enum ResponseResult {
     //   SemanticTokensFull(lsp_types::SemanticTokensResult),
     //   SemanticTokensFullDelta(lsp_types::SemanticTokensFullDeltaResult),
     //   SemanticTokensRange(lsp_types::SemanticTokensRangeResult),
-    //
-    //   CallHierarchyPrepare(Vec<lsp_types::CallHierarchyItem>),
-    //   CallHierarchyOutgoingCalls(Vec<lsp_types::CallHierarchyOutgoingCall>),
-    //   CallHierarchyIncomingCalls(Vec<lsp_types::CallHierarchyIncomingCall>),
 }
 
 // Some custom types to make untagged enum work.
@@ -3,7 +3,7 @@ use std::fmt::{self, Display, Formatter};
 use serde::{Deserialize, Serialize};
 
 /// Request ID
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Id {
     /// Numeric ID.
@@ -32,4 +32,63 @@ pub enum Params {
 
 /// Unknown message type.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct Unknown(serde_json::Value);
+pub struct Unknown(pub(super) serde_json::Value);
+
+impl Unknown {
+    /// The method if present.
+    pub fn method(&self) -> Option<&str> {
+        self.0.get("method").and_then(|m| m.as_str())
+    }
+}
+
+// Inlay hints were added in LSP 3.17 and are not in `lsp_types` yet.
+
+/// Parameters of `textDocument/inlayHint`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHintParams {
+    pub text_document: lsp_types::TextDocumentIdentifier,
+    pub range: lsp_types::Range,
+    #[serde(flatten)]
+    pub work_done_progress_params: lsp_types::WorkDoneProgressParams,
+}
+
+/// `{position,label, kind?,textEdits?,tooltip?,paddingLeft?,paddingRight?,data?}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHint {
+    pub position: lsp_types::Position,
+    pub label: InlayHintLabel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_edits: Option<Vec<lsp_types::TextEdit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub padding_left: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub padding_right: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+/// `string | InlayHintLabelPart[]`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InlayHintLabel {
+    String(String),
+    Parts(Vec<InlayHintLabelPart>),
+}
+
+/// `{value, tooltip?,location?,command?}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InlayHintLabelPart {
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<lsp_types::Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<lsp_types::Command>,
+}
@@ -0,0 +1,329 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{
+    error::{Error, ErrorCode},
+    ext::{remap_relative_uri, SourceRoot},
+    types::{Id, Unknown},
+    Message, Notification, Request, Response,
+};
+
+/// Check a message from the client that didn't match any of the known messages.
+///
+/// Returns an error response if it's a malformed request with an extractable `id`,
+/// so the client can be answered without forwarding it to the server.
+/// Anything else, including requests with unknown methods, should be forwarded as is.
+pub fn check_unknown(unknown: &Unknown) -> Option<Response> {
+    let obj = unknown.0.as_object()?;
+    let id = obj
+        .get("id")
+        .and_then(|id| serde_json::from_value::<Id>(id.clone()).ok())?;
+
+    match obj.get("method") {
+        // Requests without params are forwarded because `params` is optional for some methods.
+        Some(Value::String(_)) if !obj.contains_key("params") => None,
+
+        // A request for a known method with invalid params.
+        Some(Value::String(_)) => {
+            match serde_json::from_value::<Request>(unknown.0.clone()) {
+                Err(err) if !err.to_string().starts_with("unknown variant") => Some(
+                    error_response(id, ErrorCode::InvalidParams, err.to_string()),
+                ),
+                _ => None,
+            }
+        }
+
+        Some(_) => Some(error_response(
+            id,
+            ErrorCode::InvalidRequest,
+            "method must be a string".to_owned(),
+        )),
+
+        // Responses from the client.
+        None if obj.contains_key("result") || obj.contains_key("error") => None,
+
+        None => Some(error_response(
+            id,
+            ErrorCode::InvalidRequest,
+            "missing method".to_owned(),
+        )),
+    }
+}
+
+/// Check a message from the client strictly for `--strict`.
+///
+/// Returns an error response for any message that doesn't match its method's shape:
+/// requests must have an `id`, notifications must not, and `params` must match the typed variant.
+/// Unlike `check_unknown`, the error is returned even if the message has no `id` to respond to.
+pub fn check_strict(value: &Value) -> Option<Response> {
+    let obj = match value.as_object() {
+        Some(obj) => obj,
+        None => {
+            return Some(invalid_request(None, "message must be an object"));
+        }
+    };
+    let id = obj
+        .get("id")
+        .and_then(|id| serde_json::from_value::<Id>(id.clone()).ok());
+
+    match obj.get("method") {
+        Some(Value::String(_)) => {
+            if obj.contains_key("id") && id.is_none() {
+                return Some(invalid_request(None, "id must be a number or a string"));
+            }
+
+            // `params` is optional for some methods, so only check its shape when present.
+            let has_params = obj.contains_key("params");
+            if let Some(res) = parse_known::<Request>(value) {
+                if id.is_none() {
+                    return Some(invalid_request(None, "request must have an id"));
+                }
+                return match res {
+                    Err(err) if has_params => Some(invalid_request(id, err.to_string())),
+                    _ => None,
+                };
+            }
+            if let Some(res) = parse_known::<Notification>(value) {
+                if id.is_some() {
+                    return Some(invalid_request(id, "notification must not have an id"));
+                }
+                return match res {
+                    Err(err) if has_params => Some(invalid_request(None, err.to_string())),
+                    _ => None,
+                };
+            }
+            // Unknown methods are forwarded as is.
+            None
+        }
+
+        Some(_) => Some(invalid_request(id, "method must be a string")),
+
+        // Responses from the client.
+        None if obj.contains_key("result") || obj.contains_key("error") => None,
+
+        None => Some(invalid_request(id, "missing method")),
+    }
+}
+
+// Parse `value` as `T`. Returns `None` if the method is not one of `T`.
+fn parse_known<T: DeserializeOwned>(value: &Value) -> Option<Result<T, serde_json::Error>> {
+    match serde_json::from_value::<T>(value.clone()) {
+        Err(err) if err.to_string().starts_with("unknown variant") => None,
+        res => Some(res),
+    }
+}
+
+fn invalid_request<T: Into<String>>(id: Option<Id>, message: T) -> Response {
+    Response::Failure {
+        id,
+        error: Error {
+            code: ErrorCode::InvalidRequest,
+            message: message.into(),
+            data: None,
+        },
+    }
+}
+
+fn error_response(id: Id, code: ErrorCode, message: String) -> Response {
+    Response::Failure {
+        id: Some(id),
+        error: Error {
+            code,
+            message,
+            data: None,
+        },
+    }
+}
+
+/// Describe how the proxy parses the message `text` for `--validate-message`: the variant it
+/// matched, why it's `unknown` if so, and the message remapped with `root` if it changes.
+pub fn describe(text: &str, root: &SourceRoot) -> Value {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(err) => return serde_json::json!({"variant": "invalid", "reason": err.to_string()}),
+    };
+    let mut msg: Message = match serde_json::from_value(value.clone()) {
+        Ok(msg) => msg,
+        Err(err) => return serde_json::json!({"variant": "invalid", "reason": err.to_string()}),
+    };
+    let variant = match &msg {
+        Message::Request(_) => "request",
+        Message::Notification(_) => "notification",
+        Message::Response(_) => "response",
+        Message::Unknown(_) => "unknown",
+    };
+    let reason = match &msg {
+        Message::Unknown(_) => Some(unknown_reason(&value)),
+        _ => None,
+    };
+    let remapped = match remap_relative_uri(&mut msg, root) {
+        Ok(()) => match serde_json::to_value(&msg) {
+            Ok(remapped) if remapped != value => Some(remapped),
+            _ => None,
+        },
+        Err(err) => return serde_json::json!({"variant": variant, "reason": err.to_string()}),
+    };
+    serde_json::json!({
+        "variant": variant,
+        "method": value.get("method"),
+        "id": value.get("id"),
+        "reason": reason,
+        "remapped": remapped,
+    })
+}
+
+// Why `value` didn't match any of the known messages.
+fn unknown_reason(value: &Value) -> String {
+    let method = match value.get("method") {
+        Some(Value::String(method)) => method,
+        Some(_) => return "method must be a string".to_owned(),
+        None if value.get("id").is_some() => return "response without result or error".to_owned(),
+        None => return "not a request, notification, or response".to_owned(),
+    };
+    let err = if value.get("id").is_some() {
+        serde_json::from_value::<Request>(value.clone()).err()
+    } else {
+        serde_json::from_value::<Notification>(value.clone()).err()
+    };
+    match err {
+        Some(err) if !err.to_string().starts_with("unknown variant") => err.to_string(),
+        _ => format!("{} is not handled by the proxy", method),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::Message;
+
+    fn check(v: Value) -> Option<Response> {
+        match serde_json::from_value::<Message>(v).unwrap() {
+            Message::Unknown(unknown) => check_unknown(&unknown),
+            other => panic!("expected unknown message, got {:?}", other),
+        }
+    }
+
+    fn error_code(res: Option<Response>) -> Option<(Option<Id>, ErrorCode)> {
+        match res {
+            Some(Response::Failure { id, error }) => Some((id, error.code)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/hover","params":{"foo":1},"id":1});
+        assert_eq!(
+            error_code(check(v)),
+            Some((Some(Id::Number(1)), ErrorCode::InvalidParams))
+        );
+    }
+
+    #[test]
+    fn test_invalid_method() {
+        let v = json!({"jsonrpc":"2.0","method":null,"params":{},"id":"a"});
+        assert_eq!(
+            error_code(check(v)),
+            Some((Some(Id::String("a".to_owned())), ErrorCode::InvalidRequest))
+        );
+    }
+
+    #[test]
+    fn test_missing_method() {
+        let v = json!({"jsonrpc":"2.0","params":{},"id":2});
+        assert_eq!(
+            error_code(check(v)),
+            Some((Some(Id::Number(2)), ErrorCode::InvalidRequest))
+        );
+    }
+
+    #[test]
+    fn test_forwards_unknown_method() {
+        let v = json!({"jsonrpc":"2.0","method":"custom/foo","params":{},"id":1});
+        assert_eq!(check(v), None);
+    }
+
+    #[test]
+    fn test_forwards_without_params() {
+        let v = json!({"jsonrpc":"2.0","method":"shutdown","id":1});
+        assert_eq!(check(v), None);
+    }
+
+    #[test]
+    fn test_forwards_without_id() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/hover","params":{"foo":1}});
+        assert_eq!(check(v), None);
+    }
+
+    #[test]
+    fn test_strict_request_without_id() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/hover","params":{
+            "textDocument":{"uri":"file:///a.rs"},"position":{"line":0,"character":0}
+        }});
+        assert_eq!(
+            error_code(check_strict(&v)),
+            Some((None, ErrorCode::InvalidRequest))
+        );
+    }
+
+    #[test]
+    fn test_strict_notification_with_id() {
+        let v = json!({"jsonrpc":"2.0","method":"initialized","params":{},"id":3});
+        assert_eq!(
+            error_code(check_strict(&v)),
+            Some((Some(Id::Number(3)), ErrorCode::InvalidRequest))
+        );
+    }
+
+    #[test]
+    fn test_strict_invalid_params() {
+        let v = json!({"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"foo":1}});
+        assert_eq!(
+            error_code(check_strict(&v)),
+            Some((None, ErrorCode::InvalidRequest))
+        );
+    }
+
+    #[test]
+    fn test_strict_valid() {
+        let v = json!({"jsonrpc":"2.0","method":"shutdown","id":1});
+        assert_eq!(check_strict(&v), None);
+        let v = json!({"jsonrpc":"2.0","method":"initialized","params":{}});
+        assert_eq!(check_strict(&v), None);
+        let v = json!({"jsonrpc":"2.0","method":"custom/foo","params":{}});
+        assert_eq!(check_strict(&v), None);
+        let v = json!({"jsonrpc":"2.0","result":null,"id":1});
+        assert_eq!(check_strict(&v), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let root = SourceRoot::new(url::Url::parse("file:///workspace/").unwrap());
+        let text = r#"{"jsonrpc":"2.0","method":"textDocument/didClose","params":{"textDocument":{"uri":"source://src/main.rs"}}}"#;
+        let described = describe(text, &root);
+        assert_eq!(described["variant"], "notification");
+        assert_eq!(described["method"], "textDocument/didClose");
+        assert_eq!(
+            described["remapped"]["params"]["textDocument"]["uri"],
+            "file:///workspace/src/main.rs"
+        );
+
+        let text = r#"{"jsonrpc":"2.0","method":"textDocument/hover","params":{"foo":1},"id":1}"#;
+        let described = describe(text, &root);
+        assert_eq!(described["variant"], "unknown");
+        assert!(described["reason"]
+            .as_str()
+            .unwrap()
+            .contains("textDocument"));
+        assert_eq!(described["remapped"], Value::Null);
+
+        let described = describe(r#"{"jsonrpc":"2.0","method":"custom/foo"}"#, &root);
+        assert_eq!(
+            described["reason"],
+            "custom/foo is not handled by the proxy"
+        );
+        assert_eq!(describe("{", &root)["variant"], "invalid");
+    }
+}
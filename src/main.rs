@@ -1,12 +1,18 @@
-use std::net::SocketAddr;
+// `serde_json::json!` of `--print-config` has many entries.
+#![recursion_limit = "256"]
+
+use std::collections::HashMap;
 
 use argh::FromArgs;
 use url::Url;
-use warp::{http, Filter};
+use warp::{http, Filter, Reply};
 
 mod api;
+mod listen;
 mod lsp;
 
+use listen::{parse_listen, Listen, ServeOptions};
+
 #[derive(FromArgs)]
 // Using block doc comments so that `argh` preserves newlines in help output.
 // We need to also write block doc comments without leading space.
@@ -16,7 +22,10 @@ Anything after the option delimiter is used to start the server.
 
 Multiple servers can be registered by separating each with an option delimiter,
 and using the query parameter `name` to specify the command name on connection.
-If no query parameter is present, the first one is started.
+If no query parameter is present, the one named by `--default-server` is started,
+or the first one. Servers are named by the file name of their programs, or the name
+given with `--server`. Names must be unique, and only contain letters, digits, `.`,
+`_`, or `-`.
 
 Examples:
   lsp-ws-proxy -- rust-analyzer
@@ -24,27 +33,304 @@ Examples:
   lsp-ws-proxy --listen 8888 -- rust-analyzer
   lsp-ws-proxy --listen 0.0.0.0:8888 -- rust-analyzer
   # Register multiple servers.
-  # Choose the server with query parameter `name` when connecting.
+  # Choose the server with query parameter `name` or path `/servers/{name}`
+  # when connecting.
   lsp-ws-proxy --listen 9999 --sync --remap \
     -- typescript-language-server --stdio \
     -- css-languageserver --stdio \
     -- html-languageserver --stdio
+  # Or pass each command as a single string, split like a shell does.
+  lsp-ws-proxy --server "sh -c 'exec rust-analyzer 2> /tmp/ra.log'"
+  # Name servers sharing a program with `<name>:`.
+  lsp-ws-proxy --server "ts: node ts-server.js" --server "css: node css-server.js"
+  # Respond with a built-in fake server for testing clients.
+  lsp-ws-proxy --echo
+
+Arguments of the commands can contain placeholders:
+  {cwd}   the current directory, or `--remap-base` if set
+  {name}  the command name
+  {port}  the TCP port the proxy is listening on
 */
 struct Options {
-    /// address or port to listen on (default: 0.0.0.0:9999)
-    #[argh(
-        option,
-        short = 'l',
-        default = "String::from(\"0.0.0.0:9999\")",
-        from_str_fn(parse_listen)
-    )]
-    listen: String,
+    /// address or port to listen on, or `unix:<path>` for unix socket.
+    /// can be repeated (default: 0.0.0.0:$PORT if set, or 0.0.0.0:9999)
+    #[argh(option, short = 'l', from_str_fn(parse_listen))]
+    listen: Vec<Listen>,
+    /// also accept cleartext HTTP/2 (prior knowledge), e.g., for clients
+    /// of `/files` reusing a connection. WebSockets use HTTP/1.1
+    #[argh(switch)]
+    http2: bool,
+    /// seconds between keep-alive probes on idle connections. TCP
+    /// keepalive, and HTTP/2 pings with `--http2`
+    #[argh(option)]
+    keep_alive: Option<u64>,
+    /// origin allowed to make requests and open WebSocket connections
+    /// from browsers, like `https://example.com`. can be repeated
+    /// (default: any origin)
+    #[argh(option, from_str_fn(parse_origin))]
+    allow_origin: Vec<String>,
+    /// log the client address from `X-Forwarded-For` or `X-Real-IP`
+    /// set by a reverse proxy. clients can set them without one
+    #[argh(switch)]
+    trust_proxy_headers: bool,
     /// write text document to disk on save, and enable `/files` endpoint
     #[argh(switch, short = 's')]
     sync: bool,
+    /// apply requests modifying files with `/files` one at a time instead
+    /// of interleaving their operations. slower with concurrent requests
+    #[argh(switch)]
+    serialize_files: bool,
+    /// limit in bytes of request bodies of `POST /files`, and of messages
+    /// to `POST /send` with `--sse`. other endpoints keep the limit of
+    /// 2 MiB (default: 2097152)
+    #[argh(option)]
+    files_body_limit: Option<u64>,
+    /// only write saved documents matching the glob with `--sync`.
+    /// can be repeated
+    #[argh(option)]
+    sync_include: Vec<String>,
+    /// never write saved documents matching the glob with `--sync`.
+    /// can be repeated
+    #[argh(option)]
+    sync_exclude: Vec<String>,
+    /// don't create missing directories when writing files. writes to
+    /// them fail instead
+    #[argh(switch)]
+    no_sync_create_dirs: bool,
+    /// rewrite line endings of files written with `--sync` and `/files`
+    /// to `lf` or `crlf`, or `none` to keep them (default: none)
+    #[argh(option, default = "api::sync::LineEnding::None")]
+    normalize_eol: api::sync::LineEnding,
+    /// never write files. `/files` rejects operations, and saved documents
+    /// are not written with `--sync`
+    #[argh(switch)]
+    readonly: bool,
+    /// require `Authorization: Bearer <token>` on `/connections` and
+    /// `/events`, and
+    /// enable `DELETE /connections/{id}` and `POST /notify-changed` to
+    /// send `didChangeWatchedFiles` to every server, for files changed
+    /// without `/files`
+    #[argh(option)]
+    api_token: Option<String>,
+    /// run each connection's server in a new directory under the path,
+    /// removed on disconnect. files are synced there with `--sync`
+    #[argh(option)]
+    per_connection_workspace: Option<std::path::PathBuf>,
+    /// copy the directory into each new directory with
+    /// `--per-connection-workspace`
+    #[argh(option)]
+    workspace_template: Option<std::path::PathBuf>,
+    /// remove files created by each connection when it disconnects. files
+    /// are attributed with `?connection=<id>` on `/files`
+    #[argh(switch)]
+    ephemeral: bool,
     /// remap relative uri (source://)
     #[argh(switch, short = 'r')]
     remap: bool,
+    /// name to prefix remapped paths with, e.g., `source://<name>/src/main.rs`.
+    /// requires `--remap`
+    #[argh(option, from_str_fn(parse_remap_prefix))]
+    remap_prefix: Option<String>,
+    /// directory to resolve `source://` and `/files` paths against instead
+    /// of the current directory, e.g., a snapshot served with `--readonly`
+    #[argh(option)]
+    remap_base: Option<std::path::PathBuf>,
+    /// redact document contents when logging messages
+    #[argh(switch)]
+    redact: bool,
+    /// pretty-print messages when logging. messages sent are unchanged
+    #[argh(switch)]
+    pretty_debug: bool,
+    /// only log errors, ignoring `RUST_LOG`
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+    /// format of logs, `text` or `json` with the fields of spans
+    /// (default: text)
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+    /// export spans of connections and requests from clients to the
+    /// OpenTelemetry collector at the URL over gRPC, e.g.,
+    /// `http://localhost:4317`
+    #[argh(option)]
+    otlp_endpoint: Option<String>,
+    /// append every forwarded message to the file as newline-delimited
+    /// JSON with the direction and timestamp. respects `--redact`
+    #[argh(option)]
+    capture: Option<std::path::PathBuf>,
+    /// send the client messages in the file written with `--capture` to
+    /// a new server, report responses differing from the captured ones,
+    /// and exit
+    #[argh(option)]
+    replay: Option<std::path::PathBuf>,
+    /// only allow commands starting the program. an absolute path also
+    /// allows names resolving to it. can be repeated (default: any)
+    #[argh(option)]
+    allow_binary: Vec<String>,
+    /// respond with an error to malformed messages from the client
+    /// instead of forwarding them
+    #[argh(switch)]
+    strict: bool,
+    /// send malformed messages from the server to the client as
+    /// `window/logMessage` errors instead of only logging them
+    #[argh(switch)]
+    report_codec_errors: bool,
+    /// batch notifications to the server by delaying writes up to
+    /// milliseconds. other messages are written immediately
+    #[argh(option)]
+    write_delay: Option<u64>,
+    /// limit messages from each client per second
+    #[argh(option)]
+    rate_limit: Option<f64>,
+    /// number of messages allowed at once with `--rate-limit` (default:
+    /// the rate)
+    #[argh(option)]
+    rate_burst: Option<u32>,
+    /// number of messages to queue when rate limited before closing the
+    /// connection (default: 100)
+    #[argh(option, default = "100")]
+    rate_queue: usize,
+    /// default `initializationOptions` as JSON. prefix with `<name>=` to
+    /// apply to a specific server. can be repeated
+    #[argh(option, from_str_fn(parse_init_options))]
+    init_options: Vec<InitOptions>,
+    /// root directory path or uri to use when the client's `initialize`
+    /// doesn't specify one. `source://` requires `--remap`
+    #[argh(option)]
+    root: Option<String>,
+    /// prefer `--init-options` over the options sent by the client
+    #[argh(switch)]
+    force_init_options: bool,
+    /// start and initialize each server ahead of connections.
+    /// the first connection adopts the ready server
+    #[argh(switch)]
+    warmup: bool,
+    /// client `capabilities` as JSON to initialize servers with on
+    /// `--warmup`. the capabilities of the adopting client are ignored
+    /// (default: {})
+    #[argh(option, from_str_fn(parse_capabilities))]
+    warmup_capabilities: Option<serde_json::Value>,
+    /// shut down servers started with `--warmup` after seconds without a
+    /// connection
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+    /// keep the server running for seconds after the client disconnects
+    /// without `shutdown`, for a reconnecting client to adopt
+    #[argh(option)]
+    linger: Option<u64>,
+    /// signal to stop the server with on disconnect, `SIGTERM` or
+    /// `SIGKILL` (default: SIGKILL)
+    #[argh(option, default = "api::pool::KillSignal::Kill")]
+    kill_signal: api::pool::KillSignal,
+    /// seconds to wait for the server to exit after `--kill-signal` before
+    /// sending `SIGKILL` (default: 5)
+    #[argh(option, default = "5")]
+    kill_timeout: u64,
+    /// don't forward `telemetry/event` notifications from the server to
+    /// the client
+    #[argh(switch)]
+    drop_telemetry: bool,
+    /// don't forward `window/showMessage` notifications of the severity,
+    /// `error`, `warning`, `info`, or `log`. can be repeated
+    #[argh(option)]
+    drop_show_message: Vec<lsp::ext::MessageSeverity>,
+    /// rewrite severities of diagnostics from the server, e.g.,
+    /// `error=warning`. severities are `error`, `warning`, `info`, or
+    /// `hint`. can be repeated
+    #[argh(option)]
+    diagnostic_severity: Vec<lsp::ext::SeverityRule>,
+    /// respond to `workspace/configuration` from the server instead of
+    /// the client with the JSON object of values keyed by section.
+    /// requests with any section not in it are forwarded
+    #[argh(option, from_str_fn(parse_config_response))]
+    config_response: Option<serde_json::Map<String, serde_json::Value>>,
+    /// respond to requests of the method from the client with an error
+    /// instead of forwarding, and remove its capability from the
+    /// `initialize` result. notifications are dropped. can be repeated
+    #[argh(option, from_str_fn(parse_block_method))]
+    block_method: Vec<String>,
+    /// coerce ids of requests to the server to `number` or `string` for
+    /// servers accepting only one. responses have the original ids
+    #[argh(option)]
+    coerce_ids: Option<lsp::ext::IdKind>,
+    /// encoding of positions sent by the client, `utf-8` or `utf-16`.
+    /// translated to `utf-16` for the server (default: utf-16)
+    #[argh(option, default = "lsp::ext::PositionEncoding::Utf16")]
+    position_encoding: lsp::ext::PositionEncoding,
+    /// log requests taking longer than milliseconds to respond at debug
+    /// level
+    #[argh(option)]
+    slow_request_threshold: Option<u64>,
+    /// number of requests from each client to have outstanding at the
+    /// server at once. more are queued until responses come back.
+    /// notifications are not limited
+    #[argh(option)]
+    max_inflight: Option<usize>,
+    /// number of messages to buffer for each client before waiting
+    /// (default: 32)
+    #[argh(option, default = "32")]
+    buffer_size: usize,
+    /// initial size in bytes of the buffers for reading from and writing to
+    /// the server. increase for servers sending very large messages
+    #[argh(option)]
+    io_buffer_size: Option<usize>,
+    /// start servers with a pseudo-terminal as stdin and stdout instead of
+    /// pipes, for servers behaving differently without a terminal
+    #[argh(switch)]
+    pty: bool,
+    /// number of times to retry starting the server on transient failures
+    /// like too many processes (default: 2)
+    #[argh(option, default = "2")]
+    spawn_retries: u32,
+    /// reject connections starting a new server with 503 while this many
+    /// servers are running, counting warmed up and lingering servers.
+    /// connections adopting a parked server are accepted
+    #[argh(option)]
+    max_servers: Option<usize>,
+    /// reject connections to a server with 503 for `--crash-cooldown`
+    /// after it crashed this many times within `--crash-window`
+    #[argh(option)]
+    crash_limit: Option<usize>,
+    /// seconds to count crashes for `--crash-limit` in (default: 60)
+    #[argh(option, default = "60")]
+    crash_window: u64,
+    /// seconds to reject connections to a server after `--crash-limit`
+    /// (default: 60)
+    #[argh(option, default = "60")]
+    crash_cooldown: u64,
+    /// also accept clients over Server-Sent Events with `GET /sse` and
+    /// `POST /send` for networks blocking WebSocket
+    #[argh(switch)]
+    sse: bool,
+    /// serve one WebSocket connection, and exit when it closes. other
+    /// connections are rejected with 503
+    #[argh(switch)]
+    single_shot: bool,
+    /// command to start a server with, split into arguments like a shell
+    /// with quotes and backslashes, optionally prefixed with `<name>:`.
+    /// registered before the commands after `--`. can be repeated
+    #[argh(option, from_str_fn(parse_server))]
+    server: Vec<api::pool::ServerCommand>,
+    /// name of the server to start when the client doesn't specify one
+    /// (default: the first one)
+    #[argh(option)]
+    default_server: Option<String>,
+    /// tell clients which server they got with a `$/proxy/serverSelected`
+    /// notification with its name and command before any other message
+    #[argh(switch)]
+    announce_server: bool,
+    /// respond to requests with a built-in fake server instead of starting
+    /// one. `initialize` gets empty capabilities and other requests get
+    /// `null` results
+    #[argh(switch)]
+    echo: bool,
+    /// print the resolved configuration as JSON and exit
+    #[argh(switch)]
+    print_config: bool,
+    /// read a JSON message from stdin, and print how it's parsed and
+    /// remapped relative to the current directory or `--remap-base` as
+    /// JSON, then exit
+    #[argh(switch)]
+    validate_message: bool,
     /// show version and exit
     #[argh(switch, short = 'v')]
     version: bool,
@@ -52,46 +338,487 @@ struct Options {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()))
-        .init();
-
     let (opts, commands) = get_opts_and_commands();
+    init_logging(opts.quiet, opts.log_format, opts.otlp_endpoint.as_deref())?;
+    if commands.is_empty() && !opts.echo && !opts.validate_message {
+        return Err("Command to start the server is required. See --help for examples.".into());
+    }
+    if !opts.allow_binary.is_empty() {
+        if let Some(command) = commands
+            .iter()
+            .find(|command| !api::pool::is_allowed_binary(command.program(), &opts.allow_binary))
+        {
+            return Err(format!("{} is not allowed by --allow-binary", command.program()).into());
+        }
+    }
+    // Resolved once so that connections don't depend on the order of commands.
+    let default_server = match &opts.default_server {
+        Some(name) => commands
+            .iter()
+            .position(|command| command.name == *name)
+            .ok_or_else(|| format!("default server {} is not registered", name))?,
+        None => 0,
+    };
 
     let cwd = std::env::current_dir()?;
+    let workspaces = match (&opts.per_connection_workspace, &opts.workspace_template) {
+        (Some(base), template) => Some(api::workspace::Workspaces {
+            base: cwd.join(base),
+            template: template.as_ref().map(|template| cwd.join(template)),
+        }),
+        (None, Some(_)) => {
+            return Err("--workspace-template requires --per-connection-workspace".into());
+        }
+        (None, None) => None,
+    };
+    if opts.remap_prefix.is_some() && !opts.remap {
+        return Err("--remap-prefix requires --remap".into());
+    }
+    if opts.linger.is_some() && workspaces.is_some() {
+        return Err("--linger cannot be used with --per-connection-workspace".into());
+    }
+    if matches!(&opts.api_token, Some(token) if token.is_empty()) {
+        return Err("--api-token must not be empty".into());
+    }
+    if opts.crash_limit == Some(0) {
+        return Err("--crash-limit must be at least 1".into());
+    }
+    let breaker = opts.crash_limit.map(|limit| {
+        api::breaker::CircuitBreaker::new(
+            limit,
+            std::time::Duration::from_secs(opts.crash_window),
+            std::time::Duration::from_secs(opts.crash_cooldown),
+        )
+    });
+    if opts.max_inflight == Some(0) {
+        return Err("--max-inflight must be at least 1".into());
+    }
+    if opts.pty && cfg!(not(unix)) {
+        return Err("--pty is only supported on Unix".into());
+    }
+    let spawn_options = api::pool::SpawnOptions {
+        io_buffer_size: opts.io_buffer_size,
+        pty: opts.pty,
+    };
+    // Servers start in the current directory, or the workspace of each connection.
+    let server_cwd = match &workspaces {
+        Some(_) => None,
+        None => Some(cwd.clone()),
+    };
+    // The project root of connections. Servers still start in the current directory.
+    let cwd = match &opts.remap_base {
+        Some(base) => {
+            let base = cwd.join(base);
+            if !base.is_dir() {
+                return Err(format!("--remap-base {} is not a directory", base.display()).into());
+            }
+            base
+        }
+        None => cwd,
+    };
     // TODO Move these to `api` module.
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(&[http::header::CONTENT_TYPE])
-        .allow_methods(&[http::Method::GET, http::Method::OPTIONS, http::Method::POST]);
+    let cors = if opts.allow_origin.is_empty() {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(opts.allow_origin.iter().map(String::as_str))
+    };
+    let cors = cors
+        .allow_headers(&[
+            http::header::AUTHORIZATION,
+            http::header::CONTENT_ENCODING,
+            http::header::CONTENT_TYPE,
+        ])
+        .allow_methods(&[
+            http::Method::GET,
+            http::Method::OPTIONS,
+            http::Method::POST,
+            http::Method::PUT,
+            http::Method::DELETE,
+        ]);
     // TODO Limit concurrent connection. Can get messy when `sync` is used.
-    // TODO? Keep track of added files and remove them on disconnect?
-    let proxy = api::proxy::handler(api::proxy::Context {
+    let init_options = resolve_init_options(&opts.init_options, &commands);
+    let (events, _) = tokio::sync::broadcast::channel(100);
+    let cwd_uri = Url::from_directory_path(&cwd).expect("valid url from current dir");
+    let root = opts
+        .root
+        .as_deref()
+        .map(|root| resolve_root(root, &cwd_uri, opts.remap))
+        .transpose()?;
+    let listens = if opts.listen.is_empty() {
+        vec![default_listen()?]
+    } else {
+        opts.listen.clone()
+    };
+    // Substituted for `{port}` in commands.
+    let port = listens.iter().find_map(|l| match l {
+        Listen::Tcp(addr) => Some(addr.port()),
+        Listen::Unix(_) => None,
+    });
+    if opts.print_config {
+        let servers: Vec<_> = commands
+            .iter()
+            .map(|command| {
+                serde_json::json!({
+                    "name": command.name,
+                    "command": api::pool::expand_command(command, &cwd_uri, port),
+                    // `null` if each connection starts its server in its own workspace.
+                    "cwd": server_cwd,
+                    "initOptions": init_options.get(&command.name),
+                })
+            })
+            .collect();
+        let config = serde_json::json!({
+            "listen": listens.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "http2": opts.http2,
+            "keepAlive": opts.keep_alive,
+            "allowOrigin": opts.allow_origin,
+            "trustProxyHeaders": opts.trust_proxy_headers,
+            "servers": servers,
+            "defaultServer": commands.get(default_server).map(|command| &command.name),
+            "announceServer": opts.announce_server,
+            "root": root,
+            "sync": opts.sync,
+            "syncInclude": opts.sync_include,
+            "syncExclude": opts.sync_exclude,
+            "syncCreateDirs": !opts.no_sync_create_dirs,
+            "normalizeEol": opts.normalize_eol,
+            "readonly": opts.readonly,
+            "apiToken": opts.api_token.is_some(),
+            "perConnectionWorkspace": workspaces.as_ref().map(|w| &w.base),
+            "workspaceTemplate": workspaces.as_ref().and_then(|w| w.template.as_ref()),
+            "serializeFiles": opts.serialize_files,
+            "filesBodyLimit": opts.files_body_limit.unwrap_or(api::JSON_BODY_LIMIT),
+            "ephemeral": opts.ephemeral,
+            "remap": opts.remap,
+            "remapPrefix": opts.remap_prefix,
+            "remapBase": cwd,
+            "redact": opts.redact,
+            "prettyDebug": opts.pretty_debug,
+            "quiet": opts.quiet,
+            "logFormat": opts.log_format,
+            "otlpEndpoint": opts.otlp_endpoint,
+            "capture": opts.capture,
+            "replay": opts.replay,
+            "allowBinary": opts.allow_binary,
+            "strict": opts.strict,
+            "reportCodecErrors": opts.report_codec_errors,
+            "warmup": opts.warmup,
+            "warmupCapabilities": opts.warmup_capabilities,
+            "idleTimeout": opts.idle_timeout,
+            "linger": opts.linger,
+            "killSignal": opts.kill_signal,
+            "killTimeout": opts.kill_timeout,
+            "forceInitOptions": opts.force_init_options,
+            "rateLimit": opts.rate_limit,
+            "rateBurst": opts.rate_burst,
+            "rateQueue": opts.rate_queue,
+            "writeDelay": opts.write_delay,
+            "slowRequestThreshold": opts.slow_request_threshold,
+            "maxInflight": opts.max_inflight,
+            "dropTelemetry": opts.drop_telemetry,
+            "dropShowMessage": opts.drop_show_message,
+            "diagnosticSeverity": opts.diagnostic_severity,
+            "configResponse": opts.config_response,
+            "blockMethod": opts.block_method,
+            "coerceIds": opts.coerce_ids,
+            "positionEncoding": opts.position_encoding,
+            "bufferSize": opts.buffer_size,
+            "ioBufferSize": opts.io_buffer_size,
+            "pty": opts.pty,
+            "spawnRetries": opts.spawn_retries,
+            "maxServers": opts.max_servers,
+            "crashLimit": opts.crash_limit,
+            "crashWindow": opts.crash_window,
+            "crashCooldown": opts.crash_cooldown,
+            "echo": opts.echo,
+            "sse": opts.sse,
+            "singleShot": opts.single_shot,
+        });
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+    if opts.validate_message {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+        let root =
+            lsp::ext::SourceRoot::new(cwd_uri.clone()).with_prefix(opts.remap_prefix.clone());
+        let described = lsp::validate::describe(&text, &root);
+        println!("{}", serde_json::to_string_pretty(&described)?);
+        return Ok(());
+    }
+    if let Some(path) = &opts.replay {
+        if commands.is_empty() {
+            return Err("`--replay` requires a command to start the server.".into());
+        }
+        return replay(
+            path,
+            &commands[default_server],
+            &cwd_uri,
+            port,
+            spawn_options,
+        )
+        .await;
+    }
+
+    let pool = api::pool::Pool::default();
+    let server_count = api::pool::ServerCount::default();
+    if opts.warmup {
+        for command in &commands {
+            // Warmed up servers count toward `--max-servers` until they exit.
+            let slot = match server_count.reserve(opts.max_servers) {
+                Some(slot) => slot,
+                None => {
+                    tracing::warn!("not warming up {} with --max-servers reached", command.name);
+                    continue;
+                }
+            };
+            let pool = pool.clone();
+            let name = command.name.clone();
+            let command = api::pool::expand_command(command, &cwd_uri, port);
+            let warmup = api::pool::Warmup {
+                root: root.clone().unwrap_or_else(|| cwd_uri.clone()),
+                capabilities: opts
+                    .warmup_capabilities
+                    .clone()
+                    .unwrap_or_else(|| serde_json::json!({})),
+                init_options: init_options.get(&name).cloned(),
+                idle_timeout: opts.idle_timeout.map(std::time::Duration::from_secs),
+            };
+            tokio::spawn(async move {
+                if let Err(err) = pool
+                    .warmup(&name, &command, warmup, spawn_options, Some(slot))
+                    .await
+                {
+                    tracing::error!("failed to warm up {}: {}", name, err);
+                }
+            });
+        }
+    }
+    let servers = api::servers::handler(api::servers::Context {
+        commands: commands.clone(),
+        pool: pool.clone(),
+        breaker: breaker.clone(),
+        readonly: opts.readonly,
+    });
+    let connections = api::connections::Registry::default();
+    let health = api::health::Context {
+        commands: commands.clone(),
+        connections: connections.clone(),
+        pool: pool.clone(),
+    };
+    let healthz = api::health::healthz(health.clone());
+    let readyz = api::health::readyz(health);
+    let (single_shot, single_shot_closed) = if opts.single_shot {
+        let (single_shot, closed) = api::proxy::SingleShot::new();
+        (Some(single_shot), Some(closed))
+    } else {
+        (None, None)
+    };
+    let proxy_ctx = api::proxy::Context {
         commands,
+        default_server,
+        allowed_origins: opts.allow_origin.clone(),
         sync: opts.sync,
+        readonly: opts.readonly,
+        sync_create_dirs: !opts.no_sync_create_dirs,
+        normalize_eol: opts.normalize_eol,
+        sync_filter: api::sync::SyncFilter::new(&cwd, &opts.sync_include, &opts.sync_exclude)?,
         remap: opts.remap,
-        cwd: Url::from_directory_path(&cwd).expect("valid url from current dir"),
+        remap_prefix: opts.remap_prefix.clone(),
+        redact: opts.redact,
+        pretty_debug: opts.pretty_debug,
+        capture: opts
+            .capture
+            .as_deref()
+            .map(|path| api::capture::Capture::open(path, opts.redact))
+            .transpose()?,
+        strict: opts.strict,
+        report_codec_errors: opts.report_codec_errors,
+        write_delay: opts.write_delay.map(std::time::Duration::from_millis),
+        filter: lsp::ext::MessageFilter {
+            drop_telemetry: opts.drop_telemetry,
+            drop_show_message: opts.drop_show_message.clone(),
+        },
+        diagnostic_severity: opts.diagnostic_severity.clone(),
+        config_response: opts.config_response.clone(),
+        blocked_methods: lsp::ext::BlockedMethods::new(opts.block_method.clone()),
+        trust_proxy_headers: opts.trust_proxy_headers,
+        coerce_ids: opts.coerce_ids,
+        position_encoding: opts.position_encoding,
+        slow_request_threshold: opts
+            .slow_request_threshold
+            .map(std::time::Duration::from_millis),
+        trace_requests: opts.otlp_endpoint.is_some(),
+        max_inflight: opts.max_inflight,
+        init_options,
+        force_init_options: opts.force_init_options,
+        events: events.clone(),
+        rate_limit: opts.rate_limit.map(|rate| api::rate_limit::RateLimit {
+            rate,
+            burst: opts.rate_burst.unwrap_or_else(|| rate.ceil() as u32),
+            queue: opts.rate_queue,
+        }),
+        pool,
+        linger: opts.linger.map(std::time::Duration::from_secs),
+        announce_server: opts.announce_server,
+        kill_signal: opts.kill_signal,
+        kill_timeout: std::time::Duration::from_secs(opts.kill_timeout),
+        single_shot,
+        server_count,
+        metrics: api::metrics::Metrics::default(),
+        max_servers: opts.max_servers,
+        breaker,
+        connections: connections.clone(),
+        cwd: cwd_uri,
+        workspaces,
+        ephemeral: opts.ephemeral,
+        buffer_size: opts.buffer_size.max(1),
+        spawn_options,
+        spawn_retries: opts.spawn_retries,
+        echo: opts.echo,
+        port,
+        root,
+    };
+    let proxy = api::proxy::handler(proxy_ctx.clone());
+    let version = api::version::handler();
+    let events = api::events::handler(api::events::Context {
+        events,
+        token: opts.api_token.clone(),
     });
-    let healthz = warp::path::end().and(warp::get()).map(|| "OK");
-    let addr = opts.listen.parse::<SocketAddr>().expect("valid addr");
+    let connections_ctx = api::connections::Context {
+        connections,
+        token: opts.api_token.clone(),
+    };
+    let connections = api::connections::handler(connections_ctx.clone());
+    let metrics = api::metrics::handler(api::metrics::Context {
+        metrics: proxy_ctx.metrics.clone(),
+    });
+    let routes = proxy
+        .or(healthz)
+        .or(readyz)
+        .or(version)
+        .or(events)
+        .or(servers)
+        .or(connections)
+        .or(metrics);
+    // Enable `/notify-changed` and `DELETE /connections/{id}` endpoints if a token is set
+    let routes = if let Some(token) = &opts.api_token {
+        let notify = api::notify::handler(api::notify::Context {
+            cwd: cwd.clone(),
+            remap: opts.remap,
+            remap_prefix: opts.remap_prefix.clone(),
+            token: token.clone(),
+            connections: proxy_ctx.connections.clone(),
+        });
+        let delete = api::connections::delete(connections_ctx);
+        routes
+            .or(notify)
+            .or(delete)
+            .map(Reply::into_response)
+            .boxed()
+    } else {
+        routes.map(Reply::into_response).boxed()
+    };
     // Enable `/files` endpoint if sync
-    if opts.sync {
+    let routes = if opts.sync {
         let files = api::files::handler(api::files::Context {
             cwd,
             remap: opts.remap,
+            remap_prefix: opts.remap_prefix.clone(),
+            readonly: opts.readonly,
+            create_dirs: !opts.no_sync_create_dirs,
+            normalize_eol: opts.normalize_eol,
+            ephemeral: opts.ephemeral,
+            connections: proxy_ctx.connections.clone(),
+            mutations: opts.serialize_files.then(Default::default),
+            body_limit: opts.files_body_limit.unwrap_or(api::JSON_BODY_LIMIT),
+        });
+        routes.or(files).map(Reply::into_response).boxed()
+    } else {
+        routes.map(Reply::into_response).boxed()
+    };
+    // Enable `/sse` and `/send` endpoints if sse
+    let routes = if opts.sse {
+        let sse = api::sse::handler(api::sse::Context {
+            proxy: proxy_ctx,
+            sessions: Default::default(),
+            body_limit: opts.files_body_limit.unwrap_or(api::JSON_BODY_LIMIT),
         });
-        warp::serve(proxy.or(healthz).or(files).recover(api::recover).with(cors))
-            .run(addr)
-            .await;
+        routes.or(sse).map(Reply::into_response).boxed()
     } else {
-        warp::serve(proxy.or(healthz).recover(api::recover).with(cors))
-            .run(addr)
-            .await;
+        routes
+    };
+    let routes = routes.recover(api::recover).with(cors);
+
+    let serve_options = ServeOptions {
+        http2: opts.http2,
+        keep_alive: opts.keep_alive.map(std::time::Duration::from_secs),
+    };
+    let mut handle = listen::start(routes, listens, serve_options);
+    let single_shot_closed = async {
+        match single_shot_closed {
+            Some(closed) => {
+                let _ = closed.await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let result = tokio::select! {
+        _ = listen::shutdown_signal() => handle.shutdown().await,
+        _ = single_shot_closed => {
+            tracing::info!("the single connection closed, shutting down");
+            handle.shutdown().await
+        }
+        result = handle.stopped() => result,
+    };
+    if opts.otlp_endpoint.is_some() {
+        // Export the remaining spans. Blocks until the exporter is done.
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
     }
-    Ok(())
+    result.map_err(|err| err as Box<dyn std::error::Error>)
 }
 
-fn get_opts_and_commands() -> (Options, Vec<Vec<String>>) {
+async fn replay(
+    path: &std::path::Path,
+    command: &api::pool::ServerCommand,
+    cwd: &Url,
+    port: Option<u16>,
+    spawn_options: api::pool::SpawnOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let transcript = api::replay::Transcript::parse(&text)?;
+    let command = api::pool::expand_command(command, cwd, port);
+    let mut server = api::pool::spawn(&command, None, spawn_options)?;
+    // The server is killed on drop.
+    let report = api::replay::replay(&mut server, &transcript)
+        .await
+        .map_err(|err| err.to_string())?;
+    for mismatch in &report.mismatches {
+        tracing::warn!(
+            "response to message {} ({}) differs: expected {}, got {}",
+            mismatch.index,
+            mismatch.method,
+            mismatch.expected,
+            mismatch
+                .actual
+                .as_ref()
+                .map_or_else(|| "no response".to_owned(), ToString::to_string),
+        );
+    }
+    if report.mismatches.is_empty() {
+        tracing::info!("all {} responses match", report.compared);
+        Ok(())
+    } else {
+        Err(format!(
+            "{} of {} responses differ",
+            report.mismatches.len(),
+            report.compared
+        )
+        .into())
+    }
+}
+
+fn get_opts_and_commands() -> (Options, Vec<api::pool::ServerCommand>) {
     let args: Vec<String> = std::env::args().collect();
     let splitted: Vec<Vec<String>> = args.split(|s| *s == "--").map(|s| s.to_vec()).collect();
     let strs: Vec<&str> = splitted[0].iter().map(|s| s.as_str()).collect();
@@ -111,22 +838,333 @@ fn get_opts_and_commands() -> (Options, Vec<Vec<String>>) {
         std::process::exit(0);
     }
 
-    if splitted.len() < 2 {
-        panic!("Command to start the server is required. See --help for examples.");
+    let commands: Vec<_> = opts
+        .server
+        .iter()
+        .cloned()
+        .chain(
+            splitted[1..]
+                .iter()
+                .cloned()
+                .map(api::pool::ServerCommand::new),
+        )
+        .collect();
+    if let Err(err) = validate_commands(&commands) {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
-
-    let commands = splitted[1..].iter().map(|s| s.to_owned()).collect();
     (opts, commands)
 }
 
-fn parse_listen(value: &str) -> Result<String, String> {
-    // Allow specifying only a port number.
-    if value.chars().all(|c| c.is_ascii_digit()) {
-        return Ok(format!("0.0.0.0:{}", value));
+// Names select servers in paths like `/servers/{name}`, so they must be unique and safe
+// in a path segment. The programs can be anywhere.
+fn validate_commands(commands: &[api::pool::ServerCommand]) -> Result<(), String> {
+    let mut names = std::collections::HashSet::new();
+    for command in commands {
+        if command.command.is_empty() {
+            return Err("empty command after --".to_owned());
+        }
+        let name = &command.name;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            return Err(format!(
+                "invalid server name {} of {}: only letters, digits, '.', '_', and '-' are allowed",
+                name,
+                command.program()
+            ));
+        }
+        if !names.insert(name) {
+            return Err(format!(
+                "server name {} is registered more than once. name them with --server \"<name>: <command>\"",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Format of logs from `--log-format`.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("{} is not one of text or json", s)),
+        }
+    }
+}
+
+fn init_logging(
+    quiet: bool,
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+) -> Result<(), opentelemetry::trace::TraceError> {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+    let filter = if quiet {
+        "error".to_owned()
+    } else {
+        std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned())
+    };
+    let tracer = match otlp_endpoint {
+        Some(endpoint) => {
+            let resource = opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )]);
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .with_endpoint(endpoint)
+                .with_trace_config(opentelemetry::sdk::trace::config().with_resource(resource))
+                .with_tonic()
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            Some(tracer)
+        }
+        None => None,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => builder
+            .finish()
+            .with(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t)))
+            .init(),
+        // Include the fields of spans like `remap` and `sync` of connections.
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .finish()
+            .with(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t)))
+            .init(),
+    }
+    Ok(())
+}
+
+/// Default `initializationOptions` from `--init-options`.
+struct InitOptions {
+    /// The command name of the server to apply to. Applies to all if `None`.
+    name: Option<String>,
+    value: serde_json::Value,
+}
+
+fn parse_init_options(value: &str) -> Result<InitOptions, String> {
+    let (name, json) = if value.starts_with('{') {
+        (None, value)
+    } else if let Some(i) = value.find('=') {
+        (Some(value[..i].to_owned()), &value[i + 1..])
+    } else {
+        return Err(format!("{} is not a JSON object or <name>=<json>", value));
+    };
+
+    match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(value) if value.is_object() => Ok(InitOptions { name, value }),
+        _ => Err(format!("{} cannot be parsed as JSON object", json)),
+    }
+}
+
+fn parse_config_response(
+    value: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    match serde_json::from_str(value) {
+        Ok(serde_json::Value::Object(presets)) => Ok(presets),
+        _ => Err(format!("{} cannot be parsed as JSON object", value)),
+    }
+}
+
+fn parse_capabilities(value: &str) -> Result<serde_json::Value, String> {
+    match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(value) if value.is_object() => Ok(value),
+        _ => Err(format!("{} cannot be parsed as JSON object", value)),
+    }
+}
+
+// Blocking the lifecycle methods would leave the server unusable.
+fn parse_block_method(value: &str) -> Result<String, String> {
+    match value {
+        "" => Err("method cannot be empty".to_owned()),
+        "initialize" | "initialized" | "shutdown" | "exit" => {
+            Err(format!("{} cannot be blocked", value))
+        }
+        _ => Ok(value.to_owned()),
+    }
+}
+
+// A leading word ending with `:` names the server. Named by the program otherwise.
+fn parse_server(value: &str) -> Result<api::pool::ServerCommand, String> {
+    let mut command = match shlex::split(value) {
+        Some(command) if !command.is_empty() => command,
+        Some(_) => return Err("command cannot be empty".to_owned()),
+        None => return Err(format!("unterminated quote or escape in {}", value)),
+    };
+    match command[0].strip_suffix(':').map(str::to_owned) {
+        Some(_) if command.len() == 1 => Err(format!("{} is missing the command", value)),
+        Some(name) => {
+            command.remove(0);
+            Ok(api::pool::ServerCommand { name, command })
+        }
+        None => Ok(api::pool::ServerCommand::new(command)),
     }
+}
+
+// Listen on `$PORT` if set, as some platforms like Heroku and Cloud Run expect.
+fn default_listen() -> Result<Listen, String> {
+    match std::env::var("PORT") {
+        Ok(port) if !port.is_empty() => parse_listen(&format!("0.0.0.0:{}", port))
+            .map_err(|_| format!("PORT {} is not a valid port", port)),
+        _ => parse_listen("0.0.0.0:9999"),
+    }
+}
+
+// `--remap-prefix` becomes the host of `source://` URIs, so it must be a single segment.
+fn parse_remap_prefix(value: &str) -> Result<String, String> {
+    match Url::parse(&format!("source://{}/", value)) {
+        Ok(uri) if !value.is_empty() && uri.host_str() == Some(value) => Ok(value.to_owned()),
+        _ => Err(format!("{} is not a valid remap prefix", value)),
+    }
+}
+
+// Normalize `--allow-origin` to the serialized origin browsers send.
+fn parse_origin(value: &str) -> Result<String, String> {
+    match Url::parse(value).map(|url| url.origin()) {
+        Ok(origin) if origin.is_tuple() => Ok(origin.ascii_serialization()),
+        _ => Err(format!("{} is not a valid origin", value)),
+    }
+}
+
+// Resolve `--root` to an absolute `file://` URI.
+fn resolve_root(root: &str, cwd: &Url, remap: bool) -> Result<Url, String> {
+    match Url::parse(root) {
+        Ok(uri) if uri.scheme() == "file" => Ok(uri),
+        Ok(uri) if uri.scheme() == "source" => {
+            if !remap {
+                return Err(format!("{} requires --remap", root));
+            }
+            cwd.join(&root["source://".len()..])
+                .map_err(|err| format!("invalid root {}: {}", root, err))
+        }
+        Ok(uri) => Err(format!("unsupported root scheme {}", uri.scheme())),
+        // Relative to the current directory.
+        Err(_) => cwd
+            .to_file_path()
+            .ok()
+            .and_then(|cwd| Url::from_directory_path(cwd.join(root)).ok())
+            .ok_or_else(|| format!("invalid root {}", root)),
+    }
+}
+
+// Merge options for each server so that specific ones take precedence.
+fn resolve_init_options(
+    init_options: &[InitOptions],
+    commands: &[api::pool::ServerCommand],
+) -> HashMap<String, serde_json::Value> {
+    let mut resolved = HashMap::new();
+    for command in commands {
+        let name = &command.name;
+        let global = init_options.iter().filter(|o| o.name.is_none());
+        let specific = init_options
+            .iter()
+            .filter(|o| o.name.as_deref() == Some(name.as_str()));
+        for options in global.chain(specific) {
+            lsp::ext::merge_json(
+                resolved
+                    .entry(name.to_owned())
+                    .or_insert_with(|| serde_json::json!({})),
+                &options.value,
+            );
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(commands: &[&[&str]]) -> Vec<api::pool::ServerCommand> {
+        commands
+            .iter()
+            .map(|c| api::pool::ServerCommand::new(c.iter().map(|s| s.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_server() {
+        assert_eq!(
+            parse_server("typescript-language-server --stdio")
+                .unwrap()
+                .command,
+            ["typescript-language-server", "--stdio"]
+        );
+        assert_eq!(
+            parse_server(r#"sh -c 'exec ra --log "a b.log"' "x y" z\ w"#)
+                .unwrap()
+                .command,
+            ["sh", "-c", r#"exec ra --log "a b.log""#, "x y", "z w"]
+        );
+        assert_eq!(
+            parse_server(r#"cmd "say \"hi\"" '' it\'s"#)
+                .unwrap()
+                .command,
+            ["cmd", r#"say "hi""#, "", "it's"]
+        );
+        assert!(parse_server("").is_err());
+        assert!(parse_server("  ").is_err());
+        assert!(parse_server("sh -c 'unterminated").is_err());
+        assert!(parse_server("sh -c \"unterminated").is_err());
+        let named = parse_server("ts: node 'ts server.js' --stdio").unwrap();
+        assert_eq!(named.name, "ts");
+        assert_eq!(named.command, ["node", "ts server.js", "--stdio"]);
+        assert!(parse_server("ts:").is_err());
+        // Only a leading word ending with `:` is a name.
+        let unnamed = parse_server("sh -c a:").unwrap();
+        assert_eq!(unnamed.name, "sh");
+        assert_eq!(unnamed.command, ["sh", "-c", "a:"]);
+        // Validated with the other commands.
+        let err = validate_commands(&[parse_server("a/b: node").unwrap()]).unwrap_err();
+        assert!(err.contains("invalid server name"));
+    }
+
+    #[test]
+    fn test_validate_commands() {
+        assert!(validate_commands(&commands(&[
+            &["rust-analyzer"],
+            &["typescript-language-server", "--stdio"],
+            &["pyls_1.0"],
+        ]))
+        .is_ok());
+        assert!(validate_commands(&[]).is_ok());
+
+        // Programs outside `PATH` are named by their file names.
+        assert!(validate_commands(&commands(&[
+            &["/usr/local/bin/rust-analyzer"],
+            &["./node_modules/.bin/typescript-language-server", "--stdio"],
+        ]))
+        .is_ok());
+
+        // Servers sharing a program are named with `--server`.
+        assert!(validate_commands(&[
+            parse_server("a: node a.js").unwrap(),
+            parse_server("b: node b.js").unwrap(),
+        ])
+        .is_ok());
 
-    match value.parse::<SocketAddr>() {
-        Ok(_) => Ok(String::from(value)),
-        Err(_) => Err(format!("{} cannot be parsed as SocketAddr", value)),
+        let err = validate_commands(&commands(&[&["sh", "-c", "a"], &["/bin/sh", "-c", "b"]]))
+            .unwrap_err();
+        assert!(err.contains("more than once"));
+        let err = validate_commands(&commands(&[&["rust analyzer"]])).unwrap_err();
+        assert!(err.contains("invalid server name"));
+        assert!(validate_commands(&commands(&[&["/"]])).is_err());
+        assert!(validate_commands(&commands(&[&[]])).is_err());
     }
 }